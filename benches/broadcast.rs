@@ -0,0 +1,127 @@
+//! Broadcasts QoS0 publish with shared encoded packet
+//!
+//! Connects 100 clients to local server and publishes 1k messages to all
+//! of them with `broadcast()` and with `publish().send_at_most_once()` to
+//! every sink. Only publish calls are timed, write buffers are flushed
+//! after every message.
+//!
+//! Run with `cargo bench --bench broadcast`
+use std::{convert::TryFrom, time::Duration, time::Instant};
+
+use futures::future::ok;
+use ntex::server;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::v5::{broadcast, client, codec, Handshake, MqttServer, MqttSink};
+use ntex_mqtt::v5::{Publish, PublishAck};
+
+const CLIENTS: usize = 100;
+const MESSAGES: usize = 1_000;
+const ROUNDS: usize = 10;
+const TOPIC: &str = "devices/device-1/telemetry";
+const PAYLOAD: &[u8] = &[0u8; 256];
+
+#[derive(Debug)]
+struct BenchError;
+
+impl From<()> for BenchError {
+    fn from(_: ()) -> Self {
+        BenchError
+    }
+}
+
+impl TryFrom<BenchError> for PublishAck {
+    type Error = BenchError;
+
+    fn try_from(err: BenchError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let publishes = MESSAGES * CLIENTS;
+    println!(
+        "{}: {} messages to {} clients in {:?}, {:?} per message, {:?} per recipient",
+        name,
+        MESSAGES,
+        CLIENTS,
+        elapsed,
+        elapsed / MESSAGES as u32,
+        elapsed / publishes as u32
+    );
+}
+
+/// Publish messages, waits for flush after every message
+async fn run<F: Fn(&[MqttSink])>(sinks: &[MqttSink], count: usize, f: F) -> Duration {
+    let mut elapsed = Duration::default();
+    for _ in 0..count {
+        let start = Instant::now();
+        f(sinks);
+        elapsed += start.elapsed();
+        for sink in sinks {
+            sink.publish_qos0_flushed(ByteString::from_static(TOPIC), Bytes::new())
+                .await
+                .expect("flush failed");
+        }
+    }
+    elapsed
+}
+
+#[ntex::main]
+async fn main() {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| ok::<_, BenchError>(con.ack(())))
+            .publish(|p: Publish| ok::<_, BenchError>(p.ack()))
+            .finish()
+    });
+
+    let mut sinks = Vec::new();
+    for i in 0..CLIENTS {
+        let client = client::MqttConnector::new(srv.addr())
+            .client_id(format!("bench-{}", i))
+            .connect()
+            .await
+            .expect("connect failed");
+        sinks.push(client.sink());
+        ntex::rt::spawn(client.start_default());
+    }
+
+    let publish = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtMostOnce,
+        topic: ByteString::from_static(TOPIC),
+        packet_id: None,
+        payload: Bytes::from_static(PAYLOAD),
+        properties: Default::default(),
+    };
+    let shared = |sinks: &[MqttSink]| {
+        assert_eq!(broadcast(sinks, &publish), CLIENTS);
+    };
+    let regular = |sinks: &[MqttSink]| {
+        for sink in sinks {
+            sink.publish(ByteString::from_static(TOPIC), Bytes::from_static(PAYLOAD))
+                .send_at_most_once()
+                .expect("publish failed");
+        }
+    };
+
+    // warm up, rounds alternate so both paths see the same buffer state
+    let _ = run(&sinks, MESSAGES / ROUNDS, shared).await;
+    let mut shared_time = Duration::default();
+    let mut regular_time = Duration::default();
+    for round in 0..ROUNDS {
+        if round % 2 == 0 {
+            shared_time += run(&sinks, MESSAGES / ROUNDS, shared).await;
+            regular_time += run(&sinks, MESSAGES / ROUNDS, regular).await;
+        } else {
+            regular_time += run(&sinks, MESSAGES / ROUNDS, regular).await;
+            shared_time += run(&sinks, MESSAGES / ROUNDS, shared).await;
+        }
+    }
+    report("broadcast", shared_time);
+    report("send_at_most_once", regular_time);
+    println!(
+        "broadcast/regular: {:.3}",
+        shared_time.as_secs_f64() / regular_time.as_secs_f64()
+    );
+}
@@ -0,0 +1,130 @@
+//! Connects with shared CONNECT template
+//!
+//! Creates 10k connectors and runs 10k connects to local server with
+//! connectors created from shared template and with connectors that build
+//! CONNECT packet from scratch.
+//!
+//! Run with `cargo bench --bench connect`
+use std::{convert::TryFrom, net::SocketAddr, rc::Rc, time::Duration, time::Instant};
+
+use futures::future::ok;
+use ntex::util::{ByteString, Bytes};
+use ntex::{connect, server, time::Seconds};
+use ntex_mqtt::v5::{client, codec, Handshake, MqttServer, PublishAck};
+
+const CONNECTS: usize = 10_000;
+const ROUNDS: usize = 10;
+const USER_PROPERTIES: usize = 16;
+
+#[derive(Debug)]
+struct BenchError;
+
+impl From<()> for BenchError {
+    fn from(_: ()) -> Self {
+        BenchError
+    }
+}
+
+impl TryFrom<BenchError> for PublishAck {
+    type Error = BenchError;
+
+    fn try_from(err: BenchError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+fn user_properties() -> codec::UserProperties {
+    (0..USER_PROPERTIES)
+        .map(|i| {
+            (ByteString::from(format!("key-{}", i)), ByteString::from(format!("value-{}", i)))
+        })
+        .collect()
+}
+
+type Connector = client::MqttConnector<SocketAddr, connect::Connector<SocketAddr>>;
+
+fn report(name: &str, what: &str, elapsed: Duration) {
+    println!(
+        "{}: {} {} in {:?}, {:?} each",
+        name,
+        CONNECTS,
+        what,
+        elapsed,
+        elapsed / CONNECTS as u32
+    );
+}
+
+/// Create connectors, io is not involved
+fn build<F: Fn(usize) -> Connector>(name: &str, f: F) -> Duration {
+    let start = Instant::now();
+    for idx in 0..CONNECTS {
+        drop(f(idx));
+    }
+    let elapsed = start.elapsed();
+    report(name, "connectors", elapsed);
+    elapsed
+}
+
+/// Create connectors and connect to server
+async fn run<F: Fn(usize) -> Connector>(count: usize, f: F) -> Duration {
+    let start = Instant::now();
+    for idx in 0..count {
+        let client = f(idx).connect().await.expect("connect failed");
+        drop(client);
+    }
+    start.elapsed()
+}
+
+#[ntex::main]
+async fn main() {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| ok::<_, BenchError>(con.ack(()))).finish()
+    });
+    let addr = srv.addr();
+
+    let template = Rc::new(codec::Connect {
+        keep_alive: 30,
+        user_properties: user_properties(),
+        ..codec::Connect::default()
+    });
+    let builder = |idx: usize| {
+        let id = ByteString::from(format!("device-{}", idx));
+        client::MqttConnector::new(addr)
+            .keep_alive(Seconds(30))
+            .properties(|props| *props = user_properties())
+            .client_id(id.clone())
+            .username(id)
+            .password(Bytes::from_static(b"secret"))
+    };
+    let shared = |idx: usize| {
+        let id = ByteString::from(format!("device-{}", idx));
+        client::MqttConnector::from_template(addr, template.clone())
+            .client_id(id.clone())
+            .username(id)
+            .password(Bytes::from_static(b"secret"))
+    };
+
+    // connector setup, template is not copied
+    let built = build("builder", builder);
+    let templated = build("template", shared);
+    println!("setup template/builder: {:.3}", templated.as_secs_f64() / built.as_secs_f64());
+
+    // connects, CONNECT packet is copied once per connect in both cases.
+    // rounds alternate, so both variants see the same number of sockets
+    // in TIME_WAIT state
+    let _ = run(CONNECTS / ROUNDS, builder).await;
+    let mut built = Duration::default();
+    let mut templated = Duration::default();
+    for round in 0..ROUNDS {
+        if round % 2 == 0 {
+            built += run(CONNECTS / ROUNDS, builder).await;
+            templated += run(CONNECTS / ROUNDS, shared).await;
+        } else {
+            templated += run(CONNECTS / ROUNDS, shared).await;
+            built += run(CONNECTS / ROUNDS, builder).await;
+        }
+    }
+    report("builder", "connects", built);
+    report("template", "connects", templated);
+    println!("connect template/builder: {:.3}", templated.as_secs_f64() / built.as_secs_f64());
+}
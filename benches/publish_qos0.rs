@@ -0,0 +1,107 @@
+//! Encodes QoS0 publishes with raw and regular path
+//!
+//! Publishes 100k QoS0 packets to local server with
+//! `MqttSink::publish_qos0_raw()` and with `publish().send_at_most_once()`.
+//! Only publish calls are timed, write buffer is flushed after every batch
+//! of 10 publishes, so batch fits into write buffer of memory pool and
+//! buffer growth does not dominate encoding.
+//!
+//! Run with `cargo bench --bench publish_qos0`
+use std::{convert::TryFrom, time::Duration, time::Instant};
+
+use futures::future::ok;
+use ntex::server;
+use ntex::util::{ByteString, Bytes};
+use ntex_mqtt::v5::{client, Handshake, MqttServer, MqttSink, Publish, PublishAck};
+
+const PUBLISHES: usize = 100_000;
+const BATCH: usize = 10;
+const ROUNDS: usize = 10;
+const TOPIC: &str = "devices/device-1/telemetry";
+const PAYLOAD: &[u8] = &[0u8; 256];
+
+#[derive(Debug)]
+struct BenchError;
+
+impl From<()> for BenchError {
+    fn from(_: ()) -> Self {
+        BenchError
+    }
+}
+
+impl TryFrom<BenchError> for PublishAck {
+    type Error = BenchError;
+
+    fn try_from(err: BenchError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!(
+        "{}: {} publishes in {:?}, {:?} each",
+        name,
+        PUBLISHES,
+        elapsed,
+        elapsed / PUBLISHES as u32
+    );
+}
+
+/// Publish batches, waits for flush after every batch
+async fn run<F: Fn(&MqttSink)>(sink: &MqttSink, count: usize, f: F) -> Duration {
+    let mut elapsed = Duration::default();
+    for _ in 0..count / BATCH {
+        let start = Instant::now();
+        for _ in 0..BATCH {
+            f(sink);
+        }
+        elapsed += start.elapsed();
+        sink.publish_qos0_flushed(ByteString::from_static(TOPIC), Bytes::from_static(PAYLOAD))
+            .await
+            .expect("flush failed");
+    }
+    elapsed
+}
+
+#[ntex::main]
+async fn main() {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| ok::<_, BenchError>(con.ack(())))
+            .publish(|p: Publish| ok::<_, BenchError>(p.ack()))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("bench")
+        .connect()
+        .await
+        .expect("connect failed");
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let raw = |sink: &MqttSink| {
+        sink.publish_qos0_raw(TOPIC, PAYLOAD).expect("publish failed");
+    };
+    let regular = |sink: &MqttSink| {
+        sink.publish(ByteString::from_static(TOPIC), Bytes::copy_from_slice(PAYLOAD))
+            .send_at_most_once()
+            .expect("publish failed");
+    };
+
+    // warm up, rounds alternate so both paths see the same buffer state
+    let _ = run(&sink, PUBLISHES / ROUNDS, raw).await;
+    let mut raw_time = Duration::default();
+    let mut regular_time = Duration::default();
+    for round in 0..ROUNDS {
+        if round % 2 == 0 {
+            raw_time += run(&sink, PUBLISHES / ROUNDS, raw).await;
+            regular_time += run(&sink, PUBLISHES / ROUNDS, regular).await;
+        } else {
+            regular_time += run(&sink, PUBLISHES / ROUNDS, regular).await;
+            raw_time += run(&sink, PUBLISHES / ROUNDS, raw).await;
+        }
+    }
+    report("publish_qos0_raw", raw_time);
+    report("send_at_most_once", regular_time);
+    println!("raw/regular: {:.3}", raw_time.as_secs_f64() / regular_time.as_secs_f64());
+}
@@ -0,0 +1,81 @@
+//! Counts write buffer reallocations under publish burst
+//!
+//! Encodes bursts of 200 QoS0 publishes with 256 bytes payload into
+//! connection write buffer, with default memory pool write params and with
+//! write params set by `write_buffer_capacity(64 * 1024)`. Write buffer is
+//! not flushed during burst, every capacity change is counted
+//! as reallocation.
+//!
+//! Run with `cargo bench --bench write_capacity`
+use std::time::{Duration, Instant};
+
+use ntex::framed::State;
+use ntex::util::{ByteString, Bytes, PoolId, PoolRef};
+use ntex_mqtt::v5::codec;
+
+const BURST: usize = 200;
+const ROUNDS: usize = 1_000;
+const TOPIC: &str = "devices/device-1/telemetry";
+const PAYLOAD: &[u8] = &[0u8; 256];
+
+/// Encode bursts, returns number of reallocations and encoding time
+fn run(pool: PoolRef) -> (usize, Duration) {
+    let codec = codec::Codec::new();
+    let mut reallocs = 0;
+    let mut elapsed = Duration::default();
+
+    for _ in 0..ROUNDS {
+        let state = State::with_memory_pool(pool);
+        let write = state.write();
+        let mut cap = write.with_buf(|buf| buf.capacity());
+
+        let start = Instant::now();
+        for _ in 0..BURST {
+            let pkt = codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from_static(TOPIC),
+                packet_id: None,
+                payload: Bytes::from_static(PAYLOAD),
+                properties: Default::default(),
+            };
+            let _ = write.encode(codec::Packet::Publish(pkt), &codec).expect("encode failed");
+            let new_cap = write.with_buf(|buf| buf.capacity());
+            if new_cap != cap {
+                reallocs += 1;
+                cap = new_cap;
+            }
+        }
+        elapsed += start.elapsed();
+    }
+    (reallocs, elapsed)
+}
+
+fn report(name: &str, reallocs: usize, elapsed: Duration) {
+    println!(
+        "{}: {:.2} reallocations per burst, {:?} per burst",
+        name,
+        reallocs as f64 / ROUNDS as f64,
+        elapsed / ROUNDS as u32
+    );
+}
+
+#[ntex::main]
+async fn main() {
+    // default write params, 4Kb
+    let default = PoolId::P6.pool_ref();
+    // write params as set by `write_buffer_capacity(64 * 1024)`
+    let sized = PoolId::P7.pool_ref();
+    let (_, lw) = sized.write_params().unpack();
+    sized.set_write_params(u16::MAX, lw as u16);
+
+    // warm up
+    let _ = run(default);
+    let _ = run(sized);
+
+    let (default_reallocs, default_time) = run(default);
+    let (sized_reallocs, sized_time) = run(sized);
+    report("default capacity", default_reallocs, default_time);
+    report("64Kb capacity", sized_reallocs, sized_time);
+}
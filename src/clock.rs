@@ -0,0 +1,203 @@
+//! Time source for timers
+//!
+//! By default timers use runtime time. Manual clock allows to advance time
+//! explicitly, which makes timeout handling deterministic in tests.
+//!
+//! Clock drives v5 client timers: handshake timeout, keep-alive and its
+//! backoff, connection ttl, circuit breaker cooldown and request timeout of
+//! `RequestMux`. Server clock set with `MqttServer::clock()` drives
+//! dispatcher keep-alive of idle connections, auth timeout and max
+//! connection age.
+use std::task::{Context, Poll, Waker};
+use std::{cell::Cell, cell::RefCell, fmt, future::Future, pin::Pin, rc::Rc, time};
+
+use ntex::time::{sleep, Millis, Sleep};
+use ntex::util::HashMap;
+
+/// Time source
+#[derive(Clone)]
+pub struct Clock(Option<Rc<Manual>>);
+
+struct Manual {
+    now: Cell<time::Instant>,
+    /// Waker slot per pending sleep, removed once sleep completes or drops
+    waiters: RefCell<HashMap<usize, Waker>>,
+    next_key: Cell<usize>,
+}
+
+impl Clock {
+    /// Create clock that uses runtime time
+    pub fn system() -> Self {
+        Clock(None)
+    }
+
+    /// Create manual clock, time changes only with `advance()` call
+    pub fn manual() -> Self {
+        Clock(Some(Rc::new(Manual {
+            now: Cell::new(time::Instant::now()),
+            waiters: RefCell::new(HashMap::default()),
+            next_key: Cell::new(0),
+        })))
+    }
+
+    /// Check if clock is manual
+    pub fn is_manual(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Current time
+    pub fn now(&self) -> time::Instant {
+        match self.0 {
+            Some(ref inner) => inner.now.get(),
+            None => time::Instant::now(),
+        }
+    }
+
+    /// Advance manual clock and wake up elapsed timers
+    ///
+    /// Panics if clock is not manual.
+    pub fn advance<T: Into<Millis>>(&self, dur: T) {
+        let inner = self.0.as_ref().expect("Clock is not manual");
+        inner.now.set(inner.now.get() + time::Duration::from(dur.into()));
+        let wakers: Vec<_> = inner.waiters.borrow().values().cloned().collect();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Create future that completes after `dur` time
+    pub fn sleep<T: Into<Millis>>(&self, dur: T) -> ClockSleep {
+        let dur = dur.into();
+        match self.0 {
+            Some(ref inner) => ClockSleep(SleepInner::Manual {
+                inner: inner.clone(),
+                deadline: inner.now.get() + time::Duration::from(dur),
+                key: None,
+            }),
+            None => ClockSleep(SleepInner::System(sleep(dur))),
+        }
+    }
+
+    /// Create future that completes at `deadline`
+    pub fn sleep_until(&self, deadline: time::Instant) -> ClockSleep {
+        match self.0 {
+            Some(ref inner) => {
+                ClockSleep(SleepInner::Manual { inner: inner.clone(), deadline, key: None })
+            }
+            None => {
+                let dur = deadline.saturating_duration_since(time::Instant::now());
+                ClockSleep(SleepInner::System(sleep(Millis(dur.as_millis() as u64))))
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn waiters(&self) -> usize {
+        self.0.as_ref().map(|inner| inner.waiters.borrow().len()).unwrap_or(0)
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::system()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clock").field("manual", &self.0.is_some()).finish()
+    }
+}
+
+/// Sleep future created by `Clock::sleep()`
+pub struct ClockSleep(SleepInner);
+
+enum SleepInner {
+    System(Sleep),
+    Manual { inner: Rc<Manual>, deadline: time::Instant, key: Option<usize> },
+}
+
+impl Future for ClockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.get_mut().0 {
+            SleepInner::System(ref sleep) => sleep.poll_elapsed(cx),
+            SleepInner::Manual { ref inner, deadline, ref mut key } => {
+                let mut waiters = inner.waiters.borrow_mut();
+                if inner.now.get() >= deadline {
+                    if let Some(key) = key.take() {
+                        waiters.remove(&key);
+                    }
+                    Poll::Ready(())
+                } else {
+                    match key.and_then(|key| waiters.get_mut(&key)) {
+                        Some(waker) => {
+                            if !waker.will_wake(cx.waker()) {
+                                *waker = cx.waker().clone();
+                            }
+                        }
+                        None => {
+                            let next = inner.next_key.get();
+                            inner.next_key.set(next.wrapping_add(1));
+                            waiters.insert(next, cx.waker().clone());
+                            *key = Some(next);
+                        }
+                    }
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ClockSleep {
+    fn drop(&mut self) {
+        if let SleepInner::Manual { ref inner, key: Some(key), .. } = self.0 {
+            inner.waiters.borrow_mut().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntex::util::lazy;
+
+    #[ntex::test]
+    async fn test_manual_clock() {
+        let clock = Clock::manual();
+        let start = clock.now();
+        let mut fut = Box::pin(clock.sleep(Millis(1000)));
+
+        assert!(lazy(|cx| fut.as_mut().poll(cx)).await.is_pending());
+        clock.advance(Millis(500));
+        assert!(lazy(|cx| fut.as_mut().poll(cx)).await.is_pending());
+        clock.advance(Millis(500));
+        assert!(lazy(|cx| fut.as_mut().poll(cx)).await.is_ready());
+        assert_eq!(clock.now() - start, time::Duration::from_millis(1000));
+    }
+
+    #[ntex::test]
+    async fn test_manual_clock_waiters() {
+        let clock = Clock::manual();
+        let mut fut = Box::pin(clock.sleep(Millis(1000)));
+
+        // repeated polls reuse waker slot
+        for _ in 0..10 {
+            assert!(lazy(|cx| fut.as_mut().poll(cx)).await.is_pending());
+        }
+        assert_eq!(clock.waiters(), 1);
+
+        let mut fut2 = Box::pin(clock.sleep_until(clock.now() + time::Duration::from_secs(2)));
+        assert!(lazy(|cx| fut2.as_mut().poll(cx)).await.is_pending());
+        assert_eq!(clock.waiters(), 2);
+
+        // completed and dropped sleeps release slots
+        clock.advance(Millis(1000));
+        assert!(lazy(|cx| fut.as_mut().poll(cx)).await.is_ready());
+        assert_eq!(clock.waiters(), 1);
+        drop(fut2);
+        assert_eq!(clock.waiters(), 0);
+    }
+}
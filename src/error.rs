@@ -32,6 +32,9 @@ pub enum ProtocolError {
     /// Packet id of publish ack packet does not match of send publish packet
     #[display(fmt = "Packet id of publish ack packet does not match of send publish packet")]
     PacketIdMismatch,
+    /// Number of ack reason codes does not match number of topic filters
+    #[display(fmt = "Number of ack reason codes does not match number of topic filters")]
+    ReasonCodesMismatch,
     /// Topic alias is greater than max topic alias
     #[display(fmt = "Topic alias is greater than max topic alias")]
     MaxTopicAlias,
@@ -84,6 +87,15 @@ impl From<Either<DecodeError, io::Error>> for ProtocolError {
     }
 }
 
+impl From<Either<EncodeError, io::Error>> for ProtocolError {
+    fn from(err: Either<EncodeError, io::Error>) -> Self {
+        match err {
+            Either::Left(err) => ProtocolError::Encode(err),
+            Either::Right(err) => ProtocolError::Io(err),
+        }
+    }
+}
+
 #[derive(Debug, Display, From)]
 pub enum DecodeError {
     InvalidProtocol,
@@ -96,7 +108,32 @@ pub enum DecodeError {
     UnsupportedPacketType,
     // MQTT v3 only
     PacketIdRequired,
-    MaxSizeExceeded,
+    /// Receive maximum property is set to 0, MQTT v5 only
+    ZeroReceiveMax,
+    /// Packet size exceeds max inbound size, contains packet's remaining
+    /// length and configured limit
+    #[display(fmt = "MaxSizeExceeded(size: {}, max: {})", size, max)]
+    #[from(ignore)]
+    MaxSizeExceeded {
+        size: u32,
+        max: u32,
+    },
+    /// Read buffer size exceeds configured limit, contains buffered size
+    /// and limit
+    #[display(fmt = "ReadBufferExceeded(size: {}, max: {})", size, max)]
+    #[from(ignore)]
+    ReadBufferExceeded {
+        size: usize,
+        max: usize,
+    },
+    /// Last will topic and message size exceeds configured limit, contains
+    /// will size and limit, MQTT v5 only
+    #[display(fmt = "WillSizeExceeded(size: {}, max: {})", size, max)]
+    #[from(ignore)]
+    WillSizeExceeded {
+        size: usize,
+        max: u32,
+    },
     Utf8Error(std::str::Utf8Error),
 }
 
@@ -125,7 +162,19 @@ impl PartialEq for DecodeError {
             (DecodeError::InvalidClientId, DecodeError::InvalidClientId) => true,
             (DecodeError::UnsupportedPacketType, DecodeError::UnsupportedPacketType) => true,
             (DecodeError::PacketIdRequired, DecodeError::PacketIdRequired) => true,
-            (DecodeError::MaxSizeExceeded, DecodeError::MaxSizeExceeded) => true,
+            (DecodeError::ZeroReceiveMax, DecodeError::ZeroReceiveMax) => true,
+            (
+                DecodeError::MaxSizeExceeded { size: s1, max: m1 },
+                DecodeError::MaxSizeExceeded { size: s2, max: m2 },
+            ) => s1 == s2 && m1 == m2,
+            (
+                DecodeError::ReadBufferExceeded { size: s1, max: m1 },
+                DecodeError::ReadBufferExceeded { size: s2, max: m2 },
+            ) => s1 == s2 && m1 == m2,
+            (
+                DecodeError::WillSizeExceeded { size: s1, max: m1 },
+                DecodeError::WillSizeExceeded { size: s2, max: m2 },
+            ) => s1 == s2 && m1 == m2,
             (DecodeError::MalformedPacket, DecodeError::MalformedPacket) => true,
             (DecodeError::Utf8Error(_), _) => false,
             _ => false,
@@ -140,9 +189,15 @@ pub enum SendPacketError {
     /// Provided packet id is in use
     #[display(fmt = "Provided packet id is in use")]
     PacketIdInUse(u16),
+    /// Topic alias is greater than peer's topic alias maximum
+    #[display(fmt = "Topic alias is greater than peer's topic alias maximum")]
+    TopicAliasInvalid,
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
+    /// Publish is dropped by outbound queue overflow policy
+    #[display(fmt = "Publish is dropped by outbound queue")]
+    Dropped,
 }
 
 impl error::Error for SendPacketError {}
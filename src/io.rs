@@ -1,13 +1,17 @@
 //! Framed transport dispatcher
 use std::task::{Context, Poll};
-use std::{cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc, time};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, future::Future, pin::Pin, rc::Rc};
+use std::{cmp, fmt, io, time};
 
 pub(crate) use ntex::framed::{DispatchItem, ReadTask, State, Timer, Write, WriteTask};
 
-use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder};
+use ntex::channel::condition::Condition;
+use ntex::codec::{AsyncRead, AsyncWrite, Decoder, Encoder, ReadBuf};
 use ntex::service::{IntoService, Service};
 use ntex::{time::Seconds, util::Either, util::Pool};
 
+use crate::clock::{Clock, ClockSleep};
+
 type Response<U> = <U as Encoder>::Item;
 
 pin_project_lite::pin_project! {
@@ -27,7 +31,7 @@ pin_project_lite::pin_project! {
         inner: Rc<RefCell<DispatcherState<S, U>>>,
         st: IoDispatcherState,
         pool: Pool,
-        timer: Timer,
+        timer: KeepAliveTimer,
         updated: time::Instant,
         keepalive_timeout: Seconds,
         #[pin]
@@ -118,11 +122,11 @@ where
     ) -> Self
     where
         T: AsyncRead + AsyncWrite + Unpin + 'static,
+        U: FlushSource,
     {
+        let mut timer = KeepAliveTimer::new(timer);
         let updated = timer.now();
         let keepalive_timeout = Seconds(30);
-        let io = Rc::new(RefCell::new(io));
-
         // register keepalive timer
         let expire = updated + time::Duration::from(keepalive_timeout);
         timer.register(expire, expire, &state);
@@ -134,8 +138,28 @@ where
         }));
 
         // start support tasks
-        ntex::rt::spawn(ReadTask::new(io.clone(), state.clone()));
-        ntex::rt::spawn(WriteTask::new(io, state.clone()));
+        let limit = codec.read_limit();
+        if let Some(ref limit) = limit {
+            // handshake could leave unprocessed data in read buffer
+            limit.buffered.set(state.read().with_buf(|buf| buf.len()));
+        }
+        let stats = codec.flush_stats();
+        if let Some(ref stats) = stats {
+            // handshake writes directly to io stream, bypassing write counter
+            let buffered = state.write().with_buf(|buf| buf.len()) as u64;
+            stats.written.set(codec.encoded().saturating_sub(buffered));
+        }
+        match (stats, limit) {
+            (Some(stats), Some(limit)) => {
+                let io = FlushCounter { io, stats, written: false, blocked: false };
+                start(ReadCap { io, limit }, &state)
+            }
+            (Some(stats), None) => {
+                start(FlushCounter { io, stats, written: false, blocked: false }, &state)
+            }
+            (None, Some(limit)) => start(ReadCap { io, limit }, &state),
+            (None, None) => start(io, &state),
+        }
 
         Dispatcher {
             st: IoDispatcherState::Processing,
@@ -171,6 +195,23 @@ where
         self
     }
 
+    /// Set clock of keep-alive timer.
+    ///
+    /// By default keep-alive timer uses runtime time.
+    pub(crate) fn clock(mut self, clock: Clock) -> Self {
+        let ka = time::Duration::from(self.keepalive_timeout);
+        if self.keepalive_timeout.non_zero() {
+            self.timer.unregister(self.updated + ka, &self.state);
+        }
+        self.timer.clock = clock;
+        self.updated = self.timer.now();
+        if self.keepalive_timeout.non_zero() {
+            let expire = self.updated + ka;
+            self.timer.register(expire, expire, &self.state);
+        }
+        self
+    }
+
     /// Set connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -185,6 +226,255 @@ where
     }
 }
 
+/// Write task turns counters
+#[derive(Default)]
+pub(crate) struct FlushStats {
+    /// Turns that flushed whole write buffer
+    pub(crate) full: Cell<usize>,
+    /// Turns that left data in write buffer, io stream is not writable
+    pub(crate) partial: Cell<usize>,
+    /// Bytes written to io stream
+    pub(crate) written: Cell<u64>,
+    /// Notified after every turn that wrote data to io stream
+    pub(crate) flushed: Condition,
+}
+
+impl fmt::Debug for FlushStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FlushStats")
+            .field("full", &self.full)
+            .field("partial", &self.partial)
+            .field("written", &self.written)
+            .finish()
+    }
+}
+
+/// Read buffer limit
+///
+/// Codec accounts bytes consumed by decoder, io stream accounts bytes read
+/// from socket and stops reading once read buffer exceeds limit.
+#[derive(Debug, Default)]
+pub(crate) struct ReadLimit {
+    /// Max size of read buffer, `0` means unlimited
+    pub(crate) max: Cell<usize>,
+    /// Bytes in read buffer
+    pub(crate) buffered: Cell<usize>,
+}
+
+impl ReadLimit {
+    /// Bytes are consumed from read buffer by decoder
+    pub(crate) fn consumed(&self, size: usize) {
+        self.buffered.set(self.buffered.get().saturating_sub(size));
+    }
+}
+
+/// Connection codec that provides flush counters and read buffer limit
+pub(crate) trait FlushSource {
+    /// Counters for write task flushes, `None` if flushes are not recorded
+    fn flush_stats(&self) -> Option<Rc<FlushStats>>;
+
+    /// Read buffer limit, `None` if read buffer is not limited
+    fn read_limit(&self) -> Option<Rc<ReadLimit>> {
+        None
+    }
+
+    /// Total number of bytes encoded to write buffer
+    fn encoded(&self) -> u64 {
+        0
+    }
+}
+
+impl<T: FlushSource> FlushSource for Rc<T> {
+    fn flush_stats(&self) -> Option<Rc<FlushStats>> {
+        (**self).flush_stats()
+    }
+
+    fn read_limit(&self) -> Option<Rc<ReadLimit>> {
+        (**self).read_limit()
+    }
+
+    fn encoded(&self) -> u64 {
+        (**self).encoded()
+    }
+}
+
+/// Io stream that does not read more than read buffer limit
+///
+/// Read task reads from io stream until it is not readable, so read
+/// buffer could grow far beyond limit before decoder gets chance to check
+/// it. Reads are capped, so buffer holds at most one byte over limit and
+/// decoder fails with `ReadBufferExceeded` error.
+struct ReadCap<T> {
+    io: T,
+    limit: Rc<ReadLimit>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReadCap<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let max = self.limit.max.get();
+        if max == 0 {
+            return Pin::new(&mut self.io).poll_read(cx, buf);
+        }
+        let buffered = self.limit.buffered.get();
+        if buffered > max {
+            // decoder fails on next decode, connection gets closed
+            return Poll::Pending;
+        }
+
+        let size = cmp::min(buf.remaining(), max + 1 - buffered);
+        let mut capped = buf.take(size);
+        let result = Pin::new(&mut self.io).poll_read(cx, &mut capped);
+        let n = capped.filled().len();
+        // safety: io stream initialized and filled `n` bytes of capped buffer
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        self.limit.buffered.set(buffered + n);
+        result
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ReadCap<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Io stream that counts flushes of write task and written bytes
+///
+/// Write task writes buffer until io stream is not writable and then
+/// flushes it, every flush ends write task turn.
+struct FlushCounter<T> {
+    io: T,
+    stats: Rc<FlushStats>,
+    written: bool,
+    blocked: bool,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for FlushCounter<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for FlushCounter<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.io).poll_write(cx, buf);
+        self.written = true;
+        match result {
+            Poll::Ready(Ok(n)) => self.stats.written.set(self.stats.written.get() + n as u64),
+            Poll::Pending => self.blocked = true,
+            Poll::Ready(Err(_)) => (),
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let result = Pin::new(&mut self.io).poll_flush(cx);
+        if self.written {
+            let counter = if self.blocked || result.is_pending() {
+                &self.stats.partial
+            } else {
+                &self.stats.full
+            };
+            counter.set(counter.get() + 1);
+            self.written = false;
+            self.blocked = false;
+            self.stats.flushed.notify();
+        }
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+/// Keep-alive timer of dispatcher
+///
+/// With runtime time keep-alive is tracked by shared io timer, it sets
+/// keep-alive flag of io state. With manual clock dispatcher polls
+/// clock sleep instead.
+struct KeepAliveTimer {
+    timer: Timer,
+    clock: Clock,
+    sleep: Option<ClockSleep>,
+}
+
+impl KeepAliveTimer {
+    fn new(timer: Timer) -> Self {
+        KeepAliveTimer { timer, clock: Clock::system(), sleep: None }
+    }
+
+    fn now(&self) -> time::Instant {
+        if self.clock.is_manual() {
+            self.clock.now()
+        } else {
+            self.timer.now()
+        }
+    }
+
+    fn register(&mut self, expire: time::Instant, previous: time::Instant, state: &State) {
+        if self.clock.is_manual() {
+            self.sleep = Some(self.clock.sleep_until(expire));
+        } else {
+            self.timer.register(expire, previous, state);
+        }
+    }
+
+    fn unregister(&mut self, expire: time::Instant, state: &State) {
+        if self.clock.is_manual() {
+            self.sleep = None;
+        } else {
+            self.timer.unregister(expire, state);
+        }
+    }
+
+    /// Check if keep-alive of manual clock is expired
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        if let Some(ref mut sleep) = self.sleep {
+            if Pin::new(sleep).poll(cx).is_ready() {
+                self.sleep = None;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Start io read and write tasks
+fn start<T>(io: T, state: &State)
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    let io = Rc::new(RefCell::new(io));
+    ntex::rt::spawn(ReadTask::new(io.clone(), state.clone()));
+    ntex::rt::spawn(WriteTask::new(io, state.clone()));
+}
+
 impl<S, U> DispatcherState<S, U>
 where
     S: Service<Request = DispatchItem<U>, Response = Option<Response<U>>>,
@@ -207,17 +497,13 @@ where
         if idx == 0 {
             let _ = self.queue.pop_front();
             self.base = self.base.wrapping_add(1);
-            if let Err(err) = write.encode_result(item, codec) {
-                self.error = Some(err.into());
-            }
+            self.encode_result(item, write, codec);
 
             // check remaining response
             while let Some(item) = self.queue.front_mut().and_then(|v| v.take()) {
                 let _ = self.queue.pop_front();
                 self.base = self.base.wrapping_add(1);
-                if let Err(err) = write.encode_result(item, codec) {
-                    self.error = Some(err.into());
-                }
+                self.encode_result(item, write, codec);
             }
 
             if wake && self.queue.is_empty() {
@@ -227,6 +513,20 @@ where
             self.queue[idx] = ServiceResult::Ready(item);
         }
     }
+
+    fn encode_result(
+        &mut self,
+        item: Result<S::Response, S::Error>,
+        write: Write<'_>,
+        codec: &U,
+    ) {
+        match write.encode_result(item, codec) {
+            Ok(true) => (),
+            // write buffer is full, dispatcher stops reading until data is flushed
+            Ok(false) => write.enable_backpressure(None),
+            Err(err) => self.error = Some(err.into()),
+        }
+    }
 }
 
 impl<S, U> Future for Dispatcher<S, U>
@@ -280,7 +580,7 @@ where
                             read.resume();
 
                             // check keepalive timeout
-                            if this.state.is_keepalive() {
+                            if this.state.is_keepalive() || this.timer.poll_expired(cx) {
                                 log::trace!("keepalive timeout");
                                 let mut inner = this.inner.borrow_mut();
                                 if inner.error.is_none() {
@@ -321,6 +621,11 @@ where
                                     *this.st = IoDispatcherState::Stop;
                                     item
                                 }
+                            } else if !write.is_ready() {
+                                // write buffer is full, wait until write task flushes data
+                                log::trace!("write buffer is full, register dispatch task");
+                                write.enable_backpressure(Some(cx.waker()));
+                                return Poll::Pending;
                             } else {
                                 // decode incoming bytes stream
                                 if read.is_ready() {
@@ -388,11 +693,7 @@ where
                                     if let Poll::Ready(res) = res {
                                         // check if current result is only response atm
                                         if inner.queue.is_empty() {
-                                            if let Err(err) =
-                                                write.encode_result(res, this.codec)
-                                            {
-                                                inner.error = Some(err.into());
-                                            }
+                                            inner.encode_result(res, write, this.codec);
                                         } else {
                                             *this.response_idx = response_idx;
                                             inner.queue.push_back(ServiceResult::Ready(res));
@@ -528,7 +829,7 @@ mod tests {
         where
             T: AsyncRead + AsyncWrite + Unpin + 'static,
         {
-            let timer = Timer::new(Millis::ONE_SEC);
+            let timer = KeepAliveTimer::new(Timer::new(Millis::ONE_SEC));
             let keepalive_timeout = Seconds(30);
             let updated = timer.now();
             let io = Rc::new(RefCell::new(io));
@@ -758,4 +1059,122 @@ mod tests {
         // service must be checked for readiness only once
         assert_eq!(counter.get(), 1);
     }
+
+    #[ntex::test]
+    async fn test_write_backpressure() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+        client.write("test");
+
+        // write task enables back-pressure if write buffer is full
+        let state = State::new();
+        state.write().enable_backpressure(None);
+
+        let calls = Rc::new(std::cell::Cell::new(0));
+        let calls2 = calls.clone();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            state.clone(),
+            ntex::service::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                calls2.set(calls2.get() + 1);
+                async move {
+                    if let DispatchItem::Item(msg) = msg {
+                        Ok::<_, ()>(Some(msg.freeze()))
+                    } else {
+                        panic!()
+                    }
+                }
+            }),
+        );
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+        });
+
+        // incoming data is not processed until write buffer is flushed
+        sleep(Millis(50)).await;
+        assert_eq!(calls.get(), 0);
+
+        // write task resets back-pressure after flush
+        state.write().encode(Bytes::from_static(b"data"), &BytesCodec).unwrap();
+        sleep(Millis(50)).await;
+        assert_eq!(calls.get(), 1);
+
+        let buf = client.read().await.unwrap();
+        assert_eq!(buf, Bytes::from_static(b"datatest"));
+    }
+
+    #[ntex::test]
+    async fn test_keepalive_manual_clock() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+
+        let clock = Clock::manual();
+        let expired = Rc::new(Cell::new(false));
+        let expired2 = expired.clone();
+        let disp = Dispatcher::new(
+            server,
+            BytesCodec,
+            State::new(),
+            ntex::service::fn_service(move |msg: DispatchItem<BytesCodec>| {
+                if let DispatchItem::KeepAliveTimeout = msg {
+                    expired2.set(true);
+                }
+                async { Ok::<_, ()>(None) }
+            }),
+        )
+        .keepalive_timeout(Seconds(1))
+        .clock(clock.clone());
+        ntex::rt::spawn(async move {
+            let _ = disp.await;
+        });
+
+        // runtime time does not expire keep-alive
+        sleep(Millis(1100)).await;
+        assert!(!expired.get());
+
+        clock.advance(Millis(1000));
+        sleep(Millis(50)).await;
+        assert!(expired.get());
+    }
+
+    #[ntex::test]
+    async fn test_read_cap() {
+        let (client, server) = Io::create();
+        client.remote_buffer_cap(1024);
+        client.write("0123456789abcdef");
+
+        let limit = Rc::new(ReadLimit::default());
+        limit.max.set(8);
+        limit.buffered.set(2);
+        let mut io = ReadCap { io: server, limit: limit.clone() };
+
+        // read stops one byte over limit
+        let mut data = [0u8; 64];
+        let mut buf = ReadBuf::new(&mut data);
+        let res = ntex::util::poll_fn(|cx| {
+            Poll::Ready(Pin::new(&mut io).poll_read(cx, &mut buf).map(|r| r.is_ok()))
+        })
+        .await;
+        assert_eq!(res, Poll::Ready(true));
+        assert_eq!(buf.filled(), b"0123456");
+        assert_eq!(limit.buffered.get(), 9);
+        let res = ntex::util::poll_fn(|cx| {
+            Poll::Ready(Pin::new(&mut io).poll_read(cx, &mut buf).map(|r| r.is_ok()))
+        })
+        .await;
+        assert!(res.is_pending());
+
+        // consumed bytes free up room
+        limit.consumed(4);
+        let mut data = [0u8; 64];
+        let mut buf = ReadBuf::new(&mut data);
+        let res = ntex::util::poll_fn(|cx| {
+            Poll::Ready(Pin::new(&mut io).poll_read(cx, &mut buf).map(|r| r.is_ok()))
+        })
+        .await;
+        assert_eq!(res, Poll::Ready(true));
+        assert_eq!(buf.filled(), b"789a");
+        assert_eq!(limit.buffered.get(), 9);
+    }
 }
@@ -9,11 +9,14 @@ mod topic;
 #[macro_use]
 mod utils;
 
+pub mod clock;
 pub mod error;
 pub mod v3;
 pub mod v5;
 
 mod io;
+#[cfg(feature = "quic")]
+pub mod quic;
 mod server;
 mod service;
 mod session;
@@ -22,7 +25,7 @@ mod version;
 
 pub use self::error::MqttError;
 pub use self::server::MqttServer;
-pub use self::session::Session;
+pub use self::session::{NegotiatedLimits, Session};
 pub use self::topic::{Level as TopicLevel, Topic};
 
 // http://www.iana.org/assignments/service-names-port-numbers/service-names-port-numbers.xhtml
@@ -0,0 +1,182 @@
+//! MQTT over QUIC transport
+//!
+//! Mqtt session runs over a single bidirectional QUIC stream. Stream is
+//! used as a regular connection io, codec is not changed.
+use std::task::{Context, Poll};
+use std::{fmt, future::Future, io, net::SocketAddr, pin::Pin};
+
+use ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
+use ntex::connect::{Address, Connect, ConnectError, Resolver};
+use ntex::service::{Service, ServiceFactory};
+use ntex::util::Ready;
+
+pub use quinn::ClientConfig;
+
+/// Bidirectional QUIC stream
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    connection: quinn::Connection,
+    _endpoint: quinn::Endpoint,
+}
+
+impl QuicStream {
+    /// Returns reference to underlying QUIC connection
+    pub fn connection(&self) -> &quinn::Connection {
+        &self.connection
+    }
+}
+
+impl fmt::Debug for QuicStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QuicStream")
+            .field("remote_address", &self.connection.remote_address())
+            .finish()
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// QUIC connector
+///
+/// Resolves remote host, establishes QUIC connection and opens
+/// bidirectional stream for mqtt session. ALPN protocols must be
+/// configured in provided `ClientConfig`.
+pub struct QuicConnector<T> {
+    config: ClientConfig,
+    resolver: Resolver<T>,
+}
+
+impl<T> QuicConnector<T> {
+    /// Create new QUIC connector
+    pub fn new(config: ClientConfig) -> Self {
+        QuicConnector { config, resolver: Resolver::new() }
+    }
+}
+
+impl<T> Clone for QuicConnector<T> {
+    fn clone(&self) -> Self {
+        QuicConnector { config: self.config.clone(), resolver: Resolver::new() }
+    }
+}
+
+impl<T: Address + 'static> QuicConnector<T> {
+    /// Resolve and connect to remote host
+    pub fn connect<U>(
+        &self,
+        message: U,
+    ) -> impl Future<Output = Result<QuicStream, ConnectError>>
+    where
+        Connect<T>: From<U>,
+    {
+        let req = Connect::from(message);
+        let host = server_name(req.host()).to_owned();
+        let fut = self.resolver.lookup(req);
+        let config = self.config.clone();
+
+        async move {
+            let mut req = fut.await?;
+            let addr = req.take_addrs().next().ok_or(ConnectError::Unresolved)?;
+            let bind: SocketAddr =
+                if addr.is_ipv6() { ([0u16; 8], 0).into() } else { ([0u8; 4], 0).into() };
+            log::trace!("QUIC connect to {:?} ({:?})", host, addr);
+
+            let endpoint = quinn::Endpoint::client(bind)?;
+            let conn = endpoint
+                .connect_with(config, addr, &host)
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))?
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?
+                .connection;
+            let (send, recv) =
+                conn.open_bi().await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            log::trace!("QUIC stream is opened to {:?}", host);
+
+            Ok(QuicStream { send, recv, connection: conn, _endpoint: endpoint })
+        }
+    }
+}
+
+/// Server name without port, brackets of IPv6 literal are removed
+fn server_name(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        rest.split(']').next().unwrap_or(rest)
+    } else {
+        match host.rsplit_once(':') {
+            // bare IPv6 literal contains several colons
+            Some((name, _)) if !name.contains(':') => name,
+            _ => host,
+        }
+    }
+}
+
+impl<T: Address + 'static> ServiceFactory for QuicConnector<T> {
+    type Request = Connect<T>;
+    type Response = QuicStream;
+    type Error = ConnectError;
+    type Config = ();
+    type Service = QuicConnector<T>;
+    type InitError = ();
+    type Future = Ready<Self::Service, Self::InitError>;
+
+    fn new_service(&self, _: ()) -> Self::Future {
+        Ready::Ok(self.clone())
+    }
+}
+
+impl<T: Address + 'static> Service for QuicConnector<T> {
+    type Request = Connect<T>;
+    type Response = QuicStream;
+    type Error = ConnectError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    #[inline]
+    fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&self, req: Connect<T>) -> Self::Future {
+        Box::pin(self.connect(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_name() {
+        assert_eq!(server_name("localhost"), "localhost");
+        assert_eq!(server_name("localhost:14567"), "localhost");
+        assert_eq!(server_name("127.0.0.1:14567"), "127.0.0.1");
+        assert_eq!(server_name("[::1]:14567"), "::1");
+        assert_eq!(server_name("[::1]"), "::1");
+        assert_eq!(server_name("::1"), "::1");
+    }
+}
@@ -463,7 +463,11 @@ impl<Io, Err, InitErr> DefaultProtocolServer<Io, Err, InitErr> {
     }
 }
 
-impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr> {
+impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    Err: 'static,
+{
     type Config = ();
     type Request = (Io, State, Option<Sleep>);
     type Response = ();
@@ -477,20 +481,47 @@ impl<Io, Err, InitErr> ServiceFactory for DefaultProtocolServer<Io, Err, InitErr
     }
 }
 
-impl<Io, Err, InitErr> Service for DefaultProtocolServer<Io, Err, InitErr> {
+impl<Io, Err, InitErr> Service for DefaultProtocolServer<Io, Err, InitErr>
+where
+    Io: AsyncRead + AsyncWrite + Unpin + 'static,
+    Err: 'static,
+{
     type Request = (Io, State, Option<Sleep>);
     type Response = ();
     type Error = MqttError<Err>;
-    type Future = Ready<Self::Response, Self::Error>;
+    type Future = Pin<Box<dyn Future<Output = Result<(), Self::Error>>>>;
 
     fn poll_ready(&self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&self, _: Self::Request) -> Self::Future {
-        Ready::Err(MqttError::Protocol(ProtocolError::Io(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Protocol is not supported: {:?}", self.ver),
-        ))))
+    fn call(&self, (mut io, state, _): Self::Request) -> Self::Future {
+        let ver = self.ver;
+
+        Box::pin(async move {
+            // reject connect with client's protocol version, MQTT-3.1.2-2
+            let _ = match ver {
+                ProtocolVersion::MQTT3 => {
+                    let pkt = v3::codec::Packet::ConnectAck {
+                        session_present: false,
+                        return_code: v3::codec::ConnectAckReason::UnacceptableProtocolVersion,
+                    };
+                    state.send(&mut io, &v3::codec::Codec::default(), pkt).await.is_ok()
+                }
+                ProtocolVersion::MQTT5 => {
+                    let pkt = v5::codec::ConnectAck {
+                        reason_code: v5::codec::ConnectAckReason::UnsupportedProtocolVersion,
+                        ..Default::default()
+                    };
+                    let pkt = v5::codec::Packet::ConnectAck(Box::new(pkt));
+                    state.send(&mut io, &v5::codec::Codec::default(), pkt).await.is_ok()
+                }
+            };
+
+            Err(MqttError::Protocol(ProtocolError::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Protocol is not supported: {:?}", ver),
+            ))))
+        })
     }
 }
@@ -6,7 +6,7 @@ use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
 use ntex::time::{Millis, Seconds, Sleep};
 use ntex::util::{select, Either, Pool};
 
-use super::io::{DispatchItem, Dispatcher, State, Timer};
+use super::io::{DispatchItem, Dispatcher, FlushSource, State, Timer};
 
 type ResponseItem<U> = Option<<U as Encoder>::Item>;
 
@@ -48,7 +48,7 @@ where
         > + 'static,
     <T::Service as Service>::Error: 'static,
     <T::Service as Service>::Future: 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + FlushSource + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
     type Config = ();
@@ -104,7 +104,7 @@ where
         > + 'static,
     <T::Service as Service>::Error: 'static,
     <T::Service as Service>::Future: 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + FlushSource + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
     type Request = Io;
@@ -198,7 +198,7 @@ where
         > + 'static,
     <T::Service as Service>::Error: 'static,
     <T::Service as Service>::Future: 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + FlushSource + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
     type Config = ();
@@ -254,7 +254,7 @@ where
         > + 'static,
     <T::Service as Service>::Error: 'static,
     <T::Service as Service>::Future: 'static,
-    Codec: Decoder + Encoder + Clone + 'static,
+    Codec: Decoder + Encoder + FlushSource + Clone + 'static,
     <Codec as Encoder>::Item: 'static,
 {
     type Request = (Io, State, Option<Sleep>);
@@ -1,14 +1,60 @@
 use std::ops::Deref;
 use std::rc::Rc;
 
+use ntex::util::ByteString;
+
+use crate::types::QoS;
+use crate::v5::{codec::SubscriptionOptions, MqttSink};
+
 /// Mqtt connection session
 pub struct Session<T, St>(Rc<SessionInner<T, St>>);
 
 struct SessionInner<T, St> {
     st: St,
     sink: T,
-    max_receive: u16,
-    max_topic_alias: u16,
+    limits: NegotiatedLimits,
+}
+
+/// Connection limits negotiated during handshake
+///
+/// Inbound limits are announced by server in `connect-ack` packet,
+/// outbound limits are announced by client in `connect` packet.
+/// Zero receive max or max packet size means there is no limit, zero
+/// topic alias max means topic aliases are not allowed. For v3 connections
+/// all values are zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct NegotiatedLimits {
+    /// Max QoS level of inbound publish packets
+    pub max_qos: QoS,
+    /// Max number of inbound in-flight publish packets
+    pub receive_max: u16,
+    /// Max inbound topic alias
+    pub topic_alias_max: u16,
+    /// Max size of inbound packet
+    pub max_packet_size: u32,
+    /// Max number of outbound in-flight publish packets
+    pub peer_receive_max: u16,
+    /// Max outbound topic alias
+    pub peer_topic_alias_max: u16,
+    /// Max size of outbound packet
+    pub peer_max_packet_size: u32,
+    /// Wildcard subscriptions are available
+    pub wildcard_subscriptions: bool,
+}
+
+impl Default for NegotiatedLimits {
+    fn default() -> Self {
+        NegotiatedLimits {
+            max_qos: QoS::ExactlyOnce,
+            receive_max: 0,
+            topic_alias_max: 0,
+            max_packet_size: 0,
+            peer_receive_max: 0,
+            peer_topic_alias_max: 0,
+            peer_max_packet_size: 0,
+            wildcard_subscriptions: true,
+        }
+    }
 }
 
 impl<T, St> Clone for Session<T, St> {
@@ -20,11 +66,11 @@ impl<T, St> Clone for Session<T, St> {
 
 impl<T, St> Session<T, St> {
     pub(crate) fn new(st: St, sink: T) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive: 0, max_topic_alias: 0 }))
+        Session(Rc::new(SessionInner { st, sink, limits: NegotiatedLimits::default() }))
     }
 
-    pub(crate) fn new_v5(st: St, sink: T, max_receive: u16, max_topic_alias: u16) -> Self {
-        Session(Rc::new(SessionInner { st, sink, max_receive, max_topic_alias }))
+    pub(crate) fn new_v5(st: St, sink: T, limits: NegotiatedLimits) -> Self {
+        Session(Rc::new(SessionInner { st, sink, limits }))
     }
 
     #[inline]
@@ -37,8 +83,25 @@ impl<T, St> Session<T, St> {
         &self.0.st
     }
 
+    /// Connection limits negotiated during handshake
+    #[inline]
+    pub fn limits(&self) -> &NegotiatedLimits {
+        &self.0.limits
+    }
+
     pub(crate) fn params(&self) -> (u16, u16) {
-        (self.0.max_receive, self.0.max_topic_alias)
+        (self.0.limits.receive_max, self.0.limits.topic_alias_max)
+    }
+}
+
+impl<St> Session<MqttSink, St> {
+    /// Snapshot of active subscriptions
+    ///
+    /// Contains topic filters and options requested by client, for
+    /// filters that were granted by control service. Subscriptions are
+    /// ordered by topic filter.
+    pub fn subscriptions(&self) -> Vec<(ByteString, SubscriptionOptions)> {
+        self.0.sink.subscriptions()
     }
 }
 
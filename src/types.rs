@@ -1,6 +1,8 @@
 pub const MQTT: &[u8] = b"MQTT";
 pub const MQTT_LEVEL_3: u8 = 4;
 pub const MQTT_LEVEL_5: u8 = 5;
+pub const MQTT_31: &[u8] = b"MQIsdp";
+pub const MQTT_LEVEL_31: u8 = 3;
 pub const WILL_QOS_SHIFT: u8 = 3;
 
 /// Max possible packet size
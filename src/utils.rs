@@ -1,7 +1,8 @@
 use std::num::{NonZeroU16, NonZeroU32};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, io::Cursor, pin::Pin};
+use std::{convert::TryFrom, future::Future, io, io::Cursor, pin::Pin};
 
+use ntex::codec::{AsyncRead, AsyncWrite, ReadBuf};
 use ntex::service::Service;
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut, Either};
 
@@ -138,12 +139,16 @@ pub(crate) fn take_properties(src: &mut Bytes) -> Result<Bytes, DecodeError> {
 }
 
 pub(crate) fn decode_variable_length(src: &[u8]) -> Result<Option<(u32, usize)>, DecodeError> {
-    let mut cur = Cursor::new(src);
-    match decode_variable_length_cursor(&mut cur) {
-        Ok(len) => Ok(Some((len, cur.position() as usize))),
-        Err(DecodeError::MalformedPacket) => Ok(None),
-        Err(e) => Err(e),
+    let mut len: u32 = 0;
+    for (idx, val) in src.iter().enumerate() {
+        len += ((val & 0b0111_1111u8) as u32) << (idx * 7);
+        if val & 0b1000_0000 == 0 {
+            return Ok(Some((len, idx + 1)));
+        }
+        // variable byte integer is at most 4 bytes long, MQTT-1.5.5
+        ensure!(idx < 3, DecodeError::MalformedPacket);
     }
+    Ok(None)
 }
 
 #[allow(clippy::cast_lossless)] // safe: allow cast through `as` because it is type-safe
@@ -157,7 +162,7 @@ pub(crate) fn decode_variable_length_cursor<B: Buf>(src: &mut B) -> Result<u32,
         if val & 0b1000_0000 == 0 {
             return Ok(len);
         } else {
-            ensure!(shift < 21, DecodeError::InvalidLength);
+            ensure!(shift < 21, DecodeError::MalformedPacket);
             shift += 7;
         }
     }
@@ -322,6 +327,105 @@ impl<'a, S: Service> Future for Ready<'a, S> {
     }
 }
 
+/// Io wrapper that limits number of successful reads and number of read bytes
+///
+/// Once limit is reached, read fails with `InvalidData` error.
+/// Zero limit disables check.
+pub(crate) struct ReadLimit<'a, T> {
+    io: &'a mut T,
+    reads: usize,
+    max: usize,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<'a, T> ReadLimit<'a, T> {
+    pub(crate) fn new(io: &'a mut T, max: usize, max_bytes: usize) -> Self {
+        ReadLimit { io, max, max_bytes, reads: 0, bytes: 0 }
+    }
+}
+
+impl<'a, T: AsyncRead + Unpin> AsyncRead for ReadLimit<'a, T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.max != 0 && this.reads >= this.max {
+            log::trace!("Max number of reads is reached: {}", this.max);
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Max number of reads is reached",
+            )));
+        }
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut *this.io).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            this.reads += 1;
+            this.bytes += buf.filled().len() - filled;
+            if this.max_bytes != 0 && this.bytes > this.max_bytes {
+                log::trace!("Max number of read bytes is reached: {}", this.max_bytes);
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Max number of read bytes is reached",
+                )));
+            }
+        }
+        res
+    }
+}
+
+impl<'a, T: AsyncWrite + Unpin> AsyncWrite for ReadLimit<'a, T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(unix)]
+/// Set socket receive and send buffer sizes, size `0` keeps system default
+pub(crate) fn set_socket_buffers<T>(io: &T, recv: usize, send: usize)
+where
+    T: std::os::unix::io::AsRawFd,
+{
+    let sock = socket2::SockRef::from(io);
+    if recv != 0 {
+        if let Err(err) = sock.set_recv_buffer_size(recv) {
+            log::warn!("Cannot set socket receive buffer size: {:?}", err);
+        }
+    }
+    if send != 0 {
+        if let Err(err) = sock.set_send_buffer_size(send) {
+            log::warn!("Cannot set socket send buffer size: {:?}", err);
+        }
+    }
+}
+
+#[cfg(unix)]
+/// Enable TCP keep-alive probes after `idle` time of inactivity
+pub(crate) fn set_tcp_keepalive<T>(io: &T, idle: ntex::time::Seconds)
+where
+    T: std::os::unix::io::AsRawFd,
+{
+    let keepalive =
+        socket2::TcpKeepalive::new().with_time(std::time::Duration::from_secs(idle.0.into()));
+    if let Err(err) = socket2::SockRef::from(io).set_tcp_keepalive(&keepalive) {
+        log::warn!("Cannot enable socket keep-alive: {:?}", err);
+    }
+}
+
 pub(crate) async fn select<F1, F2>(fut1: F1, fut2: F2) -> Either<F1::Output, F2::Output>
 where
     F1: Future,
@@ -375,7 +479,16 @@ mod tests {
 
         assert_eq!(
             decode_variable_length(b"\xff\xff\xff\xff\xff\xff"),
-            Err(DecodeError::InvalidLength)
+            Err(DecodeError::MalformedPacket)
+        );
+        // fifth byte is not required to reject length
+        assert_eq!(
+            decode_variable_length(b"\xff\xff\xff\xff"),
+            Err(DecodeError::MalformedPacket)
+        );
+        assert_eq!(
+            decode_variable_length_cursor(&mut Bytes::from_static(b"\xff\xff\xff\xff\x01")),
+            Err(DecodeError::MalformedPacket)
         );
 
         assert_variable_length(b"\x00", (0, 1));
@@ -12,6 +12,9 @@ use ntex::connect::openssl::{OpensslConnector, SslConnector};
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
+#[cfg(feature = "quic")]
+use crate::quic::{self, QuicConnector};
+
 use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
 use crate::io::State;
 use crate::v3::shared::{MqttShared, MqttSinkPool};
@@ -231,6 +234,24 @@ where
         }
     }
 
+    #[cfg(feature = "quic")]
+    /// Use QUIC connector
+    ///
+    /// Mqtt session runs over bidirectional QUIC stream.
+    pub fn quic(self, config: quic::ClientConfig) -> MqttConnector<A, QuicConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            address: self.address,
+            max_send: self.max_send,
+            max_receive: self.max_receive,
+            max_packet_size: self.max_packet_size,
+            connector: QuicConnector::new(config),
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            pool: self.pool,
+        }
+    }
+
     /// Connect to mqtt server
     pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
         if self.handshake_timeout.non_zero() {
@@ -69,7 +69,15 @@ impl Decoder for Codec {
                             // check max message size
                             let max_size = self.max_size.get();
                             if max_size != 0 && max_size < remaining_length {
-                                return Err(DecodeError::MaxSizeExceeded);
+                                log::debug!(
+                                    "MaxSizeExceeded max-size: {}, remaining: {}",
+                                    max_size,
+                                    remaining_length
+                                );
+                                return Err(DecodeError::MaxSizeExceeded {
+                                    size: remaining_length,
+                                    max: max_size,
+                                });
                             }
                             src.advance(consumed + 1);
                             self.state.set(DecodeState::Frame(FixedHeader {
@@ -132,7 +140,10 @@ mod tests {
 
         let mut buf = BytesMut::new();
         buf.extend_from_slice(b"\0\x09");
-        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 9, max: 5 })
+        );
     }
 
     #[test]
@@ -77,6 +77,11 @@ fn decode_connect_packet(src: &mut Bytes) -> Result<Packet, DecodeError> {
             message,
         })
     } else {
+        // will qos and will retain must be 0 without will, [MQTT-3.1.2-11, MQTT-3.1.2-13]
+        ensure!(
+            !flags.intersects(ConnectFlags::WILL_QOS | ConnectFlags::WILL_RETAIN),
+            DecodeError::MalformedPacket
+        );
         None
     };
     let username = if flags.contains(ConnectFlags::USERNAME) {
@@ -266,6 +271,29 @@ mod tests {
         assert_decode_packet!(b"\xe0\x00", Packet::Disconnect);
     }
 
+    #[test]
+    fn test_decode_will_flags() {
+        // will retain, will qos 1, will qos 2 without will flag
+        for raw in [
+            b"\x00\x04MQTT\x04\x22\x00\x3C\x00\x0512345",
+            b"\x00\x04MQTT\x04\x0A\x00\x3C\x00\x0512345",
+            b"\x00\x04MQTT\x04\x12\x00\x3C\x00\x0512345",
+        ] {
+            assert_eq!(
+                decode_connect_packet(&mut Bytes::from_static(raw)),
+                Err(DecodeError::MalformedPacket)
+            );
+        }
+
+        // will qos 3
+        assert_eq!(
+            decode_connect_packet(&mut Bytes::from_static(
+                b"\x00\x04MQTT\x04\x1E\x00\x3C\x00\x0512345\x00\x05topic\x00\x07message"
+            )),
+            Err(DecodeError::MalformedPacket)
+        );
+    }
+
     #[test]
     fn test_decode_publish_packets() {
         //assert_eq!(
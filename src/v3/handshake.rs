@@ -1,10 +1,32 @@
-use std::{fmt, rc::Rc};
+use std::{fmt, io, rc::Rc};
 
+use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::time::Seconds;
+use ntex::util::Either;
 
 use super::codec as mqtt;
 use super::shared::MqttShared;
 use super::sink::MqttSink;
+use crate::error::DecodeError;
+
+/// Reply with `UnacceptableProtocolVersion` CONNACK if first packet is
+/// CONNECT with unsupported protocol level, MQTT-3.1.2-2
+pub(super) async fn reject_protocol_level<Io>(
+    io: &mut Io,
+    shared: &MqttShared,
+    err: &Either<DecodeError, io::Error>,
+) where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    if let Either::Left(DecodeError::UnsupportedProtocolLevel) = err {
+        log::trace!("Unsupported protocol level, sending connect ack");
+        let pkt = mqtt::Packet::ConnectAck {
+            session_present: false,
+            return_code: mqtt::ConnectAckReason::UnacceptableProtocolVersion,
+        };
+        let _ = shared.state.send(io, &shared.codec, pkt).await;
+    }
+}
 
 /// Connect message
 pub struct Handshake<Io> {
@@ -10,7 +10,7 @@ use crate::io::{DispatchItem, State};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::handshake::{reject_protocol_level, Handshake, HandshakeAck};
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, dispatcher::factory, MqttServer, MqttSink, Publish, Session};
 
@@ -225,9 +225,11 @@ where
 
         Box::pin(async move {
             // read first packet
-            let packet = state
-                .next(&mut io, &shared.codec)
-                .await
+            let packet = state.next(&mut io, &shared.codec).await;
+            if let Err(ref err) = packet {
+                reject_protocol_level(&mut io, &shared, err).await;
+            }
+            let packet = packet
                 .map_err(|err| {
                     log::trace!("Error is received during mqtt handshake: {:?}", err);
                     MqttError::from(err)
@@ -356,9 +358,11 @@ where
 
         Box::pin(async move {
             // read first packet
-            let packet = state
-                .next(&mut io, &shared.codec)
-                .await
+            let packet = state.next(&mut io, &shared.codec).await;
+            if let Err(ref err) = packet {
+                reject_protocol_level(&mut io, &shared, err).await;
+            }
+            let packet = packet
                 .map_err(|err| {
                     log::trace!("Error is received during mqtt handshake: {:?}", err);
                     MqttError::from(err)
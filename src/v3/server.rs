@@ -12,7 +12,7 @@ use crate::service::{FramedService, FramedService2};
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::handshake::{reject_protocol_level, Handshake, HandshakeAck};
 use super::selector::SelectItem;
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, dispatcher::factory, MqttSink, Publish, Session};
@@ -370,9 +370,11 @@ where
     ));
 
     // read first packet
-    let packet = state
-        .next(&mut io, &shared.codec)
-        .await
+    let packet = state.next(&mut io, &shared.codec).await;
+    if let Err(ref err) = packet {
+        reject_protocol_level(&mut io, &shared, err).await;
+    }
+    let packet = packet
         .map_err(|err| {
             log::trace!("Error is received during mqtt handshake: {:?}", err);
             MqttError::from(err)
@@ -5,7 +5,8 @@ use ntex::codec::{Decoder, Encoder};
 use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
 
 use crate::error::{DecodeError, EncodeError};
-use crate::{io::State, types::packet_type, v3::codec};
+use crate::io::{FlushSource, FlushStats, State};
+use crate::{types::packet_type, v3::codec};
 
 pub(super) enum Ack {
     Publish(NonZeroU16),
@@ -92,6 +93,13 @@ impl MqttShared {
         }
     }
 }
+
+impl FlushSource for MqttShared {
+    fn flush_stats(&self) -> Option<Rc<FlushStats>> {
+        None
+    }
+}
+
 impl Encoder for MqttShared {
     type Item = codec::Packet;
     type Error = EncodeError;
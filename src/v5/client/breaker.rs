@@ -0,0 +1,130 @@
+//! Connect circuit breaker
+//!
+//! Breaker opens after number of consecutive connect failures, connect
+//! attempts fail immediately until cooldown period ends. After cooldown
+//! breaker is half-open and allows single probe attempt, successful probe
+//! closes breaker, failed probe opens it again. State is shared between
+//! connect attempts of the same connector.
+use std::{cell::Cell, rc::Rc, time};
+
+use ntex::time::Millis;
+
+use crate::clock::Clock;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum State {
+    /// Number of consecutive failures
+    Closed(usize),
+    /// Connect attempts fail until deadline
+    Open(time::Instant),
+    /// Probe attempt is in progress
+    HalfOpen,
+}
+
+/// Connect attempts circuit breaker
+#[derive(Debug)]
+pub(super) struct CircuitBreaker {
+    failures: usize,
+    cooldown: Millis,
+    state: Cell<State>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(failures: usize, cooldown: Millis) -> Self {
+        CircuitBreaker { failures, cooldown, state: Cell::new(State::Closed(0)) }
+    }
+
+    /// Start connect attempt, returns `None` if breaker is open
+    pub(super) fn attempt(self: &Rc<Self>, clock: &Clock) -> Option<Attempt> {
+        match self.state.get() {
+            State::Closed(_) => (),
+            State::Open(deadline) if clock.now() >= deadline => {
+                log::trace!("Circuit breaker is half-open, probing connection");
+                self.state.set(State::HalfOpen);
+            }
+            State::Open(_) | State::HalfOpen => return None,
+        }
+        Some(Attempt { breaker: self.clone(), clock: clock.clone(), done: false })
+    }
+
+    fn success(&self) {
+        self.state.set(State::Closed(0));
+    }
+
+    fn failure(&self, clock: &Clock) {
+        let failures = match self.state.get() {
+            State::Closed(n) => n + 1,
+            State::Open(_) | State::HalfOpen => self.failures,
+        };
+        if failures >= self.failures {
+            log::trace!("Circuit breaker is open after {} failures", failures);
+            let deadline = clock.now() + time::Duration::from(self.cooldown);
+            self.state.set(State::Open(deadline));
+        } else {
+            self.state.set(State::Closed(failures));
+        }
+    }
+}
+
+/// Connect attempt, dropped attempt counts as failure
+pub(super) struct Attempt {
+    breaker: Rc<CircuitBreaker>,
+    clock: Clock,
+    done: bool,
+}
+
+impl Attempt {
+    /// Record attempt result
+    pub(super) fn complete(mut self, success: bool) {
+        self.done = true;
+        if success {
+            self.breaker.success();
+        } else {
+            self.breaker.failure(&self.clock);
+        }
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breaker.failure(&self.clock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let clock = Clock::manual();
+        let breaker = Rc::new(CircuitBreaker::new(2, Millis(1000)));
+
+        breaker.attempt(&clock).unwrap().complete(false);
+        breaker.attempt(&clock).unwrap().complete(true);
+        breaker.attempt(&clock).unwrap().complete(false);
+        assert_eq!(breaker.state.get(), State::Closed(1));
+
+        // dropped attempt is failure, breaker opens
+        drop(breaker.attempt(&clock).unwrap());
+        assert!(breaker.attempt(&clock).is_none());
+        clock.advance(Millis(999));
+        assert!(breaker.attempt(&clock).is_none());
+
+        // half-open, single probe is allowed
+        clock.advance(Millis(1));
+        let probe = breaker.attempt(&clock).unwrap();
+        assert!(breaker.attempt(&clock).is_none());
+
+        // failed probe opens breaker again
+        probe.complete(false);
+        assert!(breaker.attempt(&clock).is_none());
+        clock.advance(Millis(1000));
+
+        // successful probe closes breaker
+        breaker.attempt(&clock).unwrap().complete(true);
+        assert_eq!(breaker.state.get(), State::Closed(0));
+    }
+}
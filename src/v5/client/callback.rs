@@ -0,0 +1,187 @@
+//! Per subscription publish handlers
+//!
+//! Every subscription created with `Client::subscribe_with()` gets its own
+//! subscription identifier, allocated from the top of identifiers range.
+//! Identifiers of removed handlers are reused.
+//! Publishes that carry subscription identifiers are routed by identifier,
+//! otherwise topic gets matched against subscription filters.
+use std::{cell::RefCell, future::Future, num::NonZeroU16, num::NonZeroU32, pin::Pin, rc::Rc};
+
+use ntex::service::{into_service, Service};
+use ntex::util::{ByteString, Either, HashMap};
+
+use crate::topic::Topic;
+use crate::v5::{codec, publish::Publish, publish::PublishAck};
+
+/// Max subscription identifier, MQTT-3.8.2.1.2
+const MAX_SUBSCRIPTION_ID: u32 = 268_435_455;
+
+type Handler = Rc<dyn Fn(Publish) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Subscription handlers table
+#[derive(Clone, Default)]
+pub(super) struct Callbacks(Rc<RefCell<Inner>>);
+
+#[derive(Default)]
+struct Inner {
+    allocated: u32,
+    free: Vec<NonZeroU32>,
+    entries: Vec<Entry>,
+    aliases: HashMap<NonZeroU16, ByteString>,
+}
+
+struct Entry {
+    id: NonZeroU32,
+    filter: Topic,
+    handler: Handler,
+}
+
+impl Callbacks {
+    /// Register handler, returns allocated subscription identifier
+    ///
+    /// Returns `None` if all subscription identifiers are in use.
+    pub(super) fn insert<F, R>(&self, filter: Topic, f: F) -> Option<NonZeroU32>
+    where
+        F: Fn(Publish) -> R + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        let mut inner = self.0.borrow_mut();
+        let id = if let Some(id) = inner.free.pop() {
+            id
+        } else if inner.allocated < MAX_SUBSCRIPTION_ID {
+            let id = NonZeroU32::new(MAX_SUBSCRIPTION_ID - inner.allocated)?;
+            inner.allocated += 1;
+            id
+        } else {
+            return None;
+        };
+        inner.entries.push(Entry {
+            id,
+            filter,
+            handler: Rc::new(move |publish| Box::pin(f(publish))),
+        });
+        Some(id)
+    }
+
+    /// Remove handler of rejected subscription, identifier gets reused
+    pub(super) fn remove(&self, id: NonZeroU32) {
+        let mut inner = self.0.borrow_mut();
+        let len = inner.entries.len();
+        inner.entries.retain(|entry| entry.id != id);
+        if inner.entries.len() != len {
+            inner.free.push(id);
+        }
+    }
+
+    /// Find handlers for publish, returns handlers and publish topic
+    fn route(&self, pkt: &codec::Publish) -> (Vec<Handler>, ByteString) {
+        let mut inner = self.0.borrow_mut();
+
+        // resolve topic alias
+        let mut topic = pkt.topic.clone();
+        if let Some(alias) = pkt.properties.topic_alias {
+            if topic.is_empty() {
+                match inner.aliases.get(&alias) {
+                    Some(t) => topic = t.clone(),
+                    None => return (Vec::new(), topic),
+                }
+            } else {
+                inner.aliases.insert(alias, topic.clone());
+            }
+        }
+
+        let handlers = match pkt.properties.subscription_ids {
+            Some(ref ids) if !ids.is_empty() => inner
+                .entries
+                .iter()
+                .filter(|entry| ids.contains(&entry.id))
+                .map(|entry| entry.handler.clone())
+                .collect(),
+            _ => inner
+                .entries
+                .iter()
+                .filter(|entry| entry.filter.matches_str(&topic))
+                .map(|entry| entry.handler.clone())
+                .collect(),
+        };
+        (handlers, topic)
+    }
+
+    /// Wrap publish service, matched publishes are handled by subscription
+    /// handlers and get acked once all handlers complete
+    pub(super) fn service<S, E>(
+        self,
+        srv: S,
+    ) -> impl Service<Request = Publish, Response = Either<Publish, PublishAck>, Error = E>
+    where
+        S: Service<Request = Publish, Response = Either<Publish, PublishAck>, Error = E>,
+        S::Future: 'static,
+        E: 'static,
+    {
+        into_service(move |publish: Publish| {
+            let (handlers, topic) = self.route(publish.packet());
+            if handlers.is_empty() {
+                return Either::Left(srv.call(publish));
+            }
+
+            let futs: Vec<_> = handlers
+                .iter()
+                .map(|handler| {
+                    let mut pkt = publish.packet().clone();
+                    pkt.topic = topic.clone();
+                    (*handler)(Publish::new(pkt))
+                })
+                .collect();
+            Either::Right(Box::pin(async move {
+                for fut in futs {
+                    fut.await;
+                }
+                Ok(Either::Right(publish.ack()))
+            }) as Pin<Box<dyn Future<Output = Result<_, E>>>>)
+        })
+    }
+}
+
+/// Subscription filter without shared subscription prefix
+pub(super) fn parse_filter(filter: &str) -> Option<Topic> {
+    let filter = match filter.strip_prefix("$share/") {
+        Some(rest) => rest.split_once('/')?.1,
+        None => filter,
+    };
+    filter.parse::<Topic>().ok().filter(|topic| topic.is_valid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topic(filter: &str) -> Topic {
+        parse_filter(filter).unwrap()
+    }
+
+    #[test]
+    fn test_reuse_ids() {
+        let callbacks = Callbacks::default();
+        let id1 = callbacks.insert(topic("a"), |_| async {}).unwrap();
+        let id2 = callbacks.insert(topic("b"), |_| async {}).unwrap();
+        assert_eq!(id1.get(), MAX_SUBSCRIPTION_ID);
+        assert_eq!(id2.get(), MAX_SUBSCRIPTION_ID - 1);
+
+        callbacks.remove(id1);
+        callbacks.remove(id1);
+        assert_eq!(callbacks.insert(topic("c"), |_| async {}), Some(id1));
+        assert_eq!(callbacks.insert(topic("d"), |_| async {}).unwrap().get(), id2.get() - 1);
+    }
+
+    #[test]
+    fn test_ids_exhausted() {
+        let callbacks = Callbacks::default();
+        callbacks.0.borrow_mut().allocated = MAX_SUBSCRIPTION_ID - 1;
+        let id = callbacks.insert(topic("a"), |_| async {}).unwrap();
+        assert_eq!(id.get(), 1);
+        assert_eq!(callbacks.insert(topic("b"), |_| async {}), None);
+
+        callbacks.remove(id);
+        assert_eq!(callbacks.insert(topic("b"), |_| async {}), Some(id));
+    }
+}
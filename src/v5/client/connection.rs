@@ -1,21 +1,48 @@
 use std::time::Instant;
 use std::{
-    cell::RefCell, convert::TryFrom, fmt, future::Future, marker, num::NonZeroU16, rc::Rc,
+    cell::RefCell, convert::TryFrom, error, fmt, future::Future, marker, num::NonZeroU16,
+    num::NonZeroU32, pin::Pin, rc::Rc,
 };
 
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::router::{IntoPattern, Path, Router, RouterBuilder};
 use ntex::service::{boxed, into_service, IntoService, Service};
-use ntex::time::{sleep, Millis, Seconds};
+use ntex::time::{Millis, Seconds};
 use ntex::util::{ByteString, Either, HashMap, Ready};
 
+use crate::clock::Clock;
 use crate::error::MqttError;
 use crate::io::{Dispatcher, Timer};
 use crate::v5::publish::{Publish, PublishAck};
-use crate::v5::{codec, shared::MqttShared, sink::MqttSink, ControlResult};
+use crate::v5::{
+    codec, error::RequestError, error::SendPacketError, shared::MqttShared, sink::MqttSink,
+    sink::SubscriptionChange, ControlResult,
+};
 
+use super::callback::{parse_filter, Callbacks};
 use super::control::ControlMessage;
-use super::dispatcher::create_dispatcher;
+use super::dispatcher::{create_dispatcher, DeliveryOrder};
+use super::keepalive::Pinger;
+use super::mux::RequestMux;
+
+/// Client initialization hook, runs after successful handshake
+pub(super) type OnConnected = Rc<
+    dyn Fn(
+        MqttSink,
+        &codec::ConnectAck,
+    ) -> Pin<Box<dyn Future<Output = Result<(), Box<dyn error::Error>>>>>,
+>;
+
+type InitFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Default control service, protocol errors are acked with DISCONNECT
+/// packet with error specific reason code
+fn default_control<E, E2>(msg: ControlMessage<E>) -> Ready<ControlResult, E2> {
+    Ready::Ok(match msg {
+        ControlMessage::ProtocolError(err) => err.ack(),
+        msg => msg.disconnect(codec::Disconnect::default()),
+    })
+}
 
 /// Mqtt client
 pub struct Client<Io> {
@@ -24,7 +51,13 @@ pub struct Client<Io> {
     keepalive: Seconds,
     disconnect_timeout: Seconds,
     max_receive: usize,
+    max_topic_alias: u16,
+    order: DeliveryOrder,
     pkt: Box<codec::ConnectAck>,
+    clock: Clock,
+    pinger: Pinger,
+    callbacks: Callbacks,
+    on_connected: Option<OnConnected>,
 }
 
 impl<Io> fmt::Debug for Client<Io> {
@@ -42,14 +75,20 @@ impl<T> Client<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
+    #[allow(clippy::too_many_arguments)]
     /// Construct new `Dispatcher` instance with outgoing messages stream.
     pub(super) fn new(
         io: T,
         shared: Rc<MqttShared>,
         pkt: Box<codec::ConnectAck>,
         max_receive: u16,
+        max_topic_alias: u16,
+        order: DeliveryOrder,
         keepalive: Seconds,
         disconnect_timeout: Seconds,
+        clock: Clock,
+        pinger: Pinger,
+        on_connected: Option<OnConnected>,
     ) -> Self {
         Client {
             io,
@@ -57,9 +96,31 @@ where
             shared,
             keepalive,
             disconnect_timeout,
+            clock,
+            pinger,
+            callbacks: Callbacks::default(),
+            on_connected,
+            order,
             max_receive: max_receive as usize,
+            max_topic_alias,
         }
     }
+
+    /// Create initialization hook future, hook failure closes connection
+    fn init(&self) -> Option<InitFuture> {
+        let hook = self.on_connected.as_ref()?;
+        let sink = MqttSink::new(self.shared.clone());
+        let fut = (*hook)(sink.clone(), &self.pkt);
+
+        Some(Box::pin(async move {
+            if let Err(err) = fut.await {
+                log::error!("Client initialization failed: {}", err);
+                sink.close_with_reason(codec::Disconnect::new(
+                    codec::DisconnectReasonCode::UnspecifiedError,
+                ));
+            }
+        }))
+    }
 }
 
 impl<Io> Client<Io>
@@ -78,6 +139,18 @@ where
         self.pkt.session_present
     }
 
+    #[inline]
+    /// Remaining send quota, server's receive maximum minus in-flight packets
+    pub fn send_quota(&self) -> u16 {
+        self.sink().credit().min(u16::MAX as usize) as u16
+    }
+
+    #[inline]
+    /// Negotiated keep-alive, server keep-alive of CONNACK overrides requested value
+    pub fn keep_alive(&self) -> Seconds {
+        self.keepalive
+    }
+
     #[inline]
     /// Get reference to `ConnectAck` packet
     pub fn packet(&self) -> &codec::ConnectAck {
@@ -90,6 +163,266 @@ where
         &mut self.pkt
     }
 
+    #[inline]
+    /// Get reference to `ConnectAck` packet user properties
+    pub fn connack_user_properties(&self) -> &codec::UserProperties {
+        &self.pkt.user_properties
+    }
+
+    #[inline]
+    /// Inbound topic aliases, alias to topic mappings set by server
+    pub fn alias_table(&self) -> Vec<(u16, ByteString)> {
+        self.sink().alias_table()
+    }
+
+    #[inline]
+    /// Outbound topic aliases, alias to topic mappings set by client publishes
+    pub fn outbound_alias_table(&self) -> Vec<(u16, ByteString)> {
+        self.sink().outbound_alias_table()
+    }
+
+    #[cfg(feature = "trace")]
+    #[inline]
+    /// Attach protocol trace stream to connection
+    pub fn protocol_events(&self) -> crate::v5::trace::ProtocolEvents {
+        self.shared.codec.trace_events()
+    }
+
+    /// Wait until connection is closed
+    ///
+    /// Resolves to DISCONNECT packet if connection is closed by server,
+    /// or `None` if connection is dropped without DISCONNECT.
+    pub fn closed(&self) -> impl Future<Output = Option<codec::Disconnect>> {
+        let shared = self.shared.clone();
+        let fut = shared.state.on_disconnect();
+
+        async move {
+            fut.await;
+            shared.disconnect.borrow().clone()
+        }
+    }
+
+    /// Close connection with DISCONNECT packet
+    ///
+    /// Use `DisconnectWithWillMessage` reason code to ask server to publish
+    /// last will of the connection on graceful disconnect.
+    pub fn disconnect_with(&self, pkt: codec::Disconnect) {
+        self.sink().close_with_reason(pkt);
+    }
+
+    /// Set hook for received publish acks
+    ///
+    /// Hook is called with packet id and reason code of every PUBACK packet
+    /// received for outbound publishes, after publish future is resolved.
+    ///
+    /// By default hook is not set.
+    pub fn on_ack<F>(self, f: F) -> Self
+    where
+        F: Fn(NonZeroU16, codec::PublishAckReason) + 'static,
+    {
+        *self.shared.on_ack.borrow_mut() = Some(Box::new(f));
+        self
+    }
+
+    /// Subscribe to a set of topic filters
+    ///
+    /// All filters are sent in one SUBSCRIBE packet, each filter has its own
+    /// subscription options. Returned future resolves to filters paired with
+    /// SUBACK reason codes, in request order. SUBACK packet with number of
+    /// reason codes different from number of filters is protocol error,
+    /// connection gets closed and future fails with `Disconnected` error.
+    ///
+    /// Client must be started before awaiting returned future.
+    pub fn subscribe<I>(
+        &self,
+        id: Option<NonZeroU32>,
+        filters: I,
+    ) -> impl Future<Output = Result<Vec<(ByteString, codec::SubscribeAckReason)>, SendPacketError>>
+    where
+        I: IntoIterator<Item = (ByteString, codec::SubscriptionOptions)>,
+    {
+        let filters: Vec<_> = filters.into_iter().collect();
+        let fut = filters
+            .iter()
+            .fold(self.sink().subscribe(id), |b, (filter, opts)| {
+                b.topic_filter(filter.clone(), opts.clone())
+            })
+            .send();
+
+        async move {
+            // number of reason codes is checked by dispatcher, mismatch is protocol error
+            let ack = fut.await?;
+            Ok(filters.into_iter().map(|(filter, _)| filter).zip(ack.status).collect())
+        }
+    }
+
+    /// Subscribe to topic filter with its own publish handler
+    ///
+    /// Handler is called for publishes matching this subscription, such
+    /// publishes are not passed to publish service or resources. Client
+    /// allocates subscription identifier for each subscription, if server does
+    /// not support subscription identifiers, publish topic is matched against
+    /// filters and all matching handlers are called. Publish is acked after
+    /// all handlers complete. Handler is removed if subscription is rejected.
+    /// If all subscription identifiers are in use, `QuotaExceeded` is returned.
+    ///
+    /// Client must be started before awaiting returned future.
+    pub fn subscribe_with<F, R>(
+        &self,
+        filter: ByteString,
+        options: codec::SubscriptionOptions,
+        handler: F,
+    ) -> impl Future<Output = Result<codec::SubscribeAckReason, SendPacketError>>
+    where
+        F: Fn(Publish) -> R + 'static,
+        R: Future<Output = ()> + 'static,
+    {
+        let topic = match parse_filter(&filter) {
+            Some(topic) => topic,
+            None => {
+                return Either::Left(Ready::Ok(codec::SubscribeAckReason::TopicFilterInvalid))
+            }
+        };
+        let callbacks = self.callbacks.clone();
+        let id = match callbacks.insert(topic, handler) {
+            Some(id) => id,
+            None => return Either::Left(Ready::Ok(codec::SubscribeAckReason::QuotaExceeded)),
+        };
+        let sub_id = if self.pkt.subscription_identifiers_available == Some(false) {
+            None
+        } else {
+            Some(id)
+        };
+        let fut = self.sink().subscribe(sub_id).topic_filter(filter, options).send();
+
+        Either::Right(async move {
+            let res = fut.await.map(|ack| {
+                ack.status
+                    .first()
+                    .copied()
+                    .unwrap_or(codec::SubscribeAckReason::UnspecifiedError)
+            });
+            if res.as_ref().map(|reason| u8::from(*reason) >= 0x80).unwrap_or(true) {
+                callbacks.remove(id);
+            }
+            res
+        })
+    }
+
+    /// Create request/response multiplexer
+    ///
+    /// Multiplexer subscribes to `response_topic` and routes incoming
+    /// responses to waiting requests by correlation data. Returned future
+    /// resolves after subscription is acked, so client must be started
+    /// before awaiting it.
+    pub fn request_mux(
+        &self,
+        response_topic: ByteString,
+    ) -> impl Future<Output = Result<RequestMux, RequestError>> {
+        let mux = RequestMux::new(self.sink(), response_topic.clone(), self.clock.clone());
+        let handler = mux.clone();
+        let opts = codec::SubscriptionOptions {
+            qos: codec::QoS::AtLeastOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: codec::RetainHandling::NoAtSubscribe,
+        };
+        let fut = self.subscribe_with(response_topic, opts, move |publish| {
+            handler.response(publish);
+            std::future::ready(())
+        });
+
+        async move {
+            match fut.await {
+                Ok(reason) if u8::from(reason) < 0x80 => Ok(mux),
+                Ok(reason) => Err(RequestError::Subscribe(reason)),
+                Err(err) => Err(RequestError::Send(err)),
+            }
+        }
+    }
+
+    /// Re-send subscriptions of previous connection
+    ///
+    /// Every connection tracks topic filters granted by server, `previous`
+    /// is sink of connection this client replaces. If server does not have
+    /// stored session state, tracked subscriptions of previous connection
+    /// get re-sent, filters are split into several SUBSCRIBE packets
+    /// according to server's max packet size. If session is present,
+    /// subscriptions are taken over without sending packets.
+    ///
+    /// Returned future resolves after all acks are received, so client must
+    /// be started before awaiting it.
+    pub fn resubscribe_all(
+        &self,
+        previous: &MqttSink,
+    ) -> impl Future<Output = Result<Vec<codec::SubscribeAck>, SendPacketError>> {
+        // fixed header, packet id, properties length and subscription id
+        const OVERHEAD: usize = 16;
+
+        let mut packets: Vec<(Option<NonZeroU32>, Vec<_>)> = Vec::new();
+        if let (Some(ref prev), Some(ref subs)) =
+            (&previous.0.subscriptions, &self.shared.subscriptions)
+        {
+            if self.pkt.session_present {
+                if !Rc::ptr_eq(prev, subs) {
+                    *subs.borrow_mut() = prev.borrow().clone();
+                }
+            } else {
+                let max_size = match self.shared.codec.max_outbound() {
+                    0 => usize::MAX,
+                    size => size as usize,
+                };
+                // group filters by subscription id
+                let prev = prev.borrow();
+                let mut subs: Vec<_> = prev.iter().collect();
+                subs.sort_unstable_by(|a, b| (a.1).1.cmp(&(b.1).1).then_with(|| a.0.cmp(b.0)));
+
+                let mut size = 0;
+                for (filter, (opts, id)) in subs {
+                    let filter_size = filter.len() + 3;
+                    match packets.last_mut() {
+                        Some((pkt_id, filters))
+                            if pkt_id == id && size + filter_size + OVERHEAD <= max_size =>
+                        {
+                            size += filter_size;
+                            filters.push((filter.clone(), opts.clone()));
+                        }
+                        _ => {
+                            size = filter_size;
+                            packets.push((*id, vec![(filter.clone(), opts.clone())]));
+                        }
+                    }
+                }
+            }
+        }
+        let sink = self.sink();
+
+        async move {
+            let mut acks = Vec::with_capacity(packets.len());
+            for (id, filters) in packets {
+                let builder = filters
+                    .into_iter()
+                    .fold(sink.subscribe(id), |b, (filter, opts)| b.topic_filter(filter, opts));
+                acks.push(builder.send().await?);
+            }
+            Ok(acks)
+        }
+    }
+
+    /// Change active subscriptions to `desired` set
+    ///
+    /// See `MqttSink::set_subscriptions()`. Returned future resolves after
+    /// all acks are received, so client must be started before awaiting it.
+    pub fn set_subscriptions<I>(
+        &self,
+        desired: I,
+    ) -> impl Future<Output = Result<Vec<(ByteString, SubscriptionChange)>, SendPacketError>>
+    where
+        I: IntoIterator<Item = (ByteString, codec::SubscriptionOptions)>,
+    {
+        self.sink().set_subscriptions(desired)
+    }
+
     /// Configure mqtt resource for a specific topic
     pub fn resource<T, F, U, E>(self, address: T, service: F) -> ClientRouter<Io, E, U::Error>
     where
@@ -102,15 +435,22 @@ where
         let mut builder = Router::build();
         builder.path(address, 0);
         let handlers = vec![boxed::service(service.into_service())];
+        let init = self.init();
 
         ClientRouter {
             builder,
             handlers,
+            init,
             io: self.io,
             shared: self.shared,
             keepalive: self.keepalive,
             disconnect_timeout: self.disconnect_timeout,
             max_receive: self.max_receive,
+            max_topic_alias: self.max_topic_alias,
+            order: self.order,
+            clock: self.clock,
+            pinger: self.pinger,
+            callbacks: self.callbacks,
             _t: marker::PhantomData,
         }
     }
@@ -120,17 +460,24 @@ where
     /// Default handler closes connection on any control message.
     pub async fn start_default(self) {
         if self.keepalive.non_zero() {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(self.pinger.clone().run(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.clock.clone(),
+            ));
+        }
+
+        if let Some(fut) = self.init() {
+            ntex::rt::spawn(fut);
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
-            into_service(|pkt| Ready::Ok(Either::Left(pkt))),
-            into_service(|msg: ControlMessage<()>| {
-                Ready::Ok(msg.disconnect(codec::Disconnect::default()))
-            }),
+            self.max_topic_alias,
+            self.order,
+            self.callbacks.service(into_service(|pkt| Ready::Ok(Either::Left(pkt)))),
+            into_service(default_control::<(), _>),
         );
 
         let _ = Dispatcher::with(
@@ -153,14 +500,23 @@ where
         S: Service<Request = ControlMessage<E>, Response = ControlResult, Error = E> + 'static,
     {
         if self.keepalive.non_zero() {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(self.pinger.clone().run(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.clock.clone(),
+            ));
+        }
+
+        if let Some(fut) = self.init() {
+            ntex::rt::spawn(fut);
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
-            into_service(|pkt| Ready::Ok(Either::Left(pkt))),
+            self.max_topic_alias,
+            self.order,
+            self.callbacks.service(into_service(|pkt| Ready::Ok(Either::Left(pkt)))),
             service.into_service(),
         );
 
@@ -188,6 +544,12 @@ pub struct ClientRouter<Io, Err, PErr> {
     keepalive: Seconds,
     disconnect_timeout: Seconds,
     max_receive: usize,
+    max_topic_alias: u16,
+    order: DeliveryOrder,
+    clock: Clock,
+    pinger: Pinger,
+    callbacks: Callbacks,
+    init: Option<InitFuture>,
     _t: marker::PhantomData<Err>,
 }
 
@@ -223,17 +585,24 @@ where
     /// Run client with default control messages handler
     pub async fn start_default(self) {
         if self.keepalive.non_zero() {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(self.pinger.clone().run(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.clock.clone(),
+            ));
+        }
+
+        if let Some(fut) = self.init {
+            ntex::rt::spawn(fut);
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
-            dispatch(self.builder.finish(), self.handlers),
-            into_service(|msg: ControlMessage<Err>| {
-                Ready::Ok(msg.disconnect(codec::Disconnect::default()))
-            }),
+            self.max_topic_alias,
+            self.order,
+            self.callbacks.service(dispatch(self.builder.finish(), self.handlers)),
+            into_service(default_control::<Err, _>),
         );
 
         let _ = Dispatcher::with(
@@ -256,14 +625,23 @@ where
             + 'static,
     {
         if self.keepalive.non_zero() {
-            ntex::rt::spawn(keepalive(MqttSink::new(self.shared.clone()), self.keepalive));
+            ntex::rt::spawn(self.pinger.clone().run(
+                MqttSink::new(self.shared.clone()),
+                self.keepalive,
+                self.clock.clone(),
+            ));
+        }
+
+        if let Some(fut) = self.init {
+            ntex::rt::spawn(fut);
         }
 
         let dispatcher = create_dispatcher(
             MqttSink::new(self.shared.clone()),
             self.max_receive,
-            16,
-            dispatch(self.builder.finish(), self.handlers),
+            self.max_topic_alias,
+            self.order,
+            self.callbacks.service(dispatch(self.builder.finish(), self.handlers)),
             service.into_service(),
         );
 
@@ -338,18 +716,3 @@ where
         }
     }
 }
-
-async fn keepalive(sink: MqttSink, timeout: Seconds) {
-    log::debug!("start mqtt client keep-alive task");
-
-    let keepalive = Millis::from(timeout);
-    loop {
-        sleep(keepalive).await;
-
-        if !sink.ping() {
-            // connection is closed
-            log::debug!("mqtt client connection is closed, stopping keep-alive task");
-            break;
-        }
-    }
-}
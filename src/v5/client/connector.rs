@@ -3,8 +3,8 @@ use std::{future::Future, num::NonZeroU16, num::NonZeroU32, rc::Rc, time::Durati
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::connect::{self, Address, Connect, Connector};
 use ntex::service::Service;
-use ntex::time::{timeout, Seconds};
-use ntex::util::{select, ByteString, Bytes, Either, PoolId};
+use ntex::time::{Millis, Seconds};
+use ntex::util::{select, ByteString, Bytes, Either, PoolId, Ready};
 
 #[cfg(feature = "openssl")]
 use ntex::connect::openssl::{OpensslConnector, SslConnector};
@@ -12,18 +12,113 @@ use ntex::connect::openssl::{OpensslConnector, SslConnector};
 #[cfg(feature = "rustls")]
 use ntex::connect::rustls::{ClientConfig, RustlsConnector};
 
-use super::{codec, connection::Client, error::ClientError, error::ProtocolError};
+#[cfg(feature = "quic")]
+use crate::quic::{self, QuicConnector};
+
+use super::breaker::CircuitBreaker;
+use super::connection::{Client, OnConnected};
+use super::dispatcher::DeliveryOrder;
+use super::keepalive::{AdaptiveKeepAlive, Pinger};
+use super::{codec, error::Capability, error::ClientError};
+use super::{error::ProtocolError, QoS};
+use crate::clock::Clock;
 use crate::io::State;
-use crate::v5::shared::{MqttShared, MqttSinkPool};
+use crate::v5::limit::Limit;
+use crate::v5::shared::{MqttShared, MqttSinkPool, DEFAULT_RECEIVE_MAX};
+use crate::v5::MqttSink;
+
+type MapConnAck = Rc<dyn Fn(&codec::ConnectAck) -> Result<(), ClientError>>;
+
+/// Max inbound topic alias accepted by client by default
+const DEFAULT_TOPIC_ALIAS_MAX: u16 = 16;
+
+/// Server capabilities required by client
+///
+/// Capabilities are checked against CONNACK packet, absent CONNACK property
+/// means capability is available.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Minimum required maximum QoS
+    pub max_qos: Option<QoS>,
+    /// Retained messages must be available
+    pub retain: bool,
+    /// Wildcard subscriptions must be available
+    pub wildcard_subscriptions: bool,
+    /// Subscription identifiers must be available
+    pub subscription_identifiers: bool,
+    /// Shared subscriptions must be available
+    pub shared_subscriptions: bool,
+    /// Minimum required topic alias maximum
+    pub topic_alias_max: u16,
+    /// Minimum required maximum packet size
+    pub max_packet_size: u32,
+}
+
+impl Capabilities {
+    /// Check CONNACK packet, returns first missing capability
+    pub fn check(&self, pkt: &codec::ConnectAck) -> Result<(), Capability> {
+        if let (Some(required), Some(max)) = (self.max_qos, pkt.max_qos) {
+            if (max as u8) < (required as u8) {
+                return Err(Capability::MaxQos);
+            }
+        }
+        if self.retain && pkt.retain_available == Some(false) {
+            return Err(Capability::Retain);
+        }
+        if self.wildcard_subscriptions && pkt.wildcard_subscription_available == Some(false) {
+            return Err(Capability::WildcardSubscriptions);
+        }
+        if self.subscription_identifiers
+            && pkt.subscription_identifiers_available == Some(false)
+        {
+            return Err(Capability::SubscriptionIdentifiers);
+        }
+        if self.shared_subscriptions && pkt.shared_subscription_available == Some(false) {
+            return Err(Capability::SharedSubscriptions);
+        }
+        if pkt.topic_alias_max < self.topic_alias_max {
+            return Err(Capability::TopicAlias);
+        }
+        if let Some(size) = pkt.max_packet_size {
+            if size < self.max_packet_size {
+                return Err(Capability::MaxPacketSize);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Per connection CONNECT packet fields
+#[derive(Clone, Default)]
+struct Credentials {
+    client_id: Option<ByteString>,
+    username: Option<ByteString>,
+    password: Option<Bytes>,
+}
 
 /// Mqtt client connector
 pub struct MqttConnector<A, T> {
     address: A,
     connector: T,
-    pkt: codec::Connect,
+    pkt: Rc<codec::Connect>,
+    credentials: Credentials,
     handshake_timeout: Seconds,
     disconnect_timeout: Seconds,
+    clock: Clock,
+    pinger: Pinger,
+    breaker: Option<Rc<CircuitBreaker>>,
+    capabilities: Capabilities,
+    on_connected: Option<OnConnected>,
+    map_connack: Option<MapConnAck>,
+    order: DeliveryOrder,
+    max_topic_alias: u16,
+    disconnect_on_unexpected: bool,
+    connection_ttl: Seconds,
     pool: Rc<MqttSinkPool>,
+    #[cfg(feature = "compress")]
+    compression: Option<crate::v5::compress::Compression>,
+    #[cfg(feature = "compress")]
+    negotiation: Option<Rc<crate::v5::compress::CompressionNegotiation>>,
 }
 
 impl<A> MqttConnector<A, ()>
@@ -33,13 +128,44 @@ where
     #[allow(clippy::new_ret_no_self)]
     /// Create new mqtt connector
     pub fn new(address: A) -> MqttConnector<A, Connector<A>> {
+        MqttConnector::from_template(address, codec::Connect::default())
+    }
+
+    /// Create new mqtt connector from CONNECT packet template
+    ///
+    /// Template is shared, per connection fields are client id, username
+    /// and password, setting them does not copy template. All other
+    /// connector settings that change connect packet copy template on
+    /// first change. Template could be shared between connectors with
+    /// `Rc<codec::Connect>`. Packet is still copied once per connect, when
+    /// per connection fields get applied, `benches/connect.rs` compares
+    /// setup and connect costs with connectors built from scratch.
+    pub fn from_template<P>(address: A, template: P) -> MqttConnector<A, Connector<A>>
+    where
+        Rc<codec::Connect>: From<P>,
+    {
         MqttConnector {
             address,
-            pkt: codec::Connect::default(),
+            pkt: template.into(),
+            credentials: Credentials::default(),
             connector: Connector::default(),
             handshake_timeout: Seconds::ZERO,
             disconnect_timeout: Seconds(3),
+            clock: Clock::system(),
+            pinger: Pinger::default(),
+            breaker: None,
+            capabilities: Capabilities::default(),
+            on_connected: None,
+            map_connack: None,
+            order: DeliveryOrder::Strict,
+            max_topic_alias: DEFAULT_TOPIC_ALIAS_MAX,
+            disconnect_on_unexpected: true,
+            connection_ttl: Seconds::ZERO,
             pool: Rc::new(MqttSinkPool::default()),
+            #[cfg(feature = "compress")]
+            compression: None,
+            #[cfg(feature = "compress")]
+            negotiation: None,
         }
     }
 }
@@ -56,14 +182,14 @@ where
     where
         ByteString: From<U>,
     {
-        self.pkt.client_id = client_id.into();
+        self.credentials.client_id = Some(client_id.into());
         self
     }
 
     #[inline]
     /// The handling of the Session state.
     pub fn clean_start(mut self) -> Self {
-        self.pkt.clean_start = true;
+        Rc::make_mut(&mut self.pkt).clean_start = true;
         self
     }
 
@@ -72,7 +198,42 @@ where
     ///
     /// keep-alive is set to 30 seconds by default.
     pub fn keep_alive(mut self, val: Seconds) -> Self {
-        self.pkt.keep_alive = val.seconds() as u16;
+        Rc::make_mut(&mut self.pkt).keep_alive = val.seconds() as u16;
+        self
+    }
+
+    /// Enable adaptive keep-alive.
+    ///
+    /// Client starts pinging every `min` seconds and lengthens ping interval
+    /// up to `max` seconds while pings get answered. Ping that is not answered
+    /// within ping timeout is treated as NAT timeout, connection gets closed
+    /// and interval is reduced, failed interval is not probed again. Probing state
+    /// is shared by all connections created by this connector.
+    ///
+    /// Keep-alive in CONNECT packet is set to `max`, negotiated keep-alive is
+    /// changed only at connect time, ping interval is adjusted within it.
+    /// Panics if `min` is `0` or greater than `max`.
+    ///
+    /// By default adaptive keep-alive is disabled.
+    pub fn adaptive_keepalive(mut self, min: Seconds, max: Seconds) -> Self {
+        if min.0 == 0 || min.0 > max.0 {
+            panic!("Min interval must be greater than 0 and not greater than max");
+        }
+        Rc::make_mut(&mut self.pkt).keep_alive = max.0;
+        self.pinger.adaptive = Some(Rc::new(AdaptiveKeepAlive::new(min, max)));
+        self
+    }
+
+    /// Set max time to wait for PINGRESP packet.
+    ///
+    /// If ping response is not received within timeout, connection gets
+    /// closed and `Client::closed()` resolves to DISCONNECT packet with
+    /// `KeepAliveTimeout` reason code. Timeout is capped by ping interval.
+    /// To disable ping response check set timeout to 0.
+    ///
+    /// By default timeout is set to half of keep-alive.
+    pub fn ping_timeout(mut self, timeout: Seconds) -> Self {
+        self.pinger.ping_timeout = Some(timeout);
         self
     }
 
@@ -81,29 +242,30 @@ where
     ///
     /// by default last will value is not set
     pub fn last_will(mut self, val: codec::LastWill) -> Self {
-        self.pkt.last_will = Some(val);
+        Rc::make_mut(&mut self.pkt).last_will = Some(val);
         self
     }
 
     #[inline]
     /// Set auth-method and auth-data for connect packet.
     pub fn auth(mut self, method: ByteString, data: Bytes) -> Self {
-        self.pkt.auth_method = Some(method);
-        self.pkt.auth_data = Some(data);
+        let pkt = Rc::make_mut(&mut self.pkt);
+        pkt.auth_method = Some(method);
+        pkt.auth_data = Some(data);
         self
     }
 
     #[inline]
     /// Username can be used by the Server for authentication and authorization.
     pub fn username(mut self, val: ByteString) -> Self {
-        self.pkt.username = Some(val);
+        self.credentials.username = Some(val);
         self
     }
 
     #[inline]
     /// Password can be used by the Server for authentication and authorization.
     pub fn password(mut self, val: Bytes) -> Self {
-        self.pkt.password = Some(val);
+        self.credentials.password = Some(val);
         self
     }
 
@@ -113,9 +275,9 @@ where
     /// To disable max size limit set value to 0.
     pub fn max_packet_size(mut self, val: u32) -> Self {
         if let Some(val) = NonZeroU32::new(val) {
-            self.pkt.max_packet_size = Some(val);
+            Rc::make_mut(&mut self.pkt).max_packet_size = Some(val);
         } else {
-            self.pkt.max_packet_size = None;
+            Rc::make_mut(&mut self.pkt).max_packet_size = None;
         }
         self
     }
@@ -124,23 +286,80 @@ where
     /// Set `receive max`
     ///
     /// Number of in-flight incoming publish packets. By default receive max is set to 16 packets.
-    /// To disable in-flight limit set value to 0.
+    /// To disable in-flight limit set value to 0, receive maximum property is omitted
+    /// from CONNECT packet in that case and server assumes 65535.
     pub fn receive_max(mut self, val: u16) -> Self {
         if let Some(val) = NonZeroU16::new(val) {
-            self.pkt.receive_max = Some(val);
+            Rc::make_mut(&mut self.pkt).receive_max = Some(val);
         } else {
-            self.pkt.receive_max = None;
+            Rc::make_mut(&mut self.pkt).receive_max = None;
         }
         self
     }
 
+    #[inline]
+    /// Set handling of acks with unknown packet id
+    ///
+    /// PUBACK or PUBREC for packet id that is not in-flight is protocol
+    /// error, in strict mode connection gets closed with `ProtocolError`
+    /// reason code. Lenient mode ignores such acks, for servers that
+    /// re-send acks.
+    ///
+    /// By default strict mode is enabled.
+    pub fn strict_ack_ids(self, strict: bool) -> Self {
+        self.pool.strict_ack_ids.set(strict);
+        self
+    }
+
+    #[inline]
+    /// Set connection time to live
+    ///
+    /// Client closes connection with `NormalDisconnection` reason code
+    /// once connection lives for `ttl` since handshake, for example when
+    /// credentials are leased and have to be refreshed by reconnect. To
+    /// disable set value to 0.
+    ///
+    /// By default connection time to live is not limited.
+    pub fn connection_ttl(mut self, ttl: Seconds) -> Self {
+        self.connection_ttl = ttl;
+        self
+    }
+
+    #[inline]
+    /// Set handling of unexpected packets during handshake
+    ///
+    /// Server must not send any packet before CONNACK, connect attempt
+    /// fails with `ClientError::UnexpectedPacket`. If `disconnect` is set,
+    /// client sends DISCONNECT with `ProtocolError` reason code before
+    /// closing connection, otherwise connection is closed silently.
+    ///
+    /// By default DISCONNECT is sent.
+    pub fn disconnect_on_unexpected_packet(mut self, disconnect: bool) -> Self {
+        self.disconnect_on_unexpected = disconnect;
+        self
+    }
+
+    #[inline]
+    /// Set max inbound topic alias accepted by client
+    ///
+    /// Server's publish with topic alias greater than `max` closes connection
+    /// with `TopicAliasInvalid` reason, so inbound alias table holds at most
+    /// `max` topics regardless of server behavior. Topic alias maximum
+    /// advertised in CONNECT packet is capped by this value.
+    ///
+    /// By default client accepts topic aliases up to 16.
+    pub fn max_inbound_topic_aliases(mut self, max: u16) -> Self {
+        self.max_topic_alias = max;
+        self
+    }
+
     #[inline]
     /// Update connect user properties
     pub fn properties<F>(mut self, f: F) -> Self
     where
         F: FnOnce(&mut codec::UserProperties),
     {
-        f(&mut self.pkt.user_properties);
+        f(&mut Rc::make_mut(&mut self.pkt).user_properties);
         self
     }
 
@@ -150,7 +369,7 @@ where
     where
         F: FnOnce(&mut codec::Connect),
     {
-        f(&mut self.pkt);
+        f(Rc::make_mut(&mut self.pkt));
         self
     }
 
@@ -185,6 +404,142 @@ where
         self
     }
 
+    /// Set initial capacity of connection write buffer.
+    ///
+    /// Write buffer grows dynamically, pre-sized buffer avoids
+    /// reallocations for clients that send bursts of packets.
+    /// Capacity is applied to write params of memory pool when connection
+    /// state is created, it affects all users of the pool and it is also
+    /// high watermark for write backpressure. Max capacity is 64Kb.
+    ///
+    /// By default buffer capacity is defined by memory pool.
+    pub fn write_buffer_capacity(self, bytes: usize) -> Self {
+        self.pool.write_capacity.set(bytes);
+        self
+    }
+
+    /// Set server capabilities required by client.
+    ///
+    /// If CONNACK packet does not satisfy required capabilities, `connect()`
+    /// fails with `ClientError::CapabilityNotAvailable` error.
+    ///
+    /// By default no capabilities are required.
+    pub fn require(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Set client initialization hook.
+    ///
+    /// Hook runs once client is started, after successful handshake, and
+    /// receives client sink and CONNACK packet. It is suitable for
+    /// subscribing and announcing client presence. If hook fails, connection
+    /// gets closed with `UnspecifiedError` DISCONNECT packet.
+    ///
+    /// By default hook is not set.
+    pub fn on_connected<F, R, E>(mut self, f: F) -> Self
+    where
+        F: Fn(MqttSink, &codec::ConnectAck) -> R + 'static,
+        R: Future<Output = Result<(), E>> + 'static,
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        self.on_connected = Some(Rc::new(move |sink, pkt| {
+            let fut = f(sink, pkt);
+            Box::pin(async move { fut.await.map_err(Into::into) })
+        }));
+        self
+    }
+
+    /// Set CONNACK mapping function.
+    ///
+    /// Function runs for every received CONNACK packet, before reason code
+    /// and required capabilities are checked. Returned error fails
+    /// `connect()`, it is suitable for translating broker specific CONNACK
+    /// reasons into application errors. If function accepts packet, CONNACK
+    /// is handled as usual.
+    ///
+    /// By default CONNACK is not mapped.
+    pub fn map_connack<F, E>(mut self, f: F) -> Self
+    where
+        F: Fn(&codec::ConnectAck) -> Result<(), E> + 'static,
+        E: Into<ClientError>,
+    {
+        self.map_connack = Some(Rc::new(move |pkt| f(pkt).map_err(Into::into)));
+        self
+    }
+
+    /// Set delivery order of inbound publish packets.
+    ///
+    /// QoS2 publishes are delivered once QoS2 exchange completes, check
+    /// `DeliveryOrder` for details.
+    ///
+    /// By default publishes are delivered strictly in receive order.
+    pub fn delivery_order(mut self, order: DeliveryOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Enable connect circuit breaker.
+    ///
+    /// After `failures` consecutive failed connect attempts, `connect()`
+    /// fails immediately with `ClientError::CircuitOpen` error during
+    /// `cooldown` period. Once cooldown ends, single attempt is allowed,
+    /// it either closes breaker or opens it for another cooldown period.
+    /// Any connect error, including handshake timeout, counts as failure.
+    /// State is shared by all connect attempts of this connector.
+    /// Panics if `failures` is `0`.
+    ///
+    /// By default circuit breaker is disabled.
+    pub fn circuit_breaker(mut self, failures: usize, cooldown: Millis) -> Self {
+        assert!(failures > 0, "failures must be greater than 0");
+        self.breaker = Some(Rc::new(CircuitBreaker::new(failures, cooldown)));
+        self
+    }
+
+    /// Set time source for client timers.
+    ///
+    /// Clock drives handshake timeout, keep-alive timer, connection ttl,
+    /// circuit breaker cooldown and `RequestMux` request timeout. Dispatcher
+    /// disconnect timeout always uses runtime time.
+    ///
+    /// By default runtime time is used.
+    pub fn clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Enable publish payload compression.
+    ///
+    /// Outbound publish payloads get compressed and tagged with `x-encoding`
+    /// user property, inbound publishes with this property get decompressed before
+    /// reaching publish handler. This is non-standard convention, server side must support it.
+    /// Decompressed payload is limited by `max_packet_size`, oversized publishes
+    /// are rejected with `PayloadFormatInvalid` reason.
+    ///
+    /// By default compression is disabled.
+    pub fn compress(mut self, alg: crate::v5::compress::Compression) -> Self {
+        self.compression = Some(alg);
+        self
+    }
+
+    #[cfg(feature = "compress")]
+    /// Negotiate publish payload compression with server.
+    ///
+    /// Supported algorithms are offered in CONNECT user property, compression
+    /// is enabled with algorithm chosen by server in CONNACK user property.
+    /// Negotiated result overrides algorithm set by `compress()`, if server
+    /// does not choose algorithm compression is disabled.
+    ///
+    /// By default compression is not negotiated.
+    pub fn negotiate_compression(
+        mut self,
+        negotiation: crate::v5::compress::CompressionNegotiation,
+    ) -> Self {
+        self.negotiation = Some(Rc::new(negotiation));
+        self
+    }
+
     /// Use custom connector
     pub fn connector<U>(self, connector: U) -> MqttConnector<A, U>
     where
@@ -194,10 +549,84 @@ where
         MqttConnector {
             connector,
             pkt: self.pkt,
+            credentials: self.credentials,
             address: self.address,
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            clock: self.clock,
+            pinger: self.pinger,
+            breaker: self.breaker,
+            capabilities: self.capabilities,
+            on_connected: self.on_connected,
+            map_connack: self.map_connack,
+            order: self.order,
+            max_topic_alias: self.max_topic_alias,
+            disconnect_on_unexpected: self.disconnect_on_unexpected,
+            connection_ttl: self.connection_ttl,
             pool: self.pool,
+            #[cfg(feature = "compress")]
+            compression: self.compression,
+            #[cfg(feature = "compress")]
+            negotiation: self.negotiation,
+        }
+    }
+
+    #[cfg(unix)]
+    /// Set socket receive and send buffer sizes.
+    ///
+    /// Sizes are applied with `SO_RCVBUF` and `SO_SNDBUF` options to every
+    /// connected socket before CONNECT packet is sent, size `0` keeps system
+    /// default. Sizes are hints for operating system, for example linux
+    /// doubles requested value and caps it with `net.core.rmem_max` and
+    /// `net.core.wmem_max`. Available only for connectors that return io
+    /// stream backed by socket file descriptor, connector set later with
+    /// `connector()`, `openssl()` or `rustls()` replaces it. Errors are
+    /// logged and ignored.
+    ///
+    /// By default system buffer sizes are used.
+    pub fn socket_buffers(
+        self,
+        recv: usize,
+        send: usize,
+    ) -> MqttConnector<
+        A,
+        impl Service<Request = Connect<A>, Response = T::Response, Error = connect::ConnectError>,
+    >
+    where
+        T: 'static,
+        T::Response: std::os::unix::io::AsRawFd,
+    {
+        let connector = self.connector;
+        let connector = ntex::service::apply_fn(connector, move |req, srv: &T| {
+            let fut = srv.call(req);
+            async move {
+                let io = fut.await?;
+                crate::utils::set_socket_buffers(&io, recv, send);
+                Ok(io)
+            }
+        });
+        MqttConnector {
+            connector,
+            pkt: self.pkt,
+            credentials: self.credentials,
+            address: self.address,
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            clock: self.clock,
+            pinger: self.pinger,
+            breaker: self.breaker,
+            capabilities: self.capabilities,
+            on_connected: self.on_connected,
+            map_connack: self.map_connack,
+            order: self.order,
+            max_topic_alias: self.max_topic_alias,
+            disconnect_on_unexpected: self.disconnect_on_unexpected,
+            connection_ttl: self.connection_ttl,
+            pool: self.pool,
+            #[cfg(feature = "compress")]
+            compression: self.compression,
+            #[cfg(feature = "compress")]
+            negotiation: self.negotiation,
         }
     }
 
@@ -206,12 +635,80 @@ where
     pub fn openssl(self, connector: SslConnector) -> MqttConnector<A, OpensslConnector<A>> {
         MqttConnector {
             pkt: self.pkt,
+            credentials: self.credentials,
             address: self.address,
             connector: OpensslConnector::new(connector),
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            clock: self.clock,
+            pinger: self.pinger,
+            breaker: self.breaker,
+            capabilities: self.capabilities,
+            on_connected: self.on_connected,
+            map_connack: self.map_connack,
+            order: self.order,
+            max_topic_alias: self.max_topic_alias,
+            disconnect_on_unexpected: self.disconnect_on_unexpected,
+            connection_ttl: self.connection_ttl,
             pool: self.pool,
+            #[cfg(feature = "compress")]
+            compression: self.compression,
+            #[cfg(feature = "compress")]
+            negotiation: self.negotiation,
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    /// Use openssl connector with pre-shared key
+    ///
+    /// Connector offers TLS-PSK ciphersuites only and authenticates with
+    /// `identity` and `key` instead of certificates. Identity is limited to
+    /// 128 bytes and key to 256 bytes, longer values and openssl errors are
+    /// returned as `InvalidInput` and `Other` io errors, `Other` error is
+    /// also returned if openssl is built without PSK ciphersuites. Rustls
+    /// does not support TLS-PSK, PSK mode is available only with `openssl`
+    /// feature.
+    pub fn tls_psk<I, K>(
+        self,
+        identity: I,
+        key: K,
+    ) -> std::io::Result<MqttConnector<A, OpensslConnector<A>>>
+    where
+        I: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+    {
+        use ntex::connect::openssl::SslMethod;
+        use std::io;
+
+        let identity = identity.into();
+        let key = key.into();
+        if identity.is_empty() || identity.len() > 128 || identity.contains(&0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid PSK identity"));
         }
+        if key.is_empty() || key.len() > 256 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid PSK key"));
+        }
+
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if builder.set_cipher_list("PSK").is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "TLS-PSK ciphersuites are not available in openssl",
+            ));
+        }
+        builder.set_psk_client_callback(move |_, _hint, identity_buf, psk_buf| {
+            // identity is written as nul terminated string, zero key
+            // length fails handshake
+            if identity.len() >= identity_buf.len() || key.len() > psk_buf.len() {
+                log::error!("PSK identity or key does not fit openssl buffers");
+                return Ok(0);
+            }
+            identity_buf[..identity.len()].copy_from_slice(&identity);
+            identity_buf[identity.len()] = 0;
+            psk_buf[..key.len()].copy_from_slice(&key);
+            Ok(key.len())
+        });
+        Ok(self.openssl(builder.build()))
     }
 
     #[cfg(feature = "rustls")]
@@ -221,41 +718,152 @@ where
 
         MqttConnector {
             pkt: self.pkt,
+            credentials: self.credentials,
             address: self.address,
             connector: RustlsConnector::new(Arc::new(config)),
             handshake_timeout: self.handshake_timeout,
             disconnect_timeout: self.disconnect_timeout,
+            clock: self.clock,
+            pinger: self.pinger,
+            breaker: self.breaker,
+            capabilities: self.capabilities,
+            on_connected: self.on_connected,
+            map_connack: self.map_connack,
+            order: self.order,
+            max_topic_alias: self.max_topic_alias,
+            disconnect_on_unexpected: self.disconnect_on_unexpected,
+            connection_ttl: self.connection_ttl,
+            pool: self.pool,
+            #[cfg(feature = "compress")]
+            compression: self.compression,
+            #[cfg(feature = "compress")]
+            negotiation: self.negotiation,
+        }
+    }
+
+    #[cfg(feature = "rustls")]
+    /// Use rustls connector with client certificate resolver
+    ///
+    /// Resolver selects client certificate and key during tls handshake,
+    /// so single connector could connect with different identities.
+    /// Resolver replaces client certificate of `config`.
+    ///
+    /// Openssl does not provide client certificate callback, per connection
+    /// certificate could be set with custom connector that configures
+    /// connection with `SslRef::set_certificate()` and
+    /// `SslRef::set_private_key()`.
+    pub fn client_cert_resolver<R>(
+        self,
+        mut config: ClientConfig,
+        resolver: R,
+    ) -> MqttConnector<A, RustlsConnector<A>>
+    where
+        R: tokio_rustls::rustls::client::ResolvesClientCert + 'static,
+    {
+        config.client_auth_cert_resolver = std::sync::Arc::new(resolver);
+        self.rustls(config)
+    }
+
+    #[cfg(feature = "quic")]
+    /// Use QUIC connector
+    ///
+    /// Mqtt session runs over bidirectional QUIC stream.
+    pub fn quic(self, config: quic::ClientConfig) -> MqttConnector<A, QuicConnector<A>> {
+        MqttConnector {
+            pkt: self.pkt,
+            credentials: self.credentials,
+            address: self.address,
+            connector: QuicConnector::new(config),
+            handshake_timeout: self.handshake_timeout,
+            disconnect_timeout: self.disconnect_timeout,
+            clock: self.clock,
+            pinger: self.pinger,
+            breaker: self.breaker,
+            capabilities: self.capabilities,
+            on_connected: self.on_connected,
+            map_connack: self.map_connack,
+            order: self.order,
+            max_topic_alias: self.max_topic_alias,
+            disconnect_on_unexpected: self.disconnect_on_unexpected,
+            connection_ttl: self.connection_ttl,
             pool: self.pool,
+            #[cfg(feature = "compress")]
+            compression: self.compression,
+            #[cfg(feature = "compress")]
+            negotiation: self.negotiation,
         }
     }
 
     /// Connect to mqtt server
     pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
-        if self.handshake_timeout.non_zero() {
-            let fut = timeout(self.handshake_timeout, self._connect());
+        let attempt = match self.breaker {
+            Some(ref breaker) => match breaker.attempt(&self.clock) {
+                Some(attempt) => Some(attempt),
+                None => return Either::Left(Ready::Err(ClientError::CircuitOpen)),
+            },
+            None => None,
+        };
+
+        let fut = if self.handshake_timeout.non_zero() {
+            let fut = select(self._connect(), self.clock.sleep(self.handshake_timeout));
             Either::Left(async move {
                 match fut.await {
-                    Ok(res) => res.map_err(From::from),
-                    Err(_) => Err(ClientError::HandshakeTimeout),
+                    Either::Left(res) => res,
+                    Either::Right(_) => Err(ClientError::HandshakeTimeout),
                 }
             })
         } else {
             Either::Right(self._connect())
-        }
+        };
+
+        Either::Right(async move {
+            let res = fut.await;
+            if let Some(attempt) = attempt {
+                attempt.complete(res.is_ok());
+            }
+            res
+        })
     }
 
     fn _connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
         let fut = self.connector.call(Connect::new(self.address.clone()));
-        let pkt = self.pkt.clone();
+        let mut pkt = (*self.pkt).clone();
+        if let Some(ref client_id) = self.credentials.client_id {
+            pkt.client_id = client_id.clone();
+        }
+        if let Some(ref username) = self.credentials.username {
+            pkt.username = Some(username.clone());
+        }
+        if let Some(ref password) = self.credentials.password {
+            pkt.password = Some(password.clone());
+        }
+        #[cfg(feature = "compress")]
+        if let Some(ref negotiation) = self.negotiation {
+            negotiation.offer(&mut pkt);
+        }
+        pkt.topic_alias_max = pkt.topic_alias_max.min(self.max_topic_alias);
+        let max_topic_alias = self.max_topic_alias;
+        let disconnect_on_unexpected = self.disconnect_on_unexpected;
+        let connection_ttl = self.connection_ttl;
         let keep_alive = pkt.keep_alive;
         let max_packet_size = pkt.max_packet_size.map(|v| v.get()).unwrap_or(0);
         let max_receive = pkt.receive_max.map(|v| v.get()).unwrap_or(0);
         let disconnect_timeout = self.disconnect_timeout;
+        let clock = self.clock.clone();
+        let pinger = self.pinger.clone();
+        let capabilities = self.capabilities.clone();
+        let on_connected = self.on_connected.clone();
+        let map_connack = self.map_connack.clone();
+        let order = self.order;
         let pool = self.pool.clone();
+        #[cfg(feature = "compress")]
+        let compression = self.compression;
+        #[cfg(feature = "compress")]
+        let negotiation = self.negotiation.clone();
 
         async move {
             let mut io = fut.await?;
-            let state = State::with_memory_pool(pool.pool.get());
+            let state = pool.state();
             let codec = codec::Codec::new().max_inbound_size(max_packet_size);
 
             state.send(&mut io, &codec, codec::Packet::Connect(Box::new(pkt))).await?;
@@ -271,11 +879,20 @@ where
                     })
                 })?;
             let shared = Rc::new(MqttShared::new(state.clone(), codec, 0, pool));
+            #[cfg(feature = "compress")]
+            shared.compression.set(compression);
 
             match packet {
                 codec::Packet::ConnectAck(pkt) => {
                     log::trace!("Connect ack response from server: {:#?}", pkt);
+                    if let Some(ref f) = map_connack {
+                        (*f)(&pkt)?;
+                    }
                     if pkt.reason_code == codec::ConnectAckReason::Success {
+                        if let Err(cap) = capabilities.check(&pkt) {
+                            log::trace!("Required capability is not available: {:?}", cap);
+                            return Err(ClientError::CapabilityNotAvailable(cap));
+                        }
                         // set max outbound (encoder) packet size
                         if let Some(size) = pkt.max_packet_size {
                             shared.codec.set_max_outbound_size(size);
@@ -283,26 +900,159 @@ where
                         // server keep-alive
                         let keep_alive = pkt.server_keepalive_sec.unwrap_or(keep_alive);
 
-                        shared.cap.set(pkt.receive_max.map(|v| v.get()).unwrap_or(0) as usize);
+                        shared.cap.set(
+                            pkt.receive_max
+                                .map(|v| v.get() as usize)
+                                .unwrap_or(DEFAULT_RECEIVE_MAX),
+                        );
+                        shared.topic_alias_max.set(pkt.topic_alias_max);
+                        #[cfg(feature = "compress")]
+                        if let Some(ref negotiation) = negotiation {
+                            shared.compression.set(negotiation.chosen(&pkt));
+                        }
+                        if connection_ttl.non_zero() {
+                            MqttSink::new(shared.clone()).close_after(
+                                clock.sleep(connection_ttl),
+                                codec::DisconnectReasonCode::NormalDisconnection,
+                            );
+                        }
 
                         Ok(Client::new(
                             io,
                             shared,
                             pkt,
                             max_receive,
+                            max_topic_alias,
+                            order,
                             Seconds(keep_alive),
                             disconnect_timeout,
+                            clock,
+                            pinger,
+                            on_connected,
                         ))
                     } else {
                         Err(ClientError::Ack(pkt))
                     }
                 }
-                p => Err(ProtocolError::Unexpected(
-                    p.packet_type(),
-                    "Expected CONNECT-ACK packet",
-                )
-                .into()),
+                p => {
+                    // broker misbehavior, not rejection
+                    log::trace!("Unexpected packet during handshake: {:?}", p);
+                    if disconnect_on_unexpected {
+                        let pkt =
+                            codec::Disconnect::new(codec::DisconnectReasonCode::ProtocolError);
+                        let _ = state
+                            .send(&mut io, &shared.codec, codec::Packet::Disconnect(pkt))
+                            .await;
+                    }
+                    Err(ClientError::UnexpectedPacket(p.packet_type()))
+                }
             }
         }
     }
 }
+
+/// Mqtt client connector with concurrent connects limit
+///
+/// Pool shares connector between tasks, excess `connect()` calls wait
+/// until running connects complete. Pool is cheap to clone, clones
+/// share the same limit.
+pub struct ConnectorPool<A, T> {
+    connector: Rc<MqttConnector<A, T>>,
+    limit: Option<Rc<Limit>>,
+}
+
+impl<A, T> ConnectorPool<A, T>
+where
+    A: Address + Clone,
+    T: Service<Request = Connect<A>, Error = connect::ConnectError>,
+    T::Response: AsyncRead + AsyncWrite + Unpin + 'static,
+{
+    /// Create connector pool
+    pub fn new(connector: MqttConnector<A, T>) -> Self {
+        ConnectorPool { connector: Rc::new(connector), limit: None }
+    }
+
+    /// Set max number of concurrent connects.
+    ///
+    /// Connect includes io connect and handshake. To disable limit set
+    /// value to 0.
+    ///
+    /// By default number of concurrent connects is not limited.
+    pub fn max_concurrent_connects(mut self, n: usize) -> Self {
+        self.limit = Limit::new(n);
+        self
+    }
+
+    /// Get reference to connector
+    pub fn get_ref(&self) -> &MqttConnector<A, T> {
+        &self.connector
+    }
+
+    /// Connect to mqtt server, waits if max number of concurrent connects
+    /// is reached
+    pub fn connect(&self) -> impl Future<Output = Result<Client<T::Response>, ClientError>> {
+        let connector = self.connector.clone();
+        let limit = self.limit.clone();
+
+        async move {
+            let _permit = match limit {
+                Some(ref limit) => Some(limit.acquire().await),
+                None => None,
+            };
+            connector.connect().await
+        }
+    }
+}
+
+impl<A, T> Clone for ConnectorPool<A, T> {
+    fn clone(&self) -> Self {
+        ConnectorPool { connector: self.connector.clone(), limit: self.limit.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let ack = codec::ConnectAck::default();
+        let caps = Capabilities {
+            max_qos: Some(QoS::ExactlyOnce),
+            retain: true,
+            wildcard_subscriptions: true,
+            subscription_identifiers: true,
+            shared_subscriptions: true,
+            topic_alias_max: 0,
+            max_packet_size: 1024,
+        };
+        assert_eq!(caps.check(&ack), Ok(()));
+        assert_eq!(Capabilities::default().check(&ack), Ok(()));
+
+        let checks: Vec<(Capability, fn(&mut codec::ConnectAck))> = vec![
+            (Capability::MaxQos, |ack| ack.max_qos = Some(QoS::AtLeastOnce)),
+            (Capability::Retain, |ack| ack.retain_available = Some(false)),
+            (Capability::WildcardSubscriptions, |ack| {
+                ack.wildcard_subscription_available = Some(false)
+            }),
+            (Capability::SubscriptionIdentifiers, |ack| {
+                ack.subscription_identifiers_available = Some(false)
+            }),
+            (Capability::SharedSubscriptions, |ack| {
+                ack.shared_subscription_available = Some(false)
+            }),
+            (Capability::MaxPacketSize, |ack| ack.max_packet_size = Some(512)),
+        ];
+        for (cap, f) in checks {
+            let mut ack = codec::ConnectAck::default();
+            f(&mut ack);
+            assert_eq!(caps.check(&ack), Err(cap));
+            assert_eq!(Capabilities::default().check(&ack), Ok(()));
+        }
+
+        let caps = Capabilities { topic_alias_max: 10, ..Default::default() };
+        assert_eq!(caps.check(&ack), Err(Capability::TopicAlias));
+        let ack = codec::ConnectAck { topic_alias_max: 10, ..Default::default() };
+        assert_eq!(caps.check(&ack), Ok(()));
+    }
+}
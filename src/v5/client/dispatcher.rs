@@ -1,22 +1,48 @@
 use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
-use std::{future::Future, marker::PhantomData, num::NonZeroU16, pin::Pin, rc::Rc};
+use std::{collections::VecDeque, future::Future, marker::PhantomData, num::NonZeroU16};
+use std::{pin::Pin, rc::Rc};
 
 use ntex::service::Service;
 use ntex::util::{buffer::BufferService, inflight::InFlightService, Either, HashSet, Ready};
 
 use crate::error::{MqttError, ProtocolError};
+use crate::v5::publish::{Publish, PublishAck, PublishInfo};
 use crate::v5::shared::{Ack, MqttShared};
-use crate::v5::{codec, publish::Publish, publish::PublishAck, sink::MqttSink};
-use crate::{io::DispatchItem, types::packet_type};
+use crate::v5::{codec, sink::MqttSink};
+use crate::{io::DispatchItem, types::packet_type, types::QoS};
 
 use super::control::{ControlMessage, ControlResult};
 
+/// Delivery order of inbound publish packets
+///
+/// QoS2 publish is delivered once PUBREL packet is received from the server.
+/// With `Strict` order all publishes received after pending QoS2 publish wait
+/// for it, so delivery order always matches receive order, but single slow
+/// QoS2 exchange delays all following messages. `Qos0Bypass` order delivers
+/// QoS0 publishes immediately, such messages could overtake QoS2 publishes
+/// received earlier. QoS1 publishes always keep receive order.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryOrder {
+    /// Deliver publishes in receive order
+    Strict,
+    /// Allow QoS0 publishes to bypass pending QoS2 publishes
+    Qos0Bypass,
+}
+
+#[allow(clippy::derivable_impls)]
+impl Default for DeliveryOrder {
+    fn default() -> Self {
+        DeliveryOrder::Strict
+    }
+}
+
 /// mqtt5 protocol dispatcher
 pub(super) fn create_dispatcher<T, C, E>(
     sink: MqttSink,
     max_receive: usize,
     max_topic_alias: u16,
+    order: DeliveryOrder,
     publish: T,
     control: C,
 ) -> impl Service<
@@ -36,7 +62,14 @@ where
         InFlightService::new(1, control.map_err(MqttError::Service)),
     );
 
-    Dispatcher::<_, _, E>::new(sink, max_receive as usize, max_topic_alias, publish, control)
+    Dispatcher::<_, _, E>::new(
+        sink,
+        max_receive as usize,
+        max_topic_alias,
+        order,
+        publish,
+        control,
+    )
 }
 
 /// Mqtt protocol dispatcher
@@ -52,12 +85,15 @@ pub(crate) struct Dispatcher<T, C, E> {
 struct Inner<C> {
     control: C,
     sink: MqttSink,
-    info: RefCell<PublishInfo>,
+    info: Rc<RefCell<PublishInfo>>,
+    order: DeliveryOrder,
+    // publishes waiting for delivery, in receive order
+    queue: RefCell<VecDeque<Queued>>,
 }
 
-struct PublishInfo {
-    inflight: HashSet<NonZeroU16>,
-    aliases: HashSet<NonZeroU16>,
+struct Queued {
+    publish: codec::Publish,
+    released: bool,
 }
 
 impl<T, C, E> Dispatcher<T, C, E>
@@ -69,6 +105,7 @@ where
         sink: MqttSink,
         max_receive: usize,
         max_topic_alias: u16,
+        order: DeliveryOrder,
         publish: T,
         control: C,
     ) -> Self {
@@ -80,14 +117,33 @@ where
             inner: Rc::new(Inner {
                 control,
                 sink,
-                info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
-                    inflight: HashSet::default(),
-                }),
+                info: Rc::new(RefCell::new(PublishInfo::default())),
+                order,
+                queue: RefCell::new(VecDeque::new()),
             }),
             _t: PhantomData,
         }
     }
+
+    /// Call publish service
+    fn deliver(&self, publish: codec::Publish) -> PublishResponse<T, C, E> {
+        let packet_id = publish.packet_id;
+        let qos2 = publish.qos == QoS::ExactlyOnce;
+        // ack of QoS2 publish cannot be deferred, PUBREC is already sent
+        let publish = if qos2 {
+            Publish::new(publish)
+        } else {
+            Publish::with_ack(publish, &self.inner.sink, &self.inner.info)
+        };
+
+        PublishResponse {
+            packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+            qos2,
+            inner: self.inner.clone(),
+            state: PublishResponseState::Publish { fut: self.publish.call(publish) },
+            _t: PhantomData,
+        }
+    }
 }
 
 impl<T, C, E> Service for Dispatcher<T, C, E>
@@ -100,7 +156,7 @@ where
     type Response = Option<codec::Packet>;
     type Error = MqttError<E>;
     type Future = Either<
-        PublishResponse<T, C, E>,
+        Either<PublishResponse<T, C, E>, ReleaseResponse<T, C, E>>,
         Either<Ready<Self::Response, MqttError<E>>, ControlResponse<C, E>>,
     >;
 
@@ -118,6 +174,8 @@ where
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
             self.inner.sink.drop_sink();
+            #[cfg(feature = "trace")]
+            self.inner.sink.0.codec.trace_closed();
             self.shutdown.set(true);
             let fut = self.inner.control.call(ControlMessage::closed(is_error));
             ntex::rt::spawn(async move {
@@ -132,6 +190,8 @@ where
 
         match request {
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
+                #[cfg(feature = "compress")]
+                let mut publish = publish;
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
 
@@ -156,6 +216,20 @@ where
 
                         // check for duplicated packet id
                         if !inner.inflight.insert(pid) {
+                            // re-send PUBREC for retransmitted QoS2 publish
+                            if publish.qos == QoS::ExactlyOnce
+                                && self.inner.queue.borrow().iter().any(|item| {
+                                    !item.released && item.publish.packet_id == Some(pid)
+                                })
+                            {
+                                return Either::Right(Either::Left(Ready::Ok(Some(
+                                    codec::Packet::PublishReceived(codec::PublishAck {
+                                        packet_id: pid,
+                                        ..Default::default()
+                                    }),
+                                ))));
+                            }
+
                             self.inner.sink.send(codec::Packet::PublishAck(
                                 codec::PublishAck {
                                     packet_id: pid,
@@ -171,7 +245,8 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if !self.inner.sink.0.aliases.borrow().inbound.contains_key(&alias)
+                            {
                                 return Either::Right(Either::Right(ControlResponse::new(
                                     ControlMessage::proto_error(
                                         ProtocolError::UnknownTopicAlias,
@@ -188,22 +263,107 @@ where
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            self.inner
+                                .sink
+                                .0
+                                .aliases
+                                .borrow_mut()
+                                .inbound
+                                .insert(alias, publish.topic.clone());
                         }
                     }
+
+                    #[cfg(feature = "compress")]
+                    if let Err(err) = crate::v5::compress::decompress_publish(
+                        &mut publish,
+                        self.inner.sink.0.decompress_limit(),
+                    ) {
+                        log::trace!("Cannot decompress publish payload: {:?}", err);
+                        return Either::Right(Either::Left(Ready::Ok(packet_id.map(|pid| {
+                            inner.inflight.remove(&pid);
+                            codec::Packet::PublishAck(codec::PublishAck {
+                                packet_id: pid,
+                                reason_code: codec::PublishAckReason::PayloadFormatInvalid,
+                                ..Default::default()
+                            })
+                        }))));
+                    }
                 }
 
-                Either::Left(PublishResponse {
-                    packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
-                    inner: info,
-                    state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
-                    },
-                    _t: PhantomData,
-                })
+                // QoS2 publish waits for PUBREL, following publishes wait for it
+                let qos2 = publish.qos == QoS::ExactlyOnce && packet_id.is_some();
+                {
+                    let mut queue = self.inner.queue.borrow_mut();
+                    let bypass = publish.qos == QoS::AtMostOnce
+                        && self.inner.order == DeliveryOrder::Qos0Bypass;
+                    if qos2 || (!queue.is_empty() && !bypass) {
+                        queue.push_back(Queued { publish, released: !qos2 });
+                        return Either::Right(Either::Left(Ready::Ok(packet_id.and_then(
+                            |pid| {
+                                if qos2 {
+                                    Some(codec::Packet::PublishReceived(codec::PublishAck {
+                                        packet_id: pid,
+                                        ..Default::default()
+                                    }))
+                                } else {
+                                    None
+                                }
+                            },
+                        ))));
+                    }
+                }
+
+                Either::Left(Either::Left(self.deliver(publish)))
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(pkt)) => {
+                let mut queue = self.inner.queue.borrow_mut();
+                if let Some(item) = queue.iter_mut().find(|item| {
+                    !item.released && item.publish.packet_id == Some(pkt.packet_id)
+                }) {
+                    item.released = true;
+                } else {
+                    return Either::Right(Either::Left(Ready::Ok(Some(
+                        codec::Packet::PublishComplete(codec::PublishAck2 {
+                            packet_id: pkt.packet_id,
+                            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+                            properties: Default::default(),
+                            reason_string: None,
+                        }),
+                    ))));
+                }
+
+                // deliver released publishes
+                let mut released = Vec::new();
+                while queue.front().map(|item| item.released).unwrap_or(false) {
+                    released.push(queue.pop_front().unwrap().publish);
+                }
+                drop(queue);
+
+                let pending =
+                    released.into_iter().map(|pkt| Box::pin(self.deliver(pkt))).collect();
+                Either::Left(Either::Right(ReleaseResponse {
+                    pending,
+                    sink: self.inner.sink.clone(),
+                }))
             }
             DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
-                if let Err(err) = self.inner.sink.pkt_ack(Ack::Publish(packet)) {
+                let (packet_id, reason_code) = (packet.packet_id, packet.reason_code);
+                match self.inner.sink.pkt_ack(Ack::Publish(packet)) {
+                    Err(err) => Either::Right(Either::Right(ControlResponse::new(
+                        ControlMessage::proto_error(err),
+                        &self.inner,
+                    ))),
+                    Ok(acked) => {
+                        if acked {
+                            self.inner.sink.on_ack(packet_id, reason_code);
+                        }
+                        Either::Right(Either::Left(Ready::Ok(None)))
+                    }
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishReceived(packet)) => {
+                // client does not send QoS2 publishes
+                if let Err(err) = self.inner.sink.unknown_ack(packet.packet_id.get()) {
                     Either::Right(Either::Right(ControlResponse::new(
                         ControlMessage::proto_error(err),
                         &self.inner,
@@ -235,9 +395,14 @@ where
             DispatchItem::Item(codec::Packet::PingRequest) => {
                 Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PingResponse))))
             }
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::dis(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                log::trace!("Disconnect packet is received from server: {:?}", pkt);
+                self.inner.sink.set_disconnect(&pkt);
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::dis(pkt),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Auth(_)) => {
                 Either::Right(Either::Right(ControlResponse::new(
                     ControlMessage::proto_error(ProtocolError::Unexpected(
@@ -266,6 +431,7 @@ where
                 )))
             }
             DispatchItem::Item(codec::Packet::PingResponse) => {
+                self.inner.sink.pong();
                 Either::Right(Either::Left(Ready::Ok(None)))
             }
             DispatchItem::Item(pkt) => {
@@ -307,6 +473,7 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos2: bool,
         inner: Rc<Inner<C>>,
         _t: PhantomData<E>,
     }
@@ -354,7 +521,19 @@ where
                     }
                     Poll::Pending => return Poll::Pending,
                 };
-                if let Some(id) = NonZeroU16::new(*this.packet_id) {
+                if *this.qos2 {
+                    let id = NonZeroU16::new(*this.packet_id).unwrap();
+                    log::trace!("Sending publish complete for {} id", this.packet_id);
+                    this.inner.info.borrow_mut().inflight.remove(&id);
+                    Poll::Ready(Ok(Some(publish_complete(
+                        id,
+                        ack.properties,
+                        ack.reason_string,
+                    ))))
+                } else if ack.deferred {
+                    // ack is sent by AckToken
+                    Poll::Ready(Ok(None))
+                } else if let Some(id) = NonZeroU16::new(*this.packet_id) {
                     log::trace!("Sending publish ack for {} id", this.packet_id);
                     this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
@@ -368,11 +547,67 @@ where
                     Poll::Ready(Ok(None))
                 }
             }
-            PublishResponseStateProject::Control { fut } => fut.poll(cx),
+            PublishResponseStateProject::Control { fut } => {
+                let qos2 = *this.qos2;
+                fut.poll(cx).map_ok(|pkt| match pkt {
+                    Some(codec::Packet::PublishAck(ack)) if qos2 => {
+                        Some(publish_complete(ack.packet_id, ack.properties, ack.reason_string))
+                    }
+                    pkt => pkt,
+                })
+            }
         }
     }
 }
 
+/// PUBCOMP packet, reason code of QoS2 publish is already sent with PUBREC
+fn publish_complete(
+    packet_id: NonZeroU16,
+    properties: codec::UserProperties,
+    reason_string: Option<ntex::util::ByteString>,
+) -> codec::Packet {
+    codec::Packet::PublishComplete(codec::PublishAck2 {
+        packet_id,
+        reason_code: codec::PublishAck2Reason::Success,
+        properties,
+        reason_string,
+    })
+}
+
+/// Released publishes response future
+///
+/// Publishes are completed in order, acks are sent with sink.
+pub(crate) struct ReleaseResponse<T: Service, C: Service, E> {
+    pending: VecDeque<Pin<Box<PublishResponse<T, C, E>>>>,
+    sink: MqttSink,
+}
+
+impl<T, C, E> Future for ReleaseResponse<T, C, E>
+where
+    T: Service<Request = Publish, Response = Either<Publish, PublishAck>, Error = E>,
+    C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
+{
+    type Output = Result<Option<codec::Packet>, MqttError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        while let Some(fut) = this.pending.front_mut() {
+            match fut.as_mut().poll(cx) {
+                Poll::Ready(Ok(pkt)) => {
+                    this.pending.pop_front();
+                    if let Some(pkt) = pkt {
+                        this.sink.send(pkt);
+                    }
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(None))
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Control service response future
     pub(crate) struct ControlResponse<C: Service, E>
@@ -396,6 +631,12 @@ where
             ControlMessage::Error(_) | ControlMessage::ProtocolError(_) => true,
             _ => false,
         };
+        #[cfg(feature = "trace")]
+        if let ControlMessage::ProtocolError(ref err) = pkt {
+            inner.sink.0.codec.trace(|| {
+                crate::v5::trace::ProtocolEventKind::ProtocolError(err.get_ref().to_string())
+            });
+        }
 
         Self {
             error,
@@ -0,0 +1,151 @@
+//! Client keep-alive
+//!
+//! Client pings server every keep-alive interval and expects PINGRESP
+//! within ping timeout. In adaptive mode client starts pinging with short
+//! interval and lengthens it while pings get answered. Unanswered ping
+//! after idle interval is treated as NAT timeout, interval gets reduced and
+//! does not grow up to failed value anymore. State is shared between
+//! connections of the same connector.
+use std::{cell::Cell, rc::Rc};
+
+use ntex::time::{Millis, Seconds};
+
+use crate::clock::Clock;
+use crate::v5::{codec, MqttSink};
+
+/// Client keep-alive task settings
+#[derive(Clone, Default)]
+pub(super) struct Pinger {
+    /// PINGRESP timeout, half of keep-alive if not set
+    pub(super) ping_timeout: Option<Seconds>,
+    pub(super) adaptive: Option<Rc<AdaptiveKeepAlive>>,
+}
+
+impl Pinger {
+    fn interval(&self, keepalive: Seconds) -> Seconds {
+        self.adaptive.as_ref().map(|a| a.interval(keepalive)).unwrap_or(keepalive)
+    }
+
+    /// Run keep-alive task until connection is closed
+    pub(super) async fn run(self, sink: MqttSink, keepalive: Seconds, clock: Clock) {
+        log::debug!("start mqtt client keep-alive task");
+
+        let ping_timeout = self.ping_timeout.unwrap_or(Seconds((keepalive.0 / 2).max(1)));
+        let mut interval = self.interval(keepalive);
+        clock.sleep(Millis::from(interval)).await;
+
+        loop {
+            if !sink.ping() {
+                // connection is closed
+                log::debug!("mqtt client connection is closed, stopping keep-alive task");
+                break;
+            }
+
+            let grace = Seconds(ping_timeout.0.min(interval.0));
+            if grace.non_zero() {
+                clock.sleep(Millis::from(grace)).await;
+                if !sink.is_open() {
+                    break;
+                } else if sink.ping_pending() {
+                    log::debug!("mqtt client ping is not answered in {:?}", grace);
+                    if let Some(ref adaptive) = self.adaptive {
+                        adaptive.lost(interval);
+                    }
+                    let pkt =
+                        codec::Disconnect::new(codec::DisconnectReasonCode::KeepAliveTimeout);
+                    sink.set_disconnect(&pkt);
+                    sink.close_with_reason(pkt);
+                    break;
+                }
+                if let Some(ref adaptive) = self.adaptive {
+                    adaptive.survived(interval);
+                }
+            }
+
+            let next = self.interval(keepalive);
+            clock.sleep(Millis::from(Seconds(next.0.saturating_sub(grace.0)))).await;
+            interval = next;
+        }
+    }
+}
+
+/// Ping interval probing state
+#[derive(Debug)]
+pub(super) struct AdaptiveKeepAlive {
+    min: Seconds,
+    max: Seconds,
+    current: Cell<Seconds>,
+    /// Smallest interval that caused connection loss
+    ceiling: Cell<Option<Seconds>>,
+}
+
+impl AdaptiveKeepAlive {
+    pub(super) fn new(min: Seconds, max: Seconds) -> Self {
+        AdaptiveKeepAlive { min, max, current: Cell::new(min), ceiling: Cell::new(None) }
+    }
+
+    /// Next ping interval, never exceeds negotiated keep-alive
+    pub(super) fn interval(&self, keepalive: Seconds) -> Seconds {
+        Seconds(self.current.get().0.min(keepalive.0).max(1))
+    }
+
+    /// Ping after idle `interval` got answered, lengthen interval
+    pub(super) fn survived(&self, interval: Seconds) {
+        if interval.0 < self.current.get().0 {
+            return;
+        }
+        let mut next = interval.0.saturating_add((interval.0 / 2).max(1)).min(self.max.0);
+        if let Some(ceiling) = self.ceiling.get() {
+            next = next.min(ceiling.0.saturating_sub(1));
+        }
+        self.current.set(Seconds(next.max(self.min.0)));
+    }
+
+    /// Connection got lost after idle `interval`, back off
+    pub(super) fn lost(&self, interval: Seconds) {
+        if interval.0 > self.min.0 {
+            match self.ceiling.get() {
+                Some(ceiling) if ceiling.0 <= interval.0 => (),
+                _ => self.ceiling.set(Some(interval)),
+            }
+        }
+        self.current.set(Seconds((interval.0 / 2).max(self.min.0)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_keepalive() {
+        let ka = AdaptiveKeepAlive::new(Seconds(10), Seconds(60));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(10));
+        assert_eq!(ka.interval(Seconds(5)), Seconds(5));
+
+        ka.survived(Seconds(10));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(15));
+        ka.survived(Seconds(15));
+        ka.survived(Seconds(22));
+        ka.survived(Seconds(33));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(49));
+        ka.survived(Seconds(49));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(60));
+
+        // nat timeout at 60 seconds
+        ka.lost(Seconds(60));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(30));
+        ka.survived(Seconds(30));
+        ka.survived(Seconds(45));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(59));
+        ka.survived(Seconds(59));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(59));
+
+        // stale result does not change interval
+        ka.survived(Seconds(10));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(59));
+
+        ka.lost(Seconds(10));
+        assert_eq!(ka.interval(Seconds(60)), Seconds(10));
+    }
+}
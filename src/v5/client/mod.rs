@@ -1,14 +1,23 @@
 //! MQTT5 client
 
+mod breaker;
+mod callback;
 mod connection;
 mod connector;
 pub mod control;
 mod dispatcher;
+mod keepalive;
+mod mux;
 
 pub use self::connection::{Client, ClientRouter};
-pub use self::connector::MqttConnector;
+pub use self::connector::{Capabilities, ConnectorPool, MqttConnector};
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::dispatcher::DeliveryOrder;
+pub use self::mux::RequestMux;
+
+#[cfg(feature = "compress")]
+pub use crate::v5::compress::{Compression, CompressionNegotiation};
 
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
-pub use crate::v5::{codec, error, sink::MqttSink};
+pub use crate::v5::{codec, error, sink::MqttSink, sink::SubscriptionChange};
@@ -0,0 +1,136 @@
+//! Request/response multiplexing
+//!
+//! `RequestMux` subscribes to single response topic and sends requests
+//! with `response_topic` and unique `correlation_data` properties. Responses
+//! are routed to waiting requests by correlation data, so any number of
+//! concurrent requests could share one connection and one subscription.
+use std::{cell::Cell, cell::RefCell, fmt, future::Future, rc::Rc};
+
+use ntex::channel::oneshot;
+use ntex::time::Millis;
+use ntex::util::{ByteString, Bytes, Either, HashMap};
+
+use crate::v5::error::{RequestError, SendPacketError};
+use crate::v5::{publish::Publish, sink::MqttSink};
+use crate::{clock::Clock, utils::select};
+
+/// Request/response multiplexer
+///
+/// Created with `Client::request_mux()`.
+#[derive(Clone)]
+pub struct RequestMux(Rc<Inner>);
+
+struct Inner {
+    sink: MqttSink,
+    response_topic: ByteString,
+    clock: Clock,
+    next_id: Cell<u64>,
+    waiters: RefCell<HashMap<Bytes, oneshot::Sender<Publish>>>,
+}
+
+impl fmt::Debug for RequestMux {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestMux")
+            .field("response_topic", &self.0.response_topic)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+impl RequestMux {
+    pub(super) fn new(sink: MqttSink, response_topic: ByteString, clock: Clock) -> Self {
+        RequestMux(Rc::new(Inner {
+            sink,
+            response_topic,
+            clock,
+            next_id: Cell::new(0),
+            waiters: RefCell::new(HashMap::default()),
+        }))
+    }
+
+    /// Response topic
+    pub fn response_topic(&self) -> &ByteString {
+        &self.0.response_topic
+    }
+
+    /// Number of requests waiting for response
+    pub fn pending(&self) -> usize {
+        self.0.waiters.borrow().len()
+    }
+
+    /// Send request and wait for response at most `timeout`
+    ///
+    /// Request is published with QoS 0, responder must publish response to
+    /// request's response topic with the same correlation data. On timeout
+    /// request is forgotten and late response is dropped.
+    pub fn request<U, P>(
+        &self,
+        topic: U,
+        payload: P,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<Publish, RequestError>>
+    where
+        ByteString: From<U>,
+        Bytes: From<P>,
+    {
+        let id = self.0.next_id.get();
+        self.0.next_id.set(id.wrapping_add(1));
+        let correlation = Bytes::copy_from_slice(&id.to_be_bytes());
+
+        let (tx, rx) = oneshot::channel();
+        self.0.waiters.borrow_mut().insert(correlation.clone(), tx);
+        let guard = Guard(self.0.clone(), correlation.clone());
+
+        let response_topic = self.0.response_topic.clone();
+        let sleep = self.0.clock.sleep(timeout);
+        let res = self
+            .0
+            .sink
+            .publish(topic, payload)
+            .properties(move |props| {
+                props.response_topic = Some(response_topic);
+                props.correlation_data = Some(correlation);
+            })
+            .send_at_most_once();
+
+        async move {
+            res.map_err(RequestError::Send)?;
+            let res = match select(rx, sleep).await {
+                Either::Left(Ok(publish)) => Ok(publish),
+                Either::Left(Err(_)) => Err(RequestError::Send(SendPacketError::Disconnected)),
+                Either::Right(_) => {
+                    log::trace!("Request timeout, correlation data: {:?}", guard.1);
+                    Err(RequestError::Timeout)
+                }
+            };
+            drop(guard);
+            res
+        }
+    }
+
+    /// Route response to waiting request
+    pub(super) fn response(&self, publish: Publish) {
+        let tx = match publish.packet().properties.correlation_data {
+            Some(ref data) => self.0.waiters.borrow_mut().remove(data),
+            None => None,
+        };
+        match tx {
+            Some(tx) => {
+                let _ = tx.send(publish);
+            }
+            None => log::trace!(
+                "Response without waiting request, correlation data: {:?}",
+                publish.packet().properties.correlation_data
+            ),
+        }
+    }
+}
+
+/// Removes waiter once request completes, times out or gets dropped
+struct Guard(Rc<Inner>, Bytes);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.0.waiters.borrow_mut().remove(&self.1);
+    }
+}
@@ -0,0 +1,76 @@
+//! Connection close handle
+//!
+//! Handle could be sent to other threads, for example to admin tooling
+//! that runs outside of server workers. Close request is stored in handle
+//! and connection task gets woken up, connection is closed by its own
+//! worker. Connection has single handle, handle is cloned for each request.
+//! Handle of closed connection does nothing.
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+
+use ntex::util::{poll_fn, Either};
+
+use super::{codec, MqttSink};
+
+/// Thread safe connection close handle
+#[derive(Clone, Debug)]
+pub struct CloseHandle(Arc<Mutex<Inner>>);
+
+#[derive(Debug, Default)]
+struct Inner {
+    closed: bool,
+    reason: Option<codec::DisconnectReasonCode>,
+    waker: Option<Waker>,
+}
+
+impl CloseHandle {
+    pub(super) fn new(sink: &MqttSink) -> Self {
+        let handle = CloseHandle(Arc::default());
+
+        let inner = handle.0.clone();
+        let sink = sink.clone();
+        ntex::rt::spawn(async move {
+            let on_disconnect = sink.0.state.on_disconnect();
+            let request = poll_fn(|cx| {
+                let mut inner = inner.lock().unwrap();
+                if let Some(reason) = inner.reason {
+                    Poll::Ready(reason)
+                } else {
+                    inner.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            });
+
+            if let Either::Left(reason) = crate::utils::select(request, on_disconnect).await {
+                log::trace!("Closing connection with {:?} reason by close handle", reason);
+                sink.close_with_reason(codec::Disconnect {
+                    reason_code: reason,
+                    ..Default::default()
+                });
+            }
+            inner.lock().unwrap().closed = true;
+        });
+        handle
+    }
+
+    /// Check if connection is closed
+    pub fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().closed
+    }
+
+    /// Close connection with DISCONNECT packet with `reason` code
+    ///
+    /// Returns `false` if connection is closed already or close is
+    /// requested by other handle clone.
+    pub fn close(&self, reason: codec::DisconnectReasonCode) -> bool {
+        let mut inner = self.0.lock().unwrap();
+        if inner.closed || inner.reason.is_some() {
+            return false;
+        }
+        inner.reason = Some(reason);
+        if let Some(waker) = inner.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+}
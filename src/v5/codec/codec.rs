@@ -1,12 +1,23 @@
-use std::cell::Cell;
+use std::task::{Context, Poll};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
 
+use ntex::channel::mpsc;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{Buf, BytesMut};
+use ntex::task::LocalWaker;
+use ntex::util::{Buf, BufMut, Bytes, BytesMut};
 
-use super::{decode::decode_packet, encode::EncodeLtd, Packet};
+use super::{
+    decode::decode_packet, encode::EncodeLtd, packet::header_size, packet::Connect, Packet,
+    PropertyValue,
+};
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{FixedHeader, MAX_PACKET_SIZE};
-use crate::utils::decode_variable_length;
+use crate::io::ReadLimit;
+use crate::types::{packet_type, FixedHeader, MAX_PACKET_SIZE};
+use crate::utils::{decode_variable_length, write_variable_length};
+#[cfg(feature = "prometheus")]
+use crate::v5::metrics::{ConnectionMetrics, Metrics};
+#[cfg(feature = "trace")]
+use crate::v5::trace::{ProtocolEvent, ProtocolEventKind, ProtocolEvents};
 
 #[derive(Debug)]
 pub struct Codec {
@@ -14,11 +25,24 @@ pub struct Codec {
     max_in_size: Cell<u32>,
     max_out_size: Cell<u32>,
     flags: Cell<CodecFlags>,
+    stream_min: Cell<u32>,
+    read_limit: Rc<ReadLimit>,
+    max_will_size: Cell<u32>,
+    encoded: Cell<u64>,
+    stream: RefCell<Option<Rc<PayloadChannel>>>,
+    stream_rx: RefCell<Option<(PayloadReceiver, usize)>>,
+    /// Raw bytes of decoded CONNECT packet
+    connect_raw: RefCell<Option<Bytes>>,
+    #[cfg(feature = "trace")]
+    trace: RefCell<Option<mpsc::Sender<ProtocolEvent>>>,
+    #[cfg(feature = "prometheus")]
+    metrics: ConnectionMetrics,
 }
 
 bitflags::bitflags! {
     pub struct CodecFlags: u8 {
         const NO_PROBLEM_INFO = 0b0000_0001;
+        const STRICT_UTF8     = 0b0000_0010;
     }
 }
 
@@ -26,6 +50,8 @@ bitflags::bitflags! {
 enum DecodeState {
     FrameHeader,
     Frame(FixedHeader),
+    /// Remaining bytes of streamed publish payload
+    Payload(usize),
 }
 
 impl Codec {
@@ -36,6 +62,17 @@ impl Codec {
             max_in_size: Cell::new(0),
             max_out_size: Cell::new(0),
             flags: Cell::new(CodecFlags::empty()),
+            stream_min: Cell::new(0),
+            read_limit: Rc::new(ReadLimit::default()),
+            max_will_size: Cell::new(0),
+            encoded: Cell::new(0),
+            stream: RefCell::new(None),
+            stream_rx: RefCell::new(None),
+            connect_raw: RefCell::new(None),
+            #[cfg(feature = "trace")]
+            trace: RefCell::new(None),
+            #[cfg(feature = "prometheus")]
+            metrics: ConnectionMetrics::default(),
         }
     }
 
@@ -76,6 +113,199 @@ impl Codec {
     pub fn set_max_outbound_size(&self, size: u32) {
         self.max_out_size.set(size);
     }
+
+    /// Set min payload size of streamed inbound publish.
+    ///
+    /// Publish packets with larger remaining length get decoded as soon as
+    /// variable header is received, payload is delivered in chunks with
+    /// payload stream. If size is set to `0`, streaming is disabled.
+    /// By default streaming is disabled.
+    pub fn set_stream_payload(&self, size: u32) {
+        self.stream_min.set(size);
+    }
+
+    /// Set max size of read buffer.
+    ///
+    /// Decoder fails with `ReadBufferExceeded` error if read buffer holds
+    /// more than `size` bytes. Unlike max inbound size, limit applies to
+    /// buffered data regardless of packets boundaries. If size is set
+    /// to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn set_max_read_buffer(&self, size: usize) {
+        self.read_limit.max.set(size);
+    }
+
+    /// Read buffer limit, `None` if read buffer is not limited
+    pub(crate) fn read_limit(&self) -> Option<Rc<ReadLimit>> {
+        if self.read_limit.max.get() != 0 {
+            Some(self.read_limit.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Set max size of last will.
+    ///
+    /// Decoder fails with `WillSizeExceeded` error if CONNECT packet
+    /// contains last will with larger topic and message. If size is set
+    /// to `0`, size is unlimited.
+    /// By default max size is set to `0`
+    pub fn set_max_will_size(&self, size: u32) {
+        self.max_will_size.set(size);
+    }
+
+    /// Set strict validation of user properties.
+    ///
+    /// User property keys and values must be valid UTF-8 strings without
+    /// null character, MQTT-1.5.4-2. Invalid UTF-8 is always rejected, in
+    /// strict mode decoder also fails with `MalformedPacket` error if user
+    /// property contains U+0000.
+    /// By default strict mode is disabled.
+    pub fn set_strict_utf8(&self, strict: bool) {
+        let mut flags = self.flags.get();
+        flags.set(CodecFlags::STRICT_UTF8, strict);
+        self.flags.set(flags);
+    }
+
+    /// Total number of encoded bytes
+    pub(crate) fn encoded(&self) -> u64 {
+        self.encoded.get()
+    }
+
+    /// Account bytes that are written to buffer without encoder
+    pub(crate) fn add_encoded(&self, size: usize) {
+        self.encoded.set(self.encoded.get() + size as u64);
+        #[cfg(feature = "prometheus")]
+        self.metrics.sent(packet_type::PUBLISH_START, size);
+    }
+
+    /// Take payload stream of last decoded publish packet
+    pub(crate) fn take_payload_stream(&self) -> Option<(PayloadReceiver, usize)> {
+        self.stream_rx.borrow_mut().take()
+    }
+
+    /// Stop payload delivery, incomplete payload stream fails
+    pub(crate) fn abort_payload_stream(&self) {
+        self.close_payload_stream();
+        self.stream_rx.borrow_mut().take();
+    }
+
+    /// Check if decoder could deliver more payload of streamed publish
+    ///
+    /// Not ready if receiver holds `STREAM_BUFFER_SIZE` bytes of not yet
+    /// consumed payload, current task is woken once receiver reads it.
+    pub(crate) fn poll_payload_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        match *self.stream.borrow() {
+            Some(ref ch) if !ch.rx_closed.get() && ch.size.get() >= STREAM_BUFFER_SIZE => {
+                log::trace!("Payload stream buffer is full, pause reading");
+                ch.tx_task.register(cx.waker());
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+
+    fn close_payload_stream(&self) {
+        if let Some(ch) = self.stream.borrow_mut().take() {
+            ch.tx_closed.set(true);
+            ch.rx_task.wake();
+        }
+    }
+
+    /// Take raw bytes of last decoded connect packet
+    pub(crate) fn take_connect_raw(&self) -> Option<Bytes> {
+        self.connect_raw.borrow_mut().take()
+    }
+
+    #[cfg(feature = "trace")]
+    /// Attach protocol trace stream, replaces previously attached stream
+    pub(crate) fn trace_events(&self) -> ProtocolEvents {
+        let (tx, rx) = mpsc::channel();
+        *self.trace.borrow_mut() = Some(tx);
+        ProtocolEvents::new(rx)
+    }
+
+    #[cfg(feature = "trace")]
+    /// Emit protocol trace event if trace stream is attached
+    pub(crate) fn trace<F>(&self, f: F)
+    where
+        F: FnOnce() -> ProtocolEventKind,
+    {
+        if let Some(ref tx) = *self.trace.borrow() {
+            if tx.send(ProtocolEvent { time: std::time::Instant::now(), kind: f() }).is_err() {
+                log::trace!("Protocol trace stream is dropped");
+            }
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    /// Emit close event and detach protocol trace stream
+    pub(crate) fn trace_closed(&self) {
+        self.trace(|| ProtocolEventKind::Closed);
+        self.trace.borrow_mut().take();
+    }
+
+    #[cfg(feature = "prometheus")]
+    /// Attach server-wide metrics
+    pub(crate) fn set_metrics(&self, metrics: Metrics) {
+        self.metrics.attach(metrics);
+    }
+
+    #[cfg(feature = "prometheus")]
+    /// Connection is closed, detach server-wide metrics
+    pub(crate) fn metrics_closed(&self) {
+        self.metrics.detach();
+    }
+
+    #[cfg(feature = "prometheus")]
+    /// Render connection metrics in Prometheus text format
+    pub(crate) fn metrics_text(&self) -> String {
+        self.metrics.metrics_text()
+    }
+
+    fn is_streamed(&self, fixed: &FixedHeader) -> bool {
+        let stream_min = self.stream_min.get();
+        stream_min != 0
+            && fixed.remaining_length > stream_min
+            && (packet_type::PUBLISH_START..=packet_type::PUBLISH_END)
+                .contains(&fixed.first_byte)
+    }
+
+    pub(crate) fn max_inbound(&self) -> u32 {
+        self.max_in_size.get()
+    }
+
+    pub(crate) fn max_outbound(&self) -> u32 {
+        self.max_out_size.get()
+    }
+
+    /// Encode QoS0 PUBLISH packet without properties directly into buffer
+    pub(crate) fn encode_publish_qos0(
+        &self,
+        topic: &str,
+        payload: &[u8],
+        dst: &mut BytesMut,
+    ) -> Result<(), EncodeError> {
+        let max_out_size = self.max_out_size.get();
+        let max_size = if max_out_size != 0 { max_out_size } else { MAX_PACKET_SIZE };
+        // topic length + topic + properties length (0) + payload
+        let content_size = 2 + topic.len() + 1 + payload.len();
+        if topic.len() > u16::MAX as usize || content_size > max_size as usize {
+            return Err(EncodeError::InvalidLength);
+        }
+        dst.reserve(content_size + 5);
+        let start = dst.len();
+        dst.put_u8(packet_type::PUBLISH_START);
+        write_variable_length(content_size as u32, dst);
+        dst.put_u16(topic.len() as u16);
+        dst.extend_from_slice(topic.as_bytes());
+        dst.put_u8(0);
+        dst.extend_from_slice(payload);
+        self.encoded.set(self.encoded.get() + (dst.len() - start) as u64);
+        #[cfg(feature = "prometheus")]
+        self.metrics.sent(packet_type::PUBLISH_START, dst.len() - start);
+        Ok(())
+    }
 }
 
 impl Default for Codec {
@@ -89,6 +319,42 @@ impl Decoder for Codec {
     type Error = DecodeError;
 
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
+        let len = src.len();
+        let res = self.decode_frame(src);
+        self.read_limit.consumed(len.saturating_sub(src.len()));
+        #[cfg(feature = "prometheus")]
+        if let Ok(ref item) = res {
+            if len > src.len() {
+                self.metrics.received_bytes(len - src.len());
+            }
+            if let Some(ref pkt) = item {
+                self.metrics.received(pkt.packet_type());
+                if let Packet::PublishAck(_) | Packet::PublishReceived(_) = pkt {
+                    self.metrics.inflight_remove();
+                }
+            }
+        }
+        if let Ok(Some(ref pkt)) = res {
+            if self.flags.get().contains(CodecFlags::STRICT_UTF8) {
+                check_user_properties(pkt)?;
+            }
+        }
+        #[cfg(feature = "trace")]
+        if let Ok(Some(ref pkt)) = res {
+            self.trace(|| ProtocolEventKind::Received(pkt.clone()));
+        }
+        res
+    }
+}
+
+impl Codec {
+    fn decode_frame(&self, src: &mut BytesMut) -> Result<Option<Packet>, DecodeError> {
+        let max_read_buf = self.read_limit.max.get();
+        if max_read_buf != 0 && src.len() > max_read_buf {
+            log::debug!("Read buffer size {} exceeds limit {}", src.len(), max_read_buf);
+            return Err(DecodeError::ReadBufferExceeded { size: src.len(), max: max_read_buf });
+        }
+
         loop {
             match self.state.get() {
                 DecodeState::FrameHeader => {
@@ -107,13 +373,17 @@ impl Decoder for Codec {
                                     max_in_size,
                                     remaining_length
                                 );
-                                return Err(DecodeError::MaxSizeExceeded);
+                                return Err(DecodeError::MaxSizeExceeded {
+                                    size: remaining_length,
+                                    max: max_in_size,
+                                });
                             }
                             src.advance(consumed + 1);
-                            self.state.set(DecodeState::Frame(FixedHeader {
-                                first_byte,
-                                remaining_length,
-                            }));
+                            let fixed = FixedHeader { first_byte, remaining_length };
+                            self.state.set(DecodeState::Frame(fixed));
+                            if self.is_streamed(&fixed) {
+                                continue;
+                            }
                             // todo: validate remaining_length against max frame size config
                             let remaining_length = remaining_length as usize;
                             if src.len() < remaining_length {
@@ -127,12 +397,52 @@ impl Decoder for Codec {
                         }
                     }
                 }
+                DecodeState::Frame(fixed) if self.is_streamed(&fixed) => {
+                    let size = match header_size(src, fixed.first_byte & 0b0000_1111)? {
+                        Some(size) if size <= fixed.remaining_length as usize => size,
+                        Some(_) => return Err(DecodeError::InvalidLength),
+                        None => return Ok(None),
+                    };
+                    let packet = decode_packet(src.split_to(size).freeze(), fixed.first_byte)?;
+
+                    let remaining = fixed.remaining_length as usize - size;
+                    let ch = Rc::new(PayloadChannel::default());
+                    *self.stream.borrow_mut() = Some(ch.clone());
+                    *self.stream_rx.borrow_mut() = Some((PayloadReceiver(ch), remaining));
+                    self.state.set(DecodeState::Payload(remaining));
+                    return Ok(Some(packet));
+                }
+                DecodeState::Payload(remaining) => {
+                    let size = remaining.min(src.len());
+                    if size != 0 {
+                        let chunk = src.split_to(size).freeze();
+                        if let Some(ref ch) = *self.stream.borrow() {
+                            ch.push(chunk);
+                        }
+                    }
+                    if size == remaining {
+                        self.close_payload_stream();
+                        self.state.set(DecodeState::FrameHeader);
+                        src.reserve(5);
+                    } else {
+                        self.state.set(DecodeState::Payload(remaining - size));
+                        return Ok(None);
+                    }
+                }
                 DecodeState::Frame(fixed) => {
                     if src.len() < fixed.remaining_length as usize {
                         return Ok(None);
                     }
-                    let packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
-                    let packet = decode_packet(packet_buf, fixed.first_byte)?;
+                    let mut packet_buf = src.split_to(fixed.remaining_length as usize).freeze();
+                    let packet = if fixed.first_byte == packet_type::CONNECT {
+                        *self.connect_raw.borrow_mut() = Some(packet_buf.clone());
+                        Packet::Connect(Box::new(Connect::decode_limited(
+                            &mut packet_buf,
+                            self.max_will_size.get(),
+                        )?))
+                    } else {
+                        decode_packet(packet_buf, fixed.first_byte)?
+                    };
                     self.state.set(DecodeState::FrameHeader);
                     src.reserve(5); // enough to fix 1 fixed header byte + 4 bytes max variable packet length
 
@@ -148,6 +458,87 @@ impl Decoder for Codec {
     }
 }
 
+/// Max size of received and not yet consumed payload of streamed publish,
+/// decoder stops reading once receiver holds that much data
+const STREAM_BUFFER_SIZE: usize = 65_536;
+
+/// Bounded channel of streamed publish payload chunks
+#[derive(Debug, Default)]
+struct PayloadChannel {
+    chunks: RefCell<VecDeque<Bytes>>,
+    /// Size of buffered chunks
+    size: Cell<usize>,
+    tx_closed: Cell<bool>,
+    rx_closed: Cell<bool>,
+    tx_task: LocalWaker,
+    rx_task: LocalWaker,
+}
+
+impl PayloadChannel {
+    fn push(&self, chunk: Bytes) {
+        // receiver is dropped, rest of payload is discarded
+        if !self.rx_closed.get() {
+            self.size.set(self.size.get() + chunk.len());
+            self.chunks.borrow_mut().push_back(chunk);
+            self.rx_task.wake();
+        }
+    }
+}
+
+/// Receiving side of streamed publish payload
+#[derive(Debug)]
+pub(crate) struct PayloadReceiver(Rc<PayloadChannel>);
+
+impl PayloadReceiver {
+    /// Next payload chunk, `None` if sender is closed and all chunks are read
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        let ch = &self.0;
+        if let Some(chunk) = ch.chunks.borrow_mut().pop_front() {
+            let size = ch.size.get();
+            ch.size.set(size - chunk.len());
+            if size >= STREAM_BUFFER_SIZE && ch.size.get() < STREAM_BUFFER_SIZE {
+                ch.tx_task.wake();
+            }
+            Poll::Ready(Some(chunk))
+        } else if ch.tx_closed.get() {
+            Poll::Ready(None)
+        } else {
+            ch.rx_task.register(cx.waker());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for PayloadReceiver {
+    fn drop(&mut self) {
+        let ch = &self.0;
+        ch.rx_closed.set(true);
+        ch.chunks.borrow_mut().clear();
+        ch.size.set(0);
+        ch.tx_task.wake();
+    }
+}
+
+/// Check user properties for null character, MQTT-1.5.4-2
+fn check_user_properties(pkt: &Packet) -> Result<(), DecodeError> {
+    let will = match pkt {
+        Packet::Connect(ref pkt) => pkt.last_will.as_ref().map(|will| will.properties()),
+        _ => None,
+    };
+    let props = pkt.properties();
+    let invalid =
+        props.iter().chain(will.iter().flat_map(|p| p.iter())).any(|prop| match prop {
+            PropertyValue::User(key, val) => key.contains('\0') || val.contains('\0'),
+            _ => false,
+        });
+    if invalid {
+        log::trace!("User property contains null character");
+        Err(DecodeError::MalformedPacket)
+    } else {
+        Ok(())
+    }
+}
+
 impl Encoder for Codec {
     type Item = Packet;
     type Error = EncodeError;
@@ -193,7 +584,20 @@ impl Encoder for Codec {
             return Err(EncodeError::InvalidLength); // todo: separate error code
         }
         dst.reserve(content_size + 5);
+        let start = dst.len();
         item.encode(dst, content_size as u32)?; // safe: max_size <= u32 max value
+        self.encoded.set(self.encoded.get() + (dst.len() - start) as u64);
+        #[cfg(feature = "prometheus")]
+        {
+            self.metrics.sent(item.packet_type(), dst.len() - start);
+            if let Packet::Publish(ref pkt) = item {
+                if pkt.qos != crate::types::QoS::AtMostOnce && !pkt.dup {
+                    self.metrics.inflight_add();
+                }
+            }
+        }
+        #[cfg(feature = "trace")]
+        self.trace(|| ProtocolEventKind::Sent(item));
         Ok(())
     }
 }
@@ -207,6 +611,214 @@ mod tests {
         let codec = Codec::new().max_inbound_size(5);
         let mut buf = BytesMut::new();
         buf.extend_from_slice(b"\0\x09");
-        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MaxSizeExceeded));
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::MaxSizeExceeded { size: 9, max: 5 })
+        );
+    }
+
+    #[test]
+    fn test_malformed_remaining_length() {
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\xff\xff\xff");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+
+        // fourth length byte must not have continuation bit
+        buf.extend_from_slice(b"\xff");
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+    }
+
+    #[test]
+    fn test_encode_publish_qos0() {
+        use crate::types::QoS;
+        use ntex::util::{ByteString, Bytes};
+
+        let codec = Codec::new();
+        let mut raw = BytesMut::new();
+        codec.encode_publish_qos0("test/topic", b"payload", &mut raw).unwrap();
+
+        let pkt = super::super::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("test/topic"),
+            packet_id: None,
+            payload: Bytes::from_static(b"payload"),
+            properties: Default::default(),
+        };
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Publish(pkt.clone()), &mut buf).unwrap();
+        assert_eq!(raw, buf);
+        assert_eq!(codec.decode(&mut raw).unwrap(), Some(Packet::Publish(pkt)));
+
+        codec.set_max_outbound_size(10);
+        assert_eq!(
+            codec.encode_publish_qos0("test/topic", b"payload", &mut raw),
+            Err(EncodeError::InvalidLength)
+        );
+    }
+
+    #[test]
+    fn test_max_read_buffer() {
+        let codec = Codec::new();
+        codec.set_max_read_buffer(8);
+
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x7f\0\x04test");
+        assert_eq!(codec.decode(&mut buf), Ok(None));
+        buf.extend_from_slice(b"/topic");
+        assert_eq!(
+            codec.decode(&mut buf),
+            Err(DecodeError::ReadBufferExceeded { size: 12, max: 8 })
+        );
+    }
+
+    #[test]
+    fn test_max_will_size() {
+        use crate::types::QoS;
+        use ntex::util::{ByteString, Bytes};
+
+        let pkt = Packet::Connect(Box::new(Connect {
+            client_id: ByteString::from_static("user"),
+            last_will: Some(super::super::LastWill {
+                qos: QoS::AtMostOnce,
+                retain: false,
+                topic: ByteString::from_static("will"),
+                message: Bytes::from_static(b"message"),
+                will_delay_interval_sec: None,
+                correlation_data: None,
+                message_expiry_interval: None,
+                content_type: None,
+                user_properties: Vec::new(),
+                is_utf8_payload: None,
+                response_topic: None,
+            }),
+            ..Connect::default()
+        }));
+        let mut buf = BytesMut::new();
+        Codec::new().encode(pkt.clone(), &mut buf).unwrap();
+        let mut buf2 = buf.clone();
+
+        let codec = Codec::new();
+        codec.set_max_will_size(11);
+        assert_eq!(codec.decode(&mut buf), Ok(Some(pkt)));
+
+        let codec = Codec::new();
+        codec.set_max_will_size(10);
+        assert_eq!(
+            codec.decode(&mut buf2),
+            Err(DecodeError::WillSizeExceeded { size: 11, max: 10 })
+        );
+    }
+
+    #[test]
+    fn test_strict_utf8() {
+        use ntex::util::ByteString;
+
+        let mut pkt = super::super::Publish {
+            dup: false,
+            retain: false,
+            qos: crate::types::QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::new(),
+            properties: Default::default(),
+        };
+        pkt.properties.user_properties.push(("key".into(), "val\0ue".into()));
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(Packet::Publish(pkt.clone()), &mut buf).unwrap();
+        let raw = buf.clone();
+        assert_eq!(codec.decode(&mut buf), Ok(Some(Packet::Publish(pkt))));
+
+        codec.set_strict_utf8(true);
+        let mut buf = raw;
+        assert_eq!(codec.decode(&mut buf), Err(DecodeError::MalformedPacket));
+
+        // invalid utf-8 is always rejected
+        let codec = Codec::new();
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\x30\x0e\x00\x04test\x07\x26\x00\x01\xff\x00\x01v");
+        assert!(std::matches!(codec.decode(&mut buf), Err(DecodeError::Utf8Error(_))));
+    }
+
+    #[ntex::test]
+    async fn test_stream_payload() {
+        use crate::types::QoS;
+        use ntex::util::{poll_fn, ByteString, Bytes};
+
+        let codec = Codec::new();
+        let pkt = super::super::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtLeastOnce,
+            topic: ByteString::from_static("test/topic"),
+            packet_id: std::num::NonZeroU16::new(1),
+            payload: Bytes::from(vec![1u8; 100]),
+            properties: Default::default(),
+        };
+        let mut raw = BytesMut::new();
+        codec.encode(Packet::Publish(pkt.clone()), &mut raw).unwrap();
+        codec.encode(Packet::PingRequest, &mut raw).unwrap();
+        codec.set_stream_payload(50);
+
+        // publish is decoded once header is received
+        let mut buf = BytesMut::from(&raw[..20]);
+        let mut rest = raw.split_off(20);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(
+            decoded,
+            Packet::Publish(super::super::Publish { payload: Bytes::new(), ..pkt })
+        );
+        let (rx, size) = codec.take_payload_stream().unwrap();
+        let recv = || poll_fn(|cx| rx.poll_recv(cx));
+        assert_eq!(size, 100);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(recv().await.unwrap().len(), 20 - 17);
+
+        let mut buf2 = rest.split_to(60);
+        assert_eq!(codec.decode(&mut buf2).unwrap(), None);
+        assert_eq!(recv().await.unwrap().len(), 60);
+
+        // rest of payload and next packet
+        assert_eq!(codec.decode(&mut rest).unwrap(), Some(Packet::PingRequest));
+        assert_eq!(recv().await.unwrap().len(), 37);
+        assert_eq!(recv().await, None);
+    }
+
+    #[ntex::test]
+    async fn test_stream_payload_backpressure() {
+        use crate::types::QoS;
+        use ntex::util::{lazy, poll_fn, ByteString, Bytes};
+
+        let codec = Codec::new();
+        let pkt = super::super::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("test/topic"),
+            packet_id: None,
+            payload: Bytes::from(vec![1u8; STREAM_BUFFER_SIZE * 2]),
+            properties: Default::default(),
+        };
+        let mut raw = BytesMut::new();
+        codec.encode(Packet::Publish(pkt), &mut raw).unwrap();
+        codec.set_stream_payload(50);
+
+        let mut buf = raw.split_to(raw.len() - STREAM_BUFFER_SIZE);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+        let (rx, _) = codec.take_payload_stream().unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+
+        // receiver holds buffer size of payload
+        assert!(lazy(|cx| codec.poll_payload_ready(cx)).await.is_pending());
+        assert!(poll_fn(|cx| rx.poll_recv(cx)).await.unwrap().len() >= STREAM_BUFFER_SIZE);
+        assert!(lazy(|cx| codec.poll_payload_ready(cx)).await.is_ready());
+
+        // unread payload of dropped receiver is discarded
+        drop(rx);
+        assert_eq!(codec.decode(&mut raw).unwrap(), None);
+        assert!(lazy(|cx| codec.poll_payload_ready(cx)).await.is_ready());
     }
 }
@@ -70,6 +70,22 @@ mod tests {
         //assert_eq!(, Ok(res));
     }
 
+    #[test]
+    fn test_decode_will_retain() {
+        let pkt = Connect::decode(&mut Bytes::from_static(
+            b"\x00\x04MQTT\x05\x34\x00\x3C\x00\x00\x0512345\x00\x00\x05topic\x00\x07message",
+        ))
+        .unwrap();
+        let will = pkt.last_will.unwrap();
+        assert!(will.retain);
+
+        let pkt = Publish::from(will);
+        assert!(pkt.retain);
+        assert_eq!(pkt.qos, QoS::ExactlyOnce);
+        assert_eq!(pkt.topic, ByteString::from_static("topic"));
+        assert_eq!(pkt.payload, Bytes::from_static(b"message"));
+    }
+
     #[test]
     fn test_decode_connect_packets() {
         assert_eq!(
@@ -187,6 +203,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_decode_zero_receive_max() {
+        let pkt = Connect::decode(&mut Bytes::from_static(
+            b"\x00\x04MQTT\x05\x02\x00\x3C\x03\x21\x00\x01\x00\x0512345",
+        ))
+        .unwrap();
+        assert_eq!(pkt.receive_max, NonZeroU16::new(1));
+
+        assert_eq!(
+            Connect::decode(&mut Bytes::from_static(
+                b"\x00\x04MQTT\x05\x02\x00\x3C\x03\x21\x00\x00\x00\x0512345"
+            )),
+            Err(DecodeError::ZeroReceiveMax)
+        );
+        assert_eq!(
+            ConnectAck::decode(&mut Bytes::from_static(b"\x00\x00\x03\x21\x00\x00")),
+            Err(DecodeError::ZeroReceiveMax)
+        );
+
+        // zero receive max is omitted on encode
+        assert_eq!(Connect::default().receive_max(0).receive_max, None);
+    }
+
+    #[test]
+    fn test_decode_will_flags() {
+        // will retain, will qos 1, will qos 2 without will flag
+        for raw in [
+            b"\x00\x04MQTT\x05\x22\x00\x3C\x00\x00\x0512345",
+            b"\x00\x04MQTT\x05\x0A\x00\x3C\x00\x00\x0512345",
+            b"\x00\x04MQTT\x05\x12\x00\x3C\x00\x00\x0512345",
+        ] {
+            assert_eq!(
+                Connect::decode(&mut Bytes::from_static(raw)),
+                Err(DecodeError::MalformedPacket)
+            );
+        }
+
+        // will qos 3
+        assert_eq!(
+            Connect::decode(&mut Bytes::from_static(
+                b"\x00\x04MQTT\x05\x1E\x00\x3C\x00\x00\x0512345\x00\x00\x05topic\x00\x07message"
+            )),
+            Err(DecodeError::MalformedPacket)
+        );
+    }
+
     #[test]
     fn test_decode_publish_packets() {
         //assert_eq!(
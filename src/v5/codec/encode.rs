@@ -486,7 +486,7 @@ mod tests {
                     ),
                 ],
             }),
-            b"\x82\x14\x12\x34\x01\x0b\x01\x00\x04test\x01\x00\x06filter\x02",
+            b"\x82\x15\x12\x34\x02\x0b\x01\x00\x04test\x01\x00\x06filter\x02",
         );
 
         assert_encode_packet(
@@ -529,6 +529,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_subscribe_roundtrip() {
+        use ntex::codec::{Decoder, Encoder};
+
+        // properties length includes subscription id property type,
+        // user properties are encoded
+        let pkt = Packet::Subscribe(Subscribe {
+            packet_id: packet_id(0x1234),
+            id: Some(NonZeroU32::new(268_435_455).unwrap()),
+            user_properties: vec![(ByteString::from("key"), ByteString::from("value"))],
+            topic_filters: vec![(
+                ByteString::from_static("test/+"),
+                SubscriptionOptions {
+                    qos: QoS::AtLeastOnce,
+                    no_local: true,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::AtSubscribe,
+                },
+            )],
+        });
+
+        let codec = crate::v5::codec::Codec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(pkt.clone(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(pkt));
+        assert!(buf.is_empty());
+    }
+
     #[test]
     fn test_encode_ping_packets() {
         assert_encode_packet(&Packet::PingRequest, b"\xc0\x00");
@@ -1,6 +1,7 @@
 use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::{convert::TryInto, num::NonZeroU16};
 
+use super::read_receive_max;
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{ConnectAckFlags, QoS};
 use crate::utils::{self, Decode, Encode, Property};
@@ -131,7 +132,7 @@ impl ConnectAck {
         while prop_src.has_remaining() {
             match prop_src.get_u8() {
                 pt::SESS_EXPIRY_INT => session_expiry_interval_secs.read_value(prop_src)?,
-                pt::RECEIVE_MAX => receive_max.read_value(prop_src)?,
+                pt::RECEIVE_MAX => read_receive_max(&mut receive_max, prop_src)?,
                 pt::MAX_QOS => {
                     ensure!(max_qos.is_none(), DecodeError::MalformedPacket); // property is set twice while not allowed
                     ensure!(prop_src.has_remaining(), DecodeError::InvalidLength);
@@ -2,6 +2,7 @@ use ntex::util::{Buf, BufMut, ByteString, Bytes, BytesMut};
 use std::convert::TryFrom;
 use std::num::{NonZeroU16, NonZeroU32};
 
+use super::{read_receive_max, Publish, PublishProperties};
 use crate::error::{DecodeError, EncodeError};
 use crate::types::{ConnectFlags, QoS, MQTT, MQTT_LEVEL_5, WILL_QOS_SHIFT};
 use crate::utils::{self, Decode, Encode, Property};
@@ -66,6 +67,44 @@ impl LastWill {
             + encoded_property_size(&self.response_topic)
             + self.user_properties.encoded_size()
     }
+
+    fn encode_properties(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
+        encode_property(&self.will_delay_interval_sec, pt::WILL_DELAY_INT, buf)?;
+        encode_property(&self.correlation_data, pt::CORR_DATA, buf)?;
+        encode_property(&self.message_expiry_interval, pt::MSG_EXPIRY_INT, buf)?;
+        encode_property(&self.content_type, pt::CONTENT_TYPE, buf)?;
+        encode_property(&self.is_utf8_payload, pt::UTF8_PAYLOAD, buf)?;
+        encode_property(&self.response_topic, pt::RESP_TOPIC, buf)?;
+        self.user_properties.encode(buf)
+    }
+}
+
+impl From<LastWill> for Publish {
+    /// Convert will to publish packet
+    ///
+    /// Retain flag, qos and message properties are preserved, so will
+    /// with retain flag must be handled as retained message. Will delay
+    /// interval is not a publish property and is dropped.
+    fn from(will: LastWill) -> Self {
+        Publish {
+            dup: false,
+            retain: will.retain,
+            qos: will.qos,
+            packet_id: None,
+            topic: will.topic,
+            payload: will.message,
+            properties: PublishProperties {
+                topic_alias: None,
+                correlation_data: will.correlation_data,
+                message_expiry_interval: will.message_expiry_interval,
+                content_type: will.content_type,
+                user_properties: will.user_properties,
+                is_utf8_payload: will.is_utf8_payload,
+                response_topic: will.response_topic,
+                subscription_ids: None,
+            },
+        }
+    }
 }
 
 impl Connect {
@@ -79,6 +118,9 @@ impl Connect {
     }
 
     /// Set receive_max value
+    ///
+    /// Value of 0 omits receive maximum property, receive maximum of 0 is not
+    /// allowed by protocol.
     pub fn receive_max(mut self, max: u16) -> Self {
         if let Some(num) = NonZeroU16::new(max) {
             self.receive_max = Some(num);
@@ -104,6 +146,17 @@ impl Connect {
     }
 
     pub(crate) fn decode(src: &mut Bytes) -> Result<Self, DecodeError> {
+        Self::decode_limited(src, 0)
+    }
+
+    /// Decode packet, fails if last will is larger than `max_will_size`
+    ///
+    /// Will size is the size of will topic and will message, `0` means
+    /// size is unlimited.
+    pub(crate) fn decode_limited(
+        src: &mut Bytes,
+        max_will_size: u32,
+    ) -> Result<Self, DecodeError> {
         ensure!(src.remaining() >= 10, DecodeError::InvalidLength);
         let len = src.get_u16();
 
@@ -135,7 +188,7 @@ impl Connect {
                 pt::AUTH_DATA => auth_data.read_value(prop_src)?,
                 pt::REQ_PROB_INFO => request_problem_info.read_value(prop_src)?,
                 pt::REQ_RESP_INFO => request_response_info.read_value(prop_src)?,
-                pt::RECEIVE_MAX => receive_max.read_value(prop_src)?,
+                pt::RECEIVE_MAX => read_receive_max(&mut receive_max, prop_src)?,
                 pt::TOPIC_ALIAS_MAX => topic_alias_max.read_value(prop_src)?,
                 pt::USER => user_properties.push(UserProperty::decode(prop_src)?),
                 pt::MAX_PACKET_SIZE => max_packet_size.read_value(prop_src)?,
@@ -143,17 +196,17 @@ impl Connect {
             }
         }
 
+        // zero-length client id is validated by the server, [MQTT-3.1.3-8]
         let client_id = ByteString::decode(src)?;
 
-        ensure!(
-            // todo: [MQTT-3.1.3-8]?
-            !client_id.is_empty() || flags.contains(ConnectFlags::CLEAN_START),
-            DecodeError::InvalidClientId
-        );
-
         let last_will = if flags.contains(ConnectFlags::WILL) {
-            Some(decode_last_will(src, flags)?)
+            Some(decode_last_will(src, flags, max_will_size)?)
         } else {
+            // will qos and will retain must be 0 without will, [MQTT-3.1.2-11, MQTT-3.1.2-13]
+            ensure!(
+                !flags.intersects(ConnectFlags::WILL_QOS | ConnectFlags::WILL_RETAIN),
+                DecodeError::MalformedPacket
+            );
             None
         };
 
@@ -212,7 +265,11 @@ impl Default for Connect {
     }
 }
 
-fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, DecodeError> {
+fn decode_last_will(
+    src: &mut Bytes,
+    flags: ConnectFlags,
+    max_size: u32,
+) -> Result<LastWill, DecodeError> {
     let mut will_delay_interval_sec = None;
     let mut correlation_data = None;
     let mut message_expiry_interval = None;
@@ -236,6 +293,11 @@ fn decode_last_will(src: &mut Bytes, flags: ConnectFlags) -> Result<LastWill, De
 
     let topic = ByteString::decode(src)?;
     let message = Bytes::decode(src)?;
+    let size = topic.len() + message.len();
+    if max_size != 0 && size > max_size as usize {
+        log::trace!("Last will is too large: {} > {}", size, max_size);
+        return Err(DecodeError::WillSizeExceeded { size, max: max_size });
+    }
     Ok(LastWill {
         qos: QoS::try_from((flags & ConnectFlags::WILL_QOS).bits() >> WILL_QOS_SHIFT)?,
         retain: flags.contains(ConnectFlags::WILL_RETAIN),
@@ -319,6 +381,7 @@ impl EncodeLtd for Connect {
         if let Some(will) = self.last_will.as_ref() {
             let prop_len = will.properties_len();
             utils::write_variable_length(prop_len as u32, buf); // safe: whole message size is checked for max already
+            will.encode_properties(buf)?;
 
             will.topic.encode(buf)?;
             will.message.encode(buf)?;
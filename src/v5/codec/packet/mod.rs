@@ -12,6 +12,7 @@ mod auth;
 mod connack;
 mod connect;
 mod disconnect;
+mod properties;
 mod pubacks;
 mod publish;
 mod subscribe;
@@ -20,6 +21,7 @@ pub use auth::*;
 pub use connack::*;
 pub use connect::*;
 pub use disconnect::*;
+pub use properties::*;
 pub use pubacks::*;
 pub use publish::*;
 pub use subscribe::*;
@@ -83,6 +85,17 @@ impl Packet {
     }
 }
 
+/// Read receive maximum property, value of 0 is protocol error, MQTT-3.1.2.11.3
+fn read_receive_max(
+    receive_max: &mut Option<std::num::NonZeroU16>,
+    src: &mut Bytes,
+) -> Result<(), DecodeError> {
+    ensure!(receive_max.is_none(), DecodeError::MalformedPacket); // property is set twice while not allowed
+    let val = u16::decode(src)?;
+    *receive_max = Some(std::num::NonZeroU16::new(val).ok_or(DecodeError::ZeroReceiveMax)?);
+    Ok(())
+}
+
 pub(super) mod property_type {
     pub(crate) const UTF8_PAYLOAD: u8 = 0x01;
     pub(crate) const MSG_EXPIRY_INT: u8 = 0x02;
@@ -0,0 +1,345 @@
+use ntex::util::{ByteString, Bytes};
+use std::num::{NonZeroU16, NonZeroU32};
+
+use super::*;
+use crate::v5::codec::property_type as pt;
+
+/// Typed MQTT 5 property
+#[derive(Debug, PartialEq, Clone)]
+pub enum PropertyValue {
+    Utf8Payload(bool),
+    MessageExpiryInterval(NonZeroU32),
+    ContentType(ByteString),
+    ResponseTopic(ByteString),
+    CorrelationData(Bytes),
+    SubscriptionId(NonZeroU32),
+    SessionExpiryInterval(u32),
+    AssignedClientId(ByteString),
+    ServerKeepAlive(u16),
+    AuthMethod(ByteString),
+    AuthData(Bytes),
+    RequestProblemInfo(bool),
+    WillDelayInterval(u32),
+    RequestResponseInfo(bool),
+    ResponseInfo(ByteString),
+    ServerReference(ByteString),
+    ReasonString(ByteString),
+    ReceiveMax(NonZeroU16),
+    TopicAliasMax(u16),
+    TopicAlias(NonZeroU16),
+    MaxQos(QoS),
+    RetainAvailable(bool),
+    User(ByteString, ByteString),
+    MaxPacketSize(u32),
+    WildcardSubscriptionAvailable(bool),
+    SubscriptionIdsAvailable(bool),
+    SharedSubscriptionAvailable(bool),
+}
+
+impl PropertyValue {
+    /// Property identifier
+    pub fn id(&self) -> u8 {
+        match self {
+            PropertyValue::Utf8Payload(_) => pt::UTF8_PAYLOAD,
+            PropertyValue::MessageExpiryInterval(_) => pt::MSG_EXPIRY_INT,
+            PropertyValue::ContentType(_) => pt::CONTENT_TYPE,
+            PropertyValue::ResponseTopic(_) => pt::RESP_TOPIC,
+            PropertyValue::CorrelationData(_) => pt::CORR_DATA,
+            PropertyValue::SubscriptionId(_) => pt::SUB_ID,
+            PropertyValue::SessionExpiryInterval(_) => pt::SESS_EXPIRY_INT,
+            PropertyValue::AssignedClientId(_) => pt::ASSND_CLIENT_ID,
+            PropertyValue::ServerKeepAlive(_) => pt::SERVER_KA,
+            PropertyValue::AuthMethod(_) => pt::AUTH_METHOD,
+            PropertyValue::AuthData(_) => pt::AUTH_DATA,
+            PropertyValue::RequestProblemInfo(_) => pt::REQ_PROB_INFO,
+            PropertyValue::WillDelayInterval(_) => pt::WILL_DELAY_INT,
+            PropertyValue::RequestResponseInfo(_) => pt::REQ_RESP_INFO,
+            PropertyValue::ResponseInfo(_) => pt::RESP_INFO,
+            PropertyValue::ServerReference(_) => pt::SERVER_REF,
+            PropertyValue::ReasonString(_) => pt::REASON_STRING,
+            PropertyValue::ReceiveMax(_) => pt::RECEIVE_MAX,
+            PropertyValue::TopicAliasMax(_) => pt::TOPIC_ALIAS_MAX,
+            PropertyValue::TopicAlias(_) => pt::TOPIC_ALIAS,
+            PropertyValue::MaxQos(_) => pt::MAX_QOS,
+            PropertyValue::RetainAvailable(_) => pt::RETAIN_AVAIL,
+            PropertyValue::User(..) => pt::USER,
+            PropertyValue::MaxPacketSize(_) => pt::MAX_PACKET_SIZE,
+            PropertyValue::WildcardSubscriptionAvailable(_) => pt::WILDCARD_SUB_AVAIL,
+            PropertyValue::SubscriptionIdsAvailable(_) => pt::SUB_IDS_AVAIL,
+            PropertyValue::SharedSubscriptionAvailable(_) => pt::SHARED_SUB_AVAIL,
+        }
+    }
+}
+
+/// Properties of a packet
+///
+/// Properties are listed in the order they are encoded. Properties with
+/// default values that are not encoded are not listed, for example
+/// `RequestProblemInfo(true)` of CONNECT packet.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Properties(Vec<PropertyValue>);
+
+impl Properties {
+    /// First property with specified id
+    pub fn get(&self, id: u8) -> Option<&PropertyValue> {
+        self.0.iter().find(|prop| prop.id() == id)
+    }
+
+    /// All properties with specified id
+    pub fn get_all(&self, id: u8) -> impl Iterator<Item = &PropertyValue> {
+        self.0.iter().filter(move |prop| prop.id() == id)
+    }
+
+    /// Iterate over properties
+    pub fn iter(&self) -> std::slice::Iter<'_, PropertyValue> {
+        self.0.iter()
+    }
+
+    /// Number of properties
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check if there are no properties
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn opt<T: Clone>(&mut self, v: &Option<T>, f: fn(T) -> PropertyValue) -> &mut Self {
+        if let Some(v) = v {
+            self.0.push(f(v.clone()));
+        }
+        self
+    }
+
+    fn user(&mut self, props: &UserProperties) -> &mut Self {
+        self.0.extend(props.iter().map(|(k, v)| PropertyValue::User(k.clone(), v.clone())));
+        self
+    }
+
+    fn reason(&mut self, props: &UserProperties, reason: &Option<ByteString>) -> &mut Self {
+        self.user(props).opt(reason, PropertyValue::ReasonString)
+    }
+
+    fn ack(props: &UserProperties, reason: &Option<ByteString>) -> Self {
+        let mut p = Properties::default();
+        p.reason(props, reason);
+        p
+    }
+}
+
+impl IntoIterator for Properties {
+    type Item = PropertyValue;
+    type IntoIter = std::vec::IntoIter<PropertyValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Properties {
+    type Item = &'a PropertyValue;
+    type IntoIter = std::slice::Iter<'a, PropertyValue>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+impl Packet {
+    /// Properties of the packet
+    ///
+    /// Will properties of CONNECT packet are available with
+    /// `LastWill::properties()`.
+    pub fn properties(&self) -> Properties {
+        match self {
+            Packet::Connect(pkt) => connect(pkt),
+            Packet::ConnectAck(pkt) => connect_ack(pkt),
+            Packet::Publish(pkt) => publish(&pkt.properties),
+            Packet::PublishAck(pkt) | Packet::PublishReceived(pkt) => {
+                Properties::ack(&pkt.properties, &pkt.reason_string)
+            }
+            Packet::PublishRelease(pkt) | Packet::PublishComplete(pkt) => {
+                Properties::ack(&pkt.properties, &pkt.reason_string)
+            }
+            Packet::Subscribe(pkt) => {
+                let mut p = Properties::default();
+                p.opt(&pkt.id, PropertyValue::SubscriptionId).user(&pkt.user_properties);
+                p
+            }
+            Packet::SubscribeAck(pkt) => Properties::ack(&pkt.properties, &pkt.reason_string),
+            Packet::Unsubscribe(pkt) => {
+                let mut p = Properties::default();
+                p.user(&pkt.user_properties);
+                p
+            }
+            Packet::UnsubscribeAck(pkt) => Properties::ack(&pkt.properties, &pkt.reason_string),
+            Packet::PingRequest | Packet::PingResponse => Properties::default(),
+            Packet::Disconnect(pkt) => {
+                let mut p = Properties::default();
+                p.opt(&pkt.session_expiry_interval_secs, PropertyValue::SessionExpiryInterval)
+                    .opt(&pkt.server_reference, PropertyValue::ServerReference)
+                    .reason(&pkt.user_properties, &pkt.reason_string);
+                p
+            }
+            Packet::Auth(pkt) => {
+                let mut p = Properties::default();
+                p.opt(&pkt.auth_method, PropertyValue::AuthMethod)
+                    .opt(&pkt.auth_data, PropertyValue::AuthData)
+                    .reason(&pkt.user_properties, &pkt.reason_string);
+                p
+            }
+        }
+    }
+}
+
+impl LastWill {
+    /// Will properties
+    pub fn properties(&self) -> Properties {
+        let mut p = Properties::default();
+        p.opt(&self.will_delay_interval_sec, PropertyValue::WillDelayInterval)
+            .opt(&self.correlation_data, PropertyValue::CorrelationData)
+            .opt(&self.message_expiry_interval, PropertyValue::MessageExpiryInterval)
+            .opt(&self.content_type, PropertyValue::ContentType)
+            .opt(&self.is_utf8_payload, PropertyValue::Utf8Payload)
+            .opt(&self.response_topic, PropertyValue::ResponseTopic)
+            .user(&self.user_properties);
+        p
+    }
+}
+
+fn connect(pkt: &Connect) -> Properties {
+    let mut p = Properties::default();
+    p.opt(&pkt.session_expiry_interval_secs, PropertyValue::SessionExpiryInterval)
+        .opt(&pkt.auth_method, PropertyValue::AuthMethod)
+        .opt(&pkt.auth_data, PropertyValue::AuthData);
+    if !pkt.request_problem_info {
+        p.0.push(PropertyValue::RequestProblemInfo(false));
+    }
+    if pkt.request_response_info {
+        p.0.push(PropertyValue::RequestResponseInfo(true));
+    }
+    p.opt(&pkt.receive_max, PropertyValue::ReceiveMax)
+        .opt(&pkt.max_packet_size.map(|v| v.get()), PropertyValue::MaxPacketSize);
+    if pkt.topic_alias_max > 0 {
+        p.0.push(PropertyValue::TopicAliasMax(pkt.topic_alias_max));
+    }
+    p.user(&pkt.user_properties);
+    p
+}
+
+fn connect_ack(pkt: &ConnectAck) -> Properties {
+    let mut p = Properties::default();
+    p.opt(&pkt.session_expiry_interval_secs, PropertyValue::SessionExpiryInterval)
+        .opt(&pkt.receive_max, PropertyValue::ReceiveMax)
+        .opt(&pkt.max_qos, PropertyValue::MaxQos)
+        .opt(&pkt.retain_available, PropertyValue::RetainAvailable)
+        .opt(&pkt.max_packet_size, PropertyValue::MaxPacketSize)
+        .opt(&pkt.assigned_client_id, PropertyValue::AssignedClientId);
+    if pkt.topic_alias_max > 0 {
+        p.0.push(PropertyValue::TopicAliasMax(pkt.topic_alias_max));
+    }
+    p.opt(&pkt.wildcard_subscription_available, PropertyValue::WildcardSubscriptionAvailable)
+        .opt(&pkt.subscription_identifiers_available, PropertyValue::SubscriptionIdsAvailable)
+        .opt(&pkt.shared_subscription_available, PropertyValue::SharedSubscriptionAvailable)
+        .opt(&pkt.server_keepalive_sec, PropertyValue::ServerKeepAlive)
+        .opt(&pkt.response_info, PropertyValue::ResponseInfo)
+        .opt(&pkt.server_reference, PropertyValue::ServerReference)
+        .opt(&pkt.auth_method, PropertyValue::AuthMethod)
+        .opt(&pkt.auth_data, PropertyValue::AuthData)
+        .reason(&pkt.user_properties, &pkt.reason_string);
+    p
+}
+
+fn publish(props: &PublishProperties) -> Properties {
+    let mut p = Properties::default();
+    p.opt(&props.topic_alias, PropertyValue::TopicAlias)
+        .opt(&props.correlation_data, PropertyValue::CorrelationData)
+        .opt(&props.message_expiry_interval, PropertyValue::MessageExpiryInterval)
+        .opt(&props.content_type, PropertyValue::ContentType)
+        .opt(&props.is_utf8_payload, PropertyValue::Utf8Payload)
+        .opt(&props.response_topic, PropertyValue::ResponseTopic);
+    if let Some(ref ids) = props.subscription_ids {
+        p.0.extend(ids.iter().map(|id| PropertyValue::SubscriptionId(*id)));
+    }
+    p.user(&props.user_properties);
+    p
+}
+
+#[cfg(test)]
+mod tests {
+    use ntex::codec::{Decoder, Encoder};
+    use ntex::util::BytesMut;
+
+    use super::*;
+    use crate::error::DecodeError;
+    use crate::v5::codec::Codec;
+
+    #[test]
+    fn test_properties_roundtrip() {
+        let mut src = BytesMut::from(
+            &[
+                0b0011_0000, // PUBLISH, qos0
+                14,          // remaining length
+                0,
+                1,
+                b'a', // topic "a"
+                9,    // properties length
+                0x01, // payload format
+                1,
+                0x26, // user property ("k", "v")
+                0,
+                1,
+                b'k',
+                0,
+                1,
+                b'v',
+                b'x', // payload
+            ][..],
+        );
+        let expected = src.clone();
+
+        let codec = Codec::new();
+        let pkt = codec.decode(&mut src).unwrap().unwrap();
+        let props = pkt.properties();
+        assert_eq!(props.len(), 2);
+        assert_eq!(props.get(pt::USER), Some(&PropertyValue::User("k".into(), "v".into())));
+        assert_eq!(props.get(pt::UTF8_PAYLOAD), Some(&PropertyValue::Utf8Payload(true)));
+
+        let mut buf = BytesMut::new();
+        codec.encode(pkt, &mut buf).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_invalid_property() {
+        // session expiry interval is not allowed in PUBLISH
+        let mut src = BytesMut::from(&b"\x30\x0a\x00\x01a\x05\x11\x00\x00\x00\x0ax"[..]);
+        assert_eq!(Codec::new().decode(&mut src), Err(DecodeError::MalformedPacket));
+
+        // value length of unknown property is not known, packet is rejected
+        let mut src = BytesMut::from(&b"\x30\x09\x00\x01a\x04\x7f\x23\x00\x01x"[..]);
+        assert_eq!(Codec::new().decode(&mut src), Err(DecodeError::MalformedPacket));
+
+        // unknown property of ack packet
+        let mut src = BytesMut::from(&b"\x40\x06\x00\x01\x00\x02\x7f\x00"[..]);
+        assert_eq!(Codec::new().decode(&mut src), Err(DecodeError::MalformedPacket));
+    }
+
+    #[test]
+    fn test_properties() {
+        let pkt = Packet::Disconnect(Disconnect {
+            session_expiry_interval_secs: Some(10),
+            reason_string: Some("reason".into()),
+            user_properties: vec![("a".into(), "1".into()), ("a".into(), "2".into())],
+            ..Disconnect::default()
+        });
+        let props = pkt.properties();
+        assert_eq!(
+            props.iter().map(|p| p.id()).collect::<Vec<_>>(),
+            vec![pt::SESS_EXPIRY_INT, pt::USER, pt::USER, pt::REASON_STRING]
+        );
+        assert_eq!(props.get_all(pt::USER).count(), 2);
+        assert!(Packet::PingRequest.properties().is_empty());
+    }
+}
@@ -72,6 +72,25 @@ impl Publish {
     }
 }
 
+/// Size of PUBLISH variable header, `None` if `src` does not contain
+/// complete header
+pub(crate) fn header_size(src: &[u8], packet_flags: u8) -> Result<Option<usize>, DecodeError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    let mut size = 2 + u16::from_be_bytes([src[0], src[1]]) as usize;
+    if QoS::try_from((packet_flags & 0b0110) >> 1)? != QoS::AtMostOnce {
+        size += 2;
+    }
+    if src.len() <= size {
+        return Ok(None);
+    }
+    match utils::decode_variable_length(&src[size..])? {
+        Some((len, consumed)) => Ok(Some(size + consumed + len as usize)),
+        None => Ok(None),
+    }
+}
+
 fn parse_publish_properties(src: &mut Bytes) -> Result<PublishProperties, DecodeError> {
     let prop_src = &mut utils::take_properties(src)?;
 
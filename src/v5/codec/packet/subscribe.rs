@@ -176,7 +176,7 @@ impl UnsubscribeAck {
 
 impl EncodeLtd for Subscribe {
     fn encoded_size(&self, _limit: u32) -> usize {
-        let prop_len = self.id.map_or(0, |v| var_int_len(v.get() as usize) as usize)
+        let prop_len = self.id.map_or(0, |v| 1 + var_int_len(v.get() as usize) as usize)
             + self.user_properties.encoded_size();
         let payload_len = self
             .topic_filters
@@ -188,7 +188,7 @@ impl EncodeLtd for Subscribe {
     fn encode(&self, buf: &mut BytesMut, _: u32) -> Result<(), EncodeError> {
         self.packet_id.encode(buf)?;
 
-        let prop_len = self.id.map_or(0, |v| var_int_len(v.get() as usize))
+        let prop_len = self.id.map_or(0, |v| 1 + var_int_len(v.get() as usize))
             + self.user_properties.encoded_size() as u32; // safe: size was already checked against maximum
         utils::write_variable_length(prop_len, buf);
 
@@ -196,6 +196,7 @@ impl EncodeLtd for Subscribe {
             buf.put_u8(pt::SUB_ID);
             write_variable_length(id.get(), buf);
         }
+        self.user_properties.encode(buf)?;
 
         for (filter, opts) in self.topic_filters.iter() {
             filter.encode(buf)?;
@@ -273,6 +274,7 @@ impl EncodeLtd for Unsubscribe {
         self.packet_id.encode(buf)?;
         let prop_len = self.user_properties.encoded_size();
         utils::write_variable_length(prop_len as u32, buf); // safe: max size check is done already
+        self.user_properties.encode(buf)?;
         for filter in self.topic_filters.iter() {
             filter.encode(buf)?;
         }
@@ -308,6 +310,48 @@ impl EncodeLtd for UnsubscribeAck {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_subscription_options() {
+        let cases = [
+            (QoS::AtMostOnce, false, true, RetainHandling::AtSubscribe, 0b0000_1000),
+            (QoS::AtLeastOnce, true, true, RetainHandling::AtSubscribe, 0b0000_1101),
+            (QoS::ExactlyOnce, true, false, RetainHandling::NoAtSubscribe, 0b0010_0110),
+            (QoS::ExactlyOnce, false, true, RetainHandling::AtSubscribeNew, 0b0001_1010),
+        ];
+        for &(qos, no_local, retain_as_published, retain_handling, byte) in cases.iter() {
+            let opts =
+                SubscriptionOptions { qos, no_local, retain_as_published, retain_handling };
+            let mut buf = BytesMut::new();
+            opts.encode(&mut buf).unwrap();
+            assert_eq!(&buf[..], &[byte]);
+            assert_eq!(SubscriptionOptions::decode(&mut buf.freeze()).unwrap(), opts);
+        }
+    }
+
+    #[test]
+    fn test_subscribe_id() {
+        let pkt = Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            id: NonZeroU32::new(268_435_455),
+            user_properties: vec![("prop1".into(), "val1".into())],
+            topic_filters: vec![(
+                "a/+".into(),
+                SubscriptionOptions {
+                    qos: QoS::AtLeastOnce,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling: RetainHandling::AtSubscribe,
+                },
+            )],
+        };
+
+        let size = pkt.encoded_size(99999);
+        let mut buf = BytesMut::with_capacity(size);
+        pkt.encode(&mut buf, size as u32).unwrap();
+        assert_eq!(buf.len(), size);
+        assert_eq!(pkt, Subscribe::decode(&mut buf.freeze()).unwrap());
+    }
+
     #[test]
     fn test_sub_ack() {
         let ack = SubscribeAck {
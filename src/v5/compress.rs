@@ -0,0 +1,280 @@
+//! Publish payload compression
+//!
+//! This is non-standard, application level convention. Compressed payload
+//! is tagged with `x-encoding` user property, both peers must support it.
+//! Algorithm could be negotiated with CONNECT and CONNACK user properties,
+//! see `CompressionNegotiation`.
+use std::io::{self, Read, Write};
+
+use flate2::{read, write};
+use ntex::util::{ByteString, Bytes};
+
+use super::codec;
+
+/// User property that carries payload encoding
+pub const ENCODING_PROPERTY: &str = "x-encoding";
+
+/// CONNECT user property that lists algorithms supported by client
+pub const ACCEPT_ENCODING_PROPERTY: &str = "x-accept-encoding";
+
+/// Payload compression algorithm
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw deflate stream, `x-encoding: deflate`
+    Deflate,
+    /// Gzip stream, `x-encoding: gzip`
+    Gzip,
+}
+
+impl Compression {
+    /// Algorithm name used as `x-encoding` property value
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+            Compression::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deflate" => Some(Compression::Deflate),
+            "gzip" => Some(Compression::Gzip),
+            _ => None,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Bytes> {
+        let level = flate2::Compression::default();
+        let buf = match self {
+            Compression::Deflate => {
+                let mut enc = write::DeflateEncoder::new(Vec::new(), level);
+                enc.write_all(data)?;
+                enc.finish()?
+            }
+            Compression::Gzip => {
+                let mut enc = write::GzEncoder::new(Vec::new(), level);
+                enc.write_all(data)?;
+                enc.finish()?
+            }
+        };
+        Ok(Bytes::from(buf))
+    }
+
+    /// Decompress data, fails if decompressed size exceeds `limit`
+    fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Bytes> {
+        // read one byte past limit to detect oversized payload
+        let take = limit as u64 + 1;
+        let mut buf = Vec::new();
+        match self {
+            Compression::Deflate => {
+                read::DeflateDecoder::new(data).take(take).read_to_end(&mut buf)?
+            }
+            Compression::Gzip => read::GzDecoder::new(data).take(take).read_to_end(&mut buf)?,
+        };
+        if buf.len() > limit {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Decompressed payload exceeds size limit",
+            ))
+        } else {
+            Ok(Bytes::from(buf))
+        }
+    }
+}
+
+/// Compression negotiation with CONNECT and CONNACK user properties
+///
+/// Client offers supported algorithms as comma separated list in
+/// `x-accept-encoding` CONNECT property, in order of preference. Server
+/// chooses one of offered algorithms and returns it in `x-encoding` CONNACK
+/// property, absent property means payloads are not compressed. Both
+/// property keys could be overridden.
+#[derive(Clone, Debug)]
+pub struct CompressionNegotiation {
+    algorithms: Vec<Compression>,
+    offer_key: ByteString,
+    choice_key: ByteString,
+}
+
+impl CompressionNegotiation {
+    /// Create negotiation for supported algorithms, in order of preference
+    pub fn new(algorithms: &[Compression]) -> Self {
+        CompressionNegotiation {
+            algorithms: algorithms.to_vec(),
+            offer_key: ByteString::from_static(ACCEPT_ENCODING_PROPERTY),
+            choice_key: ByteString::from_static(ENCODING_PROPERTY),
+        }
+    }
+
+    /// Set CONNECT property key for offered algorithms
+    ///
+    /// By default `x-accept-encoding` key is used.
+    pub fn offer_property(mut self, key: ByteString) -> Self {
+        self.offer_key = key;
+        self
+    }
+
+    /// Set CONNACK property key for chosen algorithm
+    ///
+    /// By default `x-encoding` key is used.
+    pub fn choice_property(mut self, key: ByteString) -> Self {
+        self.choice_key = key;
+        self
+    }
+
+    /// Add offered algorithms to CONNECT packet
+    pub fn offer(&self, pkt: &mut codec::Connect) {
+        if !self.algorithms.is_empty() {
+            let names: Vec<_> = self.algorithms.iter().map(|alg| alg.name()).collect();
+            pkt.user_properties.push((self.offer_key.clone(), names.join(",").into()));
+        }
+    }
+
+    /// Choose algorithm for client, server side
+    ///
+    /// First algorithm offered by client that is supported by server is
+    /// chosen and added to CONNACK packet.
+    pub fn choose(
+        &self,
+        connect: &codec::Connect,
+        ack: &mut codec::ConnectAck,
+    ) -> Option<Compression> {
+        let (_, offer) = connect.user_properties.iter().find(|(k, _)| *k == self.offer_key)?;
+        let alg = offer
+            .split(',')
+            .filter_map(|name| Compression::from_name(name.trim()))
+            .find(|alg| self.algorithms.contains(alg))?;
+        ack.user_properties.push((self.choice_key.clone(), alg.name().into()));
+        Some(alg)
+    }
+
+    /// Algorithm chosen by server, client side
+    ///
+    /// Returns `None` if server did not choose algorithm or chose
+    /// algorithm that was not offered.
+    pub fn chosen(&self, ack: &codec::ConnectAck) -> Option<Compression> {
+        let (_, name) = ack.user_properties.iter().find(|(k, _)| *k == self.choice_key)?;
+        Compression::from_name(name).filter(|alg| self.algorithms.contains(alg))
+    }
+}
+
+/// Compress publish payload and add `x-encoding` property
+pub(crate) fn compress_publish(pkt: &mut codec::Publish, alg: Compression) {
+    match alg.compress(&pkt.payload) {
+        Ok(payload) => {
+            pkt.payload = payload;
+            pkt.properties
+                .user_properties
+                .push((ByteString::from_static(ENCODING_PROPERTY), alg.name().into()));
+        }
+        Err(err) => log::error!("Cannot compress publish payload: {:?}", err),
+    }
+}
+
+/// Decompress publish payload if packet has `x-encoding` property
+///
+/// Decompressed payload must not exceed `limit` bytes.
+pub(crate) fn decompress_publish(pkt: &mut codec::Publish, limit: usize) -> io::Result<()> {
+    let props = &mut pkt.properties.user_properties;
+    if let Some(idx) = props.iter().position(|(key, _)| key == ENCODING_PROPERTY) {
+        let alg = Compression::from_name(&props[idx].1).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Unsupported payload encoding")
+        })?;
+        pkt.payload = alg.decompress(&pkt.payload, limit)?;
+        props.remove(idx);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::QoS;
+
+    fn publish(payload: &'static [u8]) -> codec::Publish {
+        codec::Publish {
+            dup: false,
+            retain: false,
+            qos: QoS::AtMostOnce,
+            topic: ByteString::from_static("test"),
+            packet_id: None,
+            payload: Bytes::from_static(payload),
+            properties: codec::PublishProperties::default(),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for alg in &[Compression::Deflate, Compression::Gzip] {
+            let mut pkt = publish(b"data data data data data data data data");
+            compress_publish(&mut pkt, *alg);
+            assert_ne!(
+                pkt.payload,
+                Bytes::from_static(b"data data data data data data data data")
+            );
+            assert_eq!(
+                pkt.properties.user_properties,
+                vec![(ENCODING_PROPERTY.into(), alg.name().into())]
+            );
+
+            decompress_publish(&mut pkt, 1024).unwrap();
+            assert_eq!(pkt, publish(b"data data data data data data data data"));
+        }
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let client = CompressionNegotiation::new(&[Compression::Gzip, Compression::Deflate]);
+        let mut connect = codec::Connect::default();
+        client.offer(&mut connect);
+        assert_eq!(
+            connect.user_properties,
+            vec![(ACCEPT_ENCODING_PROPERTY.into(), "gzip,deflate".into())]
+        );
+
+        // server supports deflate only
+        let server = CompressionNegotiation::new(&[Compression::Deflate]);
+        let mut ack = codec::ConnectAck::default();
+        assert_eq!(server.choose(&connect, &mut ack), Some(Compression::Deflate));
+        assert_eq!(client.chosen(&ack), Some(Compression::Deflate));
+
+        // server does not support offered algorithms
+        let server = CompressionNegotiation::new(&[]);
+        let mut ack = codec::ConnectAck::default();
+        assert_eq!(server.choose(&connect, &mut ack), None);
+        assert_eq!(client.chosen(&ack), None);
+
+        // custom property keys
+        let client = CompressionNegotiation::new(&[Compression::Gzip])
+            .offer_property("enc-offer".into())
+            .choice_property("enc".into());
+        let mut ack = codec::ConnectAck::default();
+        ack.user_properties.push((ENCODING_PROPERTY.into(), "gzip".into()));
+        assert_eq!(client.chosen(&ack), None);
+        ack.user_properties.push(("enc".into(), "gzip".into()));
+        assert_eq!(client.chosen(&ack), Some(Compression::Gzip));
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut pkt = publish(b"data");
+        decompress_publish(&mut pkt, 1024).unwrap();
+        assert_eq!(pkt, publish(b"data"));
+
+        let mut pkt = publish(b"data");
+        pkt.properties.user_properties.push((ENCODING_PROPERTY.into(), "br".into()));
+        assert!(decompress_publish(&mut pkt, 1024).is_err());
+
+        let mut pkt = publish(b"data");
+        pkt.properties.user_properties.push((ENCODING_PROPERTY.into(), "gzip".into()));
+        assert!(decompress_publish(&mut pkt, 1024).is_err());
+
+        // decompressed payload is larger than limit
+        let mut pkt = publish(&[0; 2048]);
+        compress_publish(&mut pkt, Compression::Gzip);
+        assert!(pkt.payload.len() < 1024);
+        assert!(decompress_publish(&mut pkt.clone(), 1024).is_err());
+        decompress_publish(&mut pkt, 2048).unwrap();
+        assert_eq!(pkt, publish(&[0; 2048]));
+    }
+}
@@ -1,6 +1,6 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Duration};
 
-use ntex::util::ByteString;
+use ntex::util::{ByteString, HashSet};
 
 use super::codec::{self, DisconnectReasonCode, QoS, UserProperties};
 use crate::error;
@@ -55,7 +55,7 @@ impl<E> ControlMessage<E> {
     /// Create a new PING `ControlMessage`.
     #[doc(hidden)]
     pub fn ping() -> Self {
-        ControlMessage::Ping(Ping)
+        ControlMessage::Ping(Ping::new(None))
     }
 
     /// Create a new `ControlMessage` from DISCONNECT packet.
@@ -72,8 +72,8 @@ impl<E> ControlMessage<E> {
         ControlMessage::Error(Error::new(err))
     }
 
-    pub(super) fn proto_error(err: error::ProtocolError) -> Self {
-        ControlMessage::ProtocolError(ProtocolError::new(err))
+    pub(super) fn proto_error(err: error::ProtocolError, reason: DisconnectReasonCode) -> Self {
+        ControlMessage::ProtocolError(ProtocolError::with_reason(err, reason))
     }
 
     /// Disconnects the client by sending DISCONNECT packet
@@ -111,9 +111,23 @@ impl Auth {
 }
 
 #[derive(Debug)]
-pub struct Ping;
+pub struct Ping {
+    interval: Option<Duration>,
+}
 
 impl Ping {
+    pub(super) fn new(interval: Option<Duration>) -> Self {
+        Ping { interval }
+    }
+
+    /// Time since previous PINGREQ of the connection
+    ///
+    /// Returns `None` for first PINGREQ. Control service could use it to
+    /// disconnect clients that ping too often.
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
     pub fn ack(self) -> ControlResult {
         ControlResult { packet: Some(codec::Packet::PingResponse), disconnect: false }
     }
@@ -128,6 +142,25 @@ impl Disconnect {
         &self.0
     }
 
+    /// Disconnect reason code
+    pub fn reason_code(&self) -> DisconnectReasonCode {
+        self.0.reason_code
+    }
+
+    /// Server reference, peer may use another server
+    pub fn server_reference(&self) -> Option<&ByteString> {
+        self.0.server_reference.as_ref()
+    }
+
+    /// Check if client asks to publish its last will
+    ///
+    /// Last will must be published if client disconnects with
+    /// `DisconnectWithWillMessage` reason, on any other reason it must be
+    /// discarded without publishing (MQTT-3.14.4-3).
+    pub fn publish_will(&self) -> bool {
+        self.0.reason_code == DisconnectReasonCode::DisconnectWithWillMessage
+    }
+
     /// Ack disconnect message
     pub fn ack(self) -> ControlResult {
         ControlResult { packet: None, disconnect: true }
@@ -139,6 +172,8 @@ impl Disconnect {
 pub struct Subscribe {
     packet: codec::Subscribe,
     result: codec::SubscribeAck,
+    /// Filters rejected by server, skipped by iterator
+    rejected: Vec<bool>,
 }
 
 impl Subscribe {
@@ -156,7 +191,60 @@ impl Subscribe {
             reason_string: None,
         };
 
-        Self { packet, result }
+        let rejected = vec![false; packet.topic_filters.len()];
+        Self { packet, result, rejected }
+    }
+
+    /// Reject topic filters with wildcards, rejected filters are skipped
+    /// by iterator
+    pub(super) fn reject_wildcards(mut self) -> Self {
+        for (idx, (topic, _)) in self.packet.topic_filters.iter().enumerate() {
+            if is_wildcard(topic) {
+                self.result.status[idx] =
+                    codec::SubscribeAckReason::WildcardSubscriptionsNotSupported;
+                self.rejected[idx] = true;
+            }
+        }
+        self
+    }
+
+    /// Reject new topic filters over `max` counted subscriptions, rejected
+    /// filters are skipped by iterator
+    ///
+    /// `counted` holds active filters and filters of SUBSCRIBE packets that
+    /// are not acked yet, such filters replace existing subscriptions and
+    /// are not counted again.
+    pub(super) fn reject_over_quota(
+        mut self,
+        counted: &HashSet<ByteString>,
+        max: usize,
+    ) -> Self {
+        let mut count = counted.len();
+        let mut added: HashSet<&ByteString> = HashSet::default();
+        for (idx, (topic, _)) in self.packet.topic_filters.iter().enumerate() {
+            if self.rejected[idx] || counted.contains(topic) || added.contains(topic) {
+                continue;
+            }
+            if count < max {
+                count += 1;
+                added.insert(topic);
+            } else {
+                self.result.status[idx] = codec::SubscribeAckReason::QuotaExceeded;
+                self.rejected[idx] = true;
+            }
+        }
+        self
+    }
+
+    /// Topic filters that are not rejected by server
+    pub(super) fn accepted_filters(&self) -> Vec<ByteString> {
+        self.packet
+            .topic_filters
+            .iter()
+            .zip(&self.rejected)
+            .filter(|(_, rejected)| !**rejected)
+            .map(|((topic, _), _)| topic.clone())
+            .collect()
     }
 
     #[inline]
@@ -206,6 +294,10 @@ impl<'a> IntoIterator for &'a mut Subscribe {
     }
 }
 
+fn is_wildcard(topic: &str) -> bool {
+    topic.contains(['+', '#'])
+}
+
 /// Iterator over subscription topics
 pub struct SubscribeIter<'a> {
     subs: *mut Subscribe,
@@ -217,6 +309,10 @@ impl<'a> SubscribeIter<'a> {
     fn next_unsafe(&mut self) -> Option<Subscription<'a>> {
         let subs = unsafe { &mut *self.subs };
 
+        while self.entry < subs.packet.topic_filters.len() && subs.rejected[self.entry] {
+            self.entry += 1;
+        }
+
         if self.entry < subs.packet.topic_filters.len() {
             let s = Subscription {
                 topic: &subs.packet.topic_filters[self.entry].0,
@@ -261,6 +357,15 @@ impl<'a> Subscription<'a> {
         self.options
     }
 
+    #[inline]
+    /// check if retain flag of forwarded messages must be kept as published
+    ///
+    /// Otherwise retain flag must be cleared when messages are forwarded
+    /// to this subscription.
+    pub fn retain_as_published(&self) -> bool {
+        self.options.retain_as_published
+    }
+
     #[inline]
     /// fail to subscribe to the topic
     pub fn fail(&mut self, status: codec::SubscribeAckReason) {
@@ -523,41 +628,23 @@ impl<E> Error<E> {
 #[derive(Debug)]
 pub struct ProtocolError {
     err: error::ProtocolError,
-    pkt: codec::Disconnect,
+    pub(super) pkt: codec::Disconnect,
 }
 
 impl ProtocolError {
     pub fn new(err: error::ProtocolError) -> Self {
+        let reason = disconnect_reason(&err);
+        Self::with_reason(err, reason)
+    }
+
+    pub(super) fn with_reason(err: error::ProtocolError, reason: DisconnectReasonCode) -> Self {
         Self {
             pkt: codec::Disconnect {
                 session_expiry_interval_secs: None,
                 server_reference: None,
                 reason_string: None,
                 user_properties: UserProperties::default(),
-                reason_code: match err {
-                    error::ProtocolError::Decode(error::DecodeError::InvalidLength) => {
-                        DisconnectReasonCode::MalformedPacket
-                    }
-                    error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded) => {
-                        DisconnectReasonCode::PacketTooLarge
-                    }
-                    error::ProtocolError::Unexpected(_, _) => {
-                        DisconnectReasonCode::ProtocolError
-                    }
-                    error::ProtocolError::ReceiveMaximumExceeded => {
-                        DisconnectReasonCode::ReceiveMaximumExceeded
-                    }
-                    error::ProtocolError::KeepAliveTimeout => {
-                        DisconnectReasonCode::KeepAliveTimeout
-                    }
-                    error::ProtocolError::UnknownTopicAlias => {
-                        DisconnectReasonCode::TopicAliasInvalid
-                    }
-                    error::ProtocolError::Encode(_) => {
-                        DisconnectReasonCode::ImplementationSpecificError
-                    }
-                    _ => DisconnectReasonCode::ImplementationSpecificError,
-                },
+                reason_code: reason,
             },
             err,
         }
@@ -618,3 +705,32 @@ impl ProtocolError {
         )
     }
 }
+
+/// Default mapping of protocol errors to DISCONNECT reason codes
+pub fn disconnect_reason(err: &error::ProtocolError) -> DisconnectReasonCode {
+    match err {
+        error::ProtocolError::Decode(error::DecodeError::MaxSizeExceeded { .. }) => {
+            DisconnectReasonCode::PacketTooLarge
+        }
+        error::ProtocolError::Decode(error::DecodeError::ReadBufferExceeded { .. }) => {
+            DisconnectReasonCode::QuotaExceeded
+        }
+        error::ProtocolError::Decode(error::DecodeError::ZeroReceiveMax) => {
+            DisconnectReasonCode::ProtocolError
+        }
+        error::ProtocolError::Decode(_) => DisconnectReasonCode::MalformedPacket,
+        error::ProtocolError::Unexpected(_, _)
+        | error::ProtocolError::PacketIdMismatch
+        | error::ProtocolError::ReasonCodesMismatch => DisconnectReasonCode::ProtocolError,
+        error::ProtocolError::ReceiveMaximumExceeded => {
+            DisconnectReasonCode::ReceiveMaximumExceeded
+        }
+        error::ProtocolError::KeepAliveTimeout => DisconnectReasonCode::KeepAliveTimeout,
+        error::ProtocolError::UnknownTopicAlias | error::ProtocolError::MaxTopicAlias => {
+            DisconnectReasonCode::TopicAliasInvalid
+        }
+        error::ProtocolError::Encode(_) | error::ProtocolError::Io(_) => {
+            DisconnectReasonCode::ImplementationSpecificError
+        }
+    }
+}
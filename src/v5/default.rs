@@ -87,6 +87,7 @@ impl<S, E: fmt::Debug> Service for DefaultControlService<S, E> {
         match pkt {
             ControlMessage::Ping(pkt) => Ready::Ok(pkt.ack()),
             ControlMessage::Disconnect(pkt) => Ready::Ok(pkt.ack()),
+            ControlMessage::ProtocolError(pkt) => Ready::Ok(pkt.ack()),
             _ => {
                 log::warn!("MQTT5 Control service is not configured, pkt: {:?}", pkt);
                 Ready::Ok(pkt.disconnect_with(super::codec::Disconnect::new(
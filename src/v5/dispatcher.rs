@@ -1,25 +1,34 @@
 use std::cell::{Cell, RefCell};
 use std::task::{Context, Poll};
-use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc};
+use std::{convert::TryFrom, future::Future, marker, num, pin::Pin, rc::Rc, time::Instant};
 
 use ntex::service::{fn_factory_with_config, Service, ServiceFactory};
 use ntex::util::{
-    buffer::BufferService, inflight::InFlightService, join, Either, HashSet, Ready,
+    buffer::BufferService, inflight::InFlightService, join, ByteString, Either, HashSet, Ready,
 };
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::DispatchItem;
+use crate::types::packet_type;
 
 use super::control::{self, ControlMessage, ControlResult};
-use super::publish::{Publish, PublishAck};
-use super::shared::{Ack, MqttShared};
+use super::publish::{Publish, PublishAck, PublishInfo};
+use super::retained::Retained;
+use super::server::Qos0ViolationPolicy;
+use super::shared::{Ack, MqttShared, Reservation};
 use super::sink::MqttSink;
 use super::{codec, Session};
 
+/// Mapping of protocol errors to DISCONNECT reason codes
+pub(super) type ErrorReasonMap = Rc<dyn Fn(&ProtocolError) -> codec::DisconnectReasonCode>;
+
 /// mqtt3 protocol dispatcher
 pub(super) fn factory<St, T, C, E>(
     publish: T,
     control: C,
+    reason_map: ErrorReasonMap,
+    max_unacked: usize,
+    control_buffer: usize,
 ) -> impl ServiceFactory<
     Config = Session<St>,
     Request = DispatchItem<Rc<MqttShared>>,
@@ -50,12 +59,14 @@ where
         let fut = join(publish.new_service(cfg.clone()), control.new_service(cfg.clone()));
 
         let (max_receive, max_topic_alias) = cfg.params();
+        let wildcards = cfg.limits().wildcard_subscriptions;
+        let reason_map = reason_map.clone();
 
         async move {
             let (publish, control) = fut.await;
 
             let control = BufferService::new(
-                16,
+                control_buffer,
                 || MqttError::<C::Error>::Disconnected,
                 // limit number of in-flight messages
                 InFlightService::new(1, control?.map_err(MqttError::Service)),
@@ -65,8 +76,11 @@ where
                 cfg.sink().clone(),
                 max_receive as usize,
                 max_topic_alias,
+                wildcards,
                 publish?,
                 control,
+                reason_map,
+                max_unacked,
             ))
         }
     })
@@ -79,6 +93,10 @@ pub(crate) struct Dispatcher<T, C, E, E2> {
     shutdown: Cell<bool>,
     max_receive: usize,
     max_topic_alias: u16,
+    wildcards: bool,
+    max_unacked: usize,
+    /// Arrival time of last PINGREQ
+    last_ping: Cell<Option<Instant>>,
     inner: Rc<Inner<C>>,
     _t: marker::PhantomData<(E, E2)>,
 }
@@ -86,12 +104,15 @@ pub(crate) struct Dispatcher<T, C, E, E2> {
 struct Inner<C> {
     control: C,
     sink: MqttSink,
-    info: RefCell<PublishInfo>,
+    info: Rc<RefCell<PublishInfo>>,
+    reason_map: ErrorReasonMap,
 }
 
-struct PublishInfo {
-    inflight: HashSet<num::NonZeroU16>,
-    aliases: HashSet<num::NonZeroU16>,
+impl<C> Inner<C> {
+    fn proto_error<E>(&self, err: ProtocolError) -> ControlMessage<E> {
+        let reason = (*self.reason_map)(&err);
+        ControlMessage::proto_error(err, reason)
+    }
 }
 
 impl<T, C, E, E2> Dispatcher<T, C, E, E2>
@@ -100,26 +121,31 @@ where
     PublishAck: TryFrom<E2, Error = E>,
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         sink: MqttSink,
         max_receive: usize,
         max_topic_alias: u16,
+        wildcards: bool,
         publish: T,
         control: C,
+        reason_map: ErrorReasonMap,
+        max_unacked: usize,
     ) -> Self {
         Self {
             publish,
             max_receive,
             max_topic_alias,
+            wildcards,
+            max_unacked,
             sink: sink.clone(),
             shutdown: Cell::new(false),
+            last_ping: Cell::new(None),
             inner: Rc::new(Inner {
                 control,
                 sink,
-                info: RefCell::new(PublishInfo {
-                    aliases: HashSet::default(),
-                    inflight: HashSet::default(),
-                }),
+                info: Rc::new(RefCell::new(PublishInfo::default())),
+                reason_map,
             }),
             _t: marker::PhantomData,
         }
@@ -146,6 +172,21 @@ where
         let res1 = self.publish.poll_ready(cx).map_err(|e| MqttError::Service(e.into()))?;
         let res2 = self.inner.control.poll_ready(cx)?;
 
+        // stop reading until payload stream consumes buffered chunks
+        if self.sink.0.codec.poll_payload_ready(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        // stop reading until deferred acks get completed
+        if self.max_unacked != 0 {
+            let info = self.inner.info.borrow();
+            if info.deferred >= self.max_unacked {
+                log::trace!("Max number of unacked publish packets is reached");
+                info.deferred_waker.register(cx.waker());
+                return Poll::Pending;
+            }
+        }
+
         if res1.is_pending() || res2.is_pending() {
             Poll::Pending
         } else {
@@ -156,6 +197,14 @@ where
     fn poll_shutdown(&self, _: &mut Context<'_>, is_error: bool) -> Poll<()> {
         if !self.shutdown.get() {
             self.inner.sink.drop_sink();
+            self.sink.0.codec.abort_payload_stream();
+            if let Some(will) = self.sink.0.will.borrow_mut().take() {
+                self.sink.0.pool.retained.will(will);
+            }
+            #[cfg(feature = "trace")]
+            self.sink.0.codec.trace_closed();
+            #[cfg(feature = "prometheus")]
+            self.sink.0.codec.metrics_closed();
             self.shutdown.set(true);
             let fut = self.inner.control.call(ControlMessage::closed(is_error));
             ntex::rt::spawn(async move {
@@ -172,28 +221,42 @@ where
             DispatchItem::Item(codec::Packet::Publish(publish)) => {
                 let info = self.inner.clone();
                 let packet_id = publish.packet_id;
+                let qos = publish.qos;
+                let stream = self.sink.0.codec.take_payload_stream();
 
                 {
                     let mut inner = info.info.borrow_mut();
 
                     if let Some(pid) = packet_id {
+                        let qos2_received = self.sink.0.qos2_received.borrow();
+
+                        // publish is received already, QoS2 publish is
+                        // acked without delivery, MQTT-4.3.3-10
+                        if qos == codec::QoS::ExactlyOnce && qos2_received.contains(&pid) {
+                            log::trace!("QoS2 publish {} is received already", pid);
+                            self.sink.send(codec::Packet::PublishReceived(codec::PublishAck {
+                                packet_id: pid,
+                                ..Default::default()
+                            }));
+                            return Either::Right(Either::Left(Ready::Ok(None)));
+                        }
+
                         // check for receive maximum
-                        if self.max_receive != 0 && inner.inflight.len() >= self.max_receive {
+                        let inflight = inner.inflight.len() + qos2_received.len();
+                        if self.max_receive != 0 && inflight >= self.max_receive {
                             log::trace!(
                                 "Receive maximum exceeded: max: {} inflight: {}",
                                 self.max_receive,
-                                inner.inflight.len()
+                                inflight
                             );
                             return Either::Right(Either::Right(ControlResponse::new(
-                                ControlMessage::proto_error(
-                                    ProtocolError::ReceiveMaximumExceeded,
-                                ),
+                                self.inner.proto_error(ProtocolError::ReceiveMaximumExceeded),
                                 &self.inner,
                             )));
                         }
 
                         // check for duplicated packet id
-                        if !inner.inflight.insert(pid) {
+                        if qos2_received.contains(&pid) || !inner.inflight.insert(pid) {
                             self.sink.send(codec::Packet::PublishAck(codec::PublishAck {
                                 packet_id: pid,
                                 reason_code: codec::PublishAckReason::PacketIdentifierInUse,
@@ -207,56 +270,131 @@ where
                     if let Some(alias) = publish.properties.topic_alias {
                         // check existing topic
                         if publish.topic.is_empty() {
-                            if !inner.aliases.contains(&alias) {
+                            if !self.inner.sink.0.aliases.borrow().inbound.contains_key(&alias)
+                            {
                                 return Either::Right(Either::Right(ControlResponse::new(
-                                    ControlMessage::proto_error(
-                                        ProtocolError::UnknownTopicAlias,
-                                    ),
+                                    self.inner.proto_error(ProtocolError::UnknownTopicAlias),
                                     &self.inner,
                                 )));
                             }
                         } else {
                             if alias.get() > self.max_topic_alias {
                                 return Either::Right(Either::Right(ControlResponse::new(
-                                    ControlMessage::proto_error(ProtocolError::MaxTopicAlias),
+                                    self.inner.proto_error(ProtocolError::MaxTopicAlias),
                                     &self.inner,
                                 )));
                             }
 
                             // record new alias
-                            inner.aliases.insert(alias);
+                            self.inner
+                                .sink
+                                .0
+                                .aliases
+                                .borrow_mut()
+                                .inbound
+                                .insert(alias, publish.topic.clone());
                         }
                     }
+
+                    // check for distinct topics limit
+                    let recorded = match publish.properties.topic_alias {
+                        Some(alias) if publish.topic.is_empty() => {
+                            let aliases = self.inner.sink.0.aliases.borrow();
+                            aliases
+                                .inbound
+                                .get(&alias)
+                                .map(|topic| self.sink.0.record_topic(topic))
+                                .unwrap_or(true)
+                        }
+                        _ => self.sink.0.record_topic(&publish.topic),
+                    };
+                    if !recorded {
+                        log::trace!("Max number of distinct topics is reached");
+                        self.sink.close_with_reason(codec::Disconnect::new(
+                            codec::DisconnectReasonCode::QuotaExceeded,
+                        ));
+                        return Either::Right(Either::Left(Ready::Ok(None)));
+                    }
+                }
+
+                let retained = if stream.is_none() && self.sink.0.pool.retained.is_enabled() {
+                    Retained::from_publish(&publish, &self.sink.0.aliases.borrow().inbound)
+                } else {
+                    None
+                };
+
+                let mut publish = Publish::with_ack(publish, &self.sink, &self.inner.info);
+                if let Some((rx, size)) = stream {
+                    publish.set_payload_stream(rx, size);
                 }
 
                 Either::Left(PublishResponse {
                     packet_id: packet_id.map(|v| v.get()).unwrap_or(0),
+                    qos,
+                    retained,
                     inner: info,
-                    state: PublishResponseState::Publish {
-                        fut: self.publish.call(Publish::new(publish)),
-                    },
+                    state: PublishResponseState::Publish { fut: self.publish.call(publish) },
                     _t: marker::PhantomData,
                 })
             }
             DispatchItem::Item(codec::Packet::PublishAck(packet)) => {
                 if let Err(err) = self.sink.pkt_ack(Ack::Publish(packet)) {
                     Either::Right(Either::Right(ControlResponse::new(
-                        ControlMessage::proto_error(err),
+                        self.inner.proto_error(err),
                         &self.inner,
                     )))
                 } else {
                     Either::Right(Either::Left(Ready::Ok(None)))
                 }
             }
+            DispatchItem::Item(codec::Packet::PublishReceived(pkt)) => {
+                // server does not send QoS2 publishes
+                if let Err(err) = self.sink.unknown_ack(pkt.packet_id.get()) {
+                    Either::Right(Either::Right(ControlResponse::new(
+                        self.inner.proto_error(err),
+                        &self.inner,
+                    )))
+                } else {
+                    Either::Right(Either::Left(Ready::Ok(None)))
+                }
+            }
+            DispatchItem::Item(codec::Packet::PublishRelease(pkt)) => {
+                let reason_code =
+                    if self.sink.0.qos2_received.borrow_mut().remove(&pkt.packet_id) {
+                        codec::PublishAck2Reason::Success
+                    } else {
+                        codec::PublishAck2Reason::PacketIdNotFound
+                    };
+                Either::Right(Either::Left(Ready::Ok(Some(codec::Packet::PublishComplete(
+                    codec::PublishAck2 {
+                        packet_id: pkt.packet_id,
+                        reason_code,
+                        properties: Vec::new(),
+                        reason_string: None,
+                    },
+                )))))
+            }
             DispatchItem::Item(codec::Packet::Auth(pkt)) => Either::Right(Either::Right(
                 ControlResponse::new(ControlMessage::auth(pkt), &self.inner),
             )),
-            DispatchItem::Item(codec::Packet::PingRequest) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::ping(), &self.inner),
-            )),
-            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => Either::Right(Either::Right(
-                ControlResponse::new(ControlMessage::remote_disconnect(pkt), &self.inner),
-            )),
+            DispatchItem::Item(codec::Packet::PingRequest) => {
+                let now = Instant::now();
+                let interval = self.last_ping.replace(Some(now)).map(|last| now - last);
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::Ping(control::Ping::new(interval)),
+                    &self.inner,
+                )))
+            }
+            DispatchItem::Item(codec::Packet::Disconnect(pkt)) => {
+                // will is discarded on normal disconnect, MQTT-3.1.2-10
+                if pkt.reason_code != codec::DisconnectReasonCode::DisconnectWithWillMessage {
+                    self.sink.0.will.borrow_mut().take();
+                }
+                Either::Right(Either::Right(ControlResponse::new(
+                    ControlMessage::remote_disconnect(pkt),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(codec::Packet::Subscribe(pkt)) => {
                 // register inflight packet id
                 if !self.inner.info.borrow_mut().inflight.insert(pkt.packet_id) {
@@ -274,9 +412,22 @@ where
                     return Either::Right(Either::Left(Ready::Ok(None)));
                 }
                 let id = pkt.packet_id;
+                let mut msg = control::Subscribe::new(pkt);
+                if !self.wildcards {
+                    msg = msg.reject_wildcards();
+                }
+                // filters are counted at check time, pipelined packets
+                // see filters of each other
+                let max = self.sink.0.pool.max_subscriptions.get();
+                let reservation = if max != 0 {
+                    msg = msg.reject_over_quota(&self.sink.0.counted_filters(), max);
+                    Some(self.sink.0.reserve_filters(msg.accepted_filters()))
+                } else {
+                    None
+                };
+                let msg = ControlMessage::Subscribe(msg);
                 Either::Right(Either::Right(
-                    ControlResponse::new(ControlMessage::subscribe(pkt), &self.inner)
-                        .packet_id(id),
+                    ControlResponse::new(msg, &self.inner).packet_id(id).reserve(reservation),
                 ))
             }
             DispatchItem::Item(codec::Packet::Unsubscribe(pkt)) => {
@@ -301,27 +452,39 @@ where
                         .packet_id(id),
                 ))
             }
+            DispatchItem::Item(codec::Packet::Connect(_)) => {
+                // CONNECT could be sent while handshake service is still
+                // processing first one, it is decoded once dispatcher starts
+                log::trace!("MQTT-3.1.0-2: Second CONNECT packet");
+                Either::Right(Either::Right(ControlResponse::new(
+                    self.inner.proto_error(ProtocolError::Unexpected(
+                        packet_type::CONNECT,
+                        "MQTT-3.1.0-2: Second CONNECT packet",
+                    )),
+                    &self.inner,
+                )))
+            }
             DispatchItem::Item(_) => Either::Right(Either::Left(Ready::Ok(None))),
             DispatchItem::EncoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Encode(err)),
+                    self.inner.proto_error(ProtocolError::Encode(err)),
                     &self.inner,
                 )))
             }
             DispatchItem::KeepAliveTimeout => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::KeepAliveTimeout),
+                    self.inner.proto_error(ProtocolError::KeepAliveTimeout),
                     &self.inner,
                 )))
             }
             DispatchItem::DecoderError(err) => {
                 Either::Right(Either::Right(ControlResponse::new(
-                    ControlMessage::proto_error(ProtocolError::Decode(err)),
+                    self.inner.proto_error(ProtocolError::Decode(err)),
                     &self.inner,
                 )))
             }
             DispatchItem::IoError(err) => Either::Right(Either::Right(ControlResponse::new(
-                ControlMessage::proto_error(ProtocolError::Io(err)),
+                self.inner.proto_error(ProtocolError::Io(err)),
                 &self.inner,
             ))),
             DispatchItem::WBackPressureEnabled | DispatchItem::WBackPressureDisabled => {
@@ -337,6 +500,8 @@ pin_project_lite::pin_project! {
         #[pin]
         state: PublishResponseState<T, C, E>,
         packet_id: u16,
+        qos: codec::QoS,
+        retained: Option<Retained>,
         inner: Rc<Inner<C>>,
         _t: marker::PhantomData<(E, E2)>,
     }
@@ -392,7 +557,18 @@ where
                     }
                     Poll::Pending => return Poll::Pending,
                 };
-                if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
+
+                // pass accepted retained message to retained store
+                if ack.deferred || u8::from(ack.reason_code) < 0x80 {
+                    if let Some(retained) = this.retained.take() {
+                        this.inner.sink.0.pool.retained.update(retained);
+                    }
+                }
+
+                if ack.deferred {
+                    // ack is sent by AckToken
+                    Poll::Ready(Ok(None))
+                } else if let Some(id) = num::NonZeroU16::new(*this.packet_id) {
                     this.inner.info.borrow_mut().inflight.remove(&id);
                     let ack = codec::PublishAck {
                         packet_id: id,
@@ -400,8 +576,17 @@ where
                         reason_string: ack.reason_string,
                         properties: ack.properties,
                     };
-                    Poll::Ready(Ok(Some(codec::Packet::PublishAck(ack))))
+                    Poll::Ready(Ok(Some(this.inner.sink.0.inbound_ack(*this.qos, ack))))
                 } else {
+                    let pool = &this.inner.sink.0.pool;
+                    if u8::from(ack.reason_code) >= 0x80
+                        && pool.qos0_violation.get() == Qos0ViolationPolicy::Disconnect
+                    {
+                        log::trace!("QoS0 publish is rejected with {:?}", ack.reason_code);
+                        let mut pkt = codec::Disconnect::new(reject_reason(ack.reason_code));
+                        pkt.reason_string = ack.reason_string;
+                        this.inner.sink.close_with_reason(pkt);
+                    }
                     Poll::Ready(Ok(None))
                 }
             }
@@ -410,6 +595,20 @@ where
     }
 }
 
+/// DISCONNECT reason code for rejected QoS0 publish
+fn reject_reason(code: codec::PublishAckReason) -> codec::DisconnectReasonCode {
+    use codec::{DisconnectReasonCode as D, PublishAckReason as P};
+
+    match code {
+        P::ImplementationSpecificError => D::ImplementationSpecificError,
+        P::NotAuthorized => D::NotAuthorized,
+        P::TopicNameInvalid => D::TopicNameInvalid,
+        P::QuotaExceeded => D::QuotaExceeded,
+        P::PayloadFormatInvalid => D::PayloadFormatInvalid,
+        _ => D::UnspecifiedError,
+    }
+}
+
 pin_project_lite::pin_project! {
     /// Control service response future
     pub(crate) struct ControlResponse<C: Service, E>
@@ -418,11 +617,20 @@ pin_project_lite::pin_project! {
         fut: C::Future,
         inner: Rc<Inner<C>>,
         error: bool,
+        reason: Option<codec::DisconnectReasonCode>,
         packet_id: u16,
+        track: Option<Track>,
+        reservation: Option<Reservation>,
         _t: marker::PhantomData<E>,
     }
 }
 
+/// Subscription changes, applied once control service acks packet
+enum Track {
+    Subscribe(Option<num::NonZeroU32>, Vec<(ByteString, codec::SubscriptionOptions)>),
+    Unsubscribe(Vec<ByteString>),
+}
+
 impl<C: Service, E> ControlResponse<C, E>
 where
     C: Service<Request = ControlMessage<E>, Response = ControlResult, Error = MqttError<E>>,
@@ -433,12 +641,34 @@ where
             ControlMessage::Error(_) | ControlMessage::ProtocolError(_) => true,
             _ => false,
         };
+        let reason = match pkt {
+            ControlMessage::ProtocolError(ref err) => Some(err.pkt.reason_code),
+            _ => None,
+        };
+        #[cfg(feature = "trace")]
+        if let ControlMessage::ProtocolError(ref err) = pkt {
+            inner.sink.0.codec.trace(|| {
+                crate::v5::trace::ProtocolEventKind::ProtocolError(err.get_ref().to_string())
+            });
+        }
+        let track = match pkt {
+            ControlMessage::Subscribe(ref pkt) => {
+                Some(Track::Subscribe(pkt.packet().id, pkt.packet().topic_filters.clone()))
+            }
+            ControlMessage::Unsubscribe(ref pkt) => {
+                Some(Track::Unsubscribe(pkt.packet().topic_filters.clone()))
+            }
+            _ => None,
+        };
 
         Self {
             error,
+            reason,
             fut: inner.control.call(pkt),
             inner: inner.clone(),
             packet_id: 0,
+            track,
+            reservation: None,
             _t: marker::PhantomData,
         }
     }
@@ -447,6 +677,12 @@ where
         self.packet_id = id.get();
         self
     }
+
+    /// Keep subscription quota reservation until packet is acked
+    fn reserve(mut self, reservation: Option<Reservation>) -> Self {
+        self.reservation = reservation;
+        self
+    }
 }
 
 impl<C, E> Future for ControlResponse<C, E>
@@ -468,6 +704,12 @@ where
             Poll::Ready(Err(err)) => {
                 // do not handle nested error
                 return if *this.error {
+                    // notify peer about protocol error before closing connection
+                    if let Some(reason) = this.reason.take() {
+                        this.inner
+                            .sink
+                            .send(codec::Packet::Disconnect(codec::Disconnect::new(reason)));
+                    }
                     Poll::Ready(Err(err))
                 } else {
                     // handle error from control service
@@ -485,6 +727,20 @@ where
             Poll::Pending => return Poll::Pending,
         };
 
+        // update session subscriptions
+        let this = self.as_mut().project();
+        match (this.track.take(), &result.packet) {
+            (Some(Track::Subscribe(id, filters)), Some(codec::Packet::SubscribeAck(ack))) => {
+                this.inner.sink.track_subscribe(id, filters, &ack.status)
+            }
+            (Some(Track::Unsubscribe(filters)), Some(codec::Packet::UnsubscribeAck(ack))) => {
+                this.inner.sink.track_unsubscribe(&filters, &ack.status)
+            }
+            _ => (),
+        }
+        // granted filters are active, rejected filters are released
+        this.reservation.take();
+
         if self.error {
             if let Some(pkt) = result.packet {
                 self.inner.sink.send(pkt)
@@ -0,0 +1,163 @@
+//! Server drain mode
+//!
+//! Drain mode is used for rolling restarts. New connections get rejected,
+//! existing connections receive `ServerShuttingDown` DISCONNECT packets
+//! spread over configured window, so clients do not reconnect all at once.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::{collections::HashMap, future::Future, pin::Pin, time};
+
+use ntex::time::{sleep, Millis};
+use ntex::util::Either;
+
+use super::{codec, MqttSink};
+use crate::io::State;
+
+/// Drain handle
+///
+/// Handle is shared between all server workers, it can be cloned and
+/// used from any thread.
+#[derive(Clone, Default)]
+pub struct Drain(Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    draining: AtomicBool,
+    next_id: AtomicUsize,
+    st: Mutex<DrainState>,
+}
+
+#[derive(Default)]
+struct DrainState {
+    window: time::Duration,
+    total: usize,
+    next_slot: usize,
+    // active connections, waker is set while connection waits for drain start
+    conns: HashMap<usize, Option<Waker>>,
+    drained: Vec<Waker>,
+}
+
+impl Drain {
+    /// Create new drain handle
+    pub fn new() -> Self {
+        Drain::default()
+    }
+
+    /// Check if drain mode is enabled
+    pub fn is_draining(&self) -> bool {
+        self.0.draining.load(Ordering::Acquire)
+    }
+
+    /// Number of active connections
+    pub fn connections(&self) -> usize {
+        self.0.st.lock().unwrap().conns.len()
+    }
+
+    /// Enable drain mode.
+    ///
+    /// Server stops accepting new connections, CONNECT packets get rejected
+    /// with `ServerUnavailable` reason code. Existing connections get closed
+    /// with `ServerShuttingDown` DISCONNECT packet, disconnects are evenly
+    /// spread over `window` time. Zero window closes all connections at once.
+    pub fn begin_drain<T: Into<Millis>>(&self, window: T) {
+        let mut st = self.0.st.lock().unwrap();
+        if self.0.draining.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        log::trace!("Begin drain, {} active connections", st.conns.len());
+
+        st.window = window.into().into();
+        st.total = st.conns.len();
+        for waker in st.conns.values_mut().filter_map(Option::take) {
+            waker.wake();
+        }
+        if st.conns.is_empty() {
+            st.drained.drain(..).for_each(Waker::wake);
+        }
+    }
+
+    /// Returns future that resolves when drain mode is enabled and all
+    /// connections are closed
+    pub fn drained(&self) -> impl Future<Output = ()> {
+        Drained(self.0.clone())
+    }
+
+    /// Track connection, send DISCONNECT packet once drain begins
+    pub(super) fn register(&self, state: State, sink: MqttSink) {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        self.0.st.lock().unwrap().conns.insert(id, None);
+        let guard = ConnGuard(self.0.clone(), id);
+
+        ntex::rt::spawn(async move {
+            let slot = WaitSlot(&guard);
+            if let Either::Left(delay) = crate::utils::select(slot, state.on_disconnect()).await
+            {
+                if let Either::Left(_) =
+                    crate::utils::select(sleep(delay), state.on_disconnect()).await
+                {
+                    log::trace!("Drain connection {}", guard.1);
+                    sink.close_with_reason(codec::Disconnect::new(
+                        codec::DisconnectReasonCode::ServerShuttingDown,
+                    ));
+                    state.on_disconnect().await;
+                }
+            }
+            drop(guard);
+        });
+    }
+}
+
+/// Removes connection from drain state
+struct ConnGuard(Arc<Inner>, usize);
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut st = self.0.st.lock().unwrap();
+        st.conns.remove(&self.1);
+        if st.conns.is_empty() && self.0.draining.load(Ordering::Acquire) {
+            st.drained.drain(..).for_each(Waker::wake);
+        }
+    }
+}
+
+/// Resolves to connection's disconnect delay once drain begins
+struct WaitSlot<'a>(&'a ConnGuard);
+
+impl<'a> Future for WaitSlot<'a> {
+    type Output = Millis;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Millis> {
+        let ConnGuard(ref inner, id) = *self.0;
+        let mut st = inner.st.lock().unwrap();
+        if inner.draining.load(Ordering::Acquire) {
+            let slot = st.next_slot;
+            st.next_slot += 1;
+            let delay = if st.total > 1 {
+                st.window * (slot.min(st.total - 1) as u32) / (st.total - 1) as u32
+            } else {
+                time::Duration::ZERO
+            };
+            Poll::Ready(Millis::from(delay))
+        } else {
+            st.conns.insert(id, Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+struct Drained(Arc<Inner>);
+
+impl Future for Drained {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut st = self.0.st.lock().unwrap();
+        if self.0.draining.load(Ordering::Acquire) && st.conns.is_empty() {
+            Poll::Ready(())
+        } else {
+            st.drained.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
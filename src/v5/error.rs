@@ -13,6 +13,10 @@ pub enum ClientError {
     /// Protocol error
     #[display(fmt = "Protocol error: {:?}", _0)]
     Protocol(ProtocolError),
+    /// Server sent packet other than CONNACK during handshake
+    #[display(fmt = "Unexpected packet during handshake: {}", _0)]
+    #[from(ignore)]
+    UnexpectedPacket(u8),
     /// Handshake timeout
     #[display(fmt = "Handshake timeout")]
     HandshakeTimeout,
@@ -22,6 +26,31 @@ pub enum ClientError {
     /// Connect error
     #[display(fmt = "Connect error: {}", _0)]
     Connect(ntex::connect::ConnectError),
+    /// Required capability is not available on server
+    #[display(fmt = "Capability is not available: {:?}", _0)]
+    CapabilityNotAvailable(Capability),
+    /// Circuit breaker is open, connect attempt is not made
+    #[display(fmt = "Circuit breaker is open")]
+    CircuitOpen,
+}
+
+/// Server capability advertised in CONNACK packet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Maximum QoS
+    MaxQos,
+    /// Retained messages
+    Retain,
+    /// Wildcard subscriptions
+    WildcardSubscriptions,
+    /// Subscription identifiers
+    SubscriptionIdentifiers,
+    /// Shared subscriptions
+    SharedSubscriptions,
+    /// Topic alias maximum
+    TopicAlias,
+    /// Maximum packet size
+    MaxPacketSize,
 }
 
 impl std::error::Error for ClientError {}
@@ -45,7 +74,41 @@ pub enum PublishQos1Error {
     /// Provided packet id is in use
     #[display(fmt = "Provided packet id is in use")]
     PacketIdInUse(u16),
+    /// Topic alias is greater than peer's topic alias maximum
+    #[display(fmt = "Topic alias is greater than peer's topic alias maximum")]
+    TopicAliasInvalid,
+    /// Peer did not ack publish in time
+    #[display(fmt = "Publish ack timeout")]
+    AckTimeout,
     /// Peer disconnected
     #[display(fmt = "Peer disconnected")]
     Disconnected,
 }
+
+impl std::error::Error for PublishQos1Error {}
+
+/// Request/response error, see `client::RequestMux`
+#[derive(Debug, Display, PartialEq)]
+pub enum RequestError {
+    /// Response topic subscription is rejected by server
+    #[display(fmt = "Response topic subscription failed: {:?}", _0)]
+    Subscribe(codec::SubscribeAckReason),
+    /// Send packet error
+    #[display(fmt = "Send packet error: {}", _0)]
+    Send(SendPacketError),
+    /// Response is not received in time
+    #[display(fmt = "Response timeout")]
+    Timeout,
+}
+
+impl std::error::Error for RequestError {}
+
+/// Streamed publish payload error
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq)]
+pub enum PayloadError {
+    /// Connection is closed before whole payload is received
+    #[display(fmt = "Payload is incomplete")]
+    Incomplete,
+}
+
+impl std::error::Error for PayloadError {}
@@ -1,6 +1,38 @@
-use std::{fmt, num::NonZeroU16, rc::Rc};
+use std::{fmt, io, num::NonZeroU16, rc::Rc};
+
+use ntex::codec::{AsyncRead, AsyncWrite};
+use ntex::time::Millis;
+use ntex::util::{ByteString, Bytes, Either};
 
 use super::{codec, shared::MqttShared, sink::MqttSink};
+use crate::error::{DecodeError, ProtocolError};
+use crate::io::State;
+
+/// Reply with `UnsupportedProtocolVersion` CONNACK if first packet is
+/// CONNECT with unsupported protocol level, MQTT-3.1.2-2, or with
+/// `ProtocolError` CONNACK if CONNECT has receive maximum of 0, MQTT-3.1.2.11.3
+pub(super) async fn reject_protocol_level<Io>(
+    io: &mut Io,
+    shared: &MqttShared,
+    err: &Either<DecodeError, io::Error>,
+) where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    let reason_code = match err {
+        Either::Left(DecodeError::UnsupportedProtocolLevel) => {
+            codec::ConnectAckReason::UnsupportedProtocolVersion
+        }
+        Either::Left(DecodeError::ZeroReceiveMax) => codec::ConnectAckReason::ProtocolError,
+        Either::Left(DecodeError::WillSizeExceeded { .. }) => {
+            codec::ConnectAckReason::PacketTooLarge
+        }
+        _ => return,
+    };
+    log::trace!("Rejecting connect packet with {:?} connect ack", reason_code);
+    let pkt = codec::ConnectAck { reason_code, ..Default::default() };
+    let _ =
+        shared.state.send(io, &shared.codec, codec::Packet::ConnectAck(Box::new(pkt))).await;
+}
 
 /// Handshake message
 pub struct Handshake<Io> {
@@ -10,6 +42,9 @@ pub struct Handshake<Io> {
     pub(super) max_size: u32,
     pub(super) max_receive: u16,
     pub(super) max_topic_alias: u16,
+    assigned_client_id: Option<ByteString>,
+    raw: Bytes,
+    connack_sent: bool,
 }
 
 impl<Io> Handshake<Io> {
@@ -21,7 +56,18 @@ impl<Io> Handshake<Io> {
         max_receive: u16,
         max_topic_alias: u16,
     ) -> Self {
-        Self { io, pkt, shared, max_size, max_receive, max_topic_alias }
+        let raw = shared.codec.take_connect_raw().unwrap_or_default();
+        Self {
+            io,
+            pkt,
+            raw,
+            shared,
+            max_size,
+            max_receive,
+            max_topic_alias,
+            assigned_client_id: None,
+            connack_sent: false,
+        }
     }
 
     #[inline]
@@ -34,6 +80,53 @@ impl<Io> Handshake<Io> {
         &mut self.pkt
     }
 
+    #[inline]
+    /// Returns raw bytes of connect packet
+    ///
+    /// Bytes are exactly as received from peer, variable header and payload
+    /// without fixed header, so handshake service could verify signature of
+    /// connect packet without re-encoding it. Bytes are not copied, they
+    /// reference read buffer and keep its memory allocated, handshake
+    /// service should not hold them after handshake completes.
+    /// `packet_mut()` does not change raw bytes.
+    pub fn packet_raw(&self) -> &Bytes {
+        &self.raw
+    }
+
+    #[inline]
+    /// Returns client id assigned by server to connection with zero-length
+    /// client id
+    pub fn assigned_client_id(&self) -> Option<&ByteString> {
+        self.assigned_client_id.as_ref()
+    }
+
+    /// Replace zero-length client id with server assigned id
+    pub(super) fn assign_client_id(&mut self, id: ByteString) {
+        self.pkt.client_id = id.clone();
+        self.assigned_client_id = Some(id);
+    }
+
+    #[inline]
+    /// Returns CONNECT packet user properties
+    pub fn user_properties(&self) -> &codec::UserProperties {
+        &self.pkt.user_properties
+    }
+
+    #[inline]
+    /// Returns value of the first user property with specified key
+    pub fn user_property(&self, key: &str) -> Option<&ByteString> {
+        self.pkt.user_properties.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    #[inline]
+    /// Returns TLS server name requested by client
+    pub fn sni_hostname(&self) -> Option<&str>
+    where
+        Io: super::SniHostname,
+    {
+        self.io.sni_hostname()
+    }
+
     #[inline]
     pub fn io(&mut self) -> &mut Io {
         &mut self.io
@@ -51,6 +144,7 @@ impl<Io> Handshake<Io> {
         let mut packet = codec::ConnectAck {
             reason_code: codec::ConnectAckReason::Success,
             topic_alias_max: self.max_topic_alias,
+            assigned_client_id: self.assigned_client_id,
             ..codec::ConnectAck::default()
         };
         if self.max_size != 0 {
@@ -65,6 +159,9 @@ impl<Io> Handshake<Io> {
             shared: self.shared,
             session: Some(st),
             keepalive: 30,
+            manual: false,
+            connack_sent: self.connack_sent,
+            retry: None,
             packet,
         }
     }
@@ -77,6 +174,9 @@ impl<Io> Handshake<Io> {
             shared: self.shared,
             session: None,
             keepalive: 30,
+            manual: false,
+            connack_sent: self.connack_sent,
+            retry: None,
             packet: codec::ConnectAck { reason_code, ..codec::ConnectAck::default() },
         }
     }
@@ -90,10 +190,68 @@ impl<Io> Handshake<Io> {
             session: None,
             packet: ack,
             keepalive: 30,
+            manual: false,
+            connack_sent: self.connack_sent,
+            retry: None,
+        }
+    }
+
+    #[inline]
+    /// Create handshake ack object that retries handshake later
+    ///
+    /// Authorization is pending, for example external authorizer is
+    /// overloaded. Server releases `max_concurrent_auth` slot, holds
+    /// connection for `delay` and calls handshake service again with
+    /// the same handshake message. Retries are bounded by auth timeout,
+    /// see `MqttServer::auth_timeout()`.
+    pub fn retry<St>(self, delay: Millis) -> HandshakeAck<Io, St> {
+        HandshakeAck {
+            io: self.io,
+            shared: self.shared,
+            session: None,
+            packet: codec::ConnectAck::default(),
+            keepalive: 30,
+            manual: false,
+            connack_sent: self.connack_sent,
+            retry: Some(Box::new(Retry {
+                delay,
+                pkt: self.pkt,
+                raw: self.raw,
+                max_size: self.max_size,
+                max_receive: self.max_receive,
+                max_topic_alias: self.max_topic_alias,
+                assigned_client_id: self.assigned_client_id,
+            })),
         }
     }
 }
 
+impl<Io> Handshake<Io>
+where
+    Io: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Send packet to the client and flush it
+    ///
+    /// Intended for manual handshake only, see `HandshakeAck::manual_connack()`.
+    pub async fn send(&mut self, pkt: codec::Packet) -> Result<(), ProtocolError> {
+        let is_connack = std::matches!(pkt, codec::Packet::ConnectAck(_));
+        self.shared.state.send(&mut self.io, &self.shared.codec, pkt).await?;
+        if is_connack {
+            self.connack_sent = true;
+        }
+        Ok(())
+    }
+
+    /// Read next packet from the client
+    ///
+    /// Intended for manual handshake only, for example to receive AUTH
+    /// response after sending AUTH challenge with `send()`. Returns `None`
+    /// if client is disconnected.
+    pub async fn recv(&mut self) -> Result<Option<codec::Packet>, ProtocolError> {
+        Ok(self.shared.state.next(&mut self.io, &self.shared.codec).await?)
+    }
+}
+
 impl<T> fmt::Debug for Handshake<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         self.pkt.fmt(f)
@@ -107,9 +265,45 @@ pub struct HandshakeAck<Io, St> {
     pub(crate) shared: Rc<MqttShared>,
     pub(crate) packet: codec::ConnectAck,
     pub(crate) keepalive: u16,
+    pub(crate) manual: bool,
+    pub(crate) connack_sent: bool,
+    pub(crate) retry: Option<Box<Retry>>,
+}
+
+/// Handshake message of pending handshake
+pub(crate) struct Retry {
+    delay: Millis,
+    pkt: Box<codec::Connect>,
+    raw: Bytes,
+    max_size: u32,
+    max_receive: u16,
+    max_topic_alias: u16,
+    assigned_client_id: Option<ByteString>,
 }
 
 impl<Io, St> HandshakeAck<Io, St> {
+    /// Handshake message and delay of pending handshake
+    pub(crate) fn into_retry(self) -> Either<(Millis, Handshake<Io>), Self> {
+        match self.retry {
+            Some(retry) => {
+                let retry = *retry;
+                let hnd = Handshake {
+                    io: self.io,
+                    pkt: retry.pkt,
+                    raw: retry.raw,
+                    shared: self.shared,
+                    max_size: retry.max_size,
+                    max_receive: retry.max_receive,
+                    max_topic_alias: retry.max_topic_alias,
+                    assigned_client_id: retry.assigned_client_id,
+                    connack_sent: self.connack_sent,
+                };
+                Either::Left((retry.delay, hnd))
+            }
+            None => Either::Right(self),
+        }
+    }
+
     #[inline]
     /// Set idle keep-alive for the connection in seconds.
     /// This method sets `server_keepalive_sec` property for `ConnectAck`
@@ -146,4 +340,38 @@ impl<Io, St> HandshakeAck<Io, St> {
         f(&mut self.packet);
         self
     }
+
+    #[inline]
+    /// Stream payloads of large inbound publishes.
+    ///
+    /// Publish with remaining length larger than `size` is delivered to
+    /// publish service as soon as its header is received, payload is
+    /// available with `Publish::payload_stream()`. At most 64kb of payload
+    /// is buffered, reading is paused until stream consumes it. Streamed publish occupies receive maximum slot until
+    /// publish service completes, and payload is read only while server
+    /// accepts new packets. Service that waits for whole payload must not
+    /// be limited by `max_unacked_inbound` or its own readiness. If size is
+    /// set to `0`, streaming is disabled.
+    ///
+    /// By default streaming is disabled.
+    pub fn stream_payload(self, size: u32) -> Self {
+        self.shared.codec.set_stream_payload(size);
+        self
+    }
+
+    #[inline]
+    /// Do not send CONNACK packet, handshake service already sent it.
+    ///
+    /// Handshake service is responsible for sending CONNACK (and any AUTH
+    /// exchange before it) with `Handshake::send()` before returning ack.
+    /// Connection limits are still negotiated from the ack packet, so it must
+    /// match the packet that was sent. If service returns successful ack
+    /// without sending CONNACK, connection is closed with error, failed ack
+    /// is sent by server.
+    ///
+    /// By default server sends CONNACK from the ack packet.
+    pub fn manual_connack(mut self) -> Self {
+        self.manual = true;
+        self
+    }
 }
@@ -0,0 +1,171 @@
+//! Concurrency limit
+//!
+//! Limits number of concurrently running tasks, used for handshakes on
+//! server and for connects on client. Released slot is handed directly to
+//! the oldest waiter, so waiters acquire slots in fifo order and new
+//! acquirers can not take slot while someone is waiting.
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc, task::Poll};
+
+use ntex::{task::LocalWaker, util::poll_fn};
+
+/// Limits number of concurrent tasks
+pub(super) struct Limit {
+    max: usize,
+    inflight: Cell<usize>,
+    waiters: RefCell<VecDeque<Rc<Waiter>>>,
+}
+
+#[derive(Default)]
+struct Waiter {
+    granted: Cell<bool>,
+    waker: LocalWaker,
+}
+
+/// Task permit, releases slot on drop
+pub(super) struct Permit(Rc<Limit>);
+
+/// Queued waiter, removes itself from queue or passes granted slot on
+/// if acquire future is dropped
+struct WaitGuard(Rc<Limit>, Option<Rc<Waiter>>);
+
+impl Limit {
+    /// Create limit, `None` if `max` is 0
+    pub(super) fn new(max: usize) -> Option<Rc<Self>> {
+        if max == 0 {
+            None
+        } else {
+            Some(Rc::new(Limit {
+                max,
+                inflight: Cell::new(0),
+                waiters: RefCell::new(VecDeque::new()),
+            }))
+        }
+    }
+
+    /// Wait for free slot
+    pub(super) async fn acquire(self: &Rc<Self>) -> Permit {
+        if self.inflight.get() < self.max && self.waiters.borrow().is_empty() {
+            self.inflight.set(self.inflight.get() + 1);
+            return Permit(self.clone());
+        }
+
+        log::trace!("Max concurrent tasks is reached, waiting");
+        let waiter = Rc::new(Waiter::default());
+        self.waiters.borrow_mut().push_back(waiter.clone());
+        let mut guard = WaitGuard(self.clone(), Some(waiter.clone()));
+
+        poll_fn(|cx| {
+            if waiter.granted.get() {
+                Poll::Ready(())
+            } else {
+                waiter.waker.register(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await;
+
+        // slot is already counted by releasing permit
+        guard.1 = None;
+        Permit(self.clone())
+    }
+
+    /// Hand slot to next waiter or free it
+    fn release(&self) {
+        let waiter = self.waiters.borrow_mut().pop_front();
+        if let Some(waiter) = waiter {
+            waiter.granted.set(true);
+            waiter.waker.wake();
+        } else {
+            self.inflight.set(self.inflight.get() - 1);
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+impl Drop for WaitGuard {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.1.take() {
+            if waiter.granted.get() {
+                self.0.release();
+            } else {
+                self.0.waiters.borrow_mut().retain(|w| !Rc::ptr_eq(w, &waiter));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, pin::Pin};
+
+    use super::*;
+
+    async fn poll<F: Future>(fut: &mut Pin<Box<F>>) -> Poll<F::Output> {
+        poll_fn(|cx| Poll::Ready(fut.as_mut().poll(cx))).await
+    }
+
+    async fn permit(limit: &Rc<Limit>) -> Permit {
+        match poll(&mut Box::pin(limit.acquire())).await {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!("slot is not available"),
+        }
+    }
+
+    #[ntex::test]
+    async fn test_fifo() {
+        let limit = Limit::new(1).unwrap();
+        let permit1 = permit(&limit).await;
+
+        let mut p2 = Box::pin(limit.acquire());
+        assert!(poll(&mut p2).await.is_pending());
+        let mut p3 = Box::pin(limit.acquire());
+        assert!(poll(&mut p3).await.is_pending());
+
+        // slot goes to first waiter, new acquirer has to wait
+        drop(permit1);
+        let mut p4 = Box::pin(limit.acquire());
+        assert!(poll(&mut p4).await.is_pending());
+        assert!(poll(&mut p3).await.is_pending());
+        let permit2 = match poll(&mut p2).await {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!(),
+        };
+
+        drop(permit2);
+        assert!(poll(&mut p4).await.is_pending());
+        assert!(poll(&mut p3).await.is_ready());
+    }
+
+    #[ntex::test]
+    async fn test_dropped_waiter() {
+        let limit = Limit::new(1).unwrap();
+        let permit1 = permit(&limit).await;
+
+        let mut p2 = Box::pin(limit.acquire());
+        assert!(poll(&mut p2).await.is_pending());
+        let mut p3 = Box::pin(limit.acquire());
+        assert!(poll(&mut p3).await.is_pending());
+        let mut p4 = Box::pin(limit.acquire());
+        assert!(poll(&mut p4).await.is_pending());
+
+        // waiter is dropped before it gets slot
+        drop(p2);
+        // woken waiter is dropped, slot is passed on
+        drop(permit1);
+        drop(p3);
+        let permit4 = match poll(&mut p4).await {
+            Poll::Ready(permit) => permit,
+            Poll::Pending => panic!(),
+        };
+        assert_eq!(limit.inflight.get(), 1);
+        assert!(limit.waiters.borrow().is_empty());
+
+        drop(permit4);
+        assert_eq!(limit.inflight.get(), 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Server memory accounting
+//!
+//! Write and read buffers of server connections are periodically scanned
+//! and summed up. Only write buffers are subject to memory limit. If total
+//! size exceeds configured limit, connections with largest write buffers
+//! get disconnected with `QuotaExceeded` reason.
+//! Write task turns of connections are counted as full or partial flushes.
+//!
+//! Buffers are not accounted by memory pool. `State` takes write buffers
+//! from pool and releases them internally, and pool only tracks allocated
+//! capacity of all its buffers, read and cached ones included, so size of
+//! connection write buffer is not known at pool level. Instead, encoder
+//! counts bytes put to write buffer and io stream counts written bytes,
+//! scan reads per connection counters and does not touch buffers.
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{cell::Cell, cell::RefCell, rc::Rc, sync::Arc};
+
+use ntex::time::{sleep, Millis};
+
+use super::{codec, MqttSink};
+
+/// Interval between write buffers scans
+const SCAN_INTERVAL: Millis = Millis(250);
+
+/// Memory stats handle
+///
+/// Handle is shared between all server workers, it can be cloned and
+/// used from any thread.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryStats(Arc<StatsInner>);
+
+#[derive(Default, Debug)]
+struct StatsInner {
+    buffered: AtomicUsize,
+    read_buffered: AtomicUsize,
+    connections: AtomicUsize,
+    disconnects: AtomicUsize,
+    max_buffered: AtomicUsize,
+    flushes: AtomicUsize,
+    partial_flushes: AtomicUsize,
+}
+
+impl MemoryStats {
+    /// Create new memory stats handle
+    pub fn new() -> Self {
+        MemoryStats::default()
+    }
+
+    /// Total size of connections write buffers
+    ///
+    /// Value is updated periodically, it is not exact.
+    pub fn buffered(&self) -> usize {
+        self.0.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Total size of connections read buffers
+    ///
+    /// Value is updated periodically, it is not exact.
+    pub fn read_buffered(&self) -> usize {
+        self.0.read_buffered.load(Ordering::Relaxed)
+    }
+
+    /// Number of tracked connections
+    pub fn connections(&self) -> usize {
+        self.0.connections.load(Ordering::Relaxed)
+    }
+
+    /// Number of connections closed because of memory limit
+    pub fn disconnects(&self) -> usize {
+        self.0.disconnects.load(Ordering::Relaxed)
+    }
+
+    /// Number of write task turns that flushed whole write buffer
+    ///
+    /// Value is updated periodically, it is not exact.
+    pub fn flushes(&self) -> usize {
+        self.0.flushes.load(Ordering::Relaxed)
+    }
+
+    /// Number of write task turns that left data in write buffer
+    ///
+    /// Partial flush means peer does not read data fast enough and socket
+    /// send buffer is full. Growing share of partial flushes is early sign
+    /// of slow consumers, before write buffers hit memory limit. Value is
+    /// updated periodically, it is not exact.
+    pub fn partial_flushes(&self) -> usize {
+        self.0.partial_flushes.load(Ordering::Relaxed)
+    }
+
+    /// Set max total size of connections write buffers.
+    ///
+    /// Once limit is exceeded, connections with largest write buffers
+    /// get closed until total size gets below limit. To disable limit
+    /// set value to 0.
+    ///
+    /// By default limit is disabled.
+    pub fn set_max_buffered(&self, size: usize) {
+        self.0.max_buffered.store(size, Ordering::Relaxed);
+    }
+}
+
+/// Per worker connections tracker
+pub(super) struct MemoryTracker {
+    stats: MemoryStats,
+    conns: RefCell<Vec<MqttSink>>,
+    buffered: Cell<usize>,
+    read_buffered: Cell<usize>,
+    running: Cell<bool>,
+}
+
+impl MemoryTracker {
+    pub(super) fn new(stats: MemoryStats) -> Rc<Self> {
+        Rc::new(MemoryTracker {
+            stats,
+            conns: RefCell::new(Vec::new()),
+            buffered: Cell::new(0),
+            read_buffered: Cell::new(0),
+            running: Cell::new(false),
+        })
+    }
+
+    /// Track connection, starts scan task on first call
+    pub(super) fn register(self: &Rc<Self>, sink: MqttSink) {
+        self.conns.borrow_mut().push(sink);
+        self.stats.0.connections.fetch_add(1, Ordering::Relaxed);
+
+        if !self.running.replace(true) {
+            let tracker = self.clone();
+            ntex::rt::spawn(async move {
+                loop {
+                    sleep(SCAN_INTERVAL).await;
+                    if !tracker.scan() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Update stats and enforce limit, returns `false` if there is
+    /// nothing to track
+    fn scan(&self) -> bool {
+        let mut conns = self.conns.borrow_mut();
+        let stats = &self.stats.0;
+
+        let (mut full, mut partial) = (0, 0);
+        for sink in conns.iter() {
+            full += sink.0.flushes.full.take();
+            partial += sink.0.flushes.partial.take();
+        }
+        stats.flushes.fetch_add(full, Ordering::Relaxed);
+        stats.partial_flushes.fetch_add(partial, Ordering::Relaxed);
+
+        let before = conns.len();
+        conns.retain(|sink| sink.is_open());
+        stats.connections.fetch_sub(before - conns.len(), Ordering::Relaxed);
+
+        let read: usize = conns.iter().map(|sink| sink.read_buf_len()).sum();
+        let prev = self.read_buffered.replace(read);
+        if read >= prev {
+            stats.read_buffered.fetch_add(read - prev, Ordering::Relaxed);
+        } else {
+            stats.read_buffered.fetch_sub(prev - read, Ordering::Relaxed);
+        }
+
+        let mut sizes: Vec<_> =
+            conns.iter().enumerate().map(|(idx, sink)| (sink.write_buf_len(), idx)).collect();
+        let total: usize = sizes.iter().map(|(size, _)| size).sum();
+        let prev = self.buffered.replace(total);
+        let mut global = if total >= prev {
+            stats.buffered.fetch_add(total - prev, Ordering::Relaxed) + (total - prev)
+        } else {
+            stats.buffered.fetch_sub(prev - total, Ordering::Relaxed) - (prev - total)
+        };
+
+        let max = stats.max_buffered.load(Ordering::Relaxed);
+        if max != 0 && global > max {
+            // close worst offenders first
+            sizes.sort_unstable_by(|a, b| b.cmp(a));
+            for (size, idx) in sizes {
+                if global <= max || size == 0 {
+                    break;
+                }
+                log::debug!("Memory limit is exceeded, closing connection with {} bytes", size);
+                conns[idx].close_with_reason(codec::Disconnect::new(
+                    codec::DisconnectReasonCode::QuotaExceeded,
+                ));
+                stats.disconnects.fetch_add(1, Ordering::Relaxed);
+                global -= size;
+            }
+        }
+
+        if conns.is_empty() {
+            self.running.set(false);
+            false
+        } else {
+            true
+        }
+    }
+}
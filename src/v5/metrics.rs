@@ -0,0 +1,206 @@
+//! Prometheus metrics
+//!
+//! Server-wide counters are shared between workers, per connection counters
+//! are kept by connection codec. Both are rendered in Prometheus text
+//! exposition format, output could be served as is from scrape endpoint.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{cell::Cell, cell::RefCell, fmt::Write, sync::Arc};
+
+/// Packet type names, indexed by packet type
+const PACKET_TYPES: [&str; 16] = [
+    "reserved",
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "pubrec",
+    "pubrel",
+    "pubcomp",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+    "auth",
+];
+
+/// Server-wide metrics handle
+///
+/// Handle is shared between all server workers, it can be cloned and
+/// used from any thread.
+#[derive(Clone, Default, Debug)]
+pub struct Metrics(Arc<MetricsInner>);
+
+#[derive(Default, Debug)]
+struct MetricsInner {
+    connections_total: AtomicU64,
+    connections: AtomicU64,
+    received: [AtomicU64; 16],
+    sent: [AtomicU64; 16],
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    inflight: AtomicU64,
+}
+
+impl Metrics {
+    /// Create new metrics handle
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    /// Render server-wide metrics in Prometheus text format
+    pub fn metrics_text(&self) -> String {
+        let inner = &self.0;
+        let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+
+        let mut out = String::new();
+        render(
+            &mut out,
+            "mqtt_connections_total",
+            "Total number of accepted connections",
+            "counter",
+            load(&inner.connections_total),
+        );
+        render(
+            &mut out,
+            "mqtt_connections",
+            "Number of open connections",
+            "gauge",
+            load(&inner.connections),
+        );
+        render_packets(&mut out, "received", |idx| load(&inner.received[idx]));
+        render_packets(&mut out, "sent", |idx| load(&inner.sent[idx]));
+        render_bytes(&mut out, load(&inner.bytes_in), load(&inner.bytes_out));
+        render_inflight(&mut out, load(&inner.inflight));
+        out
+    }
+}
+
+/// Per connection counters
+#[derive(Default, Debug)]
+pub(crate) struct ConnectionMetrics {
+    server: RefCell<Option<Metrics>>,
+    received: [Cell<u64>; 16],
+    sent: [Cell<u64>; 16],
+    bytes_in: Cell<u64>,
+    bytes_out: Cell<u64>,
+    inflight: Cell<u64>,
+}
+
+impl ConnectionMetrics {
+    /// Attach server-wide metrics, connection is counted as open
+    pub(crate) fn attach(&self, metrics: Metrics) {
+        metrics.0.connections_total.fetch_add(1, Ordering::Relaxed);
+        metrics.0.connections.fetch_add(1, Ordering::Relaxed);
+        *self.server.borrow_mut() = Some(metrics);
+    }
+
+    /// Detach server-wide metrics, connection is counted as closed
+    pub(crate) fn detach(&self) {
+        if let Some(metrics) = self.server.borrow_mut().take() {
+            metrics.0.connections.fetch_sub(1, Ordering::Relaxed);
+            metrics.0.inflight.fetch_sub(self.inflight.get(), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn received(&self, packet_type: u8) {
+        let idx = (packet_type >> 4) as usize;
+        inc(&self.received[idx], 1);
+        if let Some(ref metrics) = *self.server.borrow() {
+            metrics.0.received[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes are consumed by decoder, streamed payload is counted by chunks
+    pub(crate) fn received_bytes(&self, size: usize) {
+        inc(&self.bytes_in, size as u64);
+        if let Some(ref metrics) = *self.server.borrow() {
+            metrics.0.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn sent(&self, packet_type: u8, size: usize) {
+        let idx = (packet_type >> 4) as usize;
+        inc(&self.sent[idx], 1);
+        inc(&self.bytes_out, size as u64);
+        if let Some(ref metrics) = *self.server.borrow() {
+            metrics.0.sent[idx].fetch_add(1, Ordering::Relaxed);
+            metrics.0.bytes_out.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Publish with QoS 1 or 2 is sent
+    pub(crate) fn inflight_add(&self) {
+        inc(&self.inflight, 1);
+        if let Some(ref metrics) = *self.server.borrow() {
+            metrics.0.inflight.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Publish is acked by peer
+    pub(crate) fn inflight_remove(&self) {
+        if self.inflight.get() != 0 {
+            self.inflight.set(self.inflight.get() - 1);
+            if let Some(ref metrics) = *self.server.borrow() {
+                metrics.0.inflight.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Render connection metrics in Prometheus text format
+    pub(crate) fn metrics_text(&self) -> String {
+        let mut out = String::new();
+        render_packets(&mut out, "received", |idx| self.received[idx].get());
+        render_packets(&mut out, "sent", |idx| self.sent[idx].get());
+        render_bytes(&mut out, self.bytes_in.get(), self.bytes_out.get());
+        render_inflight(&mut out, self.inflight.get());
+        out
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        self.detach();
+    }
+}
+
+fn inc(cell: &Cell<u64>, val: u64) {
+    cell.set(cell.get() + val);
+}
+
+fn render(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    let _ =
+        write!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+}
+
+fn render_packets<F>(out: &mut String, dir: &str, f: F)
+where
+    F: Fn(usize) -> u64,
+{
+    let name = format!("mqtt_packets_{}_total", dir);
+    let _ = write!(
+        out,
+        "# HELP {} Number of {} packets by type\n# TYPE {} counter\n",
+        name, dir, name
+    );
+    for (idx, ty) in PACKET_TYPES.iter().enumerate().skip(1) {
+        let _ = writeln!(out, "{}{{type=\"{}\"}} {}", name, ty, f(idx));
+    }
+}
+
+fn render_bytes(out: &mut String, bytes_in: u64, bytes_out: u64) {
+    render(out, "mqtt_bytes_in_total", "Number of received bytes", "counter", bytes_in);
+    render(out, "mqtt_bytes_out_total", "Number of sent bytes", "counter", bytes_out);
+}
+
+fn render_inflight(out: &mut String, inflight: u64) {
+    render(
+        out,
+        "mqtt_inflight",
+        "Number of sent QoS 1 and QoS 2 publishes waiting for ack",
+        "gauge",
+        inflight,
+    );
+}
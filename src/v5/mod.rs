@@ -1,28 +1,59 @@
 //! MQTT5 Client/Server framework
 
 pub mod client;
+mod close;
 pub mod codec;
+#[cfg(feature = "compress")]
+pub mod compress;
 pub mod control;
 mod default;
 mod dispatcher;
+mod drain;
 pub mod error;
 mod handshake;
+mod limit;
+mod memory;
+#[cfg(feature = "prometheus")]
+mod metrics;
+mod outbound;
+mod peer;
 mod publish;
+mod retained;
 mod router;
 mod selector;
 mod server;
+mod shaper;
 mod shared;
 mod sink;
+mod snapshot;
+mod sni;
+mod sys;
+#[cfg(feature = "trace")]
+pub mod trace;
 
 pub type Session<St> = crate::Session<MqttSink, St>;
 
+pub use self::close::CloseHandle;
 pub use self::control::{ControlMessage, ControlResult};
+pub use self::drain::Drain;
 pub use self::handshake::{Handshake, HandshakeAck};
-pub use self::publish::{Publish, PublishAck};
+pub use self::memory::MemoryStats;
+#[cfg(feature = "prometheus")]
+pub use self::metrics::Metrics;
+pub use self::outbound::OverflowPolicy;
+pub use self::publish::{AckToken, PayloadStream, Publish, PublishAck};
+pub use self::retained::Retained;
 pub use self::router::Router;
-pub use self::selector::Selector;
-pub use self::server::MqttServer;
-pub use self::sink::{MqttSink, PublishBuilder, SubscribeBuilder, UnsubscribeBuilder};
+pub use self::selector::{Matcher, Selector};
+pub use self::server::{EmptyClientId, KeepAliveMode, MqttServer, Qos0ViolationPolicy};
+pub use self::shaper::ShapePolicy;
+pub use self::sink::{
+    broadcast, MqttSink, PublishBuilder, SubscribeBuilder, SubscriptionChange,
+    UnsubscribeBuilder,
+};
+pub use self::snapshot::{SessionSnapshot, SubscriptionSnapshot};
+pub use self::sni::SniHostname;
 
+pub use crate::session::NegotiatedLimits;
 pub use crate::topic::Topic;
 pub use crate::types::QoS;
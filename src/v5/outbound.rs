@@ -0,0 +1,225 @@
+//! Outbound publish queue
+//!
+//! QoS0 publishes are written to connection write buffer until it holds
+//! more than write high watermark of memory pool, following publishes wait
+//! in per connection queue and get written once write task flushes buffer.
+//! Queue size is limited, overflow policy defines what happens to publishes
+//! over queue size. Publishes that define topic alias are never dropped,
+//! peer could not resolve following publishes of the alias without them.
+//! QoS1 publishes are limited by peer's receive maximum and are not queued.
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use ntex::channel::pool;
+use ntex::util::{poll_fn, Bytes};
+
+use super::{codec, shared::MqttShared, MqttSink};
+
+/// Policy for publishes over outbound queue size
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Apply back-pressure to publisher
+    ///
+    /// `MqttSink::ready()` waits until queue has space. Publish of publisher
+    /// that does not wait for sink readiness fails with `Dropped` error.
+    Backpressure,
+    /// Drop oldest queued publish
+    DropOldest,
+    /// Drop new publish, publish fails with `Dropped` error
+    DropNewest,
+    /// Close connection with `QuotaExceeded` reason code
+    Disconnect,
+}
+
+/// Outbound publish slot
+pub(super) enum Slot {
+    /// Publish could be written to write buffer
+    Write,
+    /// Publish must be queued
+    Queue,
+    /// Publish is dropped
+    Drop,
+}
+
+/// Connection outbound queue
+pub(super) struct Outbound {
+    size: usize,
+    policy: OverflowPolicy,
+    inner: RefCell<Inner>,
+}
+
+struct Inner {
+    /// Encoded publishes, flag is set for publishes that define topic alias
+    queue: VecDeque<(Bytes, bool)>,
+    queued: usize,
+    flushing: bool,
+    waiters: VecDeque<pool::Sender<()>>,
+}
+
+impl Outbound {
+    pub(super) fn new(size: usize, policy: OverflowPolicy) -> Self {
+        Outbound {
+            size,
+            policy,
+            inner: RefCell::new(Inner {
+                queue: VecDeque::new(),
+                queued: 0,
+                flushing: false,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Number of bytes of queued publishes
+    pub(super) fn queued(&self) -> usize {
+        self.inner.borrow().queued
+    }
+
+    /// Find slot for new publish, `alias` is set if publish defines topic alias
+    pub(super) fn reserve(&self, shared: &Rc<MqttShared>, alias: bool) -> Slot {
+        if self.inner.borrow().queue.is_empty() && !is_full(shared) {
+            return Slot::Write;
+        }
+        let mut inner = self.inner.borrow_mut();
+        if inner.queue.len() < self.size {
+            return Slot::Queue;
+        }
+
+        match self.policy {
+            OverflowPolicy::Disconnect => {
+                log::trace!("Outbound queue is full, closing connection");
+                drop(inner);
+                MqttSink::new(shared.clone()).close_with_reason(codec::Disconnect {
+                    reason_code: codec::DisconnectReasonCode::QuotaExceeded,
+                    ..Default::default()
+                });
+                Slot::Drop
+            }
+            _ if alias => {
+                log::trace!("Outbound queue is full, queueing topic alias definition");
+                Slot::Queue
+            }
+            OverflowPolicy::DropOldest => {
+                match inner.queue.iter().position(|(_, alias)| !alias) {
+                    Some(idx) => {
+                        log::trace!("Outbound queue is full, dropping oldest publish");
+                        if let Some((buf, _)) = inner.queue.remove(idx) {
+                            inner.queued -= buf.len();
+                        }
+                        Slot::Queue
+                    }
+                    None => {
+                        log::trace!("Outbound queue holds alias definitions, dropping publish");
+                        Slot::Drop
+                    }
+                }
+            }
+            OverflowPolicy::Backpressure => {
+                log::warn!(
+                    "Outbound queue is full, publisher does not wait for readiness, dropping"
+                );
+                Slot::Drop
+            }
+            OverflowPolicy::DropNewest => {
+                log::trace!("Outbound queue is full, dropping publish");
+                Slot::Drop
+            }
+        }
+    }
+
+    /// Queue encoded publish
+    pub(super) fn push(&self, shared: &Rc<MqttShared>, buf: Bytes, alias: bool) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queued += buf.len();
+        inner.queue.push_back((buf, alias));
+        if !inner.flushing {
+            inner.flushing = true;
+            ntex::rt::spawn(flush(MqttSink::new(shared.clone())));
+        }
+    }
+
+    /// Get notification when queue has space, `None` if it has space already
+    pub(super) fn wait(&self, shared: &MqttShared) -> Option<pool::Receiver<()>> {
+        let mut inner = self.inner.borrow_mut();
+        if self.policy == OverflowPolicy::Backpressure && inner.queue.len() >= self.size {
+            let (tx, rx) = shared.pool.waiters.channel();
+            inner.waiters.push_back(tx);
+            Some(rx)
+        } else {
+            None
+        }
+    }
+
+    /// Drop queued publishes and waiters
+    pub(super) fn clear(&self) {
+        let mut inner = self.inner.borrow_mut();
+        inner.queue.clear();
+        inner.queued = 0;
+        inner.waiters.clear();
+    }
+}
+
+/// Check if write buffer holds more than write high watermark
+fn is_full(shared: &MqttShared) -> bool {
+    let hw = shared.state.memory_pool().write_params().high as usize;
+    shared.write_buffered() >= hw
+}
+
+/// Write queued publishes as write task flushes write buffer
+async fn flush(sink: MqttSink) {
+    let shared = &sink.0;
+    let outbound = if let Some(ref outbound) = shared.outbound {
+        outbound
+    } else {
+        return;
+    };
+    // waiter is registered before publishes are moved to write buffer,
+    // so flush of moved publishes is not missed
+    let flushed = shared.flushes.flushed.wait();
+
+    loop {
+        if !shared.state.is_open() {
+            outbound.clear();
+            outbound.inner.borrow_mut().flushing = false;
+            return;
+        }
+
+        let hw = shared.state.memory_pool().write_params().high as usize;
+        let done = {
+            let mut inner = outbound.inner.borrow_mut();
+            let inner = &mut *inner;
+            shared.state.write().with_buf(|dst| {
+                while dst.len() < hw {
+                    if let Some((buf, _)) = inner.queue.pop_front() {
+                        inner.queued -= buf.len();
+                        dst.extend_from_slice(&buf);
+                    } else {
+                        break;
+                    }
+                }
+            });
+
+            // wake up publishers waiting for queue space
+            let mut len = inner.queue.len();
+            while len < outbound.size {
+                if let Some(tx) = inner.waiters.pop_front() {
+                    if tx.send(()).is_ok() {
+                        len += 1;
+                    }
+                } else {
+                    break;
+                }
+            }
+
+            if inner.queue.is_empty() {
+                inner.flushing = false;
+            }
+            !inner.flushing
+        };
+        if done {
+            return;
+        }
+
+        // write task notifies after each flush, connection close notifies too
+        poll_fn(|cx| flushed.poll_ready(cx)).await;
+    }
+}
@@ -0,0 +1,74 @@
+//! Per peer address connections limit
+//!
+//! Connections are counted by peer ip address, address is extracted from io
+//! stream with user provided function. Counts are tracked per worker.
+use std::{cell::RefCell, net::IpAddr, rc::Rc};
+
+use ntex::util::HashMap;
+
+use crate::io::State;
+
+/// Per worker connections counter
+pub(super) struct PeerLimit<Io> {
+    max: usize,
+    peer_addr: Box<dyn Fn(&Io) -> Option<IpAddr>>,
+    conns: Rc<RefCell<HashMap<IpAddr, usize>>>,
+}
+
+impl<Io> PeerLimit<Io> {
+    pub(super) fn new<F>(max: usize, peer_addr: F) -> Rc<Self>
+    where
+        F: Fn(&Io) -> Option<IpAddr> + 'static,
+    {
+        Rc::new(PeerLimit { max, peer_addr: Box::new(peer_addr), conns: Rc::default() })
+    }
+
+    /// Count new connection
+    ///
+    /// Returns `Err` with peer address if it has max number of connections.
+    /// Connections without peer address are not counted.
+    pub(super) fn acquire(&self, io: &Io) -> Result<Option<PeerGuard>, IpAddr> {
+        let addr = match (*self.peer_addr)(io) {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+
+        let mut conns = self.conns.borrow_mut();
+        let count = conns.entry(addr).or_insert(0);
+        if *count >= self.max {
+            log::trace!("Max number of connections is reached for {}", addr);
+            Err(addr)
+        } else {
+            *count += 1;
+            Ok(Some(PeerGuard { addr, conns: self.conns.clone() }))
+        }
+    }
+}
+
+/// Connection counter guard, decrements counter on drop
+pub(super) struct PeerGuard {
+    addr: IpAddr,
+    conns: Rc<RefCell<HashMap<IpAddr, usize>>>,
+}
+
+impl PeerGuard {
+    /// Keep connection counted until it gets disconnected
+    pub(super) fn hold(self, state: State) {
+        ntex::rt::spawn(async move {
+            state.on_disconnect().await;
+            drop(self);
+        });
+    }
+}
+
+impl Drop for PeerGuard {
+    fn drop(&mut self) {
+        let mut conns = self.conns.borrow_mut();
+        if let Some(count) = conns.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.addr);
+            }
+        }
+    }
+}
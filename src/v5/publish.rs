@@ -1,16 +1,46 @@
-use std::{mem, num::NonZeroU16};
+use std::task::{Context, Poll};
+use std::{cell::RefCell, mem, num::NonZeroU16, pin::Pin, rc::Rc};
 
+use ntex::channel::mpsc;
 use ntex::router::Path;
-use ntex::util::{ByteString, Bytes};
-use serde::de::DeserializeOwned;
+use ntex::task::LocalWaker;
+use ntex::util::{poll_fn, ByteString, Bytes, HashSet};
+use ntex::Stream;
+use serde::de::{DeserializeOwned, Error as _};
 use serde_json::Error as JsonError;
 
-use super::codec;
+use super::{codec, error::PayloadError, MqttSink};
+
+/// Content type of json payloads
+pub(super) const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Check media type of content type, parameters like `charset` are ignored
+fn is_json(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("");
+    media_type.trim().eq_ignore_ascii_case(JSON_CONTENT_TYPE)
+}
 
 /// Publish message
 pub struct Publish {
     publish: codec::Publish,
     topic: Path<ByteString>,
+    ack: Option<AckHandle>,
+    stream: Option<PayloadStream>,
+    streamed: bool,
+}
+
+/// Inbound publish packets state
+#[derive(Default)]
+pub(super) struct PublishInfo {
+    pub(super) inflight: HashSet<NonZeroU16>,
+    /// Number of outstanding ack tokens
+    pub(super) deferred: usize,
+    pub(super) deferred_waker: LocalWaker,
+}
+
+struct AckHandle {
+    sink: MqttSink,
+    info: Rc<RefCell<PublishInfo>>,
 }
 
 impl Publish {
@@ -18,7 +48,36 @@ impl Publish {
     /// packet
     #[doc(hidden)]
     pub fn new(publish: codec::Publish) -> Self {
-        Self { topic: Path::new(publish.topic.clone()), publish }
+        Self {
+            topic: Path::new(publish.topic.clone()),
+            publish,
+            ack: None,
+            stream: None,
+            streamed: false,
+        }
+    }
+
+    /// Create `Publish` message that could be acked later
+    pub(super) fn with_ack(
+        publish: codec::Publish,
+        sink: &MqttSink,
+        info: &Rc<RefCell<PublishInfo>>,
+    ) -> Self {
+        let ack =
+            publish.packet_id.map(|_| AckHandle { sink: sink.clone(), info: info.clone() });
+        Self {
+            topic: Path::new(publish.topic.clone()),
+            publish,
+            ack,
+            stream: None,
+            streamed: false,
+        }
+    }
+
+    /// Attach payload stream of streamed publish
+    pub(super) fn set_payload_stream(&mut self, rx: codec::PayloadReceiver, size: usize) {
+        self.stream = Some(PayloadStream { rx, size, remaining: size });
+        self.streamed = true;
     }
 
     #[inline]
@@ -81,9 +140,30 @@ impl Publish {
         mem::take(&mut self.publish.payload)
     }
 
+    #[inline]
+    /// Check if payload is streamed
+    ///
+    /// Streamed publish has empty payload, see `HandshakeAck::stream_payload()`.
+    pub fn is_streamed(&self) -> bool {
+        self.streamed
+    }
+
+    /// Take payload stream of streamed publish
+    pub fn payload_stream(&mut self) -> Option<PayloadStream> {
+        self.stream.take()
+    }
+
     /// Loads and parse `application/json` encoded body.
+    ///
+    /// Fails if packet has content type with media type other than
+    /// `application/json`, content type parameters are ignored.
     pub fn json<T: DeserializeOwned>(&mut self) -> Result<T, JsonError> {
-        serde_json::from_slice(&self.publish.payload)
+        match self.publish.properties.content_type {
+            Some(ref ct) if !is_json(ct) => {
+                Err(JsonError::custom(format!("Unexpected content type: {}", ct)))
+            }
+            _ => serde_json::from_slice(&self.publish.payload),
+        }
     }
 
     /// Create acknowledgement for this packet
@@ -92,7 +172,28 @@ impl Publish {
             reason_code: codec::PublishAckReason::Success,
             properties: codec::UserProperties::default(),
             reason_string: None,
+            deferred: false,
+        }
+    }
+
+    /// Defer acknowledgement of this packet
+    ///
+    /// Returned `PublishAck` must be used as publish service response, ack
+    /// packet is not sent until `AckToken::ack()` is called. Packet stays
+    /// in-flight and counts against receive maximum until then. For QoS0
+    /// packets token does nothing.
+    pub fn ack_later(mut self) -> (AckToken, PublishAck) {
+        let token = AckToken {
+            packet_id: self.publish.packet_id,
+            qos: self.publish.qos,
+            handle: self.ack.take(),
+        };
+        if let Some(ref handle) = token.handle {
+            handle.info.borrow_mut().deferred += 1;
         }
+        let mut ack = self.ack();
+        ack.deferred = true;
+        (token, ack)
     }
 
     pub(crate) fn into_inner(self) -> codec::Publish {
@@ -106,12 +207,62 @@ impl std::fmt::Debug for Publish {
     }
 }
 
+/// Streamed publish payload
+///
+/// Payload chunks are delivered as they are read from the socket, reading
+/// is paused while stream holds 64kb of not yet consumed payload. Stream
+/// fails with `PayloadError::Incomplete` if connection is closed before
+/// whole payload is received. Dropped stream discards rest of payload.
+#[derive(Debug)]
+pub struct PayloadStream {
+    rx: codec::PayloadReceiver,
+    size: usize,
+    remaining: usize,
+}
+
+impl PayloadStream {
+    /// Payload size
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Size of not yet received part of payload
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Read next payload chunk
+    pub async fn read(&mut self) -> Option<Result<Bytes, PayloadError>> {
+        poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for PayloadStream {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(chunk)) => {
+                self.remaining -= chunk.len();
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            Poll::Ready(None) if self.remaining != 0 => {
+                self.remaining = 0;
+                Poll::Ready(Some(Err(PayloadError::Incomplete)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
 #[derive(Debug)]
 /// Publish ack
 pub struct PublishAck {
     pub(crate) reason_code: codec::PublishAckReason,
     pub(crate) properties: codec::UserProperties,
     pub(crate) reason_string: Option<ByteString>,
+    pub(crate) deferred: bool,
 }
 
 impl PublishAck {
@@ -121,6 +272,7 @@ impl PublishAck {
             reason_code: code,
             properties: codec::UserProperties::default(),
             reason_string: None,
+            deferred: false,
         }
     }
 
@@ -148,3 +300,61 @@ impl PublishAck {
         self
     }
 }
+
+/// Deferred publish acknowledgement
+///
+/// Created by `Publish::ack_later()`. If token is dropped without ack,
+/// packet gets acked with `UnspecifiedError` reason code.
+pub struct AckToken {
+    packet_id: Option<NonZeroU16>,
+    qos: codec::QoS,
+    handle: Option<AckHandle>,
+}
+
+impl AckToken {
+    /// Packet id of deferred publish packet
+    pub fn packet_id(&self) -> Option<NonZeroU16> {
+        self.packet_id
+    }
+
+    /// Send acknowledgement to peer
+    pub fn ack(mut self, ack: PublishAck) {
+        self.complete(ack)
+    }
+
+    fn complete(&mut self, ack: PublishAck) {
+        if let (Some(packet_id), Some(handle)) = (self.packet_id, self.handle.take()) {
+            {
+                let mut info = handle.info.borrow_mut();
+                info.inflight.remove(&packet_id);
+                info.deferred -= 1;
+                info.deferred_waker.wake();
+            }
+            if handle.sink.is_open() {
+                log::trace!("Sending deferred publish ack for {} id", packet_id);
+                let ack = codec::PublishAck {
+                    packet_id,
+                    reason_code: ack.reason_code,
+                    reason_string: ack.reason_string,
+                    properties: ack.properties,
+                };
+                handle.sink.send(handle.sink.0.inbound_ack(self.qos, ack));
+            }
+        }
+    }
+}
+
+impl Drop for AckToken {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            log::warn!("Deferred publish ack is dropped: {:?}", self.packet_id);
+            self.complete(PublishAck::new(codec::PublishAckReason::UnspecifiedError));
+        }
+    }
+}
+
+impl std::fmt::Debug for AckToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AckToken").field("packet_id", &self.packet_id).finish()
+    }
+}
@@ -0,0 +1,189 @@
+//! Retained messages hook
+//!
+//! Server does not store retained messages, accepted publishes with retain
+//! flag are passed to application's retained store. Publish with empty
+//! payload clears retained message of the topic, MQTT-3.3.1-6. Updates
+//! could be coalesced, then only last update of the topic within coalesce
+//! window is passed to the store. Updates are coalesced per worker.
+//! Stored messages that match new subscription could be sent back with
+//! `Session::deliver_retained()`. Last will with retain flag is passed to
+//! the store once connection closes without normal DISCONNECT, MQTT-3.1.2-15.
+use std::{cell::Cell, cell::RefCell, mem, num::NonZeroU16, rc::Rc};
+
+use ntex::time::{sleep, Millis};
+use ntex::util::{ByteString, HashMap};
+
+use super::{codec, MqttSink, Session};
+use crate::types::QoS;
+
+/// Retained store callback
+type RetainedHook = Box<dyn Fn(Retained)>;
+
+/// Per worker retained store updates
+#[derive(Default)]
+pub(super) struct RetainedStore {
+    hook: RefCell<Option<RetainedHook>>,
+    coalesce: Cell<Millis>,
+    pending: RefCell<HashMap<ByteString, Retained>>,
+}
+
+impl RetainedStore {
+    pub(super) fn set_hook(&self, hook: RetainedHook) {
+        *self.hook.borrow_mut() = Some(hook);
+    }
+
+    pub(super) fn set_coalesce(&self, window: Millis) {
+        self.coalesce.set(window);
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.hook.borrow().is_some()
+    }
+
+    /// Pass update to the store, or queue it until coalesce window ends
+    pub(super) fn update(self: &Rc<Self>, msg: Retained) {
+        let window = self.coalesce.get();
+        if !window.non_zero() {
+            if let Some(ref hook) = *self.hook.borrow() {
+                (*hook)(msg);
+            }
+            return;
+        }
+
+        let mut pending = self.pending.borrow_mut();
+        if pending.is_empty() {
+            let store = self.clone();
+            ntex::rt::spawn(async move {
+                sleep(window).await;
+                store.flush();
+            });
+        }
+        pending.insert(msg.topic().clone(), msg);
+    }
+
+    /// Pass retained last will to the store, after will delay interval
+    pub(super) fn will(self: &Rc<Self>, will: codec::LastWill) {
+        let delay = will.will_delay_interval_sec.unwrap_or(0);
+        let msg = match Retained::from_publish(&codec::Publish::from(will), &HashMap::default())
+        {
+            Some(msg) => msg,
+            None => return,
+        };
+        if delay == 0 {
+            self.update(msg);
+        } else {
+            let store = self.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(u64::from(delay) * 1000)).await;
+                store.update(msg);
+            });
+        }
+    }
+
+    fn flush(&self) {
+        let pending = mem::take(&mut *self.pending.borrow_mut());
+        if let Some(ref hook) = *self.hook.borrow() {
+            for (_, msg) in pending {
+                (*hook)(msg);
+            }
+        }
+    }
+}
+
+/// Retained store update
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum Retained {
+    /// Replace retained message of the topic
+    Set(codec::Publish),
+    /// Clear retained message of the topic
+    Clear(ByteString),
+}
+
+impl Retained {
+    /// Store update for publish packet, `None` if retain flag is not set
+    ///
+    /// Topic alias is resolved with connection's inbound `aliases`, alias
+    /// is connection bound, so stored message does not keep it.
+    pub(super) fn from_publish(
+        pkt: &codec::Publish,
+        aliases: &HashMap<NonZeroU16, ByteString>,
+    ) -> Option<Self> {
+        if !pkt.retain {
+            return None;
+        }
+        let topic = match pkt.properties.topic_alias {
+            Some(alias) if pkt.topic.is_empty() => aliases.get(&alias).cloned()?,
+            _ => pkt.topic.clone(),
+        };
+        if pkt.payload.is_empty() {
+            Some(Retained::Clear(topic))
+        } else {
+            let mut pkt = pkt.clone();
+            pkt.topic = topic;
+            pkt.properties.topic_alias = None;
+            Some(Retained::Set(pkt))
+        }
+    }
+
+    /// Topic of retained message
+    pub fn topic(&self) -> &ByteString {
+        match self {
+            Retained::Set(pkt) => &pkt.topic,
+            Retained::Clear(topic) => topic,
+        }
+    }
+}
+
+impl<St> Session<St> {
+    /// Deliver retained messages that match new subscription
+    ///
+    /// Messages are sent according to subscription's retain handling option,
+    /// `new` tells if subscription did not exist before, MQTT-3.3.1-9..11.
+    /// Retain flag is set on delivery, QoS is downgraded to subscription's
+    /// max QoS. Outbound QoS 2 is not supported by sink, such messages are
+    /// delivered with QoS 1. QoS 1 messages are sent in background and wait
+    /// for peer's receive maximum. Should be called after subscription is
+    /// acked, returns number of delivered messages.
+    pub fn deliver_retained<I>(
+        &self,
+        options: &codec::SubscriptionOptions,
+        new: bool,
+        messages: I,
+    ) -> usize
+    where
+        I: IntoIterator<Item = codec::Publish>,
+    {
+        match options.retain_handling {
+            codec::RetainHandling::NoAtSubscribe => return 0,
+            codec::RetainHandling::AtSubscribeNew if !new => return 0,
+            _ => (),
+        }
+
+        let sink: &MqttSink = self.sink();
+        let mut delivered = 0;
+        for pkt in messages {
+            let at_most_once = pkt.qos == QoS::AtMostOnce || options.qos == QoS::AtMostOnce;
+            let mut properties = pkt.properties;
+            properties.topic_alias = None;
+
+            let builder = sink
+                .publish(pkt.topic, pkt.payload)
+                .retain()
+                .properties(move |props| *props = properties);
+            if at_most_once {
+                if builder.send_at_most_once().is_err() {
+                    break;
+                }
+            } else {
+                builder.send_at_least_once_with(|res| {
+                    if let Err(err) = res {
+                        log::trace!("Retained message is not delivered: {:?}", err);
+                    }
+                });
+            }
+            delivered += 1;
+        }
+        delivered
+    }
+}
@@ -10,10 +10,11 @@ use ntex::util::{timeout::Timeout, timeout::TimeoutError, Either, PoolId, Ready}
 
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, State};
+use crate::utils::ReadLimit;
 
 use super::control::{ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::handshake::{reject_protocol_level, Handshake, HandshakeAck};
 use super::publish::{Publish, PublishAck};
 use super::shared::{MqttShared, MqttSinkPool};
 use super::{codec as mqtt, dispatcher::factory, MqttServer, MqttSink, Session};
@@ -31,6 +32,52 @@ type ServerFactory<Io, Err, InitErr> = boxed::BoxServiceFactory<
 type Server<Io, Err> =
     boxed::BoxService<SelectItem<Io>, Either<SelectItem<Io>, ()>, MqttError<Err>>;
 
+/// Selector route matcher
+///
+/// Matcher checks connect packet of the connection, it is used to build
+/// selector from routing table with `Selector::from_routes()`.
+pub enum Matcher {
+    /// Client id starts with prefix
+    ClientIdPrefix(String),
+    /// Username is equal to value
+    UsernameEquals(String),
+    /// Connect packet has user property with the key
+    HasProperty(String),
+    /// Custom check
+    Custom(Box<dyn Fn(&mqtt::Connect) -> bool>),
+}
+
+impl Matcher {
+    /// Check if connect packet matches
+    pub fn matches(&self, pkt: &mqtt::Connect) -> bool {
+        match self {
+            Matcher::ClientIdPrefix(prefix) => pkt.client_id.starts_with(prefix.as_str()),
+            Matcher::UsernameEquals(username) => {
+                pkt.username.as_ref().map(|u| u == username.as_str()).unwrap_or(false)
+            }
+            Matcher::HasProperty(key) => {
+                pkt.user_properties.iter().any(|(k, _)| k == key.as_str())
+            }
+            Matcher::Custom(f) => f(pkt),
+        }
+    }
+}
+
+impl fmt::Debug for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Matcher::ClientIdPrefix(prefix) => {
+                f.debug_tuple("ClientIdPrefix").field(prefix).finish()
+            }
+            Matcher::UsernameEquals(username) => {
+                f.debug_tuple("UsernameEquals").field(username).finish()
+            }
+            Matcher::HasProperty(key) => f.debug_tuple("HasProperty").field(key).finish(),
+            Matcher::Custom(_) => f.debug_tuple("Custom").finish(),
+        }
+    }
+}
+
 /// Mqtt server selector
 ///
 /// Selector allows to choose different mqtt server impls depends on
@@ -38,6 +85,8 @@ type Server<Io, Err> =
 pub struct Selector<Io, Err, InitErr> {
     servers: Vec<ServerFactory<Io, Err, InitErr>>,
     max_size: u32,
+    max_reads: usize,
+    max_bytes: usize,
     handshake_timeout: Seconds,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
@@ -49,6 +98,8 @@ impl<Io, Err, InitErr> Selector<Io, Err, InitErr> {
         Selector {
             servers: Vec::new(),
             max_size: 0,
+            max_reads: 0,
+            max_bytes: 0,
             handshake_timeout: Seconds::ZERO,
             pool: Default::default(),
             _t: marker::PhantomData,
@@ -80,6 +131,28 @@ where
         self
     }
 
+    /// Set max number of reads for the first packet.
+    ///
+    /// If client does not send complete `connect` packet within `n` io reads,
+    /// connection gets closed. To disable check set value to 0.
+    ///
+    /// By default check is disabled.
+    pub fn handshake_max_reads(mut self, n: usize) -> Self {
+        self.max_reads = n;
+        self
+    }
+
+    /// Set max number of bytes read before first packet is decoded.
+    ///
+    /// If client does not send complete `connect` packet within `n` bytes,
+    /// connection gets closed. To disable check set value to 0.
+    ///
+    /// By default check is disabled.
+    pub fn handshake_max_bytes(mut self, n: usize) -> Self {
+        self.max_bytes = n;
+        self
+    }
+
     /// Set memory pool.
     ///
     /// Use specified memory pool for memory allocations. By default P5
@@ -127,6 +200,45 @@ where
         self
     }
 
+    /// Create selector from routing table
+    ///
+    /// Every route is added as server variant, routes are checked in
+    /// table order and first matching route handles connection.
+    pub fn from_routes<I, St, C, Cn, P>(routes: I) -> Self
+    where
+        I: IntoIterator<Item = (Matcher, MqttServer<Io, St, C, Cn, P>)>,
+        St: 'static,
+        C: ServiceFactory<
+                Config = (),
+                Request = Handshake<Io>,
+                Response = HandshakeAck<Io, St>,
+                Error = Err,
+                InitError = InitErr,
+            > + 'static,
+        C::Error: From<Cn::Error>
+            + From<Cn::InitError>
+            + From<P::Error>
+            + From<P::InitError>
+            + fmt::Debug,
+        Cn: ServiceFactory<
+                Config = Session<St>,
+                Request = ControlMessage<C::Error>,
+                Response = ControlResult,
+            > + 'static,
+
+        P: ServiceFactory<Config = Session<St>, Request = Publish, Response = PublishAck>
+            + 'static,
+        P::Error: fmt::Debug,
+        PublishAck: TryFrom<P::Error, Error = C::Error>,
+    {
+        routes.into_iter().fold(Selector::new(), |selector, (matcher, server)| {
+            selector.variant(
+                move |hnd: &Handshake<Io>| Ready::Ok(matcher.matches(hnd.packet())),
+                server,
+            )
+        })
+    }
+
     /// Set service to handle publish packets and create mqtt server factory
     pub(crate) fn finish_server(
         self,
@@ -140,6 +252,8 @@ where
         Selector2 {
             servers: self.servers,
             max_size: self.max_size,
+            max_reads: self.max_reads,
+            max_bytes: self.max_bytes,
             pool: self.pool,
             _t: marker::PhantomData,
         }
@@ -163,6 +277,8 @@ where
     fn new_service(&self, _: ()) -> Self::Future {
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
+        let max_reads = self.max_reads;
+        let max_bytes = self.max_bytes;
         let handshake_timeout = self.handshake_timeout;
         let pool = self.pool.clone();
 
@@ -171,7 +287,14 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService { max_size, handshake_timeout, pool, servers: Rc::new(servers) })
+            Ok(SelectorService {
+                max_size,
+                max_reads,
+                max_bytes,
+                handshake_timeout,
+                pool,
+                servers: Rc::new(servers),
+            })
         })
     }
 }
@@ -179,6 +302,8 @@ where
 pub struct SelectorService<Io, Err> {
     servers: Rc<Vec<Server<Io, Err>>>,
     max_size: u32,
+    max_reads: usize,
+    max_bytes: usize,
     handshake_timeout: Seconds,
     pool: Rc<MqttSinkPool>,
 }
@@ -222,20 +347,25 @@ where
     #[inline]
     fn call(&self, mut io: Io) -> Self::Future {
         let servers = self.servers.clone();
-        let state = State::with_memory_pool(self.pool.pool.get());
+        let state = self.pool.state();
         let shared = Rc::new(MqttShared::new(
             state.clone(),
             mqtt::Codec::default().max_inbound_size(self.max_size),
             0,
             self.pool.clone(),
         ));
+        let (max_reads, max_bytes) = (self.max_reads, self.max_bytes);
 
         let delay = self.handshake_timeout.map(sleep);
         Box::pin(async move {
             // read first packet
             let packet = state
-                .next(&mut io, &shared.codec)
-                .await
+                .next(&mut ReadLimit::new(&mut io, max_reads, max_bytes), &shared.codec)
+                .await;
+            if let Err(ref err) = packet {
+                reject_protocol_level(&mut io, &shared, err).await;
+            }
+            let packet = packet
                 .map_err(|err| {
                     log::trace!("Error is received during mqtt handshake: {:?}", err);
                     MqttError::from(err)
@@ -277,6 +407,8 @@ where
 pub(crate) struct Selector2<Io, Err, InitErr> {
     servers: Vec<ServerFactory<Io, Err, InitErr>>,
     max_size: u32,
+    max_reads: usize,
+    max_bytes: usize,
     pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, Err, InitErr)>,
 }
@@ -298,6 +430,8 @@ where
     fn new_service(&self, _: ()) -> Self::Future {
         let futs: Vec<_> = self.servers.iter().map(|srv| srv.new_service(())).collect();
         let max_size = self.max_size;
+        let max_reads = self.max_reads;
+        let max_bytes = self.max_bytes;
         let pool = self.pool.clone();
 
         Box::pin(async move {
@@ -305,7 +439,13 @@ where
             for fut in futs {
                 servers.push(fut.await?);
             }
-            Ok(SelectorService2 { max_size, pool, servers: Rc::new(servers) })
+            Ok(SelectorService2 {
+                max_size,
+                max_reads,
+                max_bytes,
+                pool,
+                servers: Rc::new(servers),
+            })
         })
     }
 }
@@ -313,6 +453,8 @@ where
 pub(crate) struct SelectorService2<Io, Err> {
     servers: Rc<Vec<Server<Io, Err>>>,
     max_size: u32,
+    max_reads: usize,
+    max_bytes: usize,
     pool: Rc<MqttSinkPool>,
 }
 
@@ -361,12 +503,17 @@ where
             0,
             self.pool.clone(),
         ));
+        let (max_reads, max_bytes) = (self.max_reads, self.max_bytes);
 
         Box::pin(async move {
             // read first packet
             let packet = state
-                .next(&mut io, &shared.codec)
-                .await
+                .next(&mut ReadLimit::new(&mut io, max_reads, max_bytes), &shared.codec)
+                .await;
+            if let Err(ref err) = packet {
+                reject_protocol_level(&mut io, &shared, err).await;
+            }
+            let packet = packet
                 .map_err(|err| {
                     log::trace!("Error is received during mqtt handshake: {:?}", err);
                     MqttError::from(err)
@@ -404,3 +551,38 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ntex::util::ByteString;
+
+    use super::*;
+
+    #[test]
+    fn test_matcher() {
+        let pkt = mqtt::Connect::default().client_id("dev-1");
+
+        assert!(Matcher::ClientIdPrefix("dev-".to_string()).matches(&pkt));
+        assert!(!Matcher::ClientIdPrefix("app-".to_string()).matches(&pkt));
+
+        let username = "admin".to_string();
+        assert!(!Matcher::UsernameEquals(username.clone()).matches(&pkt));
+        let mut with_username = pkt.clone();
+        with_username.username = Some(ByteString::from_static("admin"));
+        assert!(Matcher::UsernameEquals(username).matches(&with_username));
+        assert!(!Matcher::UsernameEquals("adm".to_string()).matches(&with_username));
+
+        let mut with_property = pkt.clone();
+        with_property
+            .user_properties
+            .push((ByteString::from_static("tenant"), ByteString::from_static("a")));
+        assert!(Matcher::HasProperty("tenant".to_string()).matches(&with_property));
+        assert!(!Matcher::HasProperty("tenant".to_string()).matches(&pkt));
+
+        let custom = Matcher::Custom(Box::new(|pkt| pkt.keep_alive > 60));
+        assert!(!custom.matches(&pkt));
+        let mut with_keepalive = pkt;
+        with_keepalive.keep_alive = 120;
+        assert!(custom.matches(&with_keepalive));
+    }
+}
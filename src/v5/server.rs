@@ -1,25 +1,86 @@
 use std::task::{Context, Poll};
-use std::{cell::RefCell, convert::TryFrom, fmt, future::Future, marker, pin::Pin, rc::Rc};
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, convert::TryFrom, fmt};
+use std::{future::Future, marker, net::IpAddr, pin::Pin, rc::Rc};
+use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::SystemTime};
+
+use ntex::channel::pool;
 
 use ntex::codec::{AsyncRead, AsyncWrite};
 use ntex::framed::WriteTask;
 use ntex::service::{IntoServiceFactory, Service, ServiceFactory};
-use ntex::time::{Millis, Seconds, Sleep};
+use ntex::time::{sleep, Millis, Seconds, Sleep};
 use ntex::util::timeout::{Timeout, TimeoutError};
-use ntex::util::{Either, PoolId, PoolRef};
+use ntex::util::{ByteString, Either, PoolId, PoolRef};
 
+use crate::clock::Clock;
 use crate::error::{MqttError, ProtocolError};
 use crate::io::{DispatchItem, Dispatcher, State, Timer};
 use crate::service::{FramedService, FramedService2};
+use crate::session::NegotiatedLimits;
+use crate::topic::Topic;
 use crate::types::QoS;
+use crate::utils::ReadLimit;
 
-use super::control::{ControlMessage, ControlResult};
+use super::control::{self, ControlMessage, ControlResult};
 use super::default::{DefaultControlService, DefaultPublishService};
-use super::handshake::{Handshake, HandshakeAck};
+use super::dispatcher::{factory, ErrorReasonMap};
+use super::drain::Drain;
+use super::handshake::{reject_protocol_level, Handshake, HandshakeAck};
+use super::limit::Limit;
+use super::memory::{MemoryStats, MemoryTracker};
+use super::outbound::OverflowPolicy;
+use super::peer::PeerLimit;
 use super::publish::{Publish, PublishAck};
+use super::retained::Retained;
 use super::selector::SelectItem;
-use super::shared::{MqttShared, MqttSinkPool};
-use super::{codec as mqtt, dispatcher::factory, MqttSink, Session};
+use super::shaper::{ShapePolicy, ShapeRule};
+use super::shared::{MqttShared, MqttSinkPool, DEFAULT_RECEIVE_MAX};
+use super::sni::SniHostname;
+use super::sys::SysTopics;
+use super::{codec as mqtt, MqttSink, Session};
+
+/// Handling of zero-length client id with `clean_start` flag unset
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EmptyClientId {
+    /// Reject connection with `ClientIdentifierNotValid` reason code
+    Reject,
+    /// Accept connection and force clean session
+    CleanStart,
+}
+
+/// Keep-alive handling of idle connections
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeepAliveMode {
+    /// Enforce keep-alive negotiated by handshake service
+    Strict,
+    /// Relax keep-alive of idle connections
+    ///
+    /// Connections that request shorter keep-alive get `keep_alive` as server
+    /// keep-alive, so idle clients ping less often and server wakes up less
+    /// often. Liveness of idle connections is checked by TCP keep-alive
+    /// probes instead, probes start after `tcp_idle` of inactivity. Dead peers
+    /// are detected later than requested keep-alive, which is not strict
+    /// MQTT-3.1.2-22 timing.
+    Lazy { keep_alive: Seconds, tcp_idle: Seconds },
+}
+
+/// Handling of rejected QoS0 publishes
+///
+/// QoS0 publish has no PUBACK, so reason code of rejected ack returned by
+/// publish service could not be delivered to client.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Qos0ViolationPolicy {
+    /// Drop rejected publish silently
+    Drop,
+    /// Close connection with DISCONNECT carrying reason code of the ack
+    Disconnect,
+}
+
+type ClientIdGen = Option<Rc<dyn Fn() -> ByteString>>;
+
+/// Socket options setter of accepted io stream
+type SocketOptions<Io> = Rc<dyn Fn(&Io)>;
+type SniFilter<Io> = Rc<dyn Fn(&Io) -> bool>;
 
 /// Mqtt Server
 pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceFactory> {
@@ -27,11 +88,25 @@ pub struct MqttServer<Io, St, C: ServiceFactory, Cn: ServiceFactory, P: ServiceF
     srv_control: Cn,
     srv_publish: P,
     max_size: u32,
+    max_will_size: u32,
     max_receive: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
     handshake_timeout: Seconds,
+    handshake_max_reads: usize,
+    handshake_max_bytes: usize,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    max_concurrent_auth: usize,
+    error_reason_map: ErrorReasonMap,
+    max_unacked_inbound: usize,
+    control_buffer: usize,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
     pub(super) pool: Rc<MqttSinkPool>,
     _t: marker::PhantomData<(Io, St)>,
 }
@@ -60,11 +135,25 @@ where
             srv_control: DefaultControlService::default(),
             srv_publish: DefaultPublishService::default(),
             max_size: 0,
+            max_will_size: 0,
             max_receive: 15,
             max_qos: None,
+            wildcard_subscriptions: true,
             handshake_timeout: Seconds::ZERO,
+            handshake_max_reads: 0,
+            handshake_max_bytes: 0,
             disconnect_timeout: Seconds(3),
             max_topic_alias: 32,
+            empty_client_id: EmptyClientId::Reject,
+            client_id_gen: None,
+            max_concurrent_auth: 0,
+            error_reason_map: Rc::new(control::disconnect_reason),
+            max_unacked_inbound: 0,
+            control_buffer: 16,
+            drain: None,
+            peer_limit: None,
+            socket_options: None,
+            sni_filter: None,
             pool: Rc::new(MqttSinkPool::default()),
             _t: marker::PhantomData,
         }
@@ -94,6 +183,36 @@ where
         self
     }
 
+    /// Set max number of reads for the first packet.
+    ///
+    /// If client does not send complete `connect` packet within `n` io reads,
+    /// connection gets closed. This protects against clients that send
+    /// packet byte by byte. To disable check set value to 0.
+    ///
+    /// Server that is used as `Selector` variant does not read first packet,
+    /// use `Selector::handshake_max_reads()` instead.
+    ///
+    /// By default check is disabled.
+    pub fn handshake_max_reads(mut self, n: usize) -> Self {
+        self.handshake_max_reads = n;
+        self
+    }
+
+    /// Set max number of bytes read before first packet is decoded.
+    ///
+    /// If client does not send complete `connect` packet within `n` bytes,
+    /// connection gets closed. Limit must be greater than max expected size
+    /// of `connect` packet. To disable check set value to 0.
+    ///
+    /// Server that is used as `Selector` variant does not read first packet,
+    /// use `Selector::handshake_max_bytes()` instead.
+    ///
+    /// By default check is disabled.
+    pub fn handshake_max_bytes(mut self, n: usize) -> Self {
+        self.handshake_max_bytes = n;
+        self
+    }
+
     /// Set server connection disconnect timeout.
     ///
     /// Defines a timeout for disconnect connection. If a disconnect procedure does not complete
@@ -116,6 +235,19 @@ where
         self
     }
 
+    /// Set max size of last will topic and payload.
+    ///
+    /// Connections with larger last will get rejected with `PacketTooLarge`
+    /// reason code before handshake service is called, will size is checked
+    /// while CONNECT packet is decoded. If max size is set to `0`, size is
+    /// unlimited. Whole CONNECT packet is limited by `max_size`.
+    ///
+    /// By default max will size is set to `0`
+    pub fn max_will_size(mut self, size: u32) -> Self {
+        self.max_will_size = size;
+        self
+    }
+
     /// Set `receive max`
     ///
     /// Number of in-flight publish packets. By default receive max is set to 15 packets.
@@ -133,6 +265,173 @@ where
         self
     }
 
+    /// Set wildcard subscriptions availability.
+    ///
+    /// If wildcard subscriptions are disabled, server advertises it in
+    /// CONNACK packet and rejects topic filters with wildcards with
+    /// `WildcardSubscriptionsNotSupported` reason code. Rejected filters
+    /// are not passed to control service.
+    ///
+    /// By default wildcard subscriptions are available.
+    pub fn wildcard_subscriptions(mut self, val: bool) -> Self {
+        self.wildcard_subscriptions = val;
+        self
+    }
+
+    /// Set handling of zero-length client id with `clean_start` flag unset.
+    ///
+    /// Server either rejects such connection with `ClientIdentifierNotValid`
+    /// reason code or accepts it and forces clean session.
+    /// By default connection is rejected.
+    pub fn allow_empty_client_id(mut self, policy: EmptyClientId) -> Self {
+        self.empty_client_id = policy;
+        self
+    }
+
+    /// Set client id generator.
+    ///
+    /// Generator is called for accepted connections with zero-length client
+    /// id. Generated id replaces client id of CONNECT packet and gets sent
+    /// to client with `assigned_client_id` CONNACK property, MQTT-3.2.2.3.7.
+    ///
+    /// By default client id is not assigned, handshake service gets
+    /// zero-length client id.
+    pub fn client_id_generator<F>(mut self, f: F) -> Self
+    where
+        F: Fn() -> ByteString + 'static,
+    {
+        self.client_id_gen = Some(Rc::new(f));
+        self
+    }
+
+    /// Assign client id to connections with zero-length client id.
+    ///
+    /// Unique id is generated from current time and counter, see
+    /// `client_id_generator()`.
+    pub fn assign_client_id(self) -> Self {
+        self.client_id_generator(generate_client_id)
+    }
+
+    /// Set max number of concurrent handshake service calls.
+    ///
+    /// Limit is shared by all connections of the server factory (worker).
+    /// Excess handshakes wait for a free slot, waiting time is bounded
+    /// by handshake timeout. To disable limit set value to 0.
+    ///
+    /// By default concurrent handshake calls are not limited.
+    pub fn max_concurrent_auth(mut self, val: usize) -> Self {
+        self.max_concurrent_auth = val;
+        self
+    }
+
+    /// Set authorization timeout.
+    ///
+    /// Timeout starts when handshake is ready for authorization and is
+    /// checked while handshake waits for concurrent auth slot and between
+    /// retries of pending handshake, see `Handshake::retry()`. Handshake
+    /// service calls are not interrupted, they are bounded by handshake
+    /// timeout. Connection that exceeds auth timeout gets rejected with
+    /// `ServerBusy` reason code, so clients could back off and reconnect.
+    ///
+    /// By default auth timeout is disabled.
+    pub fn auth_timeout(self, timeout: Seconds) -> Self {
+        self.pool.auth_timeout.set(timeout);
+        self
+    }
+
+    /// Set clock of server timers.
+    ///
+    /// Clock drives keep-alive of idle connections, auth timeout, retry
+    /// delay of pending handshakes and max connection age. Manual clock
+    /// makes these timeouts deterministic in tests. Handshake timeout and
+    /// disconnect timeout are handled by ntex and use runtime time.
+    ///
+    /// By default timers use runtime time.
+    pub fn clock(self, clock: Clock) -> Self {
+        *self.pool.clock.borrow_mut() = clock;
+        self
+    }
+
+    /// Set max connection age.
+    ///
+    /// Connection that lives longer than `age` since handshake gets closed
+    /// with `MaximumConnectTime` reason code, so client has to reconnect
+    /// and authenticate again. Could be used to enforce credentials lease
+    /// expiry.
+    ///
+    /// By default connection age is not limited.
+    pub fn max_connection_age(self, age: Seconds) -> Self {
+        self.pool.max_connection_age.set(age);
+        self
+    }
+
+    /// Set handling of rejected QoS0 publishes.
+    ///
+    /// Publish service could reject QoS0 publish, for example not authorized
+    /// one, by returning ack with error reason code. With `Disconnect` policy
+    /// connection gets closed with DISCONNECT that carries matching reason
+    /// code and reason string of the ack. Deferred acks are not checked.
+    ///
+    /// By default rejected QoS0 publishes are dropped.
+    pub fn qos0_violation_policy(self, policy: Qos0ViolationPolicy) -> Self {
+        self.pool.qos0_violation.set(policy);
+        self
+    }
+
+    /// Set max number of active subscriptions per session.
+    ///
+    /// New topic filters over the limit are rejected with `QuotaExceeded`
+    /// reason code before control service is called, filters within the
+    /// limit of the same SUBSCRIBE packet are passed to control service.
+    /// Filters of SUBSCRIBE packets that are not acked yet are counted too,
+    /// until control service rejects them. Re-subscribing to active topic
+    /// filter is not counted. Active subscriptions are available with
+    /// `Session::subscriptions()`. To disable limit set value to 0.
+    ///
+    /// By default number of subscriptions is not limited.
+    pub fn max_subscriptions_per_session(self, n: usize) -> Self {
+        self.pool.max_subscriptions.set(n);
+        self
+    }
+
+    /// Set max number of distinct topics per connection.
+    ///
+    /// Server tracks topics of inbound publishes, connection that publishes
+    /// to more than `n` distinct topics gets closed with `QuotaExceeded`
+    /// reason code. Number of tracked topics is available with
+    /// `MqttSink::distinct_topics()`. To disable limit set value to 0.
+    ///
+    /// By default number of topics is not limited.
+    pub fn max_distinct_topics(self, n: usize) -> Self {
+        self.pool.max_distinct_topics.set(n);
+        self
+    }
+
+    /// Set handling of acks with unknown packet id.
+    ///
+    /// PUBACK or PUBREC for packet id that is not in-flight is protocol
+    /// error, in strict mode connection gets closed with `ProtocolError`
+    /// reason code. Lenient mode ignores such acks, for clients that
+    /// re-send acks.
+    ///
+    /// By default strict mode is enabled.
+    pub fn strict_ack_ids(self, strict: bool) -> Self {
+        self.pool.strict_ack_ids.set(strict);
+        self
+    }
+
+    /// Set strict validation of user properties.
+    ///
+    /// Packet with user property that contains null character gets
+    /// rejected as malformed packet, MQTT-1.5.4-2. User properties with
+    /// invalid UTF-8 are rejected regardless of this setting.
+    ///
+    /// By default strict validation is disabled.
+    pub fn strict_utf8(self, strict: bool) -> Self {
+        self.pool.strict_utf8.set(strict);
+        self
+    }
+
     /// Set server max qos setting.
     ///
     /// By default max qos is not set`
@@ -141,6 +440,306 @@ where
         self
     }
 
+    /// Set mapping of protocol errors to DISCONNECT reason codes.
+    ///
+    /// Reason code is used for DISCONNECT packet that is sent to a client
+    /// before connection get closed because of protocol error.
+    ///
+    /// By default `control::disconnect_reason` mapping is used.
+    pub fn error_reason_map<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&ProtocolError) -> mqtt::DisconnectReasonCode + 'static,
+    {
+        self.error_reason_map = Rc::new(f);
+        self
+    }
+
+    /// Set max number of deferred publish acks per connection.
+    ///
+    /// Once number of outstanding `AckToken`s reaches the limit, server
+    /// stops reading from connection until some of the tokens get acked.
+    /// Deferred packets count against receive maximum as well. To disable
+    /// limit set value to 0.
+    ///
+    /// By default limit is disabled.
+    pub fn max_unacked_inbound(mut self, n: usize) -> Self {
+        self.max_unacked_inbound = n;
+        self
+    }
+
+    /// Set max number of buffered control messages per connection.
+    ///
+    /// Control service handles one message at a time, following
+    /// SUBSCRIBE, UNSUBSCRIBE, PINGREQ, AUTH and DISCONNECT packets get
+    /// buffered. Once buffer is full, server stops reading from connection
+    /// until control service handles buffered messages. Size must be greater
+    /// than 0.
+    ///
+    /// By default buffer size is set to 16 messages.
+    pub fn control_buffer_size(mut self, size: usize) -> Self {
+        assert!(size > 0, "Control buffer size must be greater than 0");
+        self.control_buffer = size;
+        self
+    }
+
+    /// Enable memory accounting.
+    ///
+    /// Write buffers of all server connections get accounted in provided
+    /// stats handle, see `MemoryStats::set_max_buffered()` for global limit.
+    ///
+    /// By default memory accounting is disabled.
+    pub fn memory_stats(self, stats: MemoryStats) -> Self {
+        *self.pool.memory.borrow_mut() = Some(MemoryTracker::new(stats));
+        self
+    }
+
+    #[cfg(feature = "prometheus")]
+    /// Enable Prometheus metrics.
+    ///
+    /// Packets and bytes of all server connections get counted in provided
+    /// metrics handle, see `Metrics::metrics_text()`.
+    ///
+    /// By default metrics are disabled.
+    pub fn metrics(self, metrics: super::Metrics) -> Self {
+        *self.pool.metrics.borrow_mut() = Some(metrics);
+        self
+    }
+
+    /// Set initial capacity of connection write buffer.
+    ///
+    /// Write buffer grows dynamically, pre-sized buffer avoids
+    /// reallocations for connections that send bursts of packets.
+    /// Capacity is applied to write params of memory pool when connection
+    /// state is created, it affects all users of the pool and it is also
+    /// high watermark for write backpressure. Max capacity is 64Kb.
+    ///
+    /// By default buffer capacity is defined by memory pool.
+    pub fn write_buffer_capacity(self, bytes: usize) -> Self {
+        self.pool.write_capacity.set(bytes);
+        self
+    }
+
+    /// Set max size of connection read buffer.
+    ///
+    /// Connection gets closed with `QuotaExceeded` reason code once read
+    /// buffer holds more than `size` bytes. Max packet size limits size of
+    /// single packet, this limit caps memory used by buffered inbound data,
+    /// for example by large packet that is sent slowly. Reads from socket
+    /// are capped as well, so buffer never grows over limit by more than
+    /// one byte. To disable limit set value to 0.
+    ///
+    /// By default read buffer size is not limited.
+    pub fn max_read_buffer(self, size: usize) -> Self {
+        self.pool.max_read_buffer.set(size);
+        self
+    }
+
+    /// Enable `$SYS` topics publishing.
+    ///
+    /// Server statistics get published with QoS0 to connections subscribed
+    /// to `$SYS` topics every `interval`. Published topics are
+    /// `$SYS/broker/uptime` and `$SYS/broker/clients/connected`. This is a
+    /// broker convention, not a spec requirement. Values are tracked per
+    /// server worker.
+    ///
+    /// By default `$SYS` topics are disabled.
+    pub fn sys_topics(self, interval: Seconds) -> Self {
+        *self.pool.sys.borrow_mut() = Some(SysTopics::new(interval));
+        self
+    }
+
+    /// Set retained messages store callback.
+    ///
+    /// Callback gets called for every accepted publish with retain flag.
+    /// Publish with empty payload is passed as `Retained::Clear`. Topic
+    /// aliases are resolved, passed publish has topic and no topic alias.
+    /// Streamed publishes are not passed. Last will with retain flag is
+    /// passed once connection closes without normal DISCONNECT, after will
+    /// delay interval.
+    ///
+    /// By default retained messages are not handled.
+    pub fn retained<F>(self, f: F) -> Self
+    where
+        F: Fn(Retained) + 'static,
+    {
+        self.pool.retained.set_hook(Box::new(f));
+        self
+    }
+
+    /// Set retained messages coalesce window.
+    ///
+    /// Updates of the same topic within `window` collapse to the last one,
+    /// retained store callback gets called once window ends. Coalesced
+    /// updates are tracked per worker and are lost if worker stops before
+    /// window ends. To disable coalescing set value to 0.
+    ///
+    /// By default coalescing is disabled.
+    pub fn retain_coalesce(self, window: Millis) -> Self {
+        self.pool.retained.set_coalesce(window);
+        self
+    }
+
+    /// Shape outbound rate of topics matching filter.
+    ///
+    /// Publishes written with `broadcast()` to topics matching `filter` are
+    /// limited to `rate` publishes per second per connection, with bursts
+    /// up to `rate` publishes. Publishes over rate are dropped or delayed
+    /// according to `policy`. Filters are checked in order they are added,
+    /// first matching filter applies. Panics if filter is not valid or
+    /// rate is 0.
+    ///
+    /// By default outbound rate is not shaped.
+    pub fn shape_topic(self, filter: &str, rate: u32, policy: ShapePolicy) -> Self {
+        assert!(rate > 0, "Shaped rate must be greater than 0");
+        let filter = filter
+            .parse::<Topic>()
+            .ok()
+            .filter(|topic| topic.is_valid())
+            .expect("Invalid topic filter");
+        self.pool.shape_rules.borrow_mut().push(Rc::new(ShapeRule::new(filter, rate, policy)));
+        self
+    }
+
+    /// Set outbound queue size and overflow policy.
+    ///
+    /// QoS0 publishes are written to connection write buffer until it holds
+    /// more than memory pool's write high watermark, following publishes wait
+    /// in queue of up to `size` publishes per connection. Publishes over queue
+    /// size are handled according to `policy`, publishes that define topic
+    /// alias are queued regardless of policy. QoS1 publishes are limited by
+    /// peer's receive maximum and are not queued.
+    ///
+    /// By default outbound queue is not used.
+    pub fn outbound_queue(self, size: usize, policy: OverflowPolicy) -> Self {
+        self.pool.outbound.set(Some((size, policy)));
+        self
+    }
+
+    #[cfg(unix)]
+    /// Set socket receive and send buffer sizes.
+    ///
+    /// Sizes are applied to socket of every connection with `SO_RCVBUF` and
+    /// `SO_SNDBUF` options before handshake service is called, size `0`
+    /// keeps system default. Sizes are hints for operating system, for example
+    /// linux doubles requested value and caps it with `net.core.rmem_max`
+    /// and `net.core.wmem_max`. TCP window scale is negotiated before socket
+    /// is accepted, large receive buffers could require listener socket
+    /// tuning as well. Available only for io streams backed by socket file
+    /// descriptor, errors are logged and ignored.
+    ///
+    /// By default system buffer sizes are used.
+    pub fn socket_buffers(mut self, recv: usize, send: usize) -> Self
+    where
+        Io: std::os::unix::io::AsRawFd,
+    {
+        self.socket_option(move |io| crate::utils::set_socket_buffers(io, recv, send));
+        self
+    }
+
+    /// Set server mandated keep-alive.
+    ///
+    /// Keep-alive requested by client and keep-alive set by handshake service
+    /// are ignored, `value` is sent as server keep-alive of CONNACK and client
+    /// must use it for pings. Panics if `value` is `0`.
+    ///
+    /// By default keep-alive is negotiated by handshake service.
+    pub fn force_keep_alive(self, value: Seconds) -> Self {
+        if value.0 == 0 {
+            panic!("Keep-alive must be greater than 0")
+        }
+        self.pool.forced_keep_alive.set(Some(value));
+        self
+    }
+
+    #[cfg(unix)]
+    /// Set keep-alive handling of idle connections.
+    ///
+    /// Lazy mode trades spec keep-alive timing for scalability, large number
+    /// of mostly idle connections costs less pings and timer wake ups. TCP
+    /// keep-alive is enabled with `SO_KEEPALIVE` before handshake service is
+    /// called. Keep-alive set by handshake service is used if it is longer
+    /// or if service sets server keep-alive of CONNACK. Available only
+    /// for io streams backed by socket file descriptor.
+    ///
+    /// By default keep-alive is strict.
+    pub fn keep_alive_mode(mut self, mode: KeepAliveMode) -> Self
+    where
+        Io: std::os::unix::io::AsRawFd,
+    {
+        match mode {
+            KeepAliveMode::Strict => self.pool.lazy_keep_alive.set(None),
+            KeepAliveMode::Lazy { keep_alive, tcp_idle } => {
+                self.pool.lazy_keep_alive.set(Some(keep_alive));
+                let pool = self.pool.clone();
+                self.socket_option(move |io| {
+                    if pool.lazy_keep_alive.get().is_some() {
+                        crate::utils::set_tcp_keepalive(io, tcp_idle);
+                    }
+                });
+            }
+        }
+        self
+    }
+
+    #[cfg(unix)]
+    fn socket_option<F>(&mut self, f: F)
+    where
+        F: Fn(&Io) + 'static,
+    {
+        self.socket_options = Some(match self.socket_options.take() {
+            Some(prev) => Rc::new(move |io: &Io| {
+                prev(io);
+                f(io)
+            }),
+            None => Rc::new(f),
+        });
+    }
+
+    /// Set max number of connections per peer ip address.
+    ///
+    /// `peer_addr` extracts peer address from io stream, connections without
+    /// address are not limited. Excess connections get rejected with
+    /// `QuotaExceeded` reason code. Connections are counted per worker
+    /// from accept, before CONNECT is received. Connections dispatched
+    /// by `Selector` are counted once selector has read CONNECT packet.
+    ///
+    /// By default number of connections is not limited.
+    pub fn max_connections_per_ip<F>(mut self, n: usize, peer_addr: F) -> Self
+    where
+        F: Fn(&Io) -> Option<IpAddr> + 'static,
+    {
+        self.peer_limit = Some(PeerLimit::new(n, peer_addr));
+        self
+    }
+
+    /// Set TLS server name filter.
+    ///
+    /// Filter gets server name that client requested in TLS ClientHello,
+    /// `None` for plain connections and for clients that do not send server
+    /// name. Rejected connections are closed before mqtt handshake, CONNACK
+    /// is not sent.
+    ///
+    /// By default connections are not filtered.
+    pub fn sni_filter<F>(mut self, f: F) -> Self
+    where
+        Io: SniHostname,
+        F: Fn(Option<&str>) -> bool + 'static,
+    {
+        self.sni_filter = Some(Rc::new(move |io: &Io| f(io.sni_hostname())));
+        self
+    }
+
+    /// Set drain handle.
+    ///
+    /// Handle allows to stop accepting new connections and to close
+    /// existing connections gradually, see `Drain::begin_drain()`.
+    ///
+    /// By default drain mode is not available.
+    pub fn drain(mut self, drain: Drain) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
     /// Set memory pool.
     ///
     /// Use specified memory pool for memory allocations. By default P5
@@ -169,10 +768,24 @@ where
             srv_publish: self.srv_publish,
             srv_control: service.into_factory(),
             max_size: self.max_size,
+            max_will_size: self.max_will_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
+            wildcard_subscriptions: self.wildcard_subscriptions,
+            empty_client_id: self.empty_client_id,
+            client_id_gen: self.client_id_gen,
+            max_concurrent_auth: self.max_concurrent_auth,
+            error_reason_map: self.error_reason_map,
+            max_unacked_inbound: self.max_unacked_inbound,
+            control_buffer: self.control_buffer,
+            drain: self.drain,
+            peer_limit: self.peer_limit,
+            socket_options: self.socket_options,
+            sni_filter: self.sni_filter,
             handshake_timeout: self.handshake_timeout,
+            handshake_max_reads: self.handshake_max_reads,
+            handshake_max_bytes: self.handshake_max_bytes,
             disconnect_timeout: self.disconnect_timeout,
             pool: self.pool,
             _t: marker::PhantomData,
@@ -194,10 +807,24 @@ where
             srv_publish: publish.into_factory(),
             srv_control: self.srv_control,
             max_size: self.max_size,
+            max_will_size: self.max_will_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
+            wildcard_subscriptions: self.wildcard_subscriptions,
+            empty_client_id: self.empty_client_id,
+            client_id_gen: self.client_id_gen,
+            max_concurrent_auth: self.max_concurrent_auth,
+            error_reason_map: self.error_reason_map,
+            max_unacked_inbound: self.max_unacked_inbound,
+            control_buffer: self.control_buffer,
+            drain: self.drain,
+            peer_limit: self.peer_limit,
+            socket_options: self.socket_options,
+            sni_filter: self.sni_filter,
             handshake_timeout: self.handshake_timeout,
+            handshake_max_reads: self.handshake_max_reads,
+            handshake_max_bytes: self.handshake_max_bytes,
             disconnect_timeout: self.disconnect_timeout,
             pool: self.pool,
             _t: marker::PhantomData,
@@ -242,13 +869,30 @@ where
             handshake_service_factory(
                 handshake,
                 self.max_size,
+                self.max_will_size,
                 self.max_receive,
                 self.max_topic_alias,
                 self.max_qos,
+                self.wildcard_subscriptions,
+                self.empty_client_id,
+                self.client_id_gen,
+                self.max_concurrent_auth,
+                self.drain,
+                self.peer_limit,
+                self.socket_options,
+                self.sni_filter,
                 self.handshake_timeout,
+                self.handshake_max_reads,
+                self.handshake_max_bytes,
                 self.pool,
             ),
-            factory(publish, control),
+            factory(
+                publish,
+                control,
+                self.error_reason_map,
+                self.max_unacked_inbound,
+                self.control_buffer,
+            ),
             pool,
             self.disconnect_timeout,
         )
@@ -276,13 +920,30 @@ where
             handshake_service_factory2(
                 handshake,
                 self.max_size,
+                self.max_will_size,
                 self.max_receive,
                 self.max_topic_alias,
                 self.max_qos,
+                self.wildcard_subscriptions,
+                self.empty_client_id,
+                self.client_id_gen,
+                self.max_concurrent_auth,
+                self.drain,
+                self.peer_limit,
+                self.socket_options,
+                self.sni_filter,
                 self.handshake_timeout,
+                self.handshake_max_reads,
+                self.handshake_max_bytes,
                 self.pool,
             ),
-            factory(publish, control),
+            factory(
+                publish,
+                control,
+                self.error_reason_map,
+                self.max_unacked_inbound,
+                self.control_buffer,
+            ),
             pool,
             self.disconnect_timeout,
         )
@@ -312,11 +973,26 @@ where
         ServerSelector::<St, _, _, Io, _, _> {
             check: Rc::new(check),
             connect: self.handshake,
-            handler: Rc::new(factory(publish, control)),
+            handler: Rc::new(factory(
+                publish,
+                control,
+                self.error_reason_map,
+                self.max_unacked_inbound,
+                self.control_buffer,
+            )),
             max_size: self.max_size,
+            max_will_size: self.max_will_size,
             max_receive: self.max_receive,
             max_topic_alias: self.max_topic_alias,
             max_qos: self.max_qos,
+            wildcard_subscriptions: self.wildcard_subscriptions,
+            empty_client_id: self.empty_client_id,
+            client_id_gen: self.client_id_gen,
+            auth_limit: Limit::new(self.max_concurrent_auth),
+            drain: self.drain,
+            peer_limit: self.peer_limit,
+            socket_options: self.socket_options,
+            sni_filter: self.sni_filter,
             disconnect_timeout: self.disconnect_timeout,
             time: Timer::new(Millis::ONE_SEC),
             _t: marker::PhantomData,
@@ -324,13 +1000,25 @@ where
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory<Io, St, C>(
     factory: C,
     max_size: u32,
+    max_will_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    max_concurrent_auth: usize,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
     handshake_timeout: Seconds,
+    handshake_max_reads: usize,
+    handshake_max_bytes: usize,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
@@ -343,15 +1031,24 @@ where
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
+    let auth_limit = Limit::new(max_concurrent_auth);
+
     ntex::service::apply(
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let client_id_gen = client_id_gen.clone();
+            let auth_limit = auth_limit.clone();
+            let drain = drain.clone();
+            let peer_limit = peer_limit.clone();
+            let socket_options = socket_options.clone();
+            let sni_filter = sni_filter.clone();
 
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
+                let client_id_gen = client_id_gen.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::service::apply_fn(
                     service,
@@ -361,9 +1058,20 @@ where
                             None,
                             service.clone(),
                             max_size,
+                            max_will_size,
                             max_receive,
                             max_topic_alias,
                             max_qos,
+                            wildcard_subscriptions,
+                            empty_client_id,
+                            client_id_gen.clone(),
+                            auth_limit.clone(),
+                            drain.clone(),
+                            peer_limit.clone(),
+                            socket_options.clone(),
+                            sni_filter.clone(),
+                            handshake_max_reads,
+                            handshake_max_bytes,
                             pool.clone(),
                         )
                     },
@@ -377,13 +1085,25 @@ where
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn handshake_service_factory2<Io, St, C>(
     factory: C,
     max_size: u32,
+    max_will_size: u32,
     max_receive: u16,
     max_topic_alias: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    max_concurrent_auth: usize,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
     handshake_timeout: Seconds,
+    handshake_max_reads: usize,
+    handshake_max_bytes: usize,
     pool: Rc<MqttSinkPool>,
 ) -> impl ServiceFactory<
     Config = (),
@@ -397,14 +1117,23 @@ where
     C: ServiceFactory<Config = (), Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
     C::Error: fmt::Debug,
 {
+    let auth_limit = Limit::new(max_concurrent_auth);
+
     ntex::service::apply(
         Timeout::new(Millis::from(handshake_timeout)),
         ntex::service::fn_factory(move || {
             let pool = pool.clone();
+            let client_id_gen = client_id_gen.clone();
+            let auth_limit = auth_limit.clone();
+            let drain = drain.clone();
+            let peer_limit = peer_limit.clone();
+            let socket_options = socket_options.clone();
+            let sni_filter = sni_filter.clone();
             let fut = factory.new_service(());
             async move {
                 let service = fut.await?;
                 let pool = pool.clone();
+                let client_id_gen = client_id_gen.clone();
                 let service = Rc::new(service.map_err(MqttError::Service));
                 Ok::<_, C::InitError>(ntex::service::apply_fn(
                     service,
@@ -414,9 +1143,20 @@ where
                             Some(state),
                             service.clone(),
                             max_size,
+                            max_will_size,
                             max_receive,
                             max_topic_alias,
                             max_qos,
+                            wildcard_subscriptions,
+                            empty_client_id,
+                            client_id_gen.clone(),
+                            auth_limit.clone(),
+                            drain.clone(),
+                            peer_limit.clone(),
+                            socket_options.clone(),
+                            sni_filter.clone(),
+                            handshake_max_reads,
+                            handshake_max_bytes,
                             pool.clone(),
                         )
                     },
@@ -436,9 +1176,20 @@ async fn handshake<Io, S, St, E>(
     state: Option<State>,
     service: S,
     max_size: u32,
+    max_will_size: u32,
     mut max_receive: u16,
     mut max_topic_alias: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    auth_limit: Option<Rc<Limit>>,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
+    max_reads: usize,
+    max_bytes: usize,
     pool: Rc<MqttSinkPool>,
 ) -> Result<(Io, State, Rc<MqttShared>, Session<St>, Seconds), S::Error>
 where
@@ -447,16 +1198,30 @@ where
 {
     log::trace!("Starting mqtt v5 handshake");
 
-    let state = state.unwrap_or_else(|| State::with_memory_pool(pool.pool.get()));
+    if !check_sni(&io, &sni_filter) {
+        return Err(MqttError::ServerError("Server name is rejected"));
+    }
+
+    // count connection on accept, before peer sends anything
+    let peer = match peer_limit {
+        Some(ref limit) => limit.acquire(&io),
+        None => Ok(None),
+    };
+
+    let state = state.unwrap_or_else(|| pool.state());
     let shared = Rc::new(MqttShared::new(state.clone(), mqtt::Codec::default(), 0, pool));
 
     // set max inbound (decoder) packet size
     shared.codec.set_max_inbound_size(max_size);
+    shared.codec.set_max_will_size(max_will_size);
 
     // read first packet
-    let packet = state
-        .next(&mut io, &shared.codec)
-        .await
+    let packet =
+        state.next(&mut ReadLimit::new(&mut io, max_reads, max_bytes), &shared.codec).await;
+    if let Err(ref err) = packet {
+        reject_protocol_level(&mut io, &shared, err).await;
+    }
+    let packet = packet
         .map_err(|err| {
             log::trace!("Error is received during mqtt handshake: {:?}", err);
             MqttError::from(err)
@@ -469,26 +1234,41 @@ where
         })?;
 
     match packet {
-        mqtt::Packet::Connect(connect) => {
+        mqtt::Packet::Connect(mut connect) => {
             // set max outbound (encoder) packet size
             if let Some(size) = connect.max_packet_size {
                 shared.codec.set_max_outbound_size(size.get());
             }
-            shared.cap.set(connect.receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+            shared.cap.set(
+                connect.receive_max.map(|v| v.get() as usize).unwrap_or(DEFAULT_RECEIVE_MAX),
+            );
+            shared.topic_alias_max.set(connect.topic_alias_max);
 
             let keep_alive = connect.keep_alive;
 
+            let reject = check_client_id(&mut connect, empty_client_id);
+            if let Some(ref f) = socket_options {
+                (*f)(&io);
+            }
+            shared.set_will(&connect);
+            let mut hnd =
+                Handshake::new(connect, io, shared, max_size, max_receive, max_topic_alias);
+            if let Some(ref gen) = client_id_gen {
+                if !reject && hnd.packet().client_id.is_empty() {
+                    hnd.assign_client_id((*gen)());
+                }
+            }
+
             // authenticate mqtt connection
-            let mut ack = service
-                .call(Handshake::new(
-                    connect,
-                    io,
-                    shared,
-                    max_size,
-                    max_receive,
-                    max_topic_alias,
-                ))
-                .await?;
+            let mut ack = if reject {
+                hnd.failed(mqtt::ConnectAckReason::ClientIdentifierNotValid)
+            } else if drain.as_ref().map(|d| d.is_draining()).unwrap_or(false) {
+                hnd.failed(mqtt::ConnectAckReason::ServerUnavailable)
+            } else if peer.is_err() {
+                hnd.failed(mqtt::ConnectAckReason::QuotaExceeded)
+            } else {
+                authorize(&service, hnd, &auth_limit).await?
+            };
 
             match ack.session {
                 Some(session) => {
@@ -500,6 +1280,11 @@ where
                     if ack.packet.max_qos.is_none() {
                         ack.packet.max_qos = max_qos;
                     }
+                    if !wildcard_subscriptions
+                        && ack.packet.wildcard_subscription_available.is_none()
+                    {
+                        ack.packet.wildcard_subscription_available = Some(false);
+                    }
 
                     if let Some(num) = ack.packet.receive_max {
                         max_receive = num.get();
@@ -509,30 +1294,48 @@ where
                     if let Some(size) = ack.packet.max_packet_size {
                         shared.codec.set_max_inbound_size(size);
                     }
-                    if ack.packet.server_keepalive_sec.is_none()
-                        && (keep_alive > ack.keepalive as u16)
-                    {
-                        ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
+                    negotiate_keep_alive(
+                        &mut ack.keepalive,
+                        &mut ack.packet,
+                        keep_alive,
+                        &shared.pool,
+                    );
+
+                    let limits = negotiated_limits(
+                        &shared,
+                        ack.packet.max_qos,
+                        max_receive,
+                        max_topic_alias,
+                        ack.packet.wildcard_subscription_available != Some(false),
+                    );
+
+                    if !ack.manual {
+                        state
+                            .send(
+                                &mut ack.io,
+                                &shared.codec,
+                                mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                            )
+                            .await?;
+                    } else if !ack.connack_sent {
+                        log::error!("Manual CONNACK is not sent by handshake service");
+                        return Err(MqttError::ServerError("CONNACK is not sent"));
                     }
 
-                    state
-                        .send(
-                            &mut ack.io,
-                            &shared.codec,
-                            mqtt::Packet::ConnectAck(Box::new(ack.packet)),
-                        )
-                        .await?;
+                    let sink = MqttSink::new(shared.clone());
+                    shared.pool.track(&sink);
+                    if let Some(drain) = drain {
+                        drain.register(shared.state.clone(), sink.clone());
+                    }
+                    if let Ok(Some(guard)) = peer {
+                        guard.hold(shared.state.clone());
+                    }
 
                     Ok((
                         ack.io,
                         shared.state.clone(),
-                        shared.clone(),
-                        Session::new_v5(
-                            session,
-                            MqttSink::new(shared),
-                            max_receive,
-                            max_topic_alias,
-                        ),
+                        shared,
+                        Session::new_v5(session, sink, limits),
                         Seconds(ack.keepalive),
                     ))
                 }
@@ -540,15 +1343,16 @@ where
                     log::trace!("Failed to complete handshake: {:#?}", ack.packet);
 
                     if ack.shared.state.is_open()
-                        && ack
-                            .shared
-                            .state
-                            .write()
-                            .encode(
-                                mqtt::Packet::ConnectAck(Box::new(ack.packet)),
-                                &ack.shared.codec,
-                            )
-                            .is_ok()
+                        && ((ack.manual && ack.connack_sent)
+                            || ack
+                                .shared
+                                .state
+                                .write()
+                                .encode(
+                                    mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                    &ack.shared.codec,
+                                )
+                                .is_ok())
                     {
                         WriteTask::shutdown(
                             Rc::new(RefCell::new(ack.io)),
@@ -570,14 +1374,183 @@ where
     }
 }
 
+fn negotiated_limits(
+    shared: &MqttShared,
+    max_qos: Option<QoS>,
+    receive_max: u16,
+    topic_alias_max: u16,
+    wildcard_subscriptions: bool,
+) -> NegotiatedLimits {
+    NegotiatedLimits {
+        max_qos: max_qos.unwrap_or(QoS::ExactlyOnce),
+        receive_max,
+        topic_alias_max,
+        max_packet_size: shared.codec.max_inbound(),
+        peer_receive_max: u16::try_from(shared.cap.get()).unwrap_or(u16::MAX),
+        peer_topic_alias_max: shared.topic_alias_max.get(),
+        peer_max_packet_size: shared.codec.max_outbound(),
+        wildcard_subscriptions,
+    }
+}
+
+/// Call handshake service, pending handshakes are retried
+async fn authorize<Io, S, St>(
+    service: &S,
+    mut hnd: Handshake<Io>,
+    limit: &Option<Rc<Limit>>,
+) -> Result<HandshakeAck<Io, St>, S::Error>
+where
+    S: Service<Request = Handshake<Io>, Response = HandshakeAck<Io, St>>,
+{
+    let clock = hnd.shared.pool.clock.borrow().clone();
+    let timeout = hnd.shared.pool.auth_timeout.get();
+    let mut deadline = if timeout.non_zero() { Some(clock.sleep(timeout)) } else { None };
+
+    loop {
+        let permit = match (limit, deadline.as_mut()) {
+            (Some(limit), Some(deadline)) => {
+                match crate::utils::select(limit.acquire(), deadline).await {
+                    Either::Left(permit) => Some(permit),
+                    Either::Right(_) => return Ok(auth_timeout(hnd)),
+                }
+            }
+            (Some(limit), None) => Some(limit.acquire().await),
+            (None, _) => None,
+        };
+        let ack = service.call(hnd).await?;
+        drop(permit);
+
+        match ack.into_retry() {
+            Either::Left((delay, pending)) => {
+                log::trace!("Handshake is pending, retry in {:?}", delay);
+                hnd = pending;
+                match deadline.as_mut() {
+                    Some(deadline) => {
+                        if let Either::Right(_) =
+                            crate::utils::select(clock.sleep(delay), deadline).await
+                        {
+                            return Ok(auth_timeout(hnd));
+                        }
+                    }
+                    None => clock.sleep(delay).await,
+                }
+            }
+            Either::Right(ack) => return Ok(ack),
+        }
+    }
+}
+
+fn auth_timeout<Io, St>(hnd: Handshake<Io>) -> HandshakeAck<Io, St> {
+    log::trace!("Handshake auth timeout, rejecting connection");
+    hnd.failed(mqtt::ConnectAckReason::ServerBusy)
+}
+
+/// Negotiate keep-alive of the connection
+///
+/// Server keep-alive of CONNACK is sent only if it differs from keep-alive
+/// requested by client.
+fn negotiate_keep_alive(
+    keepalive: &mut u16,
+    pkt: &mut mqtt::ConnectAck,
+    keep_alive: u16,
+    pool: &MqttSinkPool,
+) {
+    if let Some(forced) = pool.forced_keep_alive.get() {
+        *keepalive = forced.0;
+        pkt.server_keepalive_sec = Some(forced.0);
+    } else {
+        if let Some(lazy) = pool.lazy_keep_alive.get() {
+            relax_keep_alive(keepalive, pkt, keep_alive, lazy);
+        }
+        if pkt.server_keepalive_sec.is_none() && keep_alive > *keepalive {
+            pkt.server_keepalive_sec = Some(*keepalive);
+        }
+    }
+    if pkt.server_keepalive_sec == Some(keep_alive) {
+        pkt.server_keepalive_sec = None;
+    }
+}
+
+/// Use longer keep-alive of lazy mode unless handshake service set server keep-alive
+fn relax_keep_alive(
+    keepalive: &mut u16,
+    pkt: &mut mqtt::ConnectAck,
+    keep_alive: u16,
+    lazy: Seconds,
+) {
+    if pkt.server_keepalive_sec.is_none() && keep_alive != 0 && *keepalive < lazy.0 {
+        *keepalive = lazy.0;
+        pkt.server_keepalive_sec = Some(lazy.0);
+    }
+}
+
+/// Check TLS server name, returns `false` if connection must be rejected
+fn check_sni<Io>(io: &Io, filter: &Option<SniFilter<Io>>) -> bool {
+    match filter {
+        Some(ref f) if !(*f)(io) => {
+            log::trace!("Connection is rejected by server name filter");
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Check last will size, returns `true` if connection must be rejected
+///
+/// CONNECT of selector connections is decoded by selector, without will limit.
+fn check_will_size(pkt: &mqtt::Connect, max_size: u32) -> bool {
+    if let Some(ref will) = pkt.last_will {
+        let size = will.topic.len() + will.message.len();
+        if max_size != 0 && size > max_size as usize {
+            log::trace!("Last will is too large: {} > {}", size, max_size);
+            return true;
+        }
+    }
+    false
+}
+
+/// Check zero-length client id, returns `true` if connection must be rejected
+fn check_client_id(pkt: &mut mqtt::Connect, policy: EmptyClientId) -> bool {
+    if pkt.client_id.is_empty() && !pkt.clean_start {
+        match policy {
+            EmptyClientId::Reject => {
+                log::trace!("Zero-length client id with clean_start=false, rejecting");
+                return true;
+            }
+            EmptyClientId::CleanStart => pkt.clean_start = true,
+        }
+    }
+    false
+}
+
+/// Generate unique client id
+fn generate_client_id() -> ByteString {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    ByteString::from(format!("auto{:x}{:x}", nanos, NEXT.fetch_add(1, Ordering::Relaxed)))
+}
+
 pub(crate) struct ServerSelector<St, C, T, Io, F, R> {
     connect: C,
     handler: Rc<T>,
     time: Timer,
     check: Rc<F>,
     max_size: u32,
+    max_will_size: u32,
     max_receive: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    auth_limit: Option<Rc<Limit>>,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
     _t: marker::PhantomData<(St, Io, R)>,
@@ -613,9 +1586,18 @@ where
         let time = self.time.clone();
         let check = self.check.clone();
         let max_size = self.max_size;
+        let max_will_size = self.max_will_size;
         let max_receive = self.max_receive;
         let max_qos = self.max_qos;
+        let wildcard_subscriptions = self.wildcard_subscriptions;
         let max_topic_alias = self.max_topic_alias;
+        let empty_client_id = self.empty_client_id;
+        let client_id_gen = self.client_id_gen.clone();
+        let auth_limit = self.auth_limit.clone();
+        let drain = self.drain.clone();
+        let peer_limit = self.peer_limit.clone();
+        let socket_options = self.socket_options.clone();
+        let sni_filter = self.sni_filter.clone();
         let disconnect_timeout = self.disconnect_timeout;
 
         // create connect service and then create service impl
@@ -625,9 +1607,18 @@ where
                 time,
                 check,
                 max_size,
+                max_will_size,
                 max_receive,
                 max_qos,
+                wildcard_subscriptions,
                 max_topic_alias,
+                empty_client_id,
+                client_id_gen,
+                auth_limit,
+                drain,
+                peer_limit,
+                socket_options,
+                sni_filter,
                 disconnect_timeout,
                 connect: Rc::new(fut.await?),
                 _t: marker::PhantomData,
@@ -641,8 +1632,17 @@ pub(crate) struct ServerSelectorImpl<St, C, T, Io, F, R> {
     connect: Rc<C>,
     handler: Rc<T>,
     max_size: u32,
+    max_will_size: u32,
     max_receive: u16,
     max_qos: Option<QoS>,
+    wildcard_subscriptions: bool,
+    empty_client_id: EmptyClientId,
+    client_id_gen: ClientIdGen,
+    auth_limit: Option<Rc<Limit>>,
+    drain: Option<Drain>,
+    peer_limit: Option<Rc<PeerLimit<Io>>>,
+    socket_options: Option<SocketOptions<Io>>,
+    sni_filter: Option<SniFilter<Io>>,
     disconnect_timeout: Seconds,
     max_topic_alias: u16,
     time: Timer,
@@ -689,7 +1689,16 @@ where
         let timeout = self.disconnect_timeout;
         let time = self.time.clone();
         let max_qos = self.max_qos;
+        let wildcard_subscriptions = self.wildcard_subscriptions;
         let max_size = self.max_size;
+        let max_will_size = self.max_will_size;
+        let empty_client_id = self.empty_client_id;
+        let client_id_gen = self.client_id_gen.clone();
+        let auth_limit = self.auth_limit.clone();
+        let drain = self.drain.clone();
+        let peer_limit = self.peer_limit.clone();
+        let socket_options = self.socket_options.clone();
+        let sni_filter = self.sni_filter.clone();
         let mut max_receive = self.max_receive;
         let mut max_topic_alias = self.max_topic_alias;
 
@@ -708,23 +1717,51 @@ where
 
             if !result.map_err(MqttError::Service)? {
                 Ok(Either::Left((hnd, state, delay)))
+            } else if !check_sni(hnd.io(), &sni_filter) {
+                Err(MqttError::ServerError("Server name is rejected"))
             } else {
                 // set max outbound (encoder) packet size
                 if let Some(size) = hnd.packet().max_packet_size {
                     hnd.shared.codec.set_max_outbound_size(size.get());
                 }
-                hnd.shared
-                    .cap
-                    .set(hnd.packet().receive_max.map(|v| v.get()).unwrap_or(16) as usize);
+                hnd.shared.cap.set(
+                    hnd.packet()
+                        .receive_max
+                        .map(|v| v.get() as usize)
+                        .unwrap_or(DEFAULT_RECEIVE_MAX),
+                );
+                hnd.shared.topic_alias_max.set(hnd.packet().topic_alias_max);
+                hnd.shared.set_will(hnd.packet());
 
                 let keep_alive = hnd.packet().keep_alive;
                 hnd.max_size = max_size;
                 hnd.max_receive = max_receive;
                 hnd.max_topic_alias = max_topic_alias;
+                let peer = match peer_limit {
+                    Some(ref limit) => limit.acquire(hnd.io()),
+                    None => Ok(None),
+                };
+                if let Some(ref f) = socket_options {
+                    (*f)(hnd.io());
+                }
 
                 // authenticate mqtt connection
-                let mut ack = if let Some(ref mut delay) = delay {
-                    let fut = connect.call(hnd);
+                let reject = check_client_id(hnd.packet_mut(), empty_client_id);
+                if let Some(ref gen) = client_id_gen {
+                    if !reject && hnd.packet().client_id.is_empty() {
+                        hnd.assign_client_id((*gen)());
+                    }
+                }
+                let mut ack = if reject {
+                    hnd.failed(mqtt::ConnectAckReason::ClientIdentifierNotValid)
+                } else if check_will_size(hnd.packet(), max_will_size) {
+                    hnd.failed(mqtt::ConnectAckReason::PacketTooLarge)
+                } else if drain.as_ref().map(|d| d.is_draining()).unwrap_or(false) {
+                    hnd.failed(mqtt::ConnectAckReason::ServerUnavailable)
+                } else if peer.is_err() {
+                    hnd.failed(mqtt::ConnectAckReason::QuotaExceeded)
+                } else if let Some(ref mut delay) = delay {
+                    let fut = authorize(&*connect, hnd, &auth_limit);
                     match crate::utils::select(fut, delay).await {
                         Either::Left(res) => res.map_err(|e| {
                             log::trace!("Connection handshake failed: {:?}", e);
@@ -733,7 +1770,7 @@ where
                         Either::Right(_) => return Err(MqttError::HandshakeTimeout),
                     }
                 } else {
-                    connect.call(hnd).await.map_err(|e| {
+                    authorize(&*connect, hnd, &auth_limit).await.map_err(|e| {
                         log::trace!("Connection handshake failed: {:?}", e);
                         MqttError::Service(e)
                     })?
@@ -749,6 +1786,11 @@ where
                         if ack.packet.max_qos.is_none() {
                             ack.packet.max_qos = max_qos;
                         }
+                        if !wildcard_subscriptions
+                            && ack.packet.wildcard_subscription_available.is_none()
+                        {
+                            ack.packet.wildcard_subscription_available = Some(false);
+                        }
 
                         if let Some(num) = ack.packet.receive_max {
                             max_receive = num.get();
@@ -758,31 +1800,50 @@ where
                         if let Some(size) = ack.packet.max_packet_size {
                             shared.codec.set_max_inbound_size(size);
                         }
-                        if ack.packet.server_keepalive_sec.is_none()
-                            && (keep_alive > ack.keepalive as u16)
-                        {
-                            ack.packet.server_keepalive_sec = Some(ack.keepalive as u16);
-                        }
-
-                        state
-                            .send(
-                                &mut ack.io,
-                                &shared.codec,
-                                mqtt::Packet::ConnectAck(Box::new(ack.packet)),
-                            )
-                            .await?;
+                        negotiate_keep_alive(
+                            &mut ack.keepalive,
+                            &mut ack.packet,
+                            keep_alive,
+                            &shared.pool,
+                        );
 
-                        let session = Session::new_v5(
-                            session,
-                            MqttSink::new(shared.clone()),
+                        let limits = negotiated_limits(
+                            &shared,
+                            ack.packet.max_qos,
                             max_receive,
                             max_topic_alias,
+                            ack.packet.wildcard_subscription_available != Some(false),
                         );
+
+                        if !ack.manual {
+                            state
+                                .send(
+                                    &mut ack.io,
+                                    &shared.codec,
+                                    mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                )
+                                .await?;
+                        } else if !ack.connack_sent {
+                            log::error!("Manual CONNACK is not sent by handshake service");
+                            return Err(MqttError::ServerError("CONNACK is not sent"));
+                        }
+
+                        let sink = MqttSink::new(shared.clone());
+                        shared.pool.track(&sink);
+                        if let Some(drain) = drain {
+                            drain.register(shared.state.clone(), sink.clone());
+                        }
+                        if let Ok(Some(guard)) = peer {
+                            guard.hold(shared.state.clone());
+                        }
+                        let session = Session::new_v5(session, sink, limits);
                         let handler = handler.new_service(session).await?;
                         log::trace!("Connection handler is created, starting dispatcher");
 
+                        let clock = shared.pool.clock.borrow().clone();
                         Dispatcher::with(ack.io, shared.state.clone(), shared, handler, time)
                             .keepalive_timeout(Seconds(ack.keepalive))
+                            .clock(clock)
                             .disconnect_timeout(timeout)
                             .await?;
                         Ok(Either::Right(()))
@@ -791,15 +1852,16 @@ where
                         log::trace!("Failed to complete handshake: {:#?}", ack.packet);
 
                         if ack.shared.state.is_open()
-                            && ack
-                                .shared
-                                .state
-                                .write()
-                                .encode(
-                                    mqtt::Packet::ConnectAck(Box::new(ack.packet)),
-                                    &ack.shared.codec,
-                                )
-                                .is_ok()
+                            && ((ack.manual && ack.connack_sent)
+                                || ack
+                                    .shared
+                                    .state
+                                    .write()
+                                    .encode(
+                                        mqtt::Packet::ConnectAck(Box::new(ack.packet)),
+                                        &ack.shared.codec,
+                                    )
+                                    .is_ok())
                         {
                             WriteTask::shutdown(
                                 Rc::new(RefCell::new(ack.io)),
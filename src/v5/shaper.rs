@@ -0,0 +1,161 @@
+//! Outbound publish shaping
+//!
+//! Shaping limits rate of publishes that `broadcast()` writes to a connection
+//! for topics matching configured filters. Every connection gets a token
+//! bucket per filter, bucket holds up to `rate` publishes and refills with
+//! `rate` publishes per second. First matching filter applies, publishes
+//! that do not match any filter are not shaped.
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, time::Instant};
+
+use ntex::time::{sleep, Millis};
+use ntex::util::Bytes;
+
+use super::MqttSink;
+use crate::topic::Topic;
+
+/// Policy for publishes over shaped rate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShapePolicy {
+    /// Drop publishes over rate, no memory is used
+    Drop,
+    /// Queue publishes over rate and write them once rate allows
+    ///
+    /// Queue holds up to specified number of encoded publishes per filter
+    /// and connection, publishes over queue size are dropped. Queued
+    /// publishes hold memory until they are written, for slow rates and
+    /// many connections memory usage could be significant.
+    Delay(usize),
+}
+
+/// Topic filter rate
+pub(super) struct ShapeRule {
+    filter: Topic,
+    rate: u32,
+    policy: ShapePolicy,
+}
+
+impl ShapeRule {
+    pub(super) fn new(filter: Topic, rate: u32, policy: ShapePolicy) -> Self {
+        ShapeRule { filter, rate, policy }
+    }
+}
+
+/// Shaping result
+pub(super) enum Shaped {
+    /// Publish could be written
+    Send,
+    /// Publish is queued
+    Queued,
+    /// Publish is over rate and dropped
+    Dropped,
+}
+
+/// Connection shaping state
+pub(super) struct Shaper(RefCell<Vec<Bucket>>);
+
+struct Bucket {
+    rule: Rc<ShapeRule>,
+    tokens: f64,
+    updated: Instant,
+    queue: VecDeque<Bytes>,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let rate = f64::from(self.rule.rate);
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.updated = now;
+    }
+
+    /// Time until next publish is allowed
+    fn next_token(&self) -> Millis {
+        let secs = (1.0 - self.tokens).max(0.0) / f64::from(self.rule.rate);
+        Millis(((secs * 1000.0).ceil() as u64).max(1))
+    }
+}
+
+impl Shaper {
+    /// Create connection state, `None` if no rules are configured
+    pub(super) fn new(rules: &[Rc<ShapeRule>]) -> Option<Self> {
+        if rules.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        Some(Shaper(RefCell::new(
+            rules
+                .iter()
+                .map(|rule| Bucket {
+                    rule: rule.clone(),
+                    tokens: f64::from(rule.rate),
+                    updated: now,
+                    queue: VecDeque::new(),
+                })
+                .collect(),
+        )))
+    }
+
+    /// Check encoded publish of the topic against topic rate
+    pub(super) fn shape(&self, sink: &MqttSink, topic: &str, buf: &Bytes) -> Shaped {
+        let mut buckets = self.0.borrow_mut();
+        let idx = match buckets.iter().position(|b| b.rule.filter.matches_str(topic)) {
+            Some(idx) => idx,
+            None => return Shaped::Send,
+        };
+        let bucket = &mut buckets[idx];
+        bucket.refill(Instant::now());
+
+        if bucket.queue.is_empty() && bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Shaped::Send;
+        }
+        match bucket.rule.policy {
+            ShapePolicy::Delay(max) if bucket.queue.len() < max => {
+                if bucket.queue.is_empty() {
+                    let delay = bucket.next_token();
+                    ntex::rt::spawn(flush(sink.clone(), idx, delay));
+                }
+                bucket.queue.push_back(buf.clone());
+                Shaped::Queued
+            }
+            _ => {
+                log::trace!("Publish to {:?} is over rate, dropping", topic);
+                Shaped::Dropped
+            }
+        }
+    }
+}
+
+/// Write queued publishes of the bucket as rate allows
+async fn flush(sink: MqttSink, idx: usize, mut delay: Millis) {
+    loop {
+        sleep(delay).await;
+
+        let shared = &sink.0;
+        let shaper = if let Some(ref shaper) = shared.shaper {
+            shaper
+        } else {
+            return;
+        };
+        let mut buckets = shaper.0.borrow_mut();
+        let bucket = &mut buckets[idx];
+        if !shared.state.is_open() {
+            bucket.queue.clear();
+            return;
+        }
+
+        bucket.refill(Instant::now());
+        while bucket.tokens >= 1.0 {
+            if let Some(buf) = bucket.queue.pop_front() {
+                bucket.tokens -= 1.0;
+                shared.write_encoded(&buf);
+            } else {
+                break;
+            }
+        }
+        if bucket.queue.is_empty() {
+            return;
+        }
+        delay = bucket.next_token();
+    }
+}
@@ -1,31 +1,122 @@
-use std::{cell::Cell, cell::RefCell, collections::VecDeque, rc::Rc};
+use std::rc::Rc;
+use std::{cell::Cell, cell::RefCell, collections::VecDeque, num::NonZeroU16, num::NonZeroU32};
 
 use ntex::channel::pool;
 use ntex::codec::{Decoder, Encoder};
-use ntex::util::{BytesMut, HashMap, PoolId, PoolRef};
+use ntex::time::Seconds;
+use ntex::util::{ByteString, Bytes, BytesMut, HashMap, HashSet, PoolId, PoolRef};
 
-use super::codec;
-use crate::{error, io::State, types::packet_type};
+use super::memory::MemoryTracker;
+use super::outbound::{Outbound, OverflowPolicy, Slot};
+use super::retained::RetainedStore;
+use super::server::Qos0ViolationPolicy;
+use super::shaper::{ShapeRule, Shaper};
+use super::sys::SysTopics;
+use super::{close::CloseHandle, codec, MqttSink};
+use crate::clock::Clock;
+use crate::io::{FlushSource, FlushStats, ReadLimit, State};
+use crate::{error, types::packet_type};
+
+/// Receive maximum if peer does not advertise it, MQTT-3.1.2.11.3
+pub(super) const DEFAULT_RECEIVE_MAX: usize = 65535;
 
 pub(crate) struct MqttShared {
     pub(super) cap: Cell<usize>,
     queues: RefCell<MqttSharedQueues>,
     pub(super) inflight_idx: Cell<u16>,
+    /// Peer's topic alias maximum, limits outbound aliases
+    pub(super) topic_alias_max: Cell<u16>,
+    pub(super) aliases: RefCell<TopicAliases>,
     pub(super) pool: Rc<MqttSinkPool>,
     pub(super) state: State,
     pub(super) codec: codec::Codec,
+    pub(super) subscriptions: Option<Subscriptions>,
+    pub(super) disconnect: RefCell<Option<codec::Disconnect>>,
+    /// Last will with retain flag, passed to retained store on abnormal close
+    pub(super) will: RefCell<Option<codec::LastWill>>,
+    /// Client hook for received publish acks
+    pub(super) on_ack: RefCell<Option<OnAck>>,
+    /// Client ping is sent and PINGRESP is not received yet
+    pub(super) ping_pending: Cell<bool>,
+    /// Outbound broadcast shaping state
+    pub(super) shaper: Option<Shaper>,
+    /// Outbound QoS0 publish queue
+    pub(super) outbound: Option<Outbound>,
+    /// Inbound QoS2 packet ids acked with PUBREC, awaiting PUBREL
+    pub(super) qos2_received: RefCell<HashSet<NonZeroU16>>,
+    /// Distinct topics of inbound publishes, tracked if topics are limited
+    pub(super) topics: RefCell<HashSet<ByteString>>,
+    /// Write task flushes
+    pub(super) flushes: Rc<FlushStats>,
+    /// Close handle, created on first request
+    pub(super) close_handle: RefCell<Option<CloseHandle>>,
+    /// Topic filters of SUBSCRIBE packets that are not acked yet, counted
+    /// by subscription quota, with number of packets
+    pub(super) subscribing: RefCell<HashMap<ByteString, usize>>,
+    #[cfg(feature = "compress")]
+    pub(super) compression: Cell<Option<super::compress::Compression>>,
+}
+
+/// Topic alias to topic mappings of connection
+#[derive(Default)]
+pub(super) struct TopicAliases {
+    pub(super) inbound: HashMap<NonZeroU16, ByteString>,
+    pub(super) outbound: HashMap<NonZeroU16, ByteString>,
+}
+
+impl TopicAliases {
+    /// Mappings sorted by alias
+    pub(super) fn table(aliases: &HashMap<NonZeroU16, ByteString>) -> Vec<(u16, ByteString)> {
+        let mut table: Vec<_> = aliases.iter().map(|(a, t)| (a.get(), t.clone())).collect();
+        table.sort_by_key(|(alias, _)| *alias);
+        table
+    }
 }
 
+pub(super) type OnAck = Box<dyn Fn(NonZeroU16, codec::PublishAckReason)>;
+
+/// Active subscriptions by topic filter, tracked by client
+pub(super) type Subscriptions =
+    Rc<RefCell<HashMap<ByteString, (codec::SubscriptionOptions, Option<NonZeroU32>)>>>;
+
 pub(super) struct MqttSharedQueues {
     pub(super) inflight: HashMap<u16, (pool::Sender<Ack>, AckType)>,
     pub(super) inflight_order: VecDeque<u16>,
     pub(super) waiters: VecDeque<pool::Sender<()>>,
+    /// Packet ids of timed out publishes, late acks of these ids are ignored
+    pub(super) cancelled: HashSet<u16>,
+}
+
+impl MqttSharedQueues {
+    /// Check if packet id is in-flight or waits for ack of timed out publish
+    pub(super) fn in_use(&self, idx: u16) -> bool {
+        self.inflight.contains_key(&idx) || self.cancelled.contains(&idx)
+    }
 }
 
 pub(super) struct MqttSinkPool {
     pub(super) queue: pool::Pool<Ack>,
     pub(super) waiters: pool::Pool<()>,
     pub(super) pool: Cell<PoolRef>,
+    pub(super) memory: RefCell<Option<Rc<MemoryTracker>>>,
+    pub(super) sys: RefCell<Option<Rc<SysTopics>>>,
+    pub(super) retained: Rc<RetainedStore>,
+    pub(super) write_capacity: Cell<usize>,
+    pub(super) max_read_buffer: Cell<usize>,
+    pub(super) shape_rules: RefCell<Vec<Rc<ShapeRule>>>,
+    pub(super) lazy_keep_alive: Cell<Option<Seconds>>,
+    pub(super) forced_keep_alive: Cell<Option<Seconds>>,
+    pub(super) max_connection_age: Cell<Seconds>,
+    pub(super) auth_timeout: Cell<Seconds>,
+    pub(super) clock: RefCell<Clock>,
+    pub(super) qos0_violation: Cell<Qos0ViolationPolicy>,
+    pub(super) outbound: Cell<Option<(usize, OverflowPolicy)>>,
+    pub(super) max_distinct_topics: Cell<usize>,
+    pub(super) max_subscriptions: Cell<usize>,
+    pub(super) strict_ack_ids: Cell<bool>,
+    pub(super) strict_utf8: Cell<bool>,
+    #[cfg(feature = "prometheus")]
+    pub(super) metrics: RefCell<Option<super::Metrics>>,
 }
 
 impl Default for MqttSinkPool {
@@ -34,6 +125,63 @@ impl Default for MqttSinkPool {
             queue: pool::new(),
             waiters: pool::new(),
             pool: Cell::new(PoolId::P5.pool_ref()),
+            memory: RefCell::new(None),
+            sys: RefCell::new(None),
+            retained: Rc::default(),
+            write_capacity: Cell::new(0),
+            max_read_buffer: Cell::new(0),
+            shape_rules: RefCell::new(Vec::new()),
+            lazy_keep_alive: Cell::new(None),
+            forced_keep_alive: Cell::new(None),
+            max_connection_age: Cell::new(Seconds::ZERO),
+            auth_timeout: Cell::new(Seconds::ZERO),
+            clock: RefCell::new(Clock::system()),
+            qos0_violation: Cell::new(Qos0ViolationPolicy::Drop),
+            outbound: Cell::new(None),
+            max_distinct_topics: Cell::new(0),
+            max_subscriptions: Cell::new(0),
+            strict_ack_ids: Cell::new(true),
+            strict_utf8: Cell::new(false),
+            #[cfg(feature = "prometheus")]
+            metrics: RefCell::new(None),
+        }
+    }
+}
+
+impl MqttSinkPool {
+    /// Create connection state.
+    ///
+    /// Write buffer capacity is applied to memory pool, so write buffers
+    /// get allocated with configured capacity.
+    pub(super) fn state(&self) -> State {
+        let pool = self.pool.get();
+        let cap = self.write_capacity.get();
+        if cap != 0 {
+            let cap = cap.min(u16::MAX as usize);
+            let (hw, lw) = pool.write_params().unpack();
+            if hw != cap {
+                let lw = if lw < cap { lw } else { cap / 4 };
+                pool.set_write_params(cap as u16, lw as u16);
+            }
+        }
+        State::with_memory_pool(pool)
+    }
+
+    /// Track connection memory usage, `$SYS` topics and connection age
+    /// if enabled
+    pub(super) fn track(&self, sink: &MqttSink) {
+        let age = self.max_connection_age.get();
+        if age.non_zero() {
+            sink.close_after(
+                self.clock.borrow().sleep(age),
+                codec::DisconnectReasonCode::MaximumConnectTime,
+            );
+        }
+        if let Some(ref tracker) = *self.memory.borrow() {
+            tracker.register(sink.clone());
+        }
+        if let Some(ref sys) = *self.sys.borrow() {
+            sys.register(sink.clone());
         }
     }
 }
@@ -45,6 +193,14 @@ impl MqttShared {
         cap: usize,
         pool: Rc<MqttSinkPool>,
     ) -> Self {
+        codec.set_max_read_buffer(pool.max_read_buffer.get());
+        codec.set_strict_utf8(pool.strict_utf8.get());
+        #[cfg(feature = "prometheus")]
+        if let Some(ref metrics) = *pool.metrics.borrow() {
+            codec.set_metrics(metrics.clone());
+        }
+        let shaper = Shaper::new(&pool.shape_rules.borrow());
+        let outbound = pool.outbound.get().map(|(size, policy)| Outbound::new(size, policy));
         Self {
             state,
             pool,
@@ -54,31 +210,306 @@ impl MqttShared {
                 inflight: HashMap::default(),
                 inflight_order: VecDeque::with_capacity(8),
                 waiters: VecDeque::new(),
+                cancelled: HashSet::default(),
             }),
             inflight_idx: Cell::new(0),
+            topic_alias_max: Cell::new(0),
+            aliases: RefCell::new(TopicAliases::default()),
+            subscriptions: Some(Subscriptions::default()),
+            disconnect: RefCell::new(None),
+            will: RefCell::new(None),
+            on_ack: RefCell::new(None),
+            ping_pending: Cell::new(false),
+            shaper,
+            outbound,
+            qos2_received: RefCell::new(HashSet::default()),
+            topics: RefCell::new(HashSet::default()),
+            flushes: Rc::default(),
+            close_handle: RefCell::new(None),
+            subscribing: RefCell::new(HashMap::default()),
+            #[cfg(feature = "compress")]
+            compression: Cell::new(None),
+        }
+    }
+
+    /// Ack packet of inbound publish
+    ///
+    /// QoS2 publishes are acked with PUBREC, packet id of accepted publish
+    /// is kept until PUBREL is received, MQTT-4.3.3-9.
+    pub(super) fn inbound_ack(&self, qos: codec::QoS, ack: codec::PublishAck) -> codec::Packet {
+        if qos == codec::QoS::ExactlyOnce {
+            if u8::from(ack.reason_code) < 0x80 {
+                self.qos2_received.borrow_mut().insert(ack.packet_id);
+            }
+            codec::Packet::PublishReceived(ack)
+        } else {
+            codec::Packet::PublishAck(ack)
+        }
+    }
+
+    /// Record topic of inbound publish
+    ///
+    /// Returns `false` if number of distinct topics exceeds limit.
+    pub(super) fn record_topic(&self, topic: &ByteString) -> bool {
+        let max = self.pool.max_distinct_topics.get();
+        if max == 0 {
+            return true;
+        }
+        let mut topics = self.topics.borrow_mut();
+        if !topics.contains(topic) {
+            if topics.len() >= max {
+                return false;
+            }
+            topics.insert(topic.clone());
+        }
+        true
+    }
+
+    /// Write encoded QoS0 publish directly to write buffer
+    ///
+    /// Returns `false` if publish is dropped by outbound queue.
+    pub(super) fn write_encoded(self: &Rc<Self>, buf: &Bytes) -> bool {
+        match self.outbound.as_ref().map(|outbound| (outbound, outbound.reserve(self, false))) {
+            Some((_, Slot::Drop)) => return false,
+            Some((outbound, Slot::Queue)) => outbound.push(self, buf.clone(), false),
+            _ => self.state.write().with_buf(|dst| {
+                self.reserve_write_buf(dst);
+                dst.extend_from_slice(buf)
+            }),
+        }
+        self.codec.add_encoded(buf.len());
+        true
+    }
+
+    /// Encode QoS0 publish to write buffer or to outbound queue
+    ///
+    /// `alias` is set if publish defines topic alias, such publish is not
+    /// dropped by outbound queue. Fails with `Dropped` error if publish is
+    /// dropped.
+    pub(super) fn write_qos0<F>(
+        self: &Rc<Self>,
+        alias: bool,
+        encode: F,
+    ) -> Result<(), error::SendPacketError>
+    where
+        F: FnOnce(&mut BytesMut) -> Result<(), error::EncodeError>,
+    {
+        match self.outbound.as_ref().map(|outbound| (outbound, outbound.reserve(self, alias))) {
+            Some((_, Slot::Drop)) => Err(error::SendPacketError::Dropped),
+            Some((outbound, Slot::Queue)) => {
+                let mut buf = BytesMut::new();
+                encode(&mut buf).map_err(error::SendPacketError::Encode)?;
+                outbound.push(self, buf.freeze(), alias);
+                Ok(())
+            }
+            _ => self
+                .state
+                .write()
+                .with_buf(|dst| {
+                    self.reserve_write_buf(dst);
+                    encode(dst)
+                })
+                .map_err(error::SendPacketError::Encode),
+        }
+    }
+
+    /// Make room in write buffer the way `Write::encode()` does
+    ///
+    /// Buffer grows by write high watermark of memory pool, reserving
+    /// exact packet size would reallocate full buffer on every write.
+    fn reserve_write_buf(&self, dst: &mut BytesMut) {
+        let (hw, lw) = self.state.memory_pool().write_params().unpack();
+        let remaining = dst.capacity() - dst.len();
+        if remaining < lw {
+            dst.reserve(hw - remaining);
+        }
+    }
+
+    /// Keep last will with retain flag if retained store is enabled
+    pub(super) fn set_will(&self, pkt: &codec::Connect) {
+        if let Some(ref will) = pkt.last_will {
+            if will.retain && self.pool.retained.is_enabled() {
+                *self.will.borrow_mut() = Some(will.clone());
+            }
+        }
+    }
+
+    /// Number of bytes of queued outbound publishes
+    pub(super) fn queued(&self) -> usize {
+        self.outbound.as_ref().map(|outbound| outbound.queued()).unwrap_or(0)
+    }
+
+    /// Number of bytes in write buffer
+    ///
+    /// Encoder counts bytes put to write buffer or outbound queue, io stream
+    /// counts bytes written to socket. Size is known without taking write
+    /// buffer, `Write::with_buf()` wakes write task if buffer is empty.
+    pub(super) fn write_buffered(&self) -> usize {
+        let pending = self.codec.encoded().saturating_sub(self.flushes.written.get());
+        (pending as usize).saturating_sub(self.queued())
+    }
+
+    #[cfg(feature = "compress")]
+    /// Max size of decompressed inbound payload, max inbound packet size
+    pub(super) fn decompress_limit(&self) -> usize {
+        match self.codec.max_inbound() {
+            0 => crate::types::MAX_PACKET_SIZE as usize,
+            size => size as usize,
         }
     }
 
+    /// Record topic alias of outbound publish
+    pub(super) fn track_alias(&self, pkt: &codec::Publish) {
+        if let Some(alias) = pkt.properties.topic_alias {
+            if !pkt.topic.is_empty() {
+                self.aliases.borrow_mut().outbound.insert(alias, pkt.topic.clone());
+            }
+        }
+    }
+
+    /// Check publish topic alias against peer's topic alias maximum
+    pub(super) fn topic_alias_valid(&self, pkt: &codec::Publish) -> bool {
+        pkt.properties
+            .topic_alias
+            .map(|a| a.get() <= self.topic_alias_max.get())
+            .unwrap_or(true)
+    }
+
     pub(super) fn with_queues<R>(&self, f: impl FnOnce(&mut MqttSharedQueues) -> R) -> R {
         let mut queues = self.queues.borrow_mut();
         f(&mut queues)
     }
 
     pub(super) fn has_credit(&self) -> bool {
-        self.cap.get() - self.queues.borrow().inflight.len() > 0
+        self.queues.borrow().inflight.len() < self.cap.get()
+    }
+
+    /// Forget in-flight packet that is not acked by peer, packet id stays
+    /// reserved until late ack is received
+    pub(super) fn cancel_inflight(&self, idx: u16) {
+        self.with_queues(|queues| {
+            if queues.inflight.remove(&idx).is_some() {
+                queues.inflight_order.retain(|i| *i != idx);
+                // packet id is not reused until peer acks it
+                queues.cancelled.insert(idx);
+
+                // wake up queued request (receive max limit)
+                while let Some(tx) = queues.waiters.pop_front() {
+                    if tx.send(()).is_ok() {
+                        break;
+                    }
+                }
+            }
+        })
     }
 
+    /// Next packet id, ids of timed out publishes are skipped
     pub(super) fn next_id(&self) -> u16 {
-        let idx = self.inflight_idx.get() + 1;
-        self.inflight_idx.set(idx);
-        if idx == u16::max_value() {
-            self.inflight_idx.set(0);
-            u16::max_value()
-        } else {
-            self.inflight_idx.set(idx);
-            idx
+        loop {
+            let idx = self.inflight_idx.get() + 1;
+            let idx = if idx == u16::MAX {
+                self.inflight_idx.set(0);
+                u16::MAX
+            } else {
+                self.inflight_idx.set(idx);
+                idx
+            };
+            if !self.with_queues(|q| q.cancelled.contains(&idx)) {
+                return idx;
+            }
+        }
+    }
+
+    /// Topic filters counted by subscription quota, active filters and
+    /// filters of SUBSCRIBE packets that are not acked yet
+    pub(super) fn counted_filters(&self) -> HashSet<ByteString> {
+        let mut filters: HashSet<_> = self
+            .subscriptions
+            .as_ref()
+            .map(|subs| subs.borrow().keys().cloned().collect())
+            .unwrap_or_default();
+        filters.extend(self.subscribing.borrow().keys().cloned());
+        filters
+    }
+
+    /// Count topic filters of SUBSCRIBE packet until packet is acked
+    pub(super) fn reserve_filters(self: &Rc<Self>, filters: Vec<ByteString>) -> Reservation {
+        let mut subscribing = self.subscribing.borrow_mut();
+        for filter in &filters {
+            *subscribing.entry(filter.clone()).or_insert(0) += 1;
+        }
+        Reservation { shared: self.clone(), filters }
+    }
+
+    /// Remember granted topic filters
+    pub(super) fn track_subscribe(
+        &self,
+        id: Option<NonZeroU32>,
+        filters: Vec<(ByteString, codec::SubscriptionOptions)>,
+        status: &[codec::SubscribeAckReason],
+    ) {
+        if let Some(ref subs) = self.subscriptions {
+            let mut subs = subs.borrow_mut();
+            for ((filter, opts), status) in filters.into_iter().zip(status) {
+                match status {
+                    codec::SubscribeAckReason::GrantedQos0
+                    | codec::SubscribeAckReason::GrantedQos1
+                    | codec::SubscribeAckReason::GrantedQos2 => {
+                        subs.insert(filter, (opts, id));
+                    }
+                    _ => (),
+                }
+            }
         }
     }
+
+    /// Active topic filters and options, ordered by topic filter
+    pub(super) fn subscriptions(&self) -> Vec<(ByteString, codec::SubscriptionOptions)> {
+        let mut subs: Vec<_> = self
+            .subscriptions
+            .as_ref()
+            .map(|subs| {
+                subs.borrow().iter().map(|(f, (opts, _))| (f.clone(), opts.clone())).collect()
+            })
+            .unwrap_or_default();
+        subs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        subs
+    }
+
+    /// Forget unsubscribed topic filters
+    pub(super) fn track_unsubscribe(
+        &self,
+        filters: &[ByteString],
+        status: &[codec::UnsubscribeAckReason],
+    ) {
+        if let Some(ref subs) = self.subscriptions {
+            let mut subs = subs.borrow_mut();
+            for (filter, status) in filters.iter().zip(status) {
+                match status {
+                    codec::UnsubscribeAckReason::Success
+                    | codec::UnsubscribeAckReason::NoSubscriptionExisted => {
+                        subs.remove(filter);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+impl FlushSource for MqttShared {
+    fn flush_stats(&self) -> Option<Rc<FlushStats>> {
+        // flushes are also waited for by `MqttSink::publish_qos0_flushed()`
+        Some(self.flushes.clone())
+    }
+
+    fn read_limit(&self) -> Option<Rc<ReadLimit>> {
+        self.codec.read_limit()
+    }
+
+    fn encoded(&self) -> u64 {
+        self.codec.encoded()
+    }
 }
 
 impl Encoder for MqttShared {
@@ -101,11 +532,12 @@ impl Decoder for MqttShared {
     }
 }
 
+/// Expected ack, subscribe and unsubscribe acks carry number of topic filters
 #[derive(Copy, Clone)]
 pub(super) enum AckType {
     Publish,
-    Subscribe,
-    Unsubscribe,
+    Subscribe(usize),
+    Unsubscribe(usize),
 }
 
 pub(super) enum Ack {
@@ -158,19 +590,51 @@ impl Ack {
     pub(super) fn is_match(&self, tp: AckType) -> bool {
         match (self, tp) {
             (Ack::Publish(_), AckType::Publish) => true,
-            (Ack::Subscribe(_), AckType::Subscribe) => true,
-            (Ack::Unsubscribe(_), AckType::Unsubscribe) => true,
+            (Ack::Subscribe(_), AckType::Subscribe(_)) => true,
+            (Ack::Unsubscribe(_), AckType::Unsubscribe(_)) => true,
             (_, _) => false,
         }
     }
+
+    /// Check if ack has reason code for every topic filter, MQTT-3.9.3-1, MQTT-3.11.3-1
+    pub(super) fn is_complete(&self, tp: AckType) -> bool {
+        match (self, tp) {
+            (Ack::Subscribe(pkt), AckType::Subscribe(filters)) => pkt.status.len() == filters,
+            (Ack::Unsubscribe(pkt), AckType::Unsubscribe(filters)) => {
+                pkt.status.len() == filters
+            }
+            (_, _) => true,
+        }
+    }
 }
 
 impl AckType {
     pub(super) fn name(&self) -> &'static str {
         match self {
             AckType::Publish => "PublishAck",
-            AckType::Subscribe => "SubscribeAck",
-            AckType::Unsubscribe => "UnsubscribeAck",
+            AckType::Subscribe(_) => "SubscribeAck",
+            AckType::Unsubscribe(_) => "UnsubscribeAck",
+        }
+    }
+}
+
+/// Topic filters of SUBSCRIBE packet counted by subscription quota,
+/// released on drop
+pub(super) struct Reservation {
+    shared: Rc<MqttShared>,
+    filters: Vec<ByteString>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut subscribing = self.shared.subscribing.borrow_mut();
+        for filter in &self.filters {
+            if let Some(count) = subscribing.get_mut(filter) {
+                *count -= 1;
+                if *count == 0 {
+                    subscribing.remove(filter);
+                }
+            }
         }
     }
 }
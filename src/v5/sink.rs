@@ -1,14 +1,19 @@
 use std::future::{ready, Future};
 use std::{fmt, num::NonZeroU16, num::NonZeroU32, rc::Rc};
 
-use ntex::util::{ByteString, Bytes, Either, Ready};
+use ntex::codec::Encoder;
+use ntex::time::{Millis, Seconds};
+use ntex::util::{poll_fn, ByteString, Bytes, BytesMut, Either, HashSet, Ready};
 
+use super::close::CloseHandle;
 use super::codec;
-use super::error::{ProtocolError, PublishQos1Error, SendPacketError};
-use super::shared::{Ack, AckType, MqttShared};
-use crate::types::QoS;
+use super::error::{EncodeError, ProtocolError, PublishQos1Error, SendPacketError};
+use super::publish::Publish;
+use super::shaper::Shaped;
+use super::shared::{Ack, AckType, MqttShared, TopicAliases};
+use crate::{topic::Topic, types::QoS, utils::decode_variable_length};
 
-pub struct MqttSink(Rc<MqttShared>);
+pub struct MqttSink(pub(super) Rc<MqttShared>);
 
 impl Clone for MqttSink {
     fn clone(&self) -> Self {
@@ -29,25 +34,45 @@ impl MqttSink {
     /// Get client's receive credit
     pub fn credit(&self) -> usize {
         let cap = self.0.cap.get();
-        cap - self.0.with_queues(|q| q.inflight.len())
+        cap.saturating_sub(self.0.with_queues(|q| q.inflight.len()))
+    }
+
+    /// Number of distinct topics client published to
+    ///
+    /// Topics are tracked only if `MqttServer::max_distinct_topics()` is set.
+    pub fn distinct_topics(&self) -> usize {
+        self.0.topics.borrow().len()
     }
 
     /// Get notification when packet could be send to the peer.
     ///
     /// Result indicates if connection is alive
+    ///
+    /// With `OverflowPolicy::Backpressure` outbound queue, notification also waits
+    /// until queue has space.
     pub fn ready(&self) -> impl Future<Output = bool> {
         if self.0.state.is_open() {
-            self.0
-                .with_queues(|q| {
-                    if q.inflight.len() >= self.0.cap.get() {
-                        let (tx, rx) = self.0.pool.waiters.channel();
-                        q.waiters.push_back(tx);
-                        return Some(rx);
+            let credit = self.0.with_queues(|q| {
+                if q.inflight.len() >= self.0.cap.get() {
+                    let (tx, rx) = self.0.pool.waiters.channel();
+                    q.waiters.push_back(tx);
+                    return Some(rx);
+                }
+                None
+            });
+            let queue = self.0.outbound.as_ref().and_then(|outbound| outbound.wait(&self.0));
+            if credit.is_none() && queue.is_none() {
+                return Either::Left(ready(true));
+            }
+
+            Either::Right(async move {
+                for rx in credit.into_iter().chain(queue) {
+                    if rx.await.is_err() {
+                        return false;
                     }
-                    None
-                })
-                .map(|rx| Either::Right(async move { rx.await.is_ok() }))
-                .unwrap_or_else(|| Either::Left(ready(true)))
+                }
+                true
+            })
         } else {
             Either::Left(ready(false))
         }
@@ -63,22 +88,61 @@ impl MqttSink {
                 .encode(codec::Packet::Disconnect(codec::Disconnect::default()), &self.0.codec);
             self.0.state.close();
         }
-        self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
-        });
+        self.fail_pending();
     }
 
     /// Close mqtt connection
+    ///
+    /// DISCONNECT packet is written before connection closes, for example
+    /// connection displaced by client id takeover could be closed with
+    /// `SessionTakenOver` reason, so peer could tell takeover from failure.
     pub fn close_with_reason(&self, pkt: codec::Disconnect) {
         if self.is_open() {
             let _ = self.0.state.write().encode(codec::Packet::Disconnect(pkt), &self.0.codec);
             self.0.state.close();
         }
-        self.0.with_queues(|q| {
-            q.inflight.clear();
-            q.waiters.clear();
-        });
+        self.fail_pending();
+    }
+
+    /// Get thread safe close handle of connection
+    ///
+    /// Handle could be used to close connection from other thread, see
+    /// `CloseHandle::close()`. Handle is created on first call, following
+    /// calls return clones of the same handle.
+    pub fn close_handle(&self) -> CloseHandle {
+        self.0.close_handle.borrow_mut().get_or_insert_with(|| CloseHandle::new(self)).clone()
+    }
+
+    /// Inbound topic aliases, set by peer
+    ///
+    /// Returns alias to topic mappings sorted by alias.
+    pub fn alias_table(&self) -> Vec<(u16, ByteString)> {
+        TopicAliases::table(&self.0.aliases.borrow().inbound)
+    }
+
+    /// Outbound topic aliases, set by publishes sent with this sink
+    ///
+    /// Returns alias to topic mappings sorted by alias.
+    pub fn outbound_alias_table(&self) -> Vec<(u16, ByteString)> {
+        TopicAliases::table(&self.0.aliases.borrow().outbound)
+    }
+
+    #[cfg(feature = "trace")]
+    /// Attach protocol trace stream to connection
+    ///
+    /// Stream reports packets sent and received after this call, protocol
+    /// errors and connection close. Only one stream could be attached,
+    /// new stream replaces previous one.
+    pub fn protocol_events(&self) -> super::trace::ProtocolEvents {
+        self.0.codec.trace_events()
+    }
+
+    #[cfg(feature = "prometheus")]
+    /// Render connection metrics in Prometheus text format
+    ///
+    /// Connection counters are kept regardless of server-wide metrics.
+    pub fn metrics_text(&self) -> String {
+        self.0.codec.metrics_text()
     }
 
     pub(super) fn send(&self, pkt: codec::Packet) {
@@ -87,19 +151,128 @@ impl MqttSink {
 
     /// Send ping
     pub(super) fn ping(&self) -> bool {
-        self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok()
+        let res =
+            self.0.state.write().encode(codec::Packet::PingRequest, &self.0.codec).is_ok();
+        if res {
+            self.0.ping_pending.set(true);
+        }
+        res
+    }
+
+    /// Record received PINGRESP packet
+    pub(super) fn pong(&self) {
+        self.0.ping_pending.set(false);
+    }
+
+    /// Check if last sent ping is not answered yet
+    pub(super) fn ping_pending(&self) -> bool {
+        self.0.ping_pending.get()
+    }
+
+    /// Size of pending write buffer
+    pub(super) fn write_buf_len(&self) -> usize {
+        self.0.write_buffered()
+    }
+
+    /// Size of pending read buffer
+    pub(super) fn read_buf_len(&self) -> usize {
+        self.0.state.read().with_buf(|buf| buf.len())
+    }
+
+    /// Store DISCONNECT packet received from peer
+    pub(super) fn set_disconnect(&self, pkt: &codec::Disconnect) {
+        *self.0.disconnect.borrow_mut() = Some(pkt.clone());
+    }
+
+    /// Record subscriptions granted to peer
+    pub(super) fn track_subscribe(
+        &self,
+        id: Option<NonZeroU32>,
+        filters: Vec<(ByteString, codec::SubscriptionOptions)>,
+        status: &[codec::SubscribeAckReason],
+    ) {
+        self.0.track_subscribe(id, filters, status)
+    }
+
+    /// Forget unsubscribed topic filters
+    pub(super) fn track_unsubscribe(
+        &self,
+        filters: &[ByteString],
+        status: &[codec::UnsubscribeAckReason],
+    ) {
+        self.0.track_unsubscribe(filters, status)
+    }
+
+    /// Active topic filters and options
+    pub(crate) fn subscriptions(&self) -> Vec<(ByteString, codec::SubscriptionOptions)> {
+        self.0.subscriptions()
+    }
+
+    /// Check if any of active subscriptions matches topic
+    pub(super) fn is_subscribed(&self, topic: &str) -> bool {
+        self.0
+            .subscriptions
+            .as_ref()
+            .map(|subs| {
+                subs.borrow().keys().any(|filter| {
+                    filter.parse::<Topic>().map(|f| f.matches_str(topic)).unwrap_or(false)
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Close connection with `reason` once `delay` completes
+    pub(super) fn close_after<F>(&self, delay: F, reason: codec::DisconnectReasonCode)
+    where
+        F: Future<Output = ()> + 'static,
+    {
+        let sink = self.clone();
+        ntex::rt::spawn(async move {
+            let state = sink.0.state.clone();
+            if let Either::Left(_) = crate::utils::select(delay, state.on_disconnect()).await {
+                log::trace!("Connection age is reached, closing");
+                sink.close_with_reason(codec::Disconnect::new(reason));
+            }
+        });
     }
 
     /// Close mqtt connection, dont send disconnect message
     pub(super) fn drop_sink(&self) {
+        self.fail_pending();
+        self.0.state.close();
+    }
+
+    /// Fail all pending publish, subscribe and unsubscribe futures
+    ///
+    /// Pending futures and credit waiters resolve with `Disconnected` error.
+    pub(super) fn fail_pending(&self) {
         self.0.with_queues(|q| {
-            q.waiters.clear();
+            if !q.inflight.is_empty() {
+                log::trace!("Fail {} pending packets", q.inflight.len());
+            }
             q.inflight.clear();
+            q.inflight_order.clear();
+            q.waiters.clear();
+            q.cancelled.clear();
         });
-        self.0.state.close();
+        if let Some(ref outbound) = self.0.outbound {
+            outbound.clear();
+        }
+        self.0.flushes.flushed.notify();
     }
 
-    pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<(), ProtocolError> {
+    /// Complete in-flight packet with ack from peer
+    ///
+    /// Returns `false` if ack with unknown packet id is ignored.
+    pub(super) fn pkt_ack(&self, pkt: Ack) -> Result<bool, ProtocolError> {
+        if !self.0.with_queues(|q| q.inflight.contains_key(&pkt.packet_id())) {
+            if self.0.with_queues(|q| q.cancelled.remove(&pkt.packet_id())) {
+                log::trace!("Late ack of timed out publish {} is ignored", pkt.packet_id());
+                return Ok(false);
+            }
+            return self.unknown_ack(pkt.packet_id()).map(|_| false);
+        }
+
         self.0.with_queues(|queues| loop {
             // check ack order
             if let Some(idx) = queues.inflight_order.pop_front() {
@@ -127,6 +300,10 @@ impl MqttSink {
                                 tp.name(),
                             ));
                         }
+                        if !pkt.is_complete(tp) {
+                            log::trace!("MQTT protocol error, ack reason codes do not match");
+                            return Err(ProtocolError::ReasonCodesMismatch);
+                        }
                         let _ = tx.send(pkt);
 
                         // wake up queued request (receive max limit)
@@ -135,7 +312,7 @@ impl MqttSink {
                                 break;
                             }
                         }
-                        return Ok(());
+                        return Ok(true);
                     } else {
                         log::error!("In-flight state inconsistency")
                     }
@@ -147,14 +324,36 @@ impl MqttSink {
         })
     }
 
+    /// Handle ack with packet id that is not in-flight, MQTT-4.3.2-1
+    pub(super) fn unknown_ack(&self, packet_id: u16) -> Result<(), ProtocolError> {
+        if self.0.pool.strict_ack_ids.get() {
+            log::trace!("MQTT protocol error, unknown packet id: {}", packet_id);
+            Err(ProtocolError::PacketIdMismatch)
+        } else {
+            log::trace!("Ack with unknown packet id {} is ignored", packet_id);
+            Ok(())
+        }
+    }
+
+    /// Call publish ack hook
+    pub(super) fn on_ack(&self, packet_id: NonZeroU16, reason: codec::PublishAckReason) {
+        if let Some(ref hook) = *self.0.on_ack.borrow() {
+            (*hook)(packet_id, reason);
+        }
+    }
+
     /// Create publish packet builder
-    pub fn publish<U>(&self, topic: U, payload: Bytes) -> PublishBuilder
+    ///
+    /// Payload is not copied, `Bytes` payload of inbound publish packet
+    /// could be forwarded as is.
+    pub fn publish<U, P>(&self, topic: U, payload: P) -> PublishBuilder
     where
         ByteString: From<U>,
+        P: Into<Bytes>,
     {
         PublishBuilder {
             packet: codec::Publish {
-                payload,
+                payload: payload.into(),
                 dup: false,
                 retain: false,
                 topic: topic.into(),
@@ -166,6 +365,144 @@ impl MqttSink {
         }
     }
 
+    /// Send publish packet with QoS 0 and wait until it is flushed
+    ///
+    /// Returned future resolves once write buffer up to and including
+    /// publish packet is written to io stream. This is not a delivery
+    /// confirmation, it only indicates that data is handed over to OS.
+    /// Fails with `Disconnected` error if connection is closed before
+    /// publish gets flushed, or with `Dropped` error if publish is dropped
+    /// by outbound queue.
+    pub fn publish_qos0_flushed<U, P>(
+        &self,
+        topic: U,
+        payload: P,
+    ) -> impl Future<Output = Result<(), SendPacketError>>
+    where
+        ByteString: From<U>,
+        P: Into<Bytes>,
+    {
+        let shared = self.0.clone();
+        let res = self.publish(topic, payload).send_at_most_once();
+        let target = shared.codec.encoded();
+
+        async move {
+            res?;
+            let flushed = shared.flushes.flushed.wait();
+            loop {
+                if shared.state.is_io_err() {
+                    return Err(SendPacketError::Disconnected);
+                }
+                if shared.flushes.written.get() >= target {
+                    return Ok(());
+                }
+                if !shared.state.is_open() {
+                    return Err(SendPacketError::Disconnected);
+                }
+                poll_fn(|cx| flushed.poll_ready(cx)).await;
+            }
+        }
+    }
+
+    /// Send publish packet with QoS 1 and return allocated packet id
+    ///
+    /// Packet id is allocated synchronously, so it could be logged before
+    /// ack is received and correlated with peer logs. Id stays in use until
+    /// peer acks publish or returned future gets dropped before publish
+    /// is sent.
+    pub fn publish_tracked<U, P>(
+        &self,
+        topic: U,
+        payload: P,
+    ) -> (u16, impl Future<Output = Result<codec::PublishAck, PublishQos1Error>>)
+    where
+        ByteString: From<U>,
+        P: Into<Bytes>,
+    {
+        let idx = self.0.next_id();
+        (idx, self.publish(topic, payload).packet_id(idx).send_at_least_once())
+    }
+
+    /// Create publish packet builder for forwarding of inbound publish
+    ///
+    /// Topic, payload and publish properties are preserved, user properties
+    /// are kept in original order, properties added with
+    /// `PublishBuilder::user_property()` are appended. Topic alias and
+    /// subscription identifiers are scoped to inbound connection, they are
+    /// not forwarded. Inbound topic alias is not resolved, so packet topic
+    /// must not be empty.
+    pub fn forward(&self, publish: &Publish) -> PublishBuilder {
+        let pkt = publish.packet();
+        let mut properties = pkt.properties.clone();
+        properties.topic_alias = None;
+        properties.subscription_ids = None;
+
+        self.publish(pkt.topic.clone(), pkt.payload.clone())
+            .properties(move |props| *props = properties)
+    }
+
+    #[cfg(feature = "json")]
+    /// Create publish packet builder with `application/json` payload
+    ///
+    /// Value gets serialized to JSON, `content_type` and payload format
+    /// indicator properties are set.
+    pub fn publish_json<U, T>(
+        &self,
+        topic: U,
+        value: &T,
+    ) -> Result<PublishBuilder, serde_json::Error>
+    where
+        ByteString: From<U>,
+        T: serde::Serialize,
+    {
+        let payload = serde_json::to_vec(value)?;
+        Ok(self.publish(topic, payload).properties(|props| {
+            props.content_type =
+                Some(ByteString::from_static(super::publish::JSON_CONTENT_TYPE));
+            props.is_utf8_payload = Some(true);
+        }))
+    }
+
+    /// Send QoS0 publish packet without properties
+    ///
+    /// Packet is encoded directly into write buffer, topic and payload
+    /// are not copied into intermediate publish packet.
+    /// Encoding takes 60ns against 190-250ns of `send_at_most_once()` for
+    /// 256 bytes payload, see `benches/publish_qos0.rs`.
+    pub fn publish_qos0_raw(&self, topic: &str, payload: &[u8]) -> Result<(), SendPacketError> {
+        #[cfg(feature = "compress")]
+        if self.0.compression.get().is_some() {
+            return self
+                .publish(ByteString::from(topic), Bytes::copy_from_slice(payload))
+                .send_at_most_once();
+        }
+
+        if self.0.state.is_open() {
+            log::trace!("Publish (QoS-0) to {:?}", topic);
+            self.0
+                .write_qos0(false, |buf| self.0.codec.encode_publish_qos0(topic, payload, buf))
+        } else {
+            log::error!("Mqtt sink is disconnected");
+            Err(SendPacketError::Disconnected)
+        }
+    }
+
+    /// Send publish packet with QoS 1 and wait for ack at most `timeout`
+    ///
+    /// See `PublishBuilder::send_at_least_once_timeout()`.
+    pub fn publish_with_timeout<U, P>(
+        &self,
+        topic: U,
+        payload: P,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>>
+    where
+        ByteString: From<U>,
+        P: Into<Bytes>,
+    {
+        self.publish(topic, payload).send_at_least_once_timeout(timeout)
+    }
+
     /// Create subscribe packet builder
     pub fn subscribe(&self, id: Option<NonZeroU32>) -> SubscribeBuilder {
         SubscribeBuilder {
@@ -192,6 +529,141 @@ impl MqttSink {
             shared: self.0.clone(),
         }
     }
+
+    /// Change active subscriptions to `desired` set
+    ///
+    /// Desired topic filters are compared with tracked subscriptions of
+    /// connection, new filters and filters with changed options are sent with
+    /// one SUBSCRIBE packet, filters that are not desired anymore are sent
+    /// with one UNSUBSCRIBE packet. Unchanged subscriptions are not sent.
+    /// Result contains reason code of every changed topic filter.
+    ///
+    /// Diff is computed when method is called, returned future sends packets.
+    pub fn set_subscriptions<I>(
+        &self,
+        desired: I,
+    ) -> impl Future<Output = Result<Vec<(ByteString, SubscriptionChange)>, SendPacketError>>
+    where
+        I: IntoIterator<Item = (ByteString, codec::SubscriptionOptions)>,
+    {
+        let desired: Vec<_> = desired.into_iter().collect();
+        let (subscribe, unsubscribe) = match self.0.subscriptions {
+            Some(ref subs) => {
+                let subs = subs.borrow();
+                let subscribe: Vec<_> = desired
+                    .iter()
+                    .filter(|(filter, opts)| subs.get(filter).map(|(o, _)| o) != Some(opts))
+                    .cloned()
+                    .collect();
+                let desired: HashSet<_> = desired.iter().map(|(filter, _)| filter).collect();
+                let mut unsubscribe: Vec<_> =
+                    subs.keys().filter(|f| !desired.contains(f)).cloned().collect();
+                unsubscribe.sort_unstable();
+                (subscribe, unsubscribe)
+            }
+            None => (desired, Vec::new()),
+        };
+        let sink = self.clone();
+
+        async move {
+            let mut result = Vec::with_capacity(subscribe.len() + unsubscribe.len());
+            if !subscribe.is_empty() {
+                let filters: Vec<_> = subscribe.iter().map(|(f, _)| f.clone()).collect();
+                let ack = subscribe
+                    .into_iter()
+                    .fold(sink.subscribe(None), |b, (filter, opts)| {
+                        b.topic_filter(filter, opts)
+                    })
+                    .send()
+                    .await?;
+                result.extend(
+                    filters
+                        .into_iter()
+                        .zip(ack.status.into_iter().map(SubscriptionChange::Subscribed)),
+                );
+            }
+            if !unsubscribe.is_empty() {
+                let ack = unsubscribe
+                    .iter()
+                    .fold(sink.unsubscribe(), |b, filter| b.topic_filter(filter.clone()))
+                    .send()
+                    .await?;
+                result.extend(
+                    unsubscribe
+                        .into_iter()
+                        .zip(ack.status.into_iter().map(SubscriptionChange::Unsubscribed)),
+                );
+            }
+            Ok(result)
+        }
+    }
+}
+
+/// Publish message to a set of sinks with QoS 0.
+///
+/// Publish packet gets encoded only once, encoded bytes are shared between
+/// all recipients and written directly to connection write buffers. For `N`
+/// subscribers this performs one encode instead of `N`, payload bytes are copied
+/// to each write buffer without intermediate allocations. For 100 recipients
+/// and 256 bytes payload it takes 100-160ns per recipient against 215-275ns of
+/// `send_at_most_once()`, see `benches/broadcast.rs`. Packet properties are
+/// sent as is, topic alias must not be set.
+///
+/// Closed sinks and sinks with max packet size lower than encoded packet size
+/// are skipped. Publishes to topics shaped with `MqttServer::shape_topic()`
+/// are written, queued or dropped according to per connection rate.
+/// Returns number of sinks packet was written or queued to.
+pub fn broadcast(sinks: &[MqttSink], publish: &codec::Publish) -> usize {
+    let mut packet = publish.clone();
+    packet.qos = QoS::AtMostOnce;
+    packet.packet_id = None;
+    packet.dup = false;
+
+    let mut buf = BytesMut::new();
+    if let Err(err) = codec::Codec::new().encode(codec::Packet::Publish(packet), &mut buf) {
+        log::error!("Cannot encode broadcast publish packet: {:?}", err);
+        return 0;
+    }
+    let buf = buf.freeze();
+    let size = match decode_variable_length(&buf[1..]) {
+        Ok(Some((size, _))) => size,
+        _ => return 0,
+    };
+    log::trace!("Broadcast (QoS-0) to {:?}, recipients: {}", publish.topic, sinks.len());
+
+    let mut count = 0;
+    for sink in sinks {
+        let shared = &sink.0;
+        let max_size = shared.codec.max_outbound();
+        if !shared.state.is_open() || (max_size != 0 && size > max_size) {
+            continue;
+        }
+        if let Some(ref shaper) = shared.shaper {
+            match shaper.shape(sink, &publish.topic, &buf) {
+                Shaped::Send => (),
+                Shaped::Queued => {
+                    count += 1;
+                    continue;
+                }
+                Shaped::Dropped => continue,
+            }
+        }
+        if shared.write_encoded(&buf) {
+            count += 1;
+        }
+    }
+    count
+}
+
+impl<St> crate::Session<MqttSink, St> {
+    /// Close connection with DISCONNECT packet with `reason` code
+    ///
+    /// Session could be closed from any task of connection's worker, use
+    /// `MqttSink::close_handle()` to close connection from other thread.
+    pub fn close(&self, reason: codec::DisconnectReasonCode) {
+        self.sink()
+            .close_with_reason(codec::Disconnect { reason_code: reason, ..Default::default() });
+    }
 }
 
 impl fmt::Debug for MqttSink {
@@ -200,6 +672,15 @@ impl fmt::Debug for MqttSink {
     }
 }
 
+/// Change of topic filter subscription, see `MqttSink::set_subscriptions()`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SubscriptionChange {
+    /// Topic filter is subscribed or its options are changed
+    Subscribed(codec::SubscribeAckReason),
+    /// Topic filter is unsubscribed
+    Unsubscribed(codec::UnsubscribeAckReason),
+}
+
 pub struct PublishBuilder {
     shared: Rc<MqttShared>,
     packet: codec::Publish,
@@ -248,12 +729,34 @@ impl PublishBuilder {
         f(&mut self.packet.properties);
     }
 
+    /// Append user property
+    pub fn user_property(mut self, key: ByteString, value: ByteString) -> Self {
+        self.packet.properties.user_properties.push((key, value));
+        self
+    }
+
     /// Send publish packet with QoS 0
     pub fn send_at_most_once(self) -> Result<(), SendPacketError> {
-        let packet = self.packet;
+        #[allow(unused_mut)]
+        let mut packet = self.packet;
+        #[cfg(feature = "compress")]
+        if let Some(alg) = self.shared.compression.get() {
+            super::compress::compress_publish(&mut packet, alg);
+        }
 
-        if self.shared.state.is_open() {
+        if !self.shared.topic_alias_valid(&packet) {
+            log::error!("Topic alias is greater than peer's topic alias maximum");
+            Err(SendPacketError::TopicAliasInvalid)
+        } else if self.shared.state.is_open() {
             log::trace!("Publish (QoS-0) to {:?}", packet.topic);
+            self.shared.track_alias(&packet);
+            if self.shared.outbound.is_some() {
+                let shared = &self.shared;
+                let alias = packet.properties.topic_alias.is_some() && !packet.topic.is_empty();
+                return shared.write_qos0(alias, |buf| {
+                    shared.codec.encode(codec::Packet::Publish(packet), buf)
+                });
+            }
             self.shared
                 .state
                 .write()
@@ -273,8 +776,15 @@ impl PublishBuilder {
         let shared = self.shared;
         let mut packet = self.packet;
         packet.qos = QoS::AtLeastOnce;
+        #[cfg(feature = "compress")]
+        if let Some(alg) = shared.compression.get() {
+            super::compress::compress_publish(&mut packet, alg);
+        }
 
-        if shared.state.is_open() {
+        if !shared.topic_alias_valid(&packet) {
+            log::error!("Topic alias is greater than peer's topic alias maximum");
+            Either::Left(Either::Left(Ready::Err(PublishQos1Error::TopicAliasInvalid)))
+        } else if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
                 let (tx, rx) = shared.pool.waiters.channel();
@@ -293,6 +803,53 @@ impl PublishBuilder {
         }
     }
 
+    /// Send publish packet with QoS 1 and wait for ack at most `timeout`
+    ///
+    /// Future resolves with `PublishQos1Error::AckTimeout` if peer does not
+    /// ack publish in time. Packet id is not reused until late ack from
+    /// peer is received, late ack is ignored.
+    pub fn send_at_least_once_timeout(
+        mut self,
+        timeout: Millis,
+    ) -> impl Future<Output = Result<codec::PublishAck, PublishQos1Error>> {
+        let shared = self.shared.clone();
+        let idx = match self.packet.packet_id {
+            Some(idx) => idx.get(),
+            None => {
+                let idx = shared.next_id();
+                self.packet.packet_id = NonZeroU16::new(idx);
+                idx
+            }
+        };
+        let fut = self.send_at_least_once();
+
+        async move {
+            match ntex::time::timeout(timeout, fut).await {
+                Ok(res) => res,
+                Err(_) => {
+                    log::trace!("Publish ack timeout, packet id: {}", idx);
+                    shared.cancel_inflight(idx);
+                    Err(PublishQos1Error::AckTimeout)
+                }
+            }
+        }
+    }
+
+    /// Send publish packet with QoS 1 and call `f` with delivery result
+    ///
+    /// Publish is sent the same way as with `send_at_least_once()`, but
+    /// result is passed to callback instead of returned future. Callback
+    /// is called once peer acks publish or connection gets closed, so
+    /// delivery could be tracked without holding future per message.
+    /// Outbound QoS 2 publishes are not supported by sink.
+    pub fn send_at_least_once_with<F>(self, f: F)
+    where
+        F: FnOnce(Result<codec::PublishAck, PublishQos1Error>) + 'static,
+    {
+        let fut = self.send_at_least_once();
+        ntex::rt::spawn(async move { f(fut.await) });
+    }
+
     fn send_at_least_once_inner(
         mut packet: codec::Publish,
         shared: Rc<MqttShared>,
@@ -308,7 +865,7 @@ impl PublishBuilder {
             // publish ack channel
             let (tx, rx) = shared.pool.queue.channel();
 
-            if queues.inflight.contains_key(&idx) {
+            if queues.in_use(idx) {
                 return Err(PublishQos1Error::PacketIdInUse(idx));
             }
             queues.inflight.insert(idx, (tx, AckType::Publish));
@@ -323,6 +880,7 @@ impl PublishBuilder {
 
         // send publish to client
         log::trace!("Publish (QoS1) to {:#?}", packet);
+        shared.track_alias(&packet);
 
         match shared.state.write().encode(codec::Packet::Publish(packet), &shared.codec) {
             Ok(_) => {
@@ -371,6 +929,18 @@ impl SubscribeBuilder {
         self
     }
 
+    /// Set retain as published option for last added topic filter.
+    ///
+    /// If set, server keeps retain flag of messages forwarded to this
+    /// subscription, otherwise retain flag is cleared.
+    ///
+    /// panics if no topic filter is added
+    pub fn retain_as_published(mut self, val: bool) -> Self {
+        let (_, opts) = self.packet.topic_filters.last_mut().expect("topic filter is required");
+        opts.retain_as_published = val;
+        self
+    }
+
     /// Add user property
     pub fn property(mut self, key: ByteString, value: ByteString) -> Self {
         self.packet.user_properties.push((key, value));
@@ -379,11 +949,17 @@ impl SubscribeBuilder {
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send subscribe packet
+    ///
+    /// Packet without topic filters is malformed, MQTT-3.8.3-2, it is not
+    /// sent and `MalformedPacket` encode error is returned.
     pub async fn send(self) -> Result<codec::SubscribeAck, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
 
-        if shared.state.is_open() {
+        if packet.topic_filters.is_empty() {
+            log::trace!("Subscribe packet without topic filters");
+            Err(SendPacketError::Encode(EncodeError::MalformedPacket))
+        } else if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
                 let (tx, rx) = shared.pool.waiters.channel();
@@ -400,23 +976,34 @@ impl SubscribeBuilder {
                 // ack channel
                 let (tx, rx) = shared.pool.queue.channel();
 
-                if queues.inflight.contains_key(&idx) {
+                if queues.in_use(idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Subscribe));
+                queues
+                    .inflight
+                    .insert(idx, (tx, AckType::Subscribe(packet.topic_filters.len())));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
 
             // send subscribe to client
             log::trace!("Sending subscribe packet {:#?}", packet);
+            let track = shared
+                .subscriptions
+                .as_ref()
+                .map(|_| (packet.id, packet.topic_filters.clone()));
 
             match shared.state.write().encode(codec::Packet::Subscribe(packet), &shared.codec) {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
+                    let ack = rx
+                        .await
                         .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.subscribe())
+                        .map(|pkt| pkt.subscribe())?;
+                    if let Some((id, filters)) = track {
+                        shared.track_subscribe(id, filters, &ack.status);
+                    }
+                    Ok(ack)
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -459,11 +1046,17 @@ impl UnsubscribeBuilder {
 
     #[allow(clippy::await_holding_refcell_ref)]
     /// Send unsubscribe packet
+    ///
+    /// Packet without topic filters is malformed, MQTT-3.10.3-2, it is not
+    /// sent and `MalformedPacket` encode error is returned.
     pub async fn send(self) -> Result<codec::UnsubscribeAck, SendPacketError> {
         let shared = self.shared;
         let mut packet = self.packet;
 
-        if shared.state.is_open() {
+        if packet.topic_filters.is_empty() {
+            log::trace!("Unsubscribe packet without topic filters");
+            Err(SendPacketError::Encode(EncodeError::MalformedPacket))
+        } else if shared.state.is_open() {
             // handle client receive maximum
             if !shared.has_credit() {
                 let (tx, rx) = shared.pool.waiters.channel();
@@ -479,10 +1072,12 @@ impl UnsubscribeBuilder {
                 // ack channel
                 let (tx, rx) = shared.pool.queue.channel();
 
-                if queues.inflight.contains_key(&idx) {
+                if queues.in_use(idx) {
                     return Err(SendPacketError::PacketIdInUse(idx));
                 }
-                queues.inflight.insert(idx, (tx, AckType::Unsubscribe));
+                queues
+                    .inflight
+                    .insert(idx, (tx, AckType::Unsubscribe(packet.topic_filters.len())));
                 queues.inflight_order.push_back(idx);
                 Ok(rx)
             })?;
@@ -490,14 +1085,20 @@ impl UnsubscribeBuilder {
 
             // send unsubscribe to client
             log::trace!("Sending unsubscribe packet {:#?}", packet);
+            let track = shared.subscriptions.as_ref().map(|_| packet.topic_filters.clone());
 
             match shared.state.write().encode(codec::Packet::Unsubscribe(packet), &shared.codec)
             {
                 Ok(_) => {
                     // wait ack from peer
-                    rx.await
+                    let ack = rx
+                        .await
                         .map_err(|_| SendPacketError::Disconnected)
-                        .map(|pkt| pkt.unsubscribe())
+                        .map(|pkt| pkt.unsubscribe())?;
+                    if let Some(filters) = track {
+                        shared.track_unsubscribe(&filters, &ack.status);
+                    }
+                    Ok(ack)
                 }
                 Err(err) => Err(SendPacketError::Encode(err)),
             }
@@ -506,3 +1107,24 @@ impl UnsubscribeBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::State;
+
+    #[test]
+    fn test_publish_payload_not_copied() {
+        let shared =
+            MqttShared::new(State::new(), codec::Codec::new(), 16, Rc::new(Default::default()));
+        let sink = MqttSink::new(Rc::new(shared));
+
+        // inbound payload forwarded to outbound publish
+        let payload = Bytes::from(vec![0u8; 1024]);
+        let builder = sink.publish("test", payload.clone());
+        assert_eq!(builder.packet.payload.as_ptr(), payload.as_ptr());
+
+        let builder = sink.publish(ByteString::from_static("test"), &b"data"[..]);
+        assert_eq!(builder.packet.payload, Bytes::from_static(b"data"));
+    }
+}
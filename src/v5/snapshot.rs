@@ -0,0 +1,229 @@
+//! Session state snapshot
+//!
+//! Snapshot contains session state of mqtt connection, it could be used
+//! to move session to another process. Only state gets exported, live
+//! socket, unacknowledged outbound packets, pending acks futures and topic
+//! aliases are connection bound and are not part of snapshot.
+use std::{convert::TryFrom, num::NonZeroU16, num::NonZeroU32};
+
+use ntex::util::ByteString;
+#[cfg(feature = "json")]
+use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+use super::{codec, MqttSink};
+use crate::types::QoS;
+
+/// Session state snapshot
+///
+/// Snapshot is serializable with `serde` if `json` feature is enabled.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct SessionSnapshot {
+    /// Active subscriptions
+    pub subscriptions: Vec<SubscriptionSnapshot>,
+    /// Last used packet id
+    pub packet_id: u16,
+    /// Packet ids of inbound QoS2 publishes awaiting PUBREL, sorted
+    #[cfg_attr(feature = "json", serde(default))]
+    pub qos2_received: Vec<u16>,
+}
+
+/// Subscription state
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "json", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubscriptionSnapshot {
+    pub topic_filter: String,
+    #[cfg_attr(feature = "json", serde(with = "qos"))]
+    pub qos: QoS,
+    pub no_local: bool,
+    pub retain_as_published: bool,
+    #[cfg_attr(feature = "json", serde(with = "retain_handling"))]
+    pub retain_handling: codec::RetainHandling,
+    pub id: Option<NonZeroU32>,
+}
+
+#[cfg(feature = "json")]
+/// QoS is serialized as number
+mod qos {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(qos: &QoS, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u8(u8::from(*qos))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<QoS, D::Error> {
+        QoS::try_from(u8::deserialize(d)?).map_err(|_| D::Error::custom("invalid qos"))
+    }
+}
+
+#[cfg(feature = "json")]
+/// Retain handling is serialized as number
+mod retain_handling {
+    use super::*;
+
+    pub(super) fn serialize<S: Serializer>(
+        val: &codec::RetainHandling,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.serialize_u8(u8::from(*val))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<codec::RetainHandling, D::Error> {
+        codec::RetainHandling::try_from(u8::deserialize(d)?)
+            .map_err(|_| D::Error::custom("invalid retain handling"))
+    }
+}
+
+impl<St> crate::Session<MqttSink, St> {
+    /// Export session state
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let shared = &self.sink().0;
+        let mut subscriptions: Vec<_> = shared
+            .subscriptions
+            .as_ref()
+            .map(|subs| {
+                subs.borrow()
+                    .iter()
+                    .map(|(filter, (opts, id))| SubscriptionSnapshot {
+                        topic_filter: filter.to_string(),
+                        qos: opts.qos,
+                        no_local: opts.no_local,
+                        retain_as_published: opts.retain_as_published,
+                        retain_handling: opts.retain_handling,
+                        id: *id,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        subscriptions.sort_unstable_by(|a, b| a.topic_filter.cmp(&b.topic_filter));
+
+        let mut qos2_received: Vec<_> =
+            shared.qos2_received.borrow().iter().map(|id| id.get()).collect();
+        qos2_received.sort_unstable();
+
+        SessionSnapshot { subscriptions, packet_id: shared.inflight_idx.get(), qos2_received }
+    }
+
+    /// Restore session state from snapshot
+    ///
+    /// Subscriptions replace current subscriptions of the session, new
+    /// packet ids continue after snapshot's last used packet id. Unacknowledged
+    /// outbound packets are not part of snapshot, they must be re-sent by
+    /// application. Received QoS2 packet ids are restored, so retransmitted
+    /// publishes are not delivered again and PUBREL completes QoS2 flow.
+    pub fn restore(&self, snapshot: &SessionSnapshot) {
+        let shared = &self.sink().0;
+        let subscriptions = snapshot
+            .subscriptions
+            .iter()
+            .map(|sub| {
+                let opts = codec::SubscriptionOptions {
+                    qos: sub.qos,
+                    no_local: sub.no_local,
+                    retain_as_published: sub.retain_as_published,
+                    retain_handling: sub.retain_handling,
+                };
+                (ByteString::from(sub.topic_filter.as_str()), (opts, sub.id))
+            })
+            .collect();
+
+        if let Some(ref subs) = shared.subscriptions {
+            *subs.borrow_mut() = subscriptions;
+        }
+        shared.inflight_idx.set(snapshot.packet_id);
+        *shared.qos2_received.borrow_mut() =
+            snapshot.qos2_received.iter().copied().filter_map(NonZeroU16::new).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::io::State;
+    use crate::v5::shared::MqttShared;
+
+    fn new_session() -> crate::Session<MqttSink, ()> {
+        let shared = MqttShared::new(State::new(), codec::Codec::default(), 16, Rc::default());
+        crate::Session::new((), MqttSink::new(Rc::new(shared)))
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let session = new_session();
+        let opts = codec::SubscriptionOptions {
+            qos: QoS::AtLeastOnce,
+            no_local: true,
+            retain_as_published: false,
+            retain_handling: codec::RetainHandling::NoAtSubscribe,
+        };
+        session.sink().track_subscribe(
+            NonZeroU32::new(7),
+            vec![
+                (ByteString::from_static("a/+"), opts.clone()),
+                (ByteString::from_static("b/#"), opts),
+            ],
+            &[codec::SubscribeAckReason::GrantedQos1, codec::SubscribeAckReason::NotAuthorized],
+        );
+        session.sink().0.next_id();
+        session.sink().0.next_id();
+        for id in &[9, 3] {
+            session.sink().0.qos2_received.borrow_mut().insert(NonZeroU16::new(*id).unwrap());
+        }
+
+        let snapshot = session.snapshot();
+        assert_eq!(
+            snapshot,
+            SessionSnapshot {
+                subscriptions: vec![SubscriptionSnapshot {
+                    topic_filter: "a/+".to_string(),
+                    qos: QoS::AtLeastOnce,
+                    no_local: true,
+                    retain_as_published: false,
+                    retain_handling: codec::RetainHandling::NoAtSubscribe,
+                    id: NonZeroU32::new(7),
+                }],
+                packet_id: 2,
+                qos2_received: vec![3, 9],
+            }
+        );
+
+        let restored = new_session();
+        restored.restore(&snapshot);
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.subscriptions(), session.subscriptions());
+        assert_eq!(restored.sink().0.next_id(), 3);
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_snapshot_serde() {
+        let snapshot = SessionSnapshot {
+            subscriptions: vec![SubscriptionSnapshot {
+                topic_filter: "a/+".to_string(),
+                qos: QoS::ExactlyOnce,
+                no_local: false,
+                retain_as_published: true,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+                id: None,
+            }],
+            packet_id: 6,
+            qos2_received: vec![2],
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(serde_json::from_str::<SessionSnapshot>(&json).unwrap(), snapshot);
+
+        // snapshots without received QoS2 ids are accepted
+        let json = r#"{"subscriptions":[],"packet_id":1}"#;
+        let snapshot = serde_json::from_str::<SessionSnapshot>(json).unwrap();
+        assert!(snapshot.qos2_received.is_empty());
+
+        // invalid subscription options are rejected
+        let json = r#"{"subscriptions":[{"topic_filter":"a","qos":3,"no_local":false,
+            "retain_as_published":false,"retain_handling":0,"id":null}],"packet_id":1}"#;
+        assert!(serde_json::from_str::<SessionSnapshot>(json).is_err());
+    }
+}
@@ -0,0 +1,37 @@
+//! TLS server name indication
+//!
+//! Multi-tenant brokers route connections by server name that client sends
+//! in TLS ClientHello. Plain streams do not carry server name.
+
+/// Io stream with TLS server name
+pub trait SniHostname {
+    /// Returns server name requested by client
+    fn sni_hostname(&self) -> Option<&str>;
+}
+
+impl SniHostname for ntex::rt::net::TcpStream {
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl SniHostname for ntex::rt::net::UnixStream {
+    fn sni_hostname(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl<T> SniHostname for ntex::server::openssl::SslStream<T> {
+    fn sni_hostname(&self) -> Option<&str> {
+        self.ssl().servername(ntex::server::openssl::ssl::NameType::HOST_NAME)
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl<T> SniHostname for ntex::server::rustls::TlsStream<T> {
+    fn sni_hostname(&self) -> Option<&str> {
+        self.get_ref().1.sni_hostname()
+    }
+}
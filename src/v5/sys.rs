@@ -0,0 +1,79 @@
+//! `$SYS` topics publishing
+//!
+//! This is broker convention, not part of the spec. Server statistics get
+//! periodically published with QoS0 to connections that are subscribed to
+//! `$SYS` topics. Values are tracked per worker.
+use std::{cell::Cell, cell::RefCell, rc::Rc, time};
+
+use ntex::time::{sleep, Seconds};
+use ntex::util::{ByteString, Bytes};
+
+use super::MqttSink;
+
+/// Server uptime in seconds
+const SYS_UPTIME: &str = "$SYS/broker/uptime";
+/// Number of connected clients
+const SYS_CLIENTS_CONNECTED: &str = "$SYS/broker/clients/connected";
+
+/// Per worker `$SYS` topics publisher
+pub(super) struct SysTopics {
+    interval: Seconds,
+    start: time::Instant,
+    conns: RefCell<Vec<MqttSink>>,
+    running: Cell<bool>,
+}
+
+impl SysTopics {
+    pub(super) fn new(interval: Seconds) -> Rc<Self> {
+        Rc::new(SysTopics {
+            interval,
+            start: time::Instant::now(),
+            conns: RefCell::new(Vec::new()),
+            running: Cell::new(false),
+        })
+    }
+
+    /// Track connection, starts publish task on first call
+    pub(super) fn register(self: &Rc<Self>, sink: MqttSink) {
+        self.conns.borrow_mut().push(sink);
+
+        if !self.running.replace(true) {
+            let sys = self.clone();
+            ntex::rt::spawn(async move {
+                loop {
+                    sleep(sys.interval).await;
+                    if !sys.publish() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    /// Publish stats to subscribed connections, returns `false` if
+    /// there is no connections
+    fn publish(&self) -> bool {
+        let mut conns = self.conns.borrow_mut();
+        conns.retain(|sink| sink.is_open());
+
+        let values = [
+            (SYS_UPTIME, self.start.elapsed().as_secs().to_string()),
+            (SYS_CLIENTS_CONNECTED, conns.len().to_string()),
+        ];
+        for (topic, value) in values.iter() {
+            let payload = Bytes::copy_from_slice(value.as_bytes());
+            for sink in conns.iter().filter(|sink| sink.is_subscribed(topic)) {
+                let _ = sink
+                    .publish(ByteString::from_static(topic), payload.clone())
+                    .send_at_most_once();
+            }
+        }
+
+        if conns.is_empty() {
+            self.running.set(false);
+            false
+        } else {
+            true
+        }
+    }
+}
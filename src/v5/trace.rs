@@ -0,0 +1,52 @@
+//! Protocol trace events
+//!
+//! Trace stream reports every packet encoded or decoded by connection codec,
+//! protocol errors and connection close, in order they happen. It is intended
+//! for conformance test tooling. Events are emitted only while stream is
+//! attached, pre-encoded publishes of `broadcast()` and
+//! `MqttSink::publish_qos0_raw()` bypass codec and are not reported.
+use std::{pin::Pin, task::Context, task::Poll, time::Instant};
+
+use ntex::{channel::mpsc, Stream};
+
+use super::codec;
+
+/// Protocol trace event
+#[derive(Debug, Clone)]
+pub struct ProtocolEvent {
+    /// Event time
+    pub time: Instant,
+    pub kind: ProtocolEventKind,
+}
+
+/// Protocol trace event kind
+#[derive(Debug, Clone)]
+pub enum ProtocolEventKind {
+    /// Packet is decoded from io stream
+    Received(codec::Packet),
+    /// Packet is encoded to write buffer
+    Sent(codec::Packet),
+    /// Protocol error, connection is closing
+    ProtocolError(String),
+    /// Connection is closed, last event of stream
+    Closed,
+}
+
+/// Stream of protocol trace events
+pub struct ProtocolEvents {
+    rx: mpsc::Receiver<ProtocolEvent>,
+}
+
+impl ProtocolEvents {
+    pub(super) fn new(rx: mpsc::Receiver<ProtocolEvent>) -> Self {
+        ProtocolEvents { rx }
+    }
+}
+
+impl Stream for ProtocolEvents {
+    type Item = ProtocolEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
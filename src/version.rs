@@ -4,7 +4,7 @@ use ntex::codec::{Decoder, Encoder};
 use ntex::util::BytesMut;
 
 use crate::error::{DecodeError, EncodeError};
-use crate::types::{packet_type, MQTT, MQTT_LEVEL_3, MQTT_LEVEL_5};
+use crate::types::{packet_type, MQTT, MQTT_31, MQTT_LEVEL_3, MQTT_LEVEL_31, MQTT_LEVEL_5};
 use crate::utils;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -21,40 +21,42 @@ impl Decoder for VersionCodec {
     type Error = DecodeError;
 
     fn decode(&self, src: &mut BytesMut) -> Result<Option<Self::Item>, DecodeError> {
-        let len = src.len();
-        if len < 2 {
-            return Ok(None);
-        }
-
-        let src_slice = src.as_ref();
-        let first_byte = src_slice[0];
-        match utils::decode_variable_length(&src_slice[1..])? {
-            Some((_, mut consumed)) => {
-                consumed += 1;
-
-                if first_byte == packet_type::CONNECT {
-                    if len <= consumed + 6 {
-                        return Ok(None);
-                    }
-
-                    let len =
-                        u16::from_be_bytes(src[consumed..consumed + 2].try_into().unwrap());
-                    ensure!(
-                        len == 4 && &src[consumed + 2..consumed + 6] == MQTT,
-                        DecodeError::InvalidProtocol
-                    );
-
-                    match src[consumed + 6] {
-                        MQTT_LEVEL_3 => Ok(Some(ProtocolVersion::MQTT3)),
-                        MQTT_LEVEL_5 => Ok(Some(ProtocolVersion::MQTT5)),
-                        _ => Err(DecodeError::InvalidProtocol),
-                    }
-                } else {
-                    Err(DecodeError::UnsupportedPacketType)
-                }
-            }
-            None => Ok(None),
-        }
+        peek_version(src)
+    }
+}
+
+/// Peek protocol version from CONNECT variable header
+///
+/// Only fixed header, protocol name and protocol level are inspected, buffer
+/// is not consumed. Returns `None` if buffer does not contain protocol level
+/// yet. MQTT 3.1 header is recognized but is not supported.
+pub(super) fn peek_version(src: &[u8]) -> Result<Option<ProtocolVersion>, DecodeError> {
+    if src.len() < 2 {
+        return Ok(None);
+    }
+    ensure!(src[0] == packet_type::CONNECT, DecodeError::UnsupportedPacketType);
+
+    let consumed = match utils::decode_variable_length(&src[1..])? {
+        Some((_, consumed)) => consumed + 1,
+        None => return Ok(None),
+    };
+    let src = &src[consumed..];
+    if src.len() < 2 {
+        return Ok(None);
+    }
+
+    let name_len = usize::from(u16::from_be_bytes(src[..2].try_into().unwrap()));
+    ensure!(name_len == MQTT.len() || name_len == MQTT_31.len(), DecodeError::InvalidProtocol);
+    if src.len() <= name_len + 2 {
+        return Ok(None);
+    }
+
+    let name = &src[2..name_len + 2];
+    match (name, src[name_len + 2]) {
+        (MQTT, MQTT_LEVEL_3) => Ok(Some(ProtocolVersion::MQTT3)),
+        (MQTT, MQTT_LEVEL_5) => Ok(Some(ProtocolVersion::MQTT5)),
+        (MQTT_31, MQTT_LEVEL_31) => Err(DecodeError::UnsupportedProtocolLevel),
+        _ => Err(DecodeError::InvalidProtocol),
     }
 }
 
@@ -96,4 +98,36 @@ mod tests {
         let mut buf = BytesMut::from(b"\x10\x98\x02\0\x04MQTT".as_ref());
         assert_eq!(None, VersionCodec.decode(&mut buf).unwrap());
     }
+
+    #[test]
+    fn test_peek_version() {
+        // MQTT 3.1
+        assert_eq!(
+            Err(DecodeError::UnsupportedProtocolLevel),
+            peek_version(b"\x10\x11\0\x06MQIsdp\x03\x02\0\x3c\0\x03abc")
+        );
+        assert_eq!(None, peek_version(b"\x10\x11\0\x06MQIsdp").unwrap());
+
+        // MQTT 3.1.1
+        assert_eq!(
+            Some(ProtocolVersion::MQTT3),
+            peek_version(b"\x10\x0f\0\x04MQTT\x04\x02\0\x3c\0\x03abc").unwrap()
+        );
+
+        // MQTT 5, header only
+        assert_eq!(
+            Some(ProtocolVersion::MQTT5),
+            peek_version(b"\x10\x10\0\x04MQTT\x05").unwrap()
+        );
+
+        assert_eq!(None, peek_version(b"\x10").unwrap());
+        assert_eq!(None, peek_version(b"\x10\x98").unwrap());
+        assert_eq!(None, peek_version(b"\x10\x10\0").unwrap());
+        assert_eq!(Err(DecodeError::InvalidProtocol), peek_version(b"\x10\x10\0\x05MQTTX\x05"));
+        assert_eq!(Err(DecodeError::InvalidProtocol), peek_version(b"\x10\x10\0\x04MQTT\x03"));
+        assert_eq!(
+            Err(DecodeError::UnsupportedPacketType),
+            peek_version(b"\x30\x10\0\x04MQTT")
+        );
+    }
 }
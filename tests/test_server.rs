@@ -1,4 +1,4 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
+use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc, Mutex};
 use std::{num::NonZeroU16, time::Duration};
 
 use futures::{future::ok, FutureExt, SinkExt, StreamExt};
@@ -7,6 +7,7 @@ use ntex::server;
 use ntex::time::{sleep, Seconds};
 use ntex::util::{poll_fn, ByteString, Bytes};
 
+use ntex_mqtt::error::{DecodeError, ProtocolError};
 use ntex_mqtt::v3::{
     client, codec, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish, Session,
 };
@@ -327,3 +328,83 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_max_size() -> std::io::Result<()> {
+    let error = Arc::new(Mutex::new(None));
+    let error2 = error.clone();
+    let srv = server::test_server(move || {
+        let error = error2.clone();
+        MqttServer::new(handshake)
+            .max_size(64)
+            .publish(|_| ok::<_, ()>(()))
+            .control(move |msg| match msg {
+                ControlMessage::ProtocolError(msg) => {
+                    if let ProtocolError::Decode(DecodeError::MaxSizeExceeded { size, max }) =
+                        msg.get_ref()
+                    {
+                        *error.lock().unwrap() = Some((*size, *max));
+                    }
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed.send(codec::Connect::default().client_id("user").into()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(
+            codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtMostOnce,
+                topic: ByteString::from("test"),
+                packet_id: None,
+                payload: Bytes::from(vec![0u8; 128]),
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+
+    // connection is closed without response
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+    assert_eq!(*error.lock().unwrap(), Some((134, 64)));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsupported_protocol_version() -> std::io::Result<()> {
+    use ntex::codec::{BytesCodec, Encoder};
+    use ntex::util::BytesMut;
+    use ntex_mqtt::v5::codec as codec5;
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|_| ok::<_, ()>(())).finish()
+    });
+
+    // v5 connect packet
+    let mut buf = BytesMut::new();
+    codec5::Codec::default()
+        .encode(
+            codec5::Packet::Connect(Box::new(codec5::Connect::default().client_id("user"))),
+            &mut buf,
+        )
+        .unwrap();
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    framed.send(buf.freeze()).await.unwrap();
+
+    // connack with unacceptable protocol version return code
+    let ack = framed.next().await.unwrap().unwrap();
+    assert_eq!(ack.as_ref(), b"\x20\x02\x00\x01");
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+
+    Ok(())
+}
@@ -61,3 +61,23 @@ async fn test_simple() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_unsupported_protocol_version() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new()
+            .v5(v5::MqttServer::new(|con: v5::Handshake<_>| ok::<_, TestError>(con.ack(St)))
+                .publish(|p: v5::Publish| ok::<_, TestError>(p.ack())))
+    });
+
+    // v3 client gets rejected by v5 only server
+    let res = v3::client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    match res {
+        Err(v3::client::ClientError::Ack { return_code, .. }) => {
+            assert_eq!(return_code, v3::codec::ConnectAckReason::UnacceptableProtocolVersion)
+        }
+        _ => panic!("expected connect ack error"),
+    }
+
+    Ok(())
+}
@@ -1,15 +1,22 @@
-use std::sync::{atomic::AtomicBool, atomic::Ordering::Relaxed, Arc};
-use std::{convert::TryFrom, num::NonZeroU16, time::Duration};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
+use std::{
+    cell::RefCell, convert::TryFrom, convert::TryInto, num::NonZeroU16, rc::Rc, sync::Arc,
+    sync::Mutex, time::Duration, time::Instant,
+};
 
-use futures::{future::ok, FutureExt, SinkExt, StreamExt};
-use ntex::codec::Framed;
+use futures::{future::err, future::ok, FutureExt, SinkExt, StreamExt};
+use ntex::codec::{BytesCodec, Encoder, Framed};
 use ntex::server;
-use ntex::time::sleep;
-use ntex::util::{poll_fn, ByteString, Bytes};
+use ntex::time::{sleep, Millis, Seconds};
+use ntex::util::{poll_fn, ByteString, Bytes, BytesMut, PoolId};
 
+use ntex_mqtt::clock::Clock;
+use ntex_mqtt::error::ProtocolError;
 use ntex_mqtt::v5::{
-    client, codec, error, ControlMessage, Handshake, HandshakeAck, MqttServer, Publish,
-    PublishAck, Session,
+    broadcast, client, codec, control, error, CloseHandle, ControlMessage, ControlResult,
+    Drain, EmptyClientId, Handshake, HandshakeAck, KeepAliveMode, Matcher, MemoryStats,
+    MqttServer, MqttSink, OverflowPolicy, Publish, PublishAck, Qos0ViolationPolicy, Retained,
+    Selector, Session, SessionSnapshot, ShapePolicy,
 };
 
 struct St;
@@ -69,6 +76,32 @@ async fn test_simple() -> std::io::Result<()> {
     Ok(())
 }
 
+#[ntex::test]
+async fn test_write_buffer_capacity() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .memory_pool(PoolId::P6)
+        .write_buffer_capacity(16 * 1024)
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(PoolId::P6.pool_ref().write_params().unpack().0, 16 * 1024);
+
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    sink.close();
+    Ok(())
+}
+
 #[ntex::test]
 async fn test_disconnect() -> std::io::Result<()> {
     let srv = server::test_server(|| {
@@ -742,3 +775,5551 @@ async fn test_handle_incoming() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[ntex::test]
+async fn test_empty_client_id() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr()).connect().await.err().unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::ClientIdentifierNotValid);
+    } else {
+        panic!("Expected ClientError::Ack, got {:?}", err);
+    }
+
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| async move {
+            assert!(con.packet().clean_start);
+            Ok::<_, TestError>(con.ack(St))
+        })
+        .allow_empty_client_id(EmptyClientId::CleanStart)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr()).connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res =
+        sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sink.close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_broadcast() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let sinks = Rc::new(RefCell::new(Vec::new()));
+        let sinks2 = sinks.clone();
+
+        MqttServer::new(move |con: Handshake<_>| {
+            sinks.borrow_mut().push(con.sink());
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(move |p: Publish| {
+            assert_eq!(broadcast(&sinks2.borrow(), p.packet()), 2);
+            ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    });
+
+    let received = Arc::new(AtomicUsize::new(0));
+    let mut sinks = Vec::new();
+    for id in &["client1", "client2"] {
+        let received = received.clone();
+        let client =
+            client::MqttConnector::new(srv.addr()).client_id(*id).connect().await.unwrap();
+        sinks.push(client.sink());
+        ntex::rt::spawn(
+            client
+                .resource("test", move |p: Publish| {
+                    assert_eq!(p.qos(), codec::QoS::AtMostOnce);
+                    assert_eq!(p.payload().as_ref(), b"data");
+                    received.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(p.ack())
+                })
+                .start_default(),
+        );
+    }
+
+    let res = sinks[0]
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "compress")]
+#[ntex::test]
+async fn test_compress() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let props = &p.packet().properties.user_properties;
+                    assert_eq!(props, &vec![("x-encoding".into(), "gzip".into())]);
+                    assert_ne!(p.payload().as_ref(), b"data");
+
+                    // send compressed packet back as is
+                    let pkt = p.packet().clone();
+                    let props = pkt.properties;
+                    session
+                        .sink()
+                        .publish(pkt.topic, pkt.payload)
+                        .properties(|p| *p = props)
+                        .send_at_most_once()
+                        .unwrap();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let received = Arc::new(AtomicBool::new(false));
+    let received2 = received.clone();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .compress(client::Compression::Gzip)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("test", move |p: Publish| {
+                assert_eq!(p.payload().as_ref(), b"data");
+                assert!(p.packet().properties.user_properties.is_empty());
+                received2.store(true, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(100)).await;
+    assert!(received.load(Relaxed));
+
+    Ok(())
+}
+
+#[cfg(feature = "compress")]
+#[ntex::test]
+async fn test_compress_negotiation() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let connect = con.packet().clone();
+            let negotiation =
+                client::CompressionNegotiation::new(&[client::Compression::Deflate]);
+            ok::<_, TestError>(con.ack(St).with(|ack| {
+                negotiation.choose(&connect, ack);
+            }))
+        })
+        .publish(|p: Publish| {
+            let props = &p.packet().properties.user_properties;
+            if props.is_empty() {
+                assert_eq!(p.payload().as_ref(), b"data");
+            } else {
+                assert_eq!(props, &vec![("x-encoding".into(), "deflate".into())]);
+            }
+            ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    });
+
+    // deflate is chosen by server
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .negotiate_compression(client::CompressionNegotiation::new(&[
+            client::Compression::Gzip,
+            client::Compression::Deflate,
+        ]))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(
+        client.connack_user_properties(),
+        &vec![("x-encoding".into(), "deflate".into())]
+    );
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    // server does not support gzip, compression is disabled
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .compress(client::Compression::Gzip)
+        .negotiate_compression(client::CompressionNegotiation::new(&[
+            client::Compression::Gzip,
+        ]))
+        .connect()
+        .await
+        .unwrap();
+    assert!(client.connack_user_properties().is_empty());
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
+#[cfg(feature = "json")]
+#[ntex::test]
+async fn test_publish_json() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |mut p: Publish| {
+                let props = &p.packet().properties;
+                assert_eq!(props.content_type.as_ref().unwrap(), "application/json");
+                assert_eq!(props.is_utf8_payload, Some(true));
+                received.lock().unwrap().push(p.json::<serde_json::Value>().unwrap());
+
+                // content type parameters are ignored
+                p.packet_mut().properties.content_type =
+                    Some("Application/JSON; charset=utf-8".into());
+                assert!(p.json::<serde_json::Value>().is_ok());
+
+                // other content types are rejected
+                p.packet_mut().properties.content_type = Some("text/plain".into());
+                assert!(p.json::<serde_json::Value>().is_err());
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let value = serde_json::json!({"temp": 21, "unit": "C"});
+    let res = sink.publish_json("test", &value).unwrap().send_at_least_once().await;
+    assert!(res.is_ok());
+    assert_eq!(*received.lock().unwrap(), vec![value]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_concurrent_auth() -> std::io::Result<()> {
+    let inflight = Arc::new(AtomicUsize::new(0));
+    let max_inflight = Arc::new(AtomicUsize::new(0));
+    let inflight2 = inflight.clone();
+    let max_inflight2 = max_inflight.clone();
+
+    let srv = server::test_server(move || {
+        let inflight = inflight2.clone();
+        let max_inflight = max_inflight2.clone();
+
+        MqttServer::new(move |con: Handshake<_>| {
+            let inflight = inflight.clone();
+            let max_inflight = max_inflight.clone();
+            async move {
+                let num = inflight.fetch_add(1, Relaxed) + 1;
+                max_inflight.fetch_max(num, Relaxed);
+                sleep(Duration::from_millis(50)).await;
+                inflight.fetch_sub(1, Relaxed);
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .max_concurrent_auth(1)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let clients = futures::future::join_all((0..3).map(|i| {
+        client::MqttConnector::new(srv.addr()).client_id(format!("user{}", i)).connect()
+    }))
+    .await;
+    assert!(clients.iter().all(|c| c.is_ok()));
+    assert_eq!(max_inflight.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connector_pool() -> std::io::Result<()> {
+    let inflight = Arc::new(AtomicUsize::new(0));
+    let max_inflight = Arc::new(AtomicUsize::new(0));
+    let inflight2 = inflight.clone();
+    let max_inflight2 = max_inflight.clone();
+
+    let srv = server::test_server(move || {
+        let inflight = inflight2.clone();
+        let max_inflight = max_inflight2.clone();
+
+        MqttServer::new(move |con: Handshake<_>| {
+            let inflight = inflight.clone();
+            let max_inflight = max_inflight.clone();
+            async move {
+                let num = inflight.fetch_add(1, Relaxed) + 1;
+                max_inflight.fetch_max(num, Relaxed);
+                sleep(Duration::from_millis(100)).await;
+                inflight.fetch_sub(1, Relaxed);
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let pool =
+        client::ConnectorPool::new(client::MqttConnector::new(srv.addr()).client_id("user"))
+            .max_concurrent_connects(2);
+
+    let start = Instant::now();
+    let clients = futures::future::join_all((0..3).map(|_| pool.connect())).await;
+    assert!(clients.iter().all(|c| c.is_ok()));
+    assert_eq!(max_inflight.load(Relaxed), 2);
+    // third connect waits for one of first two
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_connack_user_properties() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(con.ack(St).with(|ack| {
+                ack.user_properties.push(("region".into(), "eu-1".into()));
+            }))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(
+        client.connack_user_properties(),
+        &vec![(ByteString::from_static("region"), ByteString::from_static("eu-1"))]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_manual_clock() -> std::io::Result<()> {
+    let ping = Arc::new(AtomicUsize::new(0));
+    let ping2 = ping.clone();
+
+    let srv = server::test_server(move || {
+        let ping = ping2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Ping(msg) => {
+                    ping.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect_with(codec::Disconnect::default())),
+            })
+            .finish()
+    });
+
+    let clock = Clock::manual();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(ntex::time::Seconds(60))
+        .clock(clock.clone())
+        .connect()
+        .await
+        .unwrap();
+    ntex::rt::spawn(client.start_default());
+
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(ping.load(Relaxed), 0);
+
+    clock.advance(ntex::time::Seconds(60));
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(ping.load(Relaxed), 1);
+
+    // handshake timeout
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| async move {
+            sleep(Duration::from_secs(60)).await;
+            Ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let clock = Clock::manual();
+    let fut = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .handshake_timeout(ntex::time::Seconds(30))
+        .clock(clock.clone())
+        .connect();
+    let clock2 = clock.clone();
+    ntex::rt::spawn(async move {
+        sleep(Duration::from_millis(50)).await;
+        clock2.advance(ntex::time::Seconds(30));
+    });
+    let err = fut.await.err().unwrap();
+    assert!(matches!(err, error::ClientError::HandshakeTimeout));
+
+    // connection ttl
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let clock = Clock::manual();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .connection_ttl(ntex::time::Seconds(60))
+        .clock(clock.clone())
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    sleep(Duration::from_millis(50)).await;
+    assert!(sink.is_open());
+    clock.advance(ntex::time::Seconds(60));
+    sleep(Duration::from_millis(50)).await;
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_resubscribe_all() -> std::io::Result<()> {
+    let subs = Arc::new(Mutex::new(Vec::new()));
+    let subs2 = subs.clone();
+
+    let srv = server::test_server(move || {
+        let subs = subs2.clone();
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(con.ack(St).with(|ack| ack.max_packet_size = Some(40)))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .control(move |msg| match msg {
+            ControlMessage::Subscribe(mut msg) => {
+                let mut topics = Vec::new();
+                msg.iter_mut().for_each(|mut s| {
+                    topics.push(s.topic().to_string());
+                    if s.topic() == "denied" {
+                        s.fail(codec::SubscribeAckReason::NotAuthorized)
+                    } else {
+                        s.confirm(codec::QoS::AtLeastOnce)
+                    }
+                });
+                subs.lock().unwrap().push(topics);
+                ok::<_, TestError>(msg.ack())
+            }
+            ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+            _ => ok(msg.disconnect()),
+        })
+        .finish()
+    });
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let connector = client::MqttConnector::new(srv.addr()).client_id("user");
+    let client = connector.connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for topic in &["a", "b", "denied", "topic/0123456789/x", "c"] {
+        sink.subscribe(None).topic_filter((*topic).into(), opts.clone()).send().await.unwrap();
+    }
+    sink.unsubscribe().topic_filter("c".into()).send().await.unwrap();
+    sink.close();
+    subs.lock().unwrap().clear();
+
+    let client = connector.connect().await.unwrap();
+    let fut = client.resubscribe_all(&sink);
+    ntex::rt::spawn(client.start_default());
+    let acks = fut.await.unwrap();
+    assert_eq!(acks.len(), 2);
+    assert_eq!(
+        *subs.lock().unwrap(),
+        vec![vec!["a".to_string(), "b".to_string()], vec!["topic/0123456789/x".to_string()]]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscriptions_per_connection() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| s.confirm(codec::QoS::AtLeastOnce));
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let connector = client::MqttConnector::new(srv.addr()).client_id("user");
+    let client_a = connector.connect().await.unwrap();
+    let sink_a = client_a.sink();
+    ntex::rt::spawn(client_a.start_default());
+    let client_b = connector.connect().await.unwrap();
+    let sink_b = client_b.sink();
+    ntex::rt::spawn(client_b.start_default());
+
+    sink_a.subscribe(None).topic_filter("a".into(), opts.clone()).send().await.unwrap();
+    sink_b.subscribe(None).topic_filter("b".into(), opts.clone()).send().await.unwrap();
+
+    // connection b does not see subscriptions of connection a
+    let res = sink_b.set_subscriptions(vec![("b".into(), opts.clone())]).await.unwrap();
+    assert!(res.is_empty());
+    let res = sink_a.set_subscriptions(vec![("a".into(), opts.clone())]).await.unwrap();
+    assert!(res.is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_set_subscriptions() -> std::io::Result<()> {
+    let packets = Arc::new(Mutex::new(Vec::new()));
+    let packets2 = packets.clone();
+
+    let srv = server::test_server(move || {
+        let packets = packets2.clone();
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    let mut topics = vec!["sub".to_string()];
+                    msg.iter_mut().for_each(|mut s| {
+                        topics.push(s.topic().to_string());
+                        let qos = s.options().qos;
+                        s.confirm(qos)
+                    });
+                    packets.lock().unwrap().push(topics);
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(mut msg) => {
+                    let mut topics = vec!["unsub".to_string()];
+                    msg.iter_mut().for_each(|s| topics.push(s.topic().to_string()));
+                    packets.lock().unwrap().push(topics);
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let opts = |qos| codec::SubscriptionOptions {
+        qos,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let fut = client.set_subscriptions(vec![
+        ("a".into(), opts(codec::QoS::AtLeastOnce)),
+        ("b".into(), opts(codec::QoS::AtLeastOnce)),
+        ("c".into(), opts(codec::QoS::AtLeastOnce)),
+    ]);
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert_eq!(fut.await.unwrap().len(), 3);
+    assert_eq!(
+        packets.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![vec!["sub", "a", "b", "c"]]
+    );
+
+    // only changed filters are sent
+    let res = sink
+        .set_subscriptions(vec![
+            ("b".into(), opts(codec::QoS::AtLeastOnce)),
+            ("c".into(), opts(codec::QoS::AtMostOnce)),
+            ("d".into(), opts(codec::QoS::AtLeastOnce)),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        res,
+        vec![
+            (
+                ByteString::from("c"),
+                client::SubscriptionChange::Subscribed(codec::SubscribeAckReason::GrantedQos0)
+            ),
+            (
+                ByteString::from("d"),
+                client::SubscriptionChange::Subscribed(codec::SubscribeAckReason::GrantedQos1)
+            ),
+            (
+                ByteString::from("a"),
+                client::SubscriptionChange::Unsubscribed(codec::UnsubscribeAckReason::Success)
+            ),
+        ]
+    );
+    assert_eq!(
+        packets.lock().unwrap().drain(..).collect::<Vec<_>>(),
+        vec![vec!["sub", "c", "d"], vec!["unsub", "a"]]
+    );
+
+    // subscriptions are up to date
+    let res = sink
+        .set_subscriptions(vec![
+            ("b".into(), opts(codec::QoS::AtLeastOnce)),
+            ("c".into(), opts(codec::QoS::AtMostOnce)),
+            ("d".into(), opts(codec::QoS::AtLeastOnce)),
+        ])
+        .await
+        .unwrap();
+    assert!(res.is_empty());
+    assert!(packets.lock().unwrap().is_empty());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_multiple_filters() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        let qos = s.options().qos;
+                        s.confirm(qos)
+                    });
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let fut = client.subscribe(
+        None,
+        vec![
+            (codec::QoS::AtMostOnce, "qos0"),
+            (codec::QoS::AtLeastOnce, "qos1"),
+            (codec::QoS::ExactlyOnce, "qos2"),
+        ]
+        .into_iter()
+        .map(|(qos, filter)| {
+            let opts = codec::SubscriptionOptions {
+                qos,
+                no_local: true,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            };
+            (ByteString::from_static(filter), opts)
+        }),
+    );
+    let empty = client.subscribe(None, Vec::new());
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(
+        fut.await.unwrap(),
+        vec![
+            ("qos0".into(), codec::SubscribeAckReason::GrantedQos0),
+            ("qos1".into(), codec::SubscribeAckReason::GrantedQos1),
+            ("qos2".into(), codec::SubscribeAckReason::GrantedQos2),
+        ]
+    );
+
+    // subscribe packet without filters is not sent
+    assert_eq!(
+        empty.await,
+        Err(error::SendPacketError::Encode(error::EncodeError::MalformedPacket))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_subscribe_ack_reason_codes_mismatch() -> std::io::Result<()> {
+    let disconnect = Arc::new(Mutex::new(None));
+    let disconnect2 = disconnect.clone();
+    let srv = server::test_server(move || {
+        let disconnect = disconnect2.clone();
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let disconnect = disconnect.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                let _ = framed.next().await.unwrap().unwrap();
+                framed
+                    .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                    .await
+                    .unwrap();
+                let pkt = framed.next().await.unwrap().unwrap();
+                let packet_id = match pkt {
+                    codec::Packet::Subscribe(pkt) => pkt.packet_id,
+                    _ => panic!("Subscribe packet is expected"),
+                };
+                // one reason code for two topic filters
+                framed
+                    .send(codec::Packet::SubscribeAck(codec::SubscribeAck {
+                        packet_id,
+                        properties: Default::default(),
+                        reason_string: None,
+                        status: vec![codec::SubscribeAckReason::GrantedQos0],
+                    }))
+                    .await
+                    .unwrap();
+                if let Some(Ok(codec::Packet::Disconnect(pkt))) = framed.next().await {
+                    *disconnect.lock().unwrap() = Some(pkt.reason_code);
+                }
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtMostOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let fut =
+        client.subscribe(None, vec![("a".into(), opts.clone()), ("b".into(), opts.clone())]);
+    ntex::rt::spawn(client.start_default());
+
+    assert_eq!(fut.await, Err(error::SendPacketError::Disconnected));
+    sleep(Millis(100)).await;
+    assert_eq!(*disconnect.lock().unwrap(), Some(codec::DisconnectReasonCode::ProtocolError));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connections_per_ip() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_connections_per_ip(2, |_| Some(std::net::IpAddr::from([10, 0, 0, 1])))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let connector = client::MqttConnector::new(srv.addr()).client_id("user");
+
+    let client1 = connector.connect().await.unwrap();
+    let client2 = connector.connect().await.unwrap();
+    let sink1 = client1.sink();
+    ntex::rt::spawn(client1.start_default());
+    ntex::rt::spawn(client2.start_default());
+
+    // third connection from the same address is rejected
+    let err = connector.connect().await.err().unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::QuotaExceeded);
+    } else {
+        panic!("Expected ClientError::Ack, got {:?}", err);
+    }
+
+    // closed connection frees slot
+    sink1.close();
+    sleep(Millis(100)).await;
+    let client3 = connector.connect().await.unwrap();
+    let sink3 = client3.sink();
+    ntex::rt::spawn(client3.start_default());
+
+    // connection is counted on accept, before CONNECT is sent
+    sink3.close();
+    sleep(Millis(100)).await;
+    let idle = srv.connect().await.unwrap();
+    sleep(Millis(100)).await;
+    let err = connector.connect().await.err().unwrap();
+    assert!(matches!(err, error::ClientError::Ack(_)));
+
+    drop(idle);
+    sleep(Millis(100)).await;
+    assert!(connector.connect().await.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_on_ack() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                let ack = if p.topic().path() == "none" {
+                    p.ack().reason_code(codec::PublishAckReason::NoMatchingSubscribers)
+                } else {
+                    p.ack()
+                };
+                ok::<_, TestError>(ack)
+            })
+            .finish()
+    });
+
+    let acks = Rc::new(RefCell::new(Vec::new()));
+    let acks2 = acks.clone();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .connect()
+        .await
+        .unwrap()
+        .on_ack(move |id, reason| acks2.borrow_mut().push((id, reason)));
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let ack1 = sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    let ack2 = match sink.publish("none", Bytes::new()).send_at_least_once().await {
+        Err(error::PublishQos1Error::Fail(ack)) => ack,
+        res => panic!("Expected failed ack, got {:?}", res),
+    };
+    sink.publish("qos0", Bytes::new()).send_at_most_once().unwrap();
+    sleep(Millis(50)).await;
+
+    assert_eq!(
+        *acks.borrow(),
+        vec![
+            (ack1.packet_id, codec::PublishAckReason::Success),
+            (ack2.packet_id, codec::PublishAckReason::NoMatchingSubscribers)
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_will_size() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_will_size(100)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let will = |size| codec::LastWill {
+        qos: codec::QoS::AtMostOnce,
+        retain: false,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from(vec![b'x'; size]),
+        will_delay_interval_sec: None,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Vec::new(),
+        is_utf8_payload: None,
+        response_topic: None,
+    };
+
+    let res = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .last_will(will(96))
+        .connect()
+        .await;
+    assert!(res.is_ok());
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .last_will(will(4096))
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::PacketTooLarge);
+    } else {
+        panic!("Expected ClientError::Ack, got {:?}", err);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_manual_connack() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|mut con: Handshake<_>| async move {
+            let auth = codec::Auth {
+                reason_code: codec::AuthReasonCode::ContinueAuth,
+                auth_method: Some(ByteString::from_static("test")),
+                auth_data: Some(Bytes::from_static(b"challenge")),
+                reason_string: None,
+                user_properties: Vec::new(),
+            };
+            con.send(codec::Packet::Auth(auth)).await.map_err(|_| TestError)?;
+            let ok = match con.recv().await.map_err(|_| TestError)? {
+                Some(codec::Packet::Auth(auth)) => {
+                    auth.auth_data == Some(Bytes::from_static(b"response"))
+                }
+                _ => false,
+            };
+            if !ok {
+                return Ok(con.failed(codec::ConnectAckReason::NotAuthorized));
+            }
+
+            let ack = codec::ConnectAck {
+                reason_code: codec::ConnectAckReason::Success,
+                auth_method: Some(ByteString::from_static("test")),
+                ..Default::default()
+            };
+            con.send(codec::Packet::ConnectAck(Box::new(ack.clone())))
+                .await
+                .map_err(|_| TestError)?;
+            Ok(con.ack(St).with(|pkt| *pkt = ack).manual_connack())
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Auth(auth) => {
+            assert_eq!(auth.reason_code, codec::AuthReasonCode::ContinueAuth);
+            assert_eq!(auth.auth_data, Some(Bytes::from_static(b"challenge")));
+        }
+        pkt => panic!("Expected AUTH, got {:?}", pkt),
+    }
+
+    framed
+        .send(codec::Packet::Auth(codec::Auth {
+            reason_code: codec::AuthReasonCode::ContinueAuth,
+            auth_method: Some(ByteString::from_static("test")),
+            auth_data: Some(Bytes::from_static(b"response")),
+            reason_string: None,
+            user_properties: Vec::new(),
+        }))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success);
+            assert_eq!(ack.auth_method, Some(ByteString::from_static("test")));
+        }
+        pkt => panic!("Expected CONNACK, got {:?}", pkt),
+    }
+
+    // connection is handled by dispatcher, and no second CONNACK is sent
+    framed
+        .send(
+            codec::Publish { packet_id: Some(NonZeroU16::new(1).unwrap()), ..pkt_publish() }
+                .into(),
+        )
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::PublishAck(ack) => assert_eq!(ack.packet_id.get(), 1),
+        pkt => panic!("Expected PUBACK, got {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_manual_connack_not_sent() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| ok::<_, TestError>(con.ack(St).manual_connack()))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // connection is closed instead of waiting for CONNACK forever
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_require_capabilities() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(con.ack(St).with(|ack| ack.retain_available = Some(false)))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .require(client::Capabilities { retain: true, ..Default::default() })
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(
+        err,
+        error::ClientError::CapabilityNotAvailable(error::Capability::Retain)
+    ));
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .require(client::Capabilities {
+            max_qos: Some(codec::QoS::AtLeastOnce),
+            ..Default::default()
+        })
+        .connect()
+        .await;
+    assert!(client.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_server_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    let pkt = codec::Disconnect {
+                        reason_code: codec::DisconnectReasonCode::UseAnotherServer,
+                        server_reference: Some("other".into()),
+                        ..Default::default()
+                    };
+                    session.sink().close_with_reason(pkt);
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let reason = Arc::new(Mutex::new(None));
+    let reason2 = reason.clone();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let closed = client.closed();
+    let closed2 = client.closed();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<()>| {
+        if let client::ControlMessage::Disconnect(ref dis) = msg {
+            *reason2.lock().unwrap() =
+                Some((dis.reason_code(), dis.server_reference().cloned()));
+        }
+        ok::<_, ()>(msg.disconnect(codec::Disconnect::default()))
+    }));
+
+    let _ = sink.publish(ByteString::from_static("#"), Bytes::new()).send_at_least_once().await;
+    let pkt = closed.await.unwrap();
+    assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::UseAnotherServer);
+    assert_eq!(pkt.server_reference, Some("other".into()));
+    // every waiter gets disconnect packet
+    assert_eq!(closed2.await, Some(pkt));
+    assert_eq!(
+        *reason.lock().unwrap(),
+        Some((codec::DisconnectReasonCode::UseAnotherServer, Some("other".into())))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_fail_pending_on_disconnect() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                let count = Rc::new(std::cell::Cell::new(0));
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    count.set(count.get() + 1);
+                    if count.get() == 3 {
+                        session.sink().close();
+                    }
+                    async move {
+                        sleep(Duration::from_secs(60)).await;
+                        Ok::<_, TestError>(p.ack())
+                    }
+                }))
+            }))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let futs = (0..3).map(|_| {
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once()
+    });
+    let res = ntex::time::timeout(Millis(1000), futures::future::join_all(futs)).await.unwrap();
+    assert!(res.iter().all(|r| matches!(r, Err(error::PublishQos1Error::Disconnected))));
+    assert!(!sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_send_quota() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(2)
+            .publish(|p: Publish| {
+                sleep(Duration::from_millis(100)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(client.send_quota(), 2);
+
+    // quota decrements on publish
+    let sink = client.sink();
+    let fut = sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once();
+    let (tx, rx) = futures::channel::oneshot::channel();
+    ntex::rt::spawn(async move {
+        let _ = tx.send(fut.await);
+    });
+    sleep(Millis(10)).await;
+    assert_eq!(client.send_quota(), 1);
+
+    // quota is restored on ack
+    ntex::rt::spawn(client.start_default());
+    assert!(rx.await.unwrap().is_ok());
+    assert_eq!(sink.credit(), 2);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_ack_timeout() -> std::io::Result<()> {
+    // server never acks publishes
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(2)
+            .publish(|_: Publish| futures::future::pending::<Result<PublishAck, TestError>>())
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish_with_timeout(ByteString::from_static("test"), Bytes::new(), Millis(100))
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::AckTimeout));
+
+    // packet id is released
+    assert_eq!(sink.credit(), 2);
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_late_ack() -> std::io::Result<()> {
+    // server acks publishes to "slow" topic after client timeout
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| {
+                let delay = if p.topic().path() == "slow" { 300 } else { 0 };
+                sleep(Duration::from_millis(delay)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("slow"), Bytes::new())
+        .packet_id(1)
+        .send_at_least_once_timeout(Millis(100))
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::AckTimeout));
+
+    // packet id is reserved until late ack
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .packet_id(1)
+        .send_at_least_once()
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::PacketIdInUse(1)));
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    // late ack is ignored, connection stays open
+    sleep(Duration::from_millis(400)).await;
+    assert!(sink.is_open());
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .packet_id(1)
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_receive_max_symmetric() -> std::io::Result<()> {
+    // client respects server's receive maximum
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .receive_max(2)
+            .publish(move |p: Publish| {
+                received.fetch_add(1, Relaxed);
+                sleep(Duration::from_millis(200)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let futs = (0..3).map(|_| {
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once()
+    });
+    let fut = futures::future::join_all(futs);
+    ntex::rt::spawn(async move {
+        let _ = fut.await;
+    });
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(received.load(Relaxed), 2);
+    assert_eq!(sink.credit(), 0);
+
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(received.load(Relaxed), 3);
+    assert_eq!(sink.credit(), 2);
+
+    // server respects client's receive maximum
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| {
+            let sink = con.sink();
+            ntex::rt::spawn(async move {
+                sleep(Duration::from_millis(50)).await;
+                for _ in 0..3 {
+                    let fut = sink
+                        .publish(ByteString::from_static("test"), Bytes::new())
+                        .send_at_least_once();
+                    ntex::rt::spawn(async move {
+                        let _ = fut.await;
+                    });
+                }
+            });
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let received = Rc::new(std::cell::Cell::new(0));
+    let received2 = received.clone();
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .receive_max(2)
+        .connect()
+        .await
+        .unwrap();
+    ntex::rt::spawn(
+        client
+            .resource("test", move |p: Publish| {
+                received2.set(received2.get() + 1);
+                sleep(Duration::from_millis(200)).map(move |_| Ok::<_, TestError>(p.ack()))
+            })
+            .start_default(),
+    );
+    sleep(Duration::from_millis(150)).await;
+    assert_eq!(received.get(), 2);
+
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(received.get(), 3);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_error_reason_map() -> std::io::Result<()> {
+    async fn unexpected_ack(srv: &server::TestServer) -> codec::Packet {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed
+            .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+        framed
+            .send(codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            }))
+            .await
+            .unwrap();
+        framed.next().await.unwrap().unwrap()
+    }
+
+    // default mapping
+    let srv = server::test_server(move || MqttServer::new(handshake).finish());
+    let pkt = unexpected_ack(&srv).await;
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ProtocolError
+        ))
+    );
+
+    // custom mapping, control service fails
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .error_reason_map(|err| match err {
+                ProtocolError::PacketIdMismatch => {
+                    codec::DisconnectReasonCode::UnspecifiedError
+                }
+                err => control::disconnect_reason(err),
+            })
+            .control(|msg| match msg {
+                ControlMessage::ProtocolError(_) => err::<ControlResult, _>(TestError),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+    let pkt = unexpected_ack(&srv).await;
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::UnspecifiedError
+        ))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_strict_ack_ids() -> std::io::Result<()> {
+    async fn unknown_ack(srv: &server::TestServer, pkt: codec::Packet) -> codec::Packet {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        framed
+            .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+            .await
+            .unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+        framed.send(pkt).await.unwrap();
+        framed.send(codec::Packet::PingRequest).await.unwrap();
+        framed.next().await.unwrap().unwrap()
+    }
+    let ack = codec::PublishAck {
+        packet_id: NonZeroU16::new(5).unwrap(),
+        reason_code: codec::PublishAckReason::Success,
+        properties: Default::default(),
+        reason_string: None,
+    };
+
+    // strict mode
+    let srv = server::test_server(move || MqttServer::new(handshake).finish());
+    for pkt in
+        [codec::Packet::PublishAck(ack.clone()), codec::Packet::PublishReceived(ack.clone())]
+    {
+        assert_eq!(
+            unknown_ack(&srv, pkt).await,
+            codec::Packet::Disconnect(codec::Disconnect::new(
+                codec::DisconnectReasonCode::ProtocolError
+            ))
+        );
+    }
+
+    // lenient mode
+    let srv =
+        server::test_server(move || MqttServer::new(handshake).strict_ack_ids(false).finish());
+    for pkt in
+        [codec::Packet::PublishAck(ack.clone()), codec::Packet::PublishReceived(ack.clone())]
+    {
+        assert_eq!(unknown_ack(&srv, pkt).await, codec::Packet::PingResponse);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_strict_utf8() -> std::io::Result<()> {
+    async fn publish(srv: &server::TestServer, raw: &'static [u8]) -> codec::Packet {
+        use ntex::codec::Decoder;
+
+        let codec = codec::Codec::default();
+        let mut buf = BytesMut::new();
+        let connect = codec::Connect::default().client_id("user");
+        codec.encode(codec::Packet::Connect(Box::new(connect)), &mut buf).unwrap();
+
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, BytesCodec);
+        framed.send(buf.split().freeze()).await.unwrap();
+        let _ = framed.next().await.unwrap().unwrap();
+
+        buf.extend_from_slice(raw);
+        codec.encode(codec::Packet::PingRequest, &mut buf).unwrap();
+        framed.send(buf.freeze()).await.unwrap();
+
+        let mut received = BytesMut::new();
+        loop {
+            received.extend_from_slice(&framed.next().await.unwrap().unwrap());
+            if let Some(pkt) = codec.decode(&mut received).unwrap() {
+                return pkt;
+            }
+        }
+    }
+    let disconnect = codec::Packet::Disconnect(codec::Disconnect::new(
+        codec::DisconnectReasonCode::MalformedPacket,
+    ));
+
+    // null character in user property
+    const NULL: &[u8] = b"\x30\x0e\x00\x04test\x07\x26\x00\x01k\x00\x01\x00";
+    // invalid utf-8 in user property
+    const INVALID: &[u8] = b"\x30\x0e\x00\x04test\x07\x26\x00\x01k\x00\x01\xff";
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+    assert_eq!(publish(&srv, NULL).await, codec::Packet::PingResponse);
+    assert_eq!(publish(&srv, INVALID).await, disconnect);
+
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .strict_utf8(true)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    assert_eq!(publish(&srv, NULL).await, disconnect);
+    assert_eq!(publish(&srv, INVALID).await, disconnect);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_drain() -> std::io::Result<()> {
+    let drain = Drain::new();
+    let drain2 = drain.clone();
+    let srv =
+        server::test_server(move || MqttServer::new(handshake).drain(drain2.clone()).finish());
+
+    let mut closed = Vec::new();
+    for idx in 0..10 {
+        let client = client::MqttConnector::new(srv.addr())
+            .client_id(format!("user-{}", idx))
+            .connect()
+            .await
+            .unwrap();
+        closed.push(client.closed());
+        ntex::rt::spawn(client.start_default());
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(drain.connections(), 10);
+
+    let start = std::time::Instant::now();
+    drain.begin_drain(Millis(500));
+    let times = Rc::new(RefCell::new(Vec::new()));
+    let futs = closed.into_iter().map(|fut| {
+        let times = times.clone();
+        async move {
+            let pkt = fut.await.unwrap();
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ServerShuttingDown);
+            times.borrow_mut().push(start.elapsed());
+        }
+    });
+    futures::future::join_all(futs).await;
+
+    // disconnects are spread over drain window
+    let times = times.borrow();
+    assert!(times[0] < Duration::from_millis(100));
+    assert!(times[9] >= Duration::from_millis(400));
+
+    drain.drained().await;
+    assert_eq!(drain.connections(), 0);
+
+    // new connections are rejected
+    let res = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    if let Err(error::ClientError::Ack(ack)) = res {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerUnavailable);
+    } else {
+        panic!("expected connect ack error");
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_user_properties() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let res = if con.user_property("x-tenant").map(|v| v == "acme").unwrap_or(false)
+                && con.user_property("x-missing").is_none()
+                && con.user_properties().len() == 2
+            {
+                con.ack(St)
+            } else {
+                con.failed(codec::ConnectAckReason::BadUserNameOrPassword)
+            };
+            ok::<_, TestError>(res)
+        })
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .properties(|props| {
+            props.push(("x-tenant".into(), "acme".into()));
+            props.push(("x-region".into(), "eu".into()));
+        })
+        .connect()
+        .await;
+    assert!(client.is_ok());
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .properties(|props| props.push(("x-tenant".into(), "other".into())))
+        .connect()
+        .await;
+    assert!(matches!(client, Err(error::ClientError::Ack(_))));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_topic_alias_disabled() -> std::io::Result<()> {
+    let srv = server::test_server(|| MqttServer::new(handshake).max_topic_alias(0).finish());
+
+    // server rejects topic alias
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let ack = framed.next().await.unwrap().unwrap();
+    if let codec::Packet::ConnectAck(ack) = ack {
+        assert_eq!(ack.topic_alias_max, 0);
+    } else {
+        panic!("expected connect ack");
+    }
+
+    let mut pkt = pkt_publish();
+    pkt.properties.topic_alias = NonZeroU16::new(1);
+    framed.send(pkt.into()).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::TopicAliasInvalid
+        ))
+    );
+
+    // client does not use topic alias
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .properties(|props| props.topic_alias = NonZeroU16::new(1))
+        .send_at_most_once();
+    assert_eq!(res, Err(error::SendPacketError::TopicAliasInvalid));
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::new())
+        .properties(|props| props.topic_alias = NonZeroU16::new(1))
+        .send_at_least_once()
+        .await;
+    assert_eq!(res, Err(error::PublishQos1Error::TopicAliasInvalid));
+
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_on_connected() -> std::io::Result<()> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events2 = events.clone();
+
+    let srv = server::test_server(move || {
+        let events = events2.clone();
+        let events2 = events2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                events.lock().unwrap().push(format!("publish {}", p.topic().path()));
+                ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        events2.lock().unwrap().push(format!("subscribe {}", s.topic()));
+                        s.confirm(codec::QoS::AtLeastOnce)
+                    });
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Disconnect(msg) => {
+                    events2.lock().unwrap().push(format!("disconnect {:?}", msg.reason_code()));
+                    ok(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .on_connected(move |sink, ack| {
+            assert!(!ack.session_present);
+            let opts = opts.clone();
+            async move {
+                sink.subscribe(None).topic_filter("topic".into(), opts).send().await?;
+                sink.publish(ByteString::from_static("presence"), Bytes::new())
+                    .send_at_least_once()
+                    .await?;
+                Ok::<_, Box<dyn std::error::Error>>(())
+            }
+        })
+        .connect()
+        .await
+        .unwrap();
+    ntex::rt::spawn(client.start_default());
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec!["subscribe topic".to_string(), "publish presence".to_string()]
+    );
+    events.lock().unwrap().clear();
+
+    // failed hook closes connection
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .on_connected(|_, _| err::<(), _>("failed"))
+        .connect()
+        .await
+        .unwrap();
+    let closed = client.closed();
+    ntex::rt::spawn(client.start_default());
+    closed.await;
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(*events.lock().unwrap(), vec!["disconnect UnspecifiedError".to_string()]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos0_raw() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                received.lock().unwrap().push((
+                    p.topic().path().to_string(),
+                    p.qos(),
+                    p.payload().clone(),
+                ));
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    for _ in 0..3 {
+        sink.publish_qos0_raw("test/raw", b"data").unwrap();
+    }
+    sleep(Duration::from_millis(50)).await;
+    assert_eq!(
+        *received.lock().unwrap(),
+        vec![("test/raw".to_string(), codec::QoS::AtMostOnce, Bytes::from_static(b"data")); 3]
+    );
+
+    sink.close();
+    assert_eq!(
+        sink.publish_qos0_raw("test/raw", b"data"),
+        Err(error::SendPacketError::Disconnected)
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_memory_stats() -> std::io::Result<()> {
+    let stats = MemoryStats::new();
+    stats.set_max_buffered(1024 * 1024);
+    let stats2 = stats.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| {
+            if con.packet().client_id == "slow" {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let payload = Bytes::from(vec![0u8; 256 * 1024]);
+                    for _ in 0..40 {
+                        if sink.publish("test", payload.clone()).send_at_most_once().is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            ok::<_, TestError>(con.ack(St))
+        })
+        .memory_stats(stats2.clone())
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // slow consumer does not read incoming data
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("slow"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Duration::from_millis(100)).await;
+    assert_eq!(stats.connections(), 2);
+
+    sleep(Duration::from_millis(600)).await;
+    assert_eq!(stats.disconnects(), 1);
+    assert_eq!(stats.connections(), 1);
+    assert!(stats.buffered() < 1024 * 1024);
+    assert!(sink.is_open());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_flush_stats() -> std::io::Result<()> {
+    let stats = MemoryStats::new();
+    let stats2 = stats.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| {
+            if con.packet().client_id == "slow" {
+                let sink = con.sink();
+                ntex::rt::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    let payload = Bytes::from(vec![0u8; 256 * 1024]);
+                    for _ in 0..16 {
+                        let _ = sink.publish("test", payload.clone()).send_at_most_once();
+                    }
+                });
+            }
+            ok::<_, TestError>(con.ack(St))
+        })
+        .memory_stats(stats2.clone())
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // responsive client, write buffer gets flushed
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    let res =
+        sink.publish(ByteString::from_static("test"), Bytes::new()).send_at_least_once().await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(400)).await;
+    assert!(stats.flushes() > 0);
+    assert_eq!(stats.partial_flushes(), 0);
+    assert_eq!(stats.buffered(), 0);
+
+    // slow consumer does not read incoming data
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("slow"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Duration::from_millis(400)).await;
+    assert!(stats.partial_flushes() > 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_max_reads() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| ok::<_, TestError>(con.ack(St)))
+            .handshake_max_reads(3)
+            .finish()
+    });
+
+    // complete packet is received with single read
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+
+    let mut buf = BytesMut::new();
+    codec::Codec::default()
+        .encode(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("slow"))),
+            &mut buf,
+        )
+        .unwrap();
+
+    // client sends connect packet byte by byte
+    assert!(send_by_byte(&srv, &buf).await);
+
+    // selector reads first packet
+    let srv = server::test_server(move || {
+        Selector::new()
+            .handshake_max_reads(3)
+            .variant(|_| ok::<_, TestError>(true), MqttServer::new(handshake))
+    });
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+    assert!(send_by_byte(&srv, &buf).await);
+
+    Ok(())
+}
+
+/// Send packet byte by byte, returns true if server closes connection
+async fn send_by_byte(srv: &server::TestServer, buf: &[u8]) -> bool {
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    for b in buf.iter() {
+        if framed.send(Bytes::copy_from_slice(&[*b])).await.is_err() {
+            break;
+        }
+        sleep(Millis(20)).await;
+    }
+    framed.next().await.map(|res| res.is_err()).unwrap_or(true)
+}
+
+#[ntex::test]
+async fn test_handshake_max_bytes() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| ok::<_, TestError>(con.ack(St)))
+            .handshake_max_bytes(64)
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+
+    // connect packet is larger than byte budget
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .properties(|p| p.push(("key".into(), "v".repeat(64).into())))
+        .connect()
+        .await;
+    assert!(client.is_err());
+
+    // selector reads first packet
+    let srv = server::test_server(move || {
+        Selector::new()
+            .handshake_max_bytes(64)
+            .variant(|_| ok::<_, TestError>(true), MqttServer::new(handshake))
+    });
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .properties(|p| p.push(("key".into(), "v".repeat(64).into())))
+        .connect()
+        .await;
+    assert!(client.is_err());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_limits() -> std::io::Result<()> {
+    let limits = Arc::new(Mutex::new(None));
+    let limits2 = limits.clone();
+    let srv = server::test_server(move || {
+        let limits = limits2.clone();
+        MqttServer::new(handshake)
+            .receive_max(10)
+            .max_topic_alias(5)
+            .max_size(4096)
+            .max_qos(codec::QoS::AtLeastOnce)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                *limits.lock().unwrap() = Some(*session.limits());
+                ok::<_, TestError>(ntex::service::fn_service(|p: Publish| {
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .receive_max(20)
+        .max_packet_size(8192)
+        .packet(|pkt| pkt.topic_alias_max = 3)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+
+    let limits = limits.lock().unwrap().unwrap();
+    assert_eq!(limits.max_qos, codec::QoS::AtLeastOnce);
+    assert_eq!(limits.receive_max, 10);
+    assert_eq!(limits.topic_alias_max, 5);
+    assert_eq!(limits.max_packet_size, 4096);
+    assert_eq!(limits.peer_receive_max, 20);
+    assert_eq!(limits.peer_topic_alias_max, 3);
+    assert_eq!(limits.peer_max_packet_size, 8192);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_size() -> std::io::Result<()> {
+    let srv = server::test_server(move || MqttServer::new(handshake).max_size(64).finish());
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtMostOnce,
+            topic: ByteString::from("test"),
+            packet_id: None,
+            payload: Bytes::from(vec![0u8; 128]),
+            properties: Default::default(),
+        }))
+        .await
+        .unwrap();
+
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::PacketTooLarge
+        ))
+    );
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_subscriptions() -> std::io::Result<()> {
+    let subs = Arc::new(Mutex::new(Vec::new()));
+    let subs2 = subs.clone();
+    let srv = server::test_server(move || {
+        let subs = subs2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let subs = subs.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    *subs.lock().unwrap() = session.subscriptions();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        if s.topic() == "denied" {
+                            s.fail(codec::SubscribeAckReason::NotAuthorized)
+                        } else {
+                            s.confirm(codec::QoS::AtLeastOnce)
+                        }
+                    });
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    sink.subscribe(None)
+        .topic_filter("topic1".into(), opts.clone())
+        .topic_filter("topic2".into(), opts.clone())
+        .topic_filter("denied".into(), opts.clone())
+        .send()
+        .await
+        .unwrap();
+    sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(
+        *subs.lock().unwrap(),
+        vec![("topic1".into(), opts.clone()), ("topic2".into(), opts.clone())]
+    );
+
+    sink.unsubscribe().topic_filter("topic1".into()).send().await.unwrap();
+    sink.publish("test", Bytes::new()).send_at_least_once().await.unwrap();
+    assert_eq!(*subs.lock().unwrap(), vec![("topic2".into(), opts)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_sys_topics() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .sys_topics(Seconds(1))
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| s.confirm(codec::QoS::AtMostOnce));
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    // client without subscriptions
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    ntex::rt::spawn(client.start_default());
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("sys"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed
+        .send(
+            codec::Subscribe {
+                id: None,
+                packet_id: NonZeroU16::new(1).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![(
+                    ByteString::from("$SYS/broker/clients/#"),
+                    codec::SubscriptionOptions {
+                        qos: codec::QoS::AtMostOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::AtSubscribe,
+                    },
+                )],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
+
+    let pkt = framed.next().await.unwrap().unwrap();
+    match pkt {
+        codec::Packet::Publish(pkt) => {
+            assert_eq!(pkt.topic, "$SYS/broker/clients/connected");
+            assert_eq!(pkt.qos, codec::QoS::AtMostOnce);
+            assert_eq!(pkt.payload, Bytes::from_static(b"2"));
+        }
+        pkt => panic!("Unexpected packet: {:?}", pkt),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ack_later() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .receive_max(1)
+            .publish(|p: Publish| {
+                let (token, ack) = p.ack_later();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(200)).await;
+                    token.ack(PublishAck::new(codec::PublishAckReason::Success));
+                });
+                ok::<_, TestError>(ack)
+            })
+            .finish()
+    });
+
+    // deferred ack is sent by token
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let start = Instant::now();
+    framed
+        .send(codec::Packet::Publish(codec::Publish {
+            dup: false,
+            retain: false,
+            qos: codec::QoS::AtLeastOnce,
+            topic: ByteString::from("test"),
+            packet_id: NonZeroU16::new(1),
+            payload: Bytes::new(),
+            properties: Default::default(),
+        }))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert_eq!(
+        pkt,
+        codec::Packet::PublishAck(codec::PublishAck {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            reason_code: codec::PublishAckReason::Success,
+            properties: Default::default(),
+            reason_string: None,
+        })
+    );
+
+    // pending deferred ack counts against receive maximum
+    let pkt = codec::Publish {
+        dup: false,
+        retain: false,
+        qos: codec::QoS::AtLeastOnce,
+        topic: ByteString::from("test"),
+        packet_id: NonZeroU16::new(2),
+        payload: Bytes::new(),
+        properties: Default::default(),
+    };
+    framed.send(codec::Packet::Publish(pkt.clone())).await.unwrap();
+    let mut pkt = pkt;
+    pkt.packet_id = NonZeroU16::new(3);
+    framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::ReceiveMaximumExceeded
+        ))
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_unacked_inbound() -> std::io::Result<()> {
+    let received = Arc::new(AtomicUsize::new(0));
+    let received2 = received.clone();
+
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(handshake)
+            .max_unacked_inbound(2)
+            .publish(move |p: Publish| {
+                received.fetch_add(1, Relaxed);
+                let (token, ack) = p.ack_later();
+                ntex::rt::spawn(async move {
+                    sleep(Millis(300)).await;
+                    token.ack(PublishAck::new(codec::PublishAckReason::Success));
+                });
+                ok::<_, TestError>(ack)
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for id in 1..5 {
+        framed
+            .send(codec::Packet::Publish(codec::Publish {
+                dup: false,
+                retain: false,
+                qos: codec::QoS::AtLeastOnce,
+                topic: ByteString::from("test"),
+                packet_id: NonZeroU16::new(id),
+                payload: Bytes::new(),
+                properties: Default::default(),
+            }))
+            .await
+            .unwrap();
+    }
+
+    // reading is paused at the limit
+    sleep(Millis(150)).await;
+    assert_eq!(received.load(Relaxed), 2);
+
+    // and resumes once tokens get completed
+    for id in 1..5 {
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert_eq!(
+            pkt,
+            codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                reason_code: codec::PublishAckReason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })
+        );
+        if id == 2 {
+            sleep(Millis(150)).await;
+            assert_eq!(received.load(Relaxed), 4);
+        }
+    }
+
+    Ok(())
+}
+
+/// Raw server that sends QoS2, QoS0 and QoS1 publishes and completes QoS2
+/// exchange after delay, returns topics in client delivery order
+async fn delivery_order(order: client::DeliveryOrder) -> Vec<ByteString> {
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: ntex::rt::net::TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                .await
+                .unwrap();
+            // let client start dispatcher
+            sleep(Millis(50)).await;
+
+            for (topic, qos, id) in &[
+                ("qos2", codec::QoS::ExactlyOnce, NonZeroU16::new(1)),
+                ("qos0", codec::QoS::AtMostOnce, None),
+                ("qos1", codec::QoS::AtLeastOnce, NonZeroU16::new(2)),
+            ] {
+                framed
+                    .send(codec::Packet::Publish(codec::Publish {
+                        dup: false,
+                        retain: false,
+                        qos: *qos,
+                        topic: ByteString::from_static(topic),
+                        packet_id: *id,
+                        payload: Bytes::new(),
+                        properties: Default::default(),
+                    }))
+                    .await
+                    .unwrap();
+            }
+            let pkt = framed.next().await.unwrap().unwrap();
+            assert_eq!(
+                pkt,
+                codec::Packet::PublishReceived(codec::PublishAck {
+                    packet_id: NonZeroU16::new(1).unwrap(),
+                    ..Default::default()
+                })
+            );
+
+            sleep(Millis(100)).await;
+            framed
+                .send(codec::Packet::PublishRelease(codec::PublishAck2 {
+                    packet_id: NonZeroU16::new(1).unwrap(),
+                    reason_code: codec::PublishAck2Reason::Success,
+                    properties: Default::default(),
+                    reason_string: None,
+                }))
+                .await
+                .unwrap();
+
+            let mut acks = Vec::new();
+            for _ in 0..2 {
+                acks.push(framed.next().await.unwrap().unwrap());
+            }
+            assert!(acks.contains(&codec::Packet::PublishComplete(codec::PublishAck2 {
+                packet_id: NonZeroU16::new(1).unwrap(),
+                reason_code: codec::PublishAck2Reason::Success,
+                properties: Default::default(),
+                reason_string: None,
+            })));
+            assert!(acks.contains(&codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: NonZeroU16::new(2).unwrap(),
+                ..Default::default()
+            })));
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .delivery_order(order)
+        .connect()
+        .await
+        .unwrap();
+
+    let topics = Rc::new(RefCell::new(Vec::new()));
+    let topics2 = topics.clone();
+    let _ = client
+        .start(move |msg: client::ControlMessage<()>| {
+            let res = match msg {
+                client::ControlMessage::Publish(pkt) => {
+                    topics2.borrow_mut().push(pkt.packet().topic.clone());
+                    pkt.ack(codec::PublishAckReason::Success)
+                }
+                msg => msg.disconnect(Default::default()),
+            };
+            ok::<_, ()>(res)
+        })
+        .await;
+
+    let topics = topics.borrow().clone();
+    topics
+}
+
+#[ntex::test]
+async fn test_client_delivery_order() {
+    assert_eq!(
+        delivery_order(client::DeliveryOrder::Strict).await,
+        vec!["qos2", "qos0", "qos1"]
+    );
+    assert_eq!(
+        delivery_order(client::DeliveryOrder::Qos0Bypass).await,
+        vec!["qos0", "qos2", "qos1"]
+    );
+}
+
+/// Mock server that answers pings with delay
+fn ping_server(delay: Millis) -> server::TestServer {
+    server::test_server(move || {
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                .await
+                .unwrap();
+
+            while let Some(Ok(pkt)) = framed.next().await {
+                if pkt == codec::Packet::PingRequest {
+                    sleep(delay).await;
+                    if framed.send(codec::Packet::PingResponse).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok::<_, ()>(())
+        })
+    })
+}
+
+#[ntex::test]
+async fn test_client_ping_timeout() {
+    // response within grace
+    let srv = ping_server(Millis(300));
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(1))
+        .ping_timeout(Seconds(1))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(2500)).await;
+    assert!(sink.is_open());
+    sink.close();
+
+    // response is late
+    let srv = ping_server(Millis(1500));
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(1))
+        .ping_timeout(Seconds(1))
+        .connect()
+        .await
+        .unwrap();
+    let closed = client.closed();
+    ntex::rt::spawn(client.start_default());
+    let start = Instant::now();
+    let pkt = closed.await.unwrap();
+    assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::KeepAliveTimeout);
+    assert!(start.elapsed() < Duration::from_secs(4));
+}
+
+#[ntex::test]
+async fn test_client_subscribe_with() {
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: ntex::rt::net::TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                .await
+                .unwrap();
+            // let client start dispatcher
+            sleep(Millis(50)).await;
+
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                if let codec::Packet::Subscribe(pkt) = framed.next().await.unwrap().unwrap() {
+                    ids.push(pkt.id.unwrap());
+                    framed
+                        .send(codec::Packet::SubscribeAck(codec::SubscribeAck {
+                            packet_id: pkt.packet_id,
+                            status: vec![codec::SubscribeAckReason::GrantedQos1],
+                            properties: Default::default(),
+                            reason_string: None,
+                        }))
+                        .await
+                        .unwrap();
+                } else {
+                    panic!("Expected SUBSCRIBE");
+                }
+            }
+            assert_ne!(ids[0], ids[1]);
+
+            let publish = |topic, packet_id, subscription_ids| {
+                let mut pkt = codec::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: codec::QoS::AtMostOnce,
+                    topic: ByteString::from_static(topic),
+                    packet_id: NonZeroU16::new(packet_id),
+                    payload: Bytes::new(),
+                    properties: Default::default(),
+                };
+                if packet_id != 0 {
+                    pkt.qos = codec::QoS::AtLeastOnce;
+                }
+                pkt.properties.subscription_ids = subscription_ids;
+                codec::Packet::Publish(pkt)
+            };
+            // overlapping subscriptions, routed by topic
+            framed.send(publish("a/b", 0, None)).await.unwrap();
+            framed.send(publish("a/b/c", 0, None)).await.unwrap();
+            // routed by subscription id
+            framed.send(publish("a/c", 1, Some(vec![ids[0]]))).await.unwrap();
+
+            let pkt = framed.next().await.unwrap().unwrap();
+            assert_eq!(
+                pkt,
+                codec::Packet::PublishAck(codec::PublishAck {
+                    packet_id: NonZeroU16::new(1).unwrap(),
+                    ..Default::default()
+                })
+            );
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let mut futs = Vec::new();
+    for filter in &["a/+", "a/#"] {
+        let received = received.clone();
+        futs.push(client.subscribe_with(
+            ByteString::from_static(filter),
+            opts.clone(),
+            move |p: Publish| {
+                received.borrow_mut().push((*filter, p.publish_topic().to_string()));
+                async {}
+            },
+        ));
+    }
+    let invalid = client.subscribe_with("a/#/b".into(), opts, |_| async {});
+    assert_eq!(invalid.await.unwrap(), codec::SubscribeAckReason::TopicFilterInvalid);
+
+    ntex::rt::spawn(client.start_default());
+    for fut in futs {
+        assert_eq!(fut.await.unwrap(), codec::SubscribeAckReason::GrantedQos1);
+    }
+
+    sleep(Millis(200)).await;
+    assert_eq!(
+        *received.borrow(),
+        vec![
+            ("a/+", "a/b".to_string()),
+            ("a/#", "a/b".to_string()),
+            ("a/#", "a/b/c".to_string()),
+            ("a/+", "a/c".to_string()),
+        ]
+    );
+}
+
+#[ntex::test]
+async fn test_forward_user_properties() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    if p.publish_topic() == "test" {
+                        session
+                            .sink()
+                            .forward(&p)
+                            .user_property("hop".into(), "1".into())
+                            .send_at_most_once()
+                            .unwrap();
+                    }
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let received = Arc::new(AtomicBool::new(false));
+    let received2 = received.clone();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("test", move |p: Publish| {
+                assert_eq!(p.payload().as_ref(), b"data");
+                assert_eq!(
+                    p.packet().properties.user_properties,
+                    vec![
+                        ("trace-id".into(), "abc".into()),
+                        ("trace-id".into(), "def".into()),
+                        ("hop".into(), "1".into()),
+                    ]
+                );
+                assert_eq!(p.packet().properties.content_type, Some("text/plain".into()));
+                received2.store(true, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    let res = sink
+        .publish(ByteString::from_static("test"), Bytes::from_static(b"data"))
+        .properties(|props| {
+            props.content_type = Some("text/plain".into());
+            props.user_properties.push(("trace-id".into(), "abc".into()));
+            props.user_properties.push(("trace-id".into(), "def".into()));
+        })
+        .send_at_least_once()
+        .await;
+    assert!(res.is_ok());
+    sleep(Duration::from_millis(100)).await;
+    assert!(received.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_clear() -> std::io::Result<()> {
+    let store = Arc::new(Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        MqttServer::new(handshake)
+            .retained(move |msg| store.lock().unwrap().push(msg))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    sink.publish(topic.clone(), Bytes::from_static(b"data"))
+        .retain()
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(topic.clone(), Bytes::from_static(b"data"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(topic.clone(), Bytes::new()).retain().send_at_least_once().await.unwrap();
+
+    let store = store.lock().unwrap();
+    assert_eq!(store.len(), 2);
+    match store[0] {
+        Retained::Set(ref pkt) => assert_eq!(pkt.payload, Bytes::from_static(b"data")),
+        _ => panic!("expected retained message"),
+    }
+    assert_eq!(store[1], Retained::Clear(topic));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_topic_alias() -> std::io::Result<()> {
+    let store = Arc::new(Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        MqttServer::new(handshake)
+            .max_topic_alias(5)
+            .retained(move |msg| store.lock().unwrap().push(msg))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // alias is defined, then used with empty topic
+    for (id, topic, payload) in
+        &[(1, "test", &b"data1"[..]), (2, "", &b"data2"[..]), (3, "", &b""[..])]
+    {
+        let mut pkt = pkt_publish();
+        pkt.retain = true;
+        pkt.packet_id = NonZeroU16::new(*id);
+        pkt.topic = ByteString::from_static(topic);
+        pkt.payload = Bytes::from_static(payload);
+        pkt.properties.topic_alias = NonZeroU16::new(1);
+        framed.send(pkt.into()).await.unwrap();
+        assert!(matches!(framed.next().await.unwrap().unwrap(), codec::Packet::PublishAck(_)));
+    }
+
+    let store = store.lock().unwrap();
+    assert_eq!(store.len(), 3);
+    for (msg, payload) in store.iter().zip(&[&b"data1"[..], &b"data2"[..]]) {
+        match msg {
+            Retained::Set(ref pkt) => {
+                assert_eq!(pkt.topic, "test");
+                assert_eq!(pkt.payload, Bytes::from_static(payload));
+                assert_eq!(pkt.properties.topic_alias, None);
+            }
+            _ => panic!("expected retained message"),
+        }
+    }
+    assert_eq!(store[2], Retained::Clear(ByteString::from_static("test")));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retained_will() -> std::io::Result<()> {
+    let store = Arc::new(Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        let store2 = store2.clone();
+        MqttServer::new(handshake)
+            .retained(move |msg| {
+                if let Retained::Set(pkt) = msg {
+                    store.lock().unwrap().push(pkt)
+                }
+            })
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let store = store2.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        let mut subs = Vec::new();
+                        for mut sub in msg.iter_mut() {
+                            sub.confirm(sub.options().qos);
+                            subs.push((sub.topic().clone(), sub.options().clone()));
+                        }
+                        let session = session.clone();
+                        let store = store.clone();
+                        ntex::rt::spawn(async move {
+                            for (topic, opts) in subs {
+                                let msgs: Vec<_> = store
+                                    .lock()
+                                    .unwrap()
+                                    .iter()
+                                    .filter(|pkt: &&codec::Publish| pkt.topic == topic)
+                                    .cloned()
+                                    .collect();
+                                session.deliver_retained(&opts, true, msgs);
+                            }
+                        });
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    ControlMessage::Ping(msg) => ok(msg.ack()),
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let connect = |id: &'static str, topic: &'static str| {
+        codec::Packet::Connect(Box::new(codec::Connect {
+            last_will: Some(codec::LastWill {
+                qos: codec::QoS::AtMostOnce,
+                retain: true,
+                topic: ByteString::from_static(topic),
+                message: Bytes::from_static(b"offline"),
+                will_delay_interval_sec: None,
+                correlation_data: None,
+                message_expiry_interval: None,
+                content_type: None,
+                user_properties: Vec::new(),
+                is_utf8_payload: None,
+                response_topic: None,
+            }),
+            ..codec::Connect::default().client_id(id)
+        }))
+    };
+
+    // will is discarded on normal disconnect
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed.send(connect("user1", "status1")).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Disconnect(codec::Disconnect::default())).await.unwrap();
+    drop(framed);
+
+    // connection is dropped without DISCONNECT
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed.send(connect("user2", "status2")).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    drop(framed);
+    sleep(Millis(100)).await;
+
+    {
+        let store = store.lock().unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store[0].topic, "status2");
+        assert!(store[0].retain);
+    }
+
+    // later subscriber gets retained will
+    let (tx, rx) = ntex::channel::oneshot::channel();
+    let tx = RefCell::new(Some(tx));
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user3").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(move |msg: client::ControlMessage<TestError>| {
+        if let client::ControlMessage::Publish(ref p) = msg {
+            if let Some(tx) = tx.borrow_mut().take() {
+                let _ = tx.send(p.packet().clone());
+            }
+        }
+        ok::<_, TestError>(msg.disconnect(codec::Disconnect::default()))
+    }));
+    let res = sink
+        .subscribe(None)
+        .topic_filter(
+            ByteString::from_static("status2"),
+            codec::SubscriptionOptions {
+                qos: codec::QoS::AtMostOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            },
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status, vec![codec::SubscribeAckReason::GrantedQos0]);
+    let pkt = rx.await.unwrap();
+    assert_eq!(pkt.topic, "status2");
+    assert_eq!(pkt.payload, Bytes::from_static(b"offline"));
+    assert!(pkt.retain);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_zero_receive_max() -> std::io::Result<()> {
+    use ntex::codec::Decoder;
+
+    let receive_max = Arc::new(Mutex::new(None));
+    let receive_max2 = receive_max.clone();
+    let srv = server::test_server(move || {
+        let receive_max = receive_max2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            *receive_max.lock().unwrap() = Some(con.packet().receive_max);
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // receive max of 0 is omitted by client
+    let _client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .receive_max(0)
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(receive_max.lock().unwrap().take(), Some(None));
+
+    // explicit receive max of 0 is protocol error
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    framed
+        .send(Bytes::from_static(
+            b"\x10\x15\x00\x04MQTT\x05\x02\x00\x3C\x03\x21\x00\x00\x00\x05user1",
+        ))
+        .await
+        .unwrap();
+
+    let ack = framed.next().await.unwrap().unwrap();
+    let pkt = codec::Codec::default().decode(&mut BytesMut::from(ack.as_ref())).unwrap();
+    match pkt {
+        Some(codec::Packet::ConnectAck(ack)) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::ProtocolError)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+    assert!(receive_max.lock().unwrap().is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_inconsistent_will_flags() -> std::io::Result<()> {
+    let calls = Arc::new(AtomicUsize::new(0));
+    let calls2 = calls.clone();
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            calls.fetch_add(1, Relaxed);
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // will retain without will flag
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    framed
+        .send(Bytes::from_static(b"\x10\x12\x00\x04MQTT\x05\x22\x00\x3C\x00\x00\x05user1"))
+        .await
+        .unwrap();
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+    assert_eq!(calls.load(Relaxed), 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_unsupported_protocol_version() -> std::io::Result<()> {
+    use ntex::codec::Decoder;
+    use ntex_mqtt::v3::codec as codec3;
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    // v3 connect packet
+    let mut buf = BytesMut::new();
+    codec3::Codec::default()
+        .encode(codec3::Connect::default().client_id("user").into(), &mut buf)
+        .unwrap();
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    framed.send(buf.freeze()).await.unwrap();
+
+    let ack = framed.next().await.unwrap().unwrap();
+    let pkt = codec::Codec::default().decode(&mut BytesMut::from(ack.as_ref())).unwrap();
+    match pkt {
+        Some(codec::Packet::ConnectAck(ack)) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::UnsupportedProtocolVersion)
+        }
+        pkt => panic!("unexpected packet: {:?}", pkt),
+    }
+    assert!(framed.next().await.map(|res| res.is_err()).unwrap_or(true));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_stream_payload() -> std::io::Result<()> {
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let received2 = received.clone();
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        MqttServer::new(|con: Handshake<_>| {
+            ok::<_, TestError>(con.ack(St).stream_payload(1024))
+        })
+        .publish(move |mut p: Publish| {
+            let received = received.clone();
+            async move {
+                let mut size = p.payload().len();
+                if let Some(mut stream) = p.payload_stream() {
+                    assert_eq!(stream.size(), 65536);
+                    while let Some(chunk) = stream.read().await {
+                        let chunk = chunk.unwrap();
+                        assert!(chunk.iter().all(|b| *b == b'x'));
+                        size += chunk.len();
+                    }
+                    assert_eq!(stream.remaining(), 0);
+                }
+                received.lock().unwrap().push((p.is_streamed(), size));
+                Ok::<_, TestError>(p.ack())
+            }
+        })
+        .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let topic = ByteString::from_static("test");
+    sink.publish(topic.clone(), Bytes::from(vec![b'x'; 65536]))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.publish(topic.clone(), Bytes::from_static(b"small"))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    assert_eq!(*received.lock().unwrap(), vec![(true, 65536), (false, 5)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retain_coalesce() -> std::io::Result<()> {
+    let store = Arc::new(Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        MqttServer::new(handshake)
+            .retained(move |msg| store.lock().unwrap().push(msg))
+            .retain_coalesce(Millis(200))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    // set -> clear -> set
+    let topic = ByteString::from_static("test");
+    for payload in &[&b"first"[..], b"", b"last"] {
+        sink.publish(topic.clone(), Bytes::copy_from_slice(payload))
+            .retain()
+            .send_at_least_once()
+            .await
+            .unwrap();
+    }
+    assert!(store.lock().unwrap().is_empty());
+
+    sleep(Millis(400)).await;
+    let store = store.lock().unwrap();
+    assert_eq!(store.len(), 1);
+    match store[0] {
+        Retained::Set(ref pkt) => assert_eq!(pkt.payload, Bytes::from_static(b"last")),
+        _ => panic!("expected retained message"),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_retain_coalesce_topic_alias() -> std::io::Result<()> {
+    let store = Arc::new(Mutex::new(Vec::new()));
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        MqttServer::new(handshake)
+            .max_topic_alias(5)
+            .retained(move |msg| store.lock().unwrap().push(msg))
+            .retain_coalesce(Millis(200))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // aliased updates of different topics are not collapsed
+    let updates =
+        [(1, "a", &b"a1"[..]), (2, "b", &b"b1"[..]), (1, "", &b"a2"[..]), (2, "", &b"b2"[..])];
+    for (idx, (alias, topic, payload)) in updates.iter().enumerate() {
+        let mut pkt = pkt_publish();
+        pkt.retain = true;
+        pkt.packet_id = NonZeroU16::new(idx as u16 + 1);
+        pkt.topic = ByteString::from_static(topic);
+        pkt.payload = Bytes::from_static(payload);
+        pkt.properties.topic_alias = NonZeroU16::new(*alias);
+        framed.send(pkt.into()).await.unwrap();
+        assert!(matches!(framed.next().await.unwrap().unwrap(), codec::Packet::PublishAck(_)));
+    }
+
+    sleep(Millis(400)).await;
+    let mut store: Vec<_> = store
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|msg| match msg {
+            Retained::Set(ref pkt) => (pkt.topic.clone(), pkt.payload.clone()),
+            _ => panic!("expected retained message"),
+        })
+        .collect();
+    store.sort();
+    assert_eq!(
+        store,
+        vec![
+            (ByteString::from_static("a"), Bytes::from_static(b"a2")),
+            (ByteString::from_static("b"), Bytes::from_static(b"b2")),
+        ]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_map_connack() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let quirk = con.user_property("x-quirk").is_some();
+            ok::<_, TestError>(con.ack(St).with(|ack| {
+                if quirk {
+                    ack.user_properties.push(("x-broker".into(), "maintenance".into()));
+                }
+            }))
+        })
+        .finish()
+    });
+
+    let connector = |quirk: bool| {
+        client::MqttConnector::new(srv.addr())
+            .client_id("user")
+            .properties(|props| {
+                if quirk {
+                    props.push(("x-quirk".into(), "1".into()))
+                }
+            })
+            .map_connack(|ack| {
+                if ack
+                    .user_properties
+                    .iter()
+                    .any(|(k, v)| k == "x-broker" && v == "maintenance")
+                {
+                    Err(error::ClientError::Ack(Box::new(codec::ConnectAck {
+                        reason_code: codec::ConnectAckReason::ServerUnavailable,
+                        ..ack.clone()
+                    })))
+                } else {
+                    Ok(())
+                }
+            })
+    };
+
+    // success ack is accepted
+    assert!(connector(false).connect().await.is_ok());
+
+    // success ack with maintenance property is rejected
+    if let Err(error::ClientError::Ack(ack)) = connector(true).connect().await {
+        assert_eq!(ack.reason_code, codec::ConnectAckReason::ServerUnavailable);
+    } else {
+        panic!("expected connect ack error");
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_assigned_client_id() -> std::io::Result<()> {
+    async fn connect(srv: &server::TestServer) -> codec::ConnectAck {
+        let io = srv.connect().await.unwrap();
+        let mut framed = Framed::new(io, codec::Codec::default());
+        let mut pkt = codec::Connect::default();
+        pkt.clean_start = true;
+        framed.send(codec::Packet::Connect(Box::new(pkt))).await.unwrap();
+        match framed.next().await.unwrap().unwrap() {
+            codec::Packet::ConnectAck(ack) => *ack,
+            p => panic!("expected connect ack, got {:?}", p),
+        }
+    }
+
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            assert_eq!(Some(&con.packet().client_id), con.assigned_client_id());
+            ok::<_, TestError>(con.ack(St))
+        })
+        .assign_client_id()
+        .finish()
+    });
+
+    let ack1 = connect(&srv).await;
+    let ack2 = connect(&srv).await;
+    assert_eq!(ack1.reason_code, codec::ConnectAckReason::Success);
+    assert!(!ack1.assigned_client_id.as_ref().unwrap().is_empty());
+    assert_ne!(ack1.assigned_client_id, ack2.assigned_client_id);
+
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            if con.assigned_client_id().is_some() {
+                assert_eq!(con.packet().client_id, "generated");
+            }
+            ok::<_, TestError>(con.ack(St))
+        })
+        .client_id_generator(|| ByteString::from_static("generated"))
+        .finish()
+    });
+
+    let ack = connect(&srv).await;
+    assert_eq!(ack.assigned_client_id, Some(ByteString::from_static("generated")));
+
+    // client id is not assigned to connection with client id
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    assert_eq!(client.packet().assigned_client_id, None);
+
+    // client id is not assigned by default
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            assert!(con.packet().client_id.is_empty());
+            assert!(con.assigned_client_id().is_none());
+            ok::<_, TestError>(con.ack(St))
+        })
+        .finish()
+    });
+    let ack = connect(&srv).await;
+    assert_eq!(ack.assigned_client_id, None);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_tracked() -> std::io::Result<()> {
+    let ids = Arc::new(Mutex::new(Vec::new()));
+    let ids2 = ids.clone();
+    let srv = server::test_server(move || {
+        let ids = ids2.clone();
+        MqttServer::new(handshake)
+            .publish(move |p: Publish| {
+                ids.lock().unwrap().push(p.id().unwrap().get());
+                ok::<_, TestError>(p.ack())
+            })
+            .finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+
+    let (id1, fut1) = sink.publish_tracked(ByteString::from_static("test"), Bytes::new());
+    let (id2, fut2) = sink.publish_tracked(ByteString::from_static("test"), Bytes::new());
+    assert_ne!(id1, id2);
+    assert!(fut1.await.is_ok());
+    assert!(fut2.await.is_ok());
+    assert_eq!(*ids.lock().unwrap(), vec![id1, id2]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_wildcard_subscriptions_disabled() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let topics2 = topics.clone();
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(handshake)
+            .wildcard_subscriptions(false)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    msg.iter_mut().for_each(|mut s| {
+                        topics.lock().unwrap().push(s.topic().to_string());
+                        s.confirm(codec::QoS::AtLeastOnce)
+                    });
+                    ok::<_, TestError>(msg.ack())
+                }
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::ConnectAck(ack) => {
+            assert_eq!(ack.wildcard_subscription_available, Some(false))
+        }
+        p => panic!("expected connect ack, got {:?}", p),
+    }
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    framed
+        .send(codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(1).unwrap(),
+            topic_filters: vec![
+                ("a/+".into(), opts.clone()),
+                ("a/b".into(), opts.clone()),
+                ("$share/group/#".into(), opts),
+            ],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        }))
+        .await
+        .unwrap();
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::SubscribeAck(ack) => assert_eq!(
+            ack.status,
+            vec![
+                codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+                codec::SubscribeAckReason::GrantedQos1,
+                codec::SubscribeAckReason::WildcardSubscriptionsNotSupported,
+            ]
+        ),
+        p => panic!("expected subscribe ack, got {:?}", p),
+    }
+    assert_eq!(*topics.lock().unwrap(), vec!["a/b".to_string()]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_circuit_breaker() -> std::io::Result<()> {
+    let accept = Arc::new(AtomicBool::new(false));
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let accept2 = accept.clone();
+    let attempts2 = attempts.clone();
+    let srv = server::test_server(move || {
+        let accept = accept2.clone();
+        let attempts = attempts2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            attempts.fetch_add(1, Relaxed);
+            if accept.load(Relaxed) {
+                ok::<_, TestError>(con.ack(St))
+            } else {
+                ok(con.failed(codec::ConnectAckReason::ServerUnavailable))
+            }
+        })
+        .finish()
+    });
+
+    let clock = Clock::manual();
+    let connector = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .clock(clock.clone())
+        .circuit_breaker(2, Millis(1000));
+
+    // breaker opens after 2 failures, no connect attempt is made
+    for _ in 0..2 {
+        assert!(matches!(connector.connect().await, Err(error::ClientError::Ack(_))));
+    }
+    assert!(matches!(connector.connect().await, Err(error::ClientError::CircuitOpen)));
+    assert_eq!(attempts.load(Relaxed), 2);
+
+    // half-open probe fails, breaker opens again
+    clock.advance(Millis(1000));
+    assert!(matches!(connector.connect().await, Err(error::ClientError::Ack(_))));
+    assert!(matches!(connector.connect().await, Err(error::ClientError::CircuitOpen)));
+    assert_eq!(attempts.load(Relaxed), 3);
+
+    // successful probe closes breaker
+    accept.store(true, Relaxed);
+    clock.advance(Millis(1000));
+    assert!(connector.connect().await.is_ok());
+    assert!(connector.connect().await.is_ok());
+    assert_eq!(attempts.load(Relaxed), 5);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_read_buffer() -> std::io::Result<()> {
+    use ntex::codec::Decoder;
+
+    let stats = MemoryStats::new();
+    let stats2 = stats.clone();
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .memory_stats(stats2.clone())
+            .max_read_buffer(1024)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    codec
+        .encode(
+            codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))),
+            &mut buf,
+        )
+        .unwrap();
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+    framed.send(buf.split().freeze()).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.payload = Bytes::from(vec![b'x'; 4096]);
+    codec.encode(codec::Packet::Publish(pkt), &mut buf).unwrap();
+
+    // partial packet is buffered
+    framed.send(buf.split_to(512).freeze()).await.unwrap();
+    sleep(Millis(400)).await;
+    assert!(stats.read_buffered() > 500);
+
+    // buffered data exceeds limit
+    framed.send(buf.split_to(1024).freeze()).await.unwrap();
+    let mut data = framed.next().await.unwrap().unwrap();
+    match codec.decode(&mut data).unwrap() {
+        Some(codec::Packet::Disconnect(pkt)) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::QuotaExceeded)
+        }
+        p => panic!("expected disconnect, got {:?}", p),
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_qos0_flushed() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let sink = con.sink();
+            let results = results.clone();
+            ntex::rt::spawn(async move {
+                let res = sink.publish_qos0_flushed("small", Bytes::from_static(b"data")).await;
+                results.lock().unwrap().push(("small", res.is_ok()));
+                let payload = Bytes::from(vec![b'x'; 32 * 1024 * 1024]);
+                let res = sink.publish_qos0_flushed("large", payload).await;
+                results.lock().unwrap().push(("large", res.is_ok()));
+            });
+            ok::<_, TestError>(con.ack(St))
+        })
+        .finish()
+    });
+
+    // peer does not read, large publish is not flushed
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Millis(300)).await;
+    assert_eq!(*results.lock().unwrap(), vec![("small", true)]);
+
+    // peer reads, large publish gets flushed
+    loop {
+        if let codec::Packet::Publish(pkt) = framed.next().await.unwrap().unwrap() {
+            if pkt.topic == "large" {
+                break;
+            }
+        }
+    }
+    sleep(Millis(100)).await;
+    assert_eq!(*results.lock().unwrap(), vec![("small", true), ("large", true)]);
+
+    // peer disconnects before large publish is flushed
+    results.lock().unwrap().clear();
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Millis(300)).await;
+    drop(framed);
+    sleep(Millis(300)).await;
+    assert_eq!(*results.lock().unwrap(), vec![("small", true), ("large", false)]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_max_packet_size() {
+    let disconnect = Arc::new(Mutex::new(None));
+    let disconnect2 = disconnect.clone();
+    let srv = server::test_server(move || {
+        let disconnect = disconnect2.clone();
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let disconnect = disconnect.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                let _ = framed.next().await.unwrap().unwrap();
+                framed
+                    .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                    .await
+                    .unwrap();
+                sleep(Millis(100)).await;
+
+                // publish exceeds client's max packet size
+                let mut pkt = pkt_publish();
+                pkt.payload = Bytes::from(vec![b'x'; 2048]);
+                framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+
+                if let Some(Ok(codec::Packet::Disconnect(pkt))) = framed.next().await {
+                    *disconnect.lock().unwrap() = Some(pkt.reason_code);
+                }
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .max_packet_size(1024)
+        .connect()
+        .await
+        .unwrap();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(500)).await;
+
+    assert_eq!(*disconnect.lock().unwrap(), Some(codec::DisconnectReasonCode::PacketTooLarge));
+}
+
+#[ntex::test]
+async fn test_connect_template() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            let pkt = con.packet();
+            let valid = pkt.user_properties.iter().any(|(k, v)| k == "gw" && v == "1")
+                && pkt.keep_alive == 30
+                && pkt.username.as_ref() == Some(&pkt.client_id)
+                && pkt.password.as_ref().map(|p| p.as_ref()) == Some(&b"secret"[..]);
+            if valid {
+                ok::<_, TestError>(con.ack(St))
+            } else {
+                ok(con.failed(codec::ConnectAckReason::BadUserNameOrPassword))
+            }
+        })
+        .finish()
+    });
+
+    let template = Rc::new(codec::Connect {
+        keep_alive: 30,
+        user_properties: vec![("gw".into(), "1".into())],
+        ..codec::Connect::default()
+    });
+
+    for id in &["device-1", "device-2"] {
+        let client = client::MqttConnector::from_template(srv.addr(), template.clone())
+            .client_id(*id)
+            .username(ByteString::from(*id))
+            .password(Bytes::from_static(b"secret"))
+            .connect()
+            .await;
+        assert!(client.is_ok());
+    }
+
+    // overrides do not change template
+    assert!(template.client_id.is_empty());
+    assert!(template.username.is_none());
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_alias_table() -> std::io::Result<()> {
+    let table = Arc::new(Mutex::new(Vec::new()));
+    let table2 = table.clone();
+    let srv = server::test_server(move || {
+        let table = table2.clone();
+        MqttServer::new(handshake)
+            .max_topic_alias(5)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let table = table.clone();
+                let sink = session.sink().clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    *table.lock().unwrap() = sink.alias_table();
+                    let _ = sink
+                        .publish(ByteString::from_static("srv/topic"), Bytes::new())
+                        .properties(|props| props.topic_alias = NonZeroU16::new(2))
+                        .send_at_most_once();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .packet(|pkt| pkt.topic_alias_max = 3)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start(|msg: client::ControlMessage<()>| {
+        ok::<_, ()>(match msg {
+            client::ControlMessage::Publish(pkt) => pkt.ack_qos0(),
+            msg => msg.disconnect(Default::default()),
+        })
+    }));
+
+    sink.publish(ByteString::from_static("a/b"), Bytes::new())
+        .properties(|props| props.topic_alias = NonZeroU16::new(1))
+        .send_at_least_once()
+        .await
+        .unwrap();
+    // aliased publish without topic does not change table
+    sink.publish(ByteString::new(), Bytes::new())
+        .properties(|props| props.topic_alias = NonZeroU16::new(1))
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    let expected = vec![(1, ByteString::from_static("a/b"))];
+    assert_eq!(*table.lock().unwrap(), expected);
+    assert_eq!(sink.outbound_alias_table(), expected);
+    sleep(Millis(50)).await;
+    assert_eq!(sink.alias_table(), vec![(2, ByteString::from_static("srv/topic"))]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_session_taken_over() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let sessions: Rc<RefCell<Vec<(ByteString, MqttSink)>>> = Rc::default();
+        MqttServer::new(move |con: Handshake<_>| {
+            let id = con.packet().client_id.clone();
+            let mut sessions = sessions.borrow_mut();
+            if let Some(idx) = sessions.iter().position(|(cid, _)| *cid == id) {
+                let (_, sink) = sessions.remove(idx);
+                sink.close_with_reason(codec::Disconnect::new(
+                    codec::DisconnectReasonCode::SessionTakenOver,
+                ));
+            }
+            sessions.push((id, con.sink()));
+            ok::<_, TestError>(con.ack(St))
+        })
+        .finish()
+    });
+
+    let first =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let closed = first.closed();
+    ntex::rt::spawn(first.start_default());
+
+    let _second =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let pkt = ntex::time::timeout(Millis(1000), closed).await.unwrap().unwrap();
+    assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::SessionTakenOver);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_ping_rate_limit() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .control(move |msg| match msg {
+                ControlMessage::Ping(ref ping)
+                    if ping.interval().map(|i| i < Duration::from_millis(500))
+                        == Some(true) =>
+                {
+                    ok::<_, TestError>(msg.disconnect_with(codec::Disconnect::new(
+                        codec::DisconnectReasonCode::MessageRateTooHigh,
+                    )))
+                }
+                ControlMessage::Ping(ping) => ok(ping.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // first ping has no interval
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    // abusive pinger gets disconnected
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::MessageRateTooHigh
+        ))
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "trace")]
+#[ntex::test]
+async fn test_protocol_events() -> std::io::Result<()> {
+    use ntex_mqtt::v5::trace::ProtocolEventKind;
+
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake).publish(|p: Publish| ok::<_, TestError>(p.ack())).finish()
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    let mut events = client.protocol_events();
+    ntex::rt::spawn(client.start_default());
+
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+    sink.close();
+
+    let mut kinds = Vec::new();
+    while let Some(ev) = events.next().await {
+        kinds.push(ev.kind);
+    }
+    assert!(matches!(kinds[0], ProtocolEventKind::Sent(codec::Packet::Publish(_))));
+    assert!(matches!(kinds[1], ProtocolEventKind::Received(codec::Packet::PublishAck(_))));
+    assert!(matches!(kinds[2], ProtocolEventKind::Sent(codec::Packet::Disconnect(_))));
+    assert!(matches!(kinds[3], ProtocolEventKind::Closed));
+    assert_eq!(kinds.len(), 4);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_control_buffer_backpressure() -> std::io::Result<()> {
+    let release = Arc::new(AtomicBool::new(false));
+    let published = Arc::new(AtomicBool::new(false));
+    let release2 = release.clone();
+    let published2 = published.clone();
+
+    let srv = server::test_server(move || {
+        let release = release2.clone();
+        let published = published2.clone();
+        MqttServer::new(handshake)
+            .control_buffer_size(1)
+            .publish(move |p: Publish| {
+                published.store(true, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .control(move |msg| {
+                let release = release.clone();
+                async move {
+                    match msg {
+                        ControlMessage::Subscribe(mut msg) => {
+                            // slow control service
+                            while !release.load(Relaxed) {
+                                sleep(Millis(10)).await;
+                            }
+                            for mut sub in &mut msg {
+                                sub.subscribe(codec::QoS::AtLeastOnce);
+                            }
+                            Ok::<_, TestError>(msg.ack())
+                        }
+                        msg => Ok(msg.disconnect()),
+                    }
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // first subscribe is handled, second is buffered, third fills dispatcher
+    for id in 1..4 {
+        framed
+            .send(codec::Packet::Subscribe(codec::Subscribe {
+                packet_id: NonZeroU16::new(id).unwrap(),
+                topic_filters: vec![(
+                    "topic".into(),
+                    codec::SubscriptionOptions {
+                        qos: codec::QoS::AtLeastOnce,
+                        no_local: false,
+                        retain_as_published: false,
+                        retain_handling: codec::RetainHandling::AtSubscribe,
+                    },
+                )],
+                id: None,
+                user_properties: codec::UserProperties::default(),
+            }))
+            .await
+            .unwrap();
+    }
+    let mut pkt = pkt_publish();
+    pkt.packet_id = NonZeroU16::new(4);
+    framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+
+    // server does not read publish until control service is ready
+    sleep(Millis(200)).await;
+    assert!(!published.load(Relaxed));
+
+    release.store(true, Relaxed);
+    for _ in 0..3 {
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert!(matches!(pkt, codec::Packet::SubscribeAck(_)));
+    }
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    assert!(published.load(Relaxed));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_disconnect_with_will() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let subscribers: Rc<RefCell<Vec<MqttSink>>> = Rc::default();
+        let subscribers2 = subscribers.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            if con.packet().client_id == "sub" {
+                subscribers.borrow_mut().push(con.sink());
+            }
+            let will = con.packet().last_will.clone();
+            ok::<_, TestError>(con.ack(will))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .control(ntex::service::fn_factory_with_config(
+            move |session: Session<Option<codec::LastWill>>| {
+                let subscribers = subscribers2.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Disconnect(msg) => {
+                        if msg.publish_will() {
+                            if let Some(ref will) = session.state() {
+                                for sink in subscribers.borrow().iter() {
+                                    sink.publish(will.topic.clone(), will.message.clone())
+                                        .send_at_most_once()
+                                        .unwrap();
+                                }
+                            }
+                        }
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    msg => ok(msg.disconnect()),
+                }))
+            },
+        ))
+        .finish()
+    });
+
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let received2 = received.clone();
+    let sub = client::MqttConnector::new(srv.addr()).client_id("sub").connect().await.unwrap();
+    ntex::rt::spawn(sub.start(move |msg: client::ControlMessage<()>| {
+        ok::<_, ()>(match msg {
+            client::ControlMessage::Publish(pkt) => {
+                received2.borrow_mut().push(pkt.packet().payload.clone());
+                pkt.ack_qos0()
+            }
+            msg => msg.disconnect(Default::default()),
+        })
+    }));
+
+    let will = |msg| codec::LastWill {
+        qos: codec::QoS::AtMostOnce,
+        retain: false,
+        topic: ByteString::from_static("will"),
+        message: Bytes::from_static(msg),
+        will_delay_interval_sec: None,
+        correlation_data: None,
+        message_expiry_interval: None,
+        content_type: None,
+        user_properties: Vec::new(),
+        is_utf8_payload: None,
+        response_topic: None,
+    };
+
+    // normal disconnect discards will
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user1")
+        .last_will(will(b"discarded"))
+        .connect()
+        .await
+        .unwrap();
+    let closed = client.closed();
+    client.disconnect_with(codec::Disconnect::new(
+        codec::DisconnectReasonCode::NormalDisconnection,
+    ));
+    ntex::rt::spawn(client.start_default());
+    closed.await;
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .last_will(will(b"published"))
+        .connect()
+        .await
+        .unwrap();
+    let closed = client.closed();
+    client.disconnect_with(codec::Disconnect::new(
+        codec::DisconnectReasonCode::DisconnectWithWillMessage,
+    ));
+    ntex::rt::spawn(client.start_default());
+    closed.await;
+
+    sleep(Millis(100)).await;
+    assert_eq!(*received.borrow(), vec![Bytes::from_static(b"published")]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_selector_routes() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        Selector::from_routes(vec![
+            (
+                Matcher::ClientIdPrefix("dev-".to_string()),
+                MqttServer::new(handshake).max_topic_alias(5),
+            ),
+            (
+                Matcher::Custom(Box::new(|_| true)),
+                MqttServer::new(handshake).max_topic_alias(10),
+            ),
+        ])
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("dev-1").connect().await.unwrap();
+    assert_eq!(client.packet().topic_alias_max, 5);
+    client.sink().close();
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("app-1").connect().await.unwrap();
+    assert_eq!(client.packet().topic_alias_max, 10);
+    client.sink().close();
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_packet_raw() -> std::io::Result<()> {
+    let raw = Arc::new(Mutex::new(None));
+    let raw2 = raw.clone();
+    let srv = server::test_server(move || {
+        let raw = raw2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            *raw.lock().unwrap() = Some(con.packet_raw().clone());
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let mut pkt = codec::Connect::default().client_id("user").receive_max(16);
+    pkt.user_properties.push(("sig".into(), "abc".into()));
+    let mut expected = BytesMut::new();
+    codec::Codec::new()
+        .encode(codec::Packet::Connect(Box::new(pkt.clone())), &mut expected)
+        .unwrap();
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed.send(codec::Packet::Connect(Box::new(pkt))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // fixed header is 2 bytes, packet type and remaining length
+    let raw = raw.lock().unwrap().take().unwrap();
+    assert_eq!(raw, expected.split_off(2).freeze());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_shape_topic() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        let sinks = Rc::new(RefCell::new(Vec::new()));
+        let sinks2 = sinks.clone();
+
+        MqttServer::new(move |con: Handshake<_>| {
+            sinks.borrow_mut().push(con.sink());
+            ok::<_, TestError>(con.ack(St))
+        })
+        .shape_topic("dropped/#", 4, ShapePolicy::Drop)
+        .shape_topic("delayed/#", 4, ShapePolicy::Delay(16))
+        .publish(move |p: Publish| {
+            broadcast(&sinks2.borrow(), p.packet());
+            ok::<_, TestError>(p.ack())
+        })
+        .finish()
+    });
+
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let delayed = Arc::new(AtomicUsize::new(0));
+    let dropped2 = dropped.clone();
+    let delayed2 = delayed.clone();
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client
+            .resource("dropped/a", move |p: Publish| {
+                dropped2.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .resource("delayed/a", move |p: Publish| {
+                delayed2.fetch_add(1, Relaxed);
+                ok::<_, TestError>(p.ack())
+            })
+            .start_default(),
+    );
+
+    for topic in &["dropped/a", "delayed/a"] {
+        for _ in 0..6 {
+            sink.publish(ByteString::from_static(topic), Bytes::from_static(b"data"))
+                .send_at_least_once()
+                .await
+                .unwrap();
+        }
+    }
+
+    // burst is delivered immediately
+    sleep(Millis(100)).await;
+    assert_eq!(dropped.load(Relaxed), 4);
+    assert_eq!(delayed.load(Relaxed), 4);
+
+    // delayed publishes are written as rate allows
+    sleep(Millis(700)).await;
+    assert_eq!(dropped.load(Relaxed), 4);
+    assert_eq!(delayed.load(Relaxed), 6);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_qos2_resume() -> std::io::Result<()> {
+    let store: Arc<Mutex<Option<SessionSnapshot>>> = Arc::default();
+    let delivered = Arc::new(AtomicUsize::new(0));
+    let store2 = store.clone();
+    let delivered2 = delivered.clone();
+
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        let store2 = store2.clone();
+        let delivered = delivered2.clone();
+        MqttServer::new(handshake)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                // restore persisted session
+                if let Some(ref snapshot) = *store.lock().unwrap() {
+                    session.restore(snapshot);
+                }
+                let delivered = delivered.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    delivered.fetch_add(1, Relaxed);
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .control(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let store = store2.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Closed(msg) => {
+                        *store.lock().unwrap() = Some(session.snapshot());
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    msg => ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let mut pkt = pkt_publish();
+    pkt.qos = codec::QoS::ExactlyOnce;
+    let pubrec = codec::Packet::PublishReceived(codec::PublishAck {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        ..Default::default()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Publish(pkt.clone())).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), pubrec);
+
+    // connection is lost before PUBREL
+    drop(framed);
+    sleep(Millis(100)).await;
+    assert_eq!(store.lock().unwrap().as_ref().unwrap().qos2_received, vec![1]);
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // retransmitted publish is not delivered again
+    pkt.dup = true;
+    framed.send(codec::Packet::Publish(pkt.clone())).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), pubrec);
+    assert_eq!(delivered.load(Relaxed), 1);
+
+    let pubrel = codec::PublishAck2 {
+        packet_id: NonZeroU16::new(1).unwrap(),
+        reason_code: codec::PublishAck2Reason::Success,
+        properties: Vec::new(),
+        reason_string: None,
+    };
+    framed.send(codec::Packet::PublishRelease(pubrel.clone())).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishComplete(pubrel.clone())
+    );
+
+    // unknown packet id
+    framed.send(codec::Packet::PublishRelease(pubrel.clone())).await.unwrap();
+    assert_eq!(
+        framed.next().await.unwrap().unwrap(),
+        codec::Packet::PublishComplete(codec::PublishAck2 {
+            reason_code: codec::PublishAck2Reason::PacketIdNotFound,
+            ..pubrel
+        })
+    );
+
+    // packet id is released, new publish is delivered
+    pkt.dup = false;
+    framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+    assert_eq!(framed.next().await.unwrap().unwrap(), pubrec);
+    assert_eq!(delivered.load(Relaxed), 2);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[ntex::test]
+async fn test_socket_buffers() -> std::io::Result<()> {
+    let sizes = Arc::new(Mutex::new(None));
+    let sizes2 = sizes.clone();
+    let srv = server::test_server(move || {
+        let sizes = sizes2.clone();
+        MqttServer::new(move |mut con: Handshake<_>| {
+            let sock = socket2::SockRef::from(&*con.io());
+            *sizes.lock().unwrap() =
+                Some((sock.recv_buffer_size().unwrap(), sock.send_buffer_size().unwrap()));
+            ok::<_, TestError>(con.ack(St))
+        })
+        .socket_buffers(256 * 1024, 128 * 1024)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .socket_buffers(64 * 1024, 64 * 1024)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sink.publish(ByteString::from_static("test"), Bytes::new())
+        .send_at_least_once()
+        .await
+        .unwrap();
+
+    // system could adjust requested sizes
+    let (recv, send) = sizes.lock().unwrap().take().unwrap();
+    assert!(recv >= 256 * 1024);
+    assert!(send >= 128 * 1024);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[ntex::test]
+async fn test_keep_alive_mode() -> std::io::Result<()> {
+    let tcp_keepalive = Arc::new(AtomicBool::new(false));
+    let tcp_keepalive2 = tcp_keepalive.clone();
+    let srv = server::test_server(move || {
+        let tcp_keepalive = tcp_keepalive2.clone();
+        MqttServer::new(move |mut con: Handshake<_>| {
+            let sock = socket2::SockRef::from(&*con.io());
+            tcp_keepalive.store(sock.keepalive().unwrap(), Relaxed);
+            ok::<_, TestError>(con.ack(St))
+        })
+        .keep_alive_mode(KeepAliveMode::Lazy {
+            keep_alive: Seconds(300),
+            tcp_idle: Seconds(60),
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // idle client gets longer keep-alive
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(10))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, Some(300));
+    assert!(tcp_keepalive.load(Relaxed));
+
+    // client without keep-alive is not changed
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .keep_alive(Seconds(0))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, None);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_force_keep_alive() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St).keep_alive(60)) })
+            .force_keep_alive(Seconds(1))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    // server keep-alive overrides requested keep-alive
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(60))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, Some(1));
+    assert_eq!(client.keep_alive(), Seconds(1));
+
+    // client pings with server keep-alive
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    sleep(Millis(3500)).await;
+    assert!(sink.is_open());
+    sink.close();
+
+    // same keep-alive is not sent back
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .keep_alive(Seconds(1))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, None);
+    assert_eq!(client.keep_alive(), Seconds(1));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_redundant_server_keep_alive() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St).keep_alive(10)) })
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(10))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, None);
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user2")
+        .keep_alive(Seconds(30))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(client.packet().server_keepalive_sec, Some(10));
+    assert_eq!(client.keep_alive(), Seconds(10));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_retry() -> std::io::Result<()> {
+    let ready = Arc::new(AtomicBool::new(false));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let ready2 = ready.clone();
+    let calls2 = calls.clone();
+
+    let srv = server::test_server(move || {
+        let ready = ready2.clone();
+        let calls = calls2.clone();
+
+        MqttServer::new(move |con: Handshake<_>| {
+            let ready = ready.clone();
+            calls.fetch_add(1, Relaxed);
+            async move {
+                if con.packet().client_id == "authorizer" {
+                    ready.store(true, Relaxed);
+                } else if !ready.load(Relaxed) {
+                    return Ok::<_, TestError>(con.retry(Millis(20)));
+                }
+                Ok(con.ack(St))
+            }
+        })
+        .max_concurrent_auth(1)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // pending handshake does not hold auth slot
+    let addr = srv.addr();
+    let pending = ntex::rt::spawn(async move {
+        client::MqttConnector::new(addr).client_id("user").connect().await.is_ok()
+    });
+    sleep(Millis(100)).await;
+    assert!(calls.load(Relaxed) > 1);
+
+    let client = client::MqttConnector::new(srv.addr()).client_id("authorizer").connect().await;
+    assert!(client.is_ok());
+    assert!(pending.await.unwrap());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_auth_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(move |con: Handshake<_>| async move {
+            match con.packet().client_id.as_ref() {
+                "slow" => {
+                    sleep(Millis(5_000)).await;
+                    Ok(con.ack(St))
+                }
+                "retry" => Ok::<_, TestError>(con.retry(Millis(50))),
+                _ => Ok(con.ack(St)),
+            }
+        })
+        .max_concurrent_auth(1)
+        .auth_timeout(Seconds(1))
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // slow authorizer holds auth slot
+    let addr = srv.addr();
+    let _slow = ntex::rt::spawn(async move {
+        let _ = client::MqttConnector::new(addr).client_id("slow").connect().await;
+    });
+    sleep(Millis(100)).await;
+
+    // waiting handshake is rejected
+    let err =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.err().unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::ServerBusy);
+    } else {
+        panic!("Expected ClientError::Ack, got {:?}", err);
+    }
+
+    // pending handshake is rejected
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("retry")
+        .connect()
+        .await
+        .err()
+        .unwrap();
+    if let error::ClientError::Ack(pkt) = err {
+        assert_eq!(pkt.reason_code, codec::ConnectAckReason::ServerBusy);
+    } else {
+        panic!("Expected ClientError::Ack, got {:?}", err);
+    }
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_auth_slot_after_timeout() -> std::io::Result<()> {
+    let srv = server::test_server(move || {
+        MqttServer::new(move |con: Handshake<_>| async move {
+            if con.packet().client_id == "slow" {
+                sleep(Millis(1_200)).await;
+            }
+            Ok::<_, TestError>(con.ack(St))
+        })
+        .max_concurrent_auth(1)
+        .auth_timeout(Seconds(1))
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // slow authorizer holds auth slot
+    let addr = srv.addr();
+    let slow = ntex::rt::spawn(async move {
+        client::MqttConnector::new(addr).client_id("slow").connect().await.is_ok()
+    });
+    sleep(Millis(100)).await;
+
+    // first waiter times out, second one gets released slot
+    let addr = srv.addr();
+    let timeout = ntex::rt::spawn(async move {
+        client::MqttConnector::new(addr).client_id("timeout").connect().await.is_err()
+    });
+    sleep(Millis(900)).await;
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+    assert!(timeout.await.unwrap());
+    assert!(slow.await.unwrap());
+
+    Ok(())
+}
+
+fn qos0_violation_server(
+    policy: Qos0ViolationPolicy,
+    topics: Arc<Mutex<Vec<String>>>,
+) -> server::TestServer {
+    server::test_server(move || {
+        let topics = topics.clone();
+        MqttServer::new(handshake)
+            .qos0_violation_policy(policy)
+            .publish(move |p: Publish| {
+                topics.lock().unwrap().push(p.topic().path().to_string());
+                if p.topic().path() == "secret" {
+                    ok::<_, TestError>(
+                        p.ack()
+                            .reason_code(codec::PublishAckReason::NotAuthorized)
+                            .reason("denied".into()),
+                    )
+                } else {
+                    ok(p.ack())
+                }
+            })
+            .finish()
+    })
+}
+
+async fn qos0_violation_publish(
+    srv: &server::TestServer,
+) -> Framed<ntex::rt::net::TcpStream, codec::Codec> {
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let mut pkt = pkt_publish();
+    pkt.qos = codec::QoS::AtMostOnce;
+    pkt.packet_id = None;
+    pkt.topic = ByteString::from("secret");
+    framed.send(codec::Packet::Publish(pkt.clone())).await.unwrap();
+    pkt.topic = ByteString::from("test");
+    framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+    framed
+}
+
+#[ntex::test]
+async fn test_qos0_violation_drop() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let srv = qos0_violation_server(Qos0ViolationPolicy::Drop, topics.clone());
+
+    // rejected publish is dropped, connection stays open
+    let mut framed = qos0_violation_publish(&srv).await;
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+    assert_eq!(*topics.lock().unwrap(), vec!["secret".to_string(), "test".to_string()]);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_qos0_violation_disconnect() -> std::io::Result<()> {
+    let topics = Arc::new(Mutex::new(Vec::new()));
+    let srv = qos0_violation_server(Qos0ViolationPolicy::Disconnect, topics.clone());
+
+    // rejected publish closes connection with ack reason
+    let mut framed = qos0_violation_publish(&srv).await;
+    match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Disconnect(pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::NotAuthorized);
+            assert_eq!(pkt.reason_string, Some(ByteString::from("denied")));
+        }
+        p => panic!("expected disconnect, got {:?}", p),
+    }
+    assert!(framed.next().await.is_none());
+    assert_eq!(topics.lock().unwrap()[0], "secret");
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_publish_delivery() -> std::io::Result<()> {
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let results2 = results.clone();
+    let srv = server::test_server(move || {
+        let results = results2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let sink = con.sink();
+            let results = results.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                let res =
+                    sink.publish("test", Bytes::from_static(b"1")).send_at_least_once().await;
+                results.lock().unwrap().push(res.map(|ack| ack.reason_code));
+                sink.publish("test", Bytes::from_static(b"2")).send_at_least_once_with(
+                    move |res| {
+                        results.lock().unwrap().push(res.map(|ack| ack.reason_code));
+                    },
+                );
+            });
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    // simulated subscriber
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // publish future resolves on subscriber's ack
+    let packet_id = match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        p => panic!("expected publish, got {:?}", p),
+    };
+    sleep(Millis(100)).await;
+    assert!(results.lock().unwrap().is_empty());
+    framed
+        .send(codec::Packet::PublishAck(codec::PublishAck { packet_id, ..Default::default() }))
+        .await
+        .unwrap();
+
+    // callback gets negative ack
+    let packet_id = match framed.next().await.unwrap().unwrap() {
+        codec::Packet::Publish(pkt) => pkt.packet_id.unwrap(),
+        p => panic!("expected publish, got {:?}", p),
+    };
+    let ack = codec::PublishAck {
+        packet_id,
+        reason_code: codec::PublishAckReason::NotAuthorized,
+        ..Default::default()
+    };
+    framed.send(codec::Packet::PublishAck(ack.clone())).await.unwrap();
+    sleep(Millis(100)).await;
+    assert_eq!(
+        *results.lock().unwrap(),
+        vec![Ok(codec::PublishAckReason::Success), Err(error::PublishQos1Error::Fail(ack))]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_handshake_second_connect() -> std::io::Result<()> {
+    use ntex::codec::Decoder;
+
+    let handshakes = Arc::new(AtomicUsize::new(0));
+    let handshakes2 = handshakes.clone();
+    let srv = server::test_server(move || {
+        let handshakes = handshakes2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            handshakes.fetch_add(1, Relaxed);
+            async move {
+                sleep(Millis(200)).await;
+                Ok::<_, TestError>(con.ack(St))
+            }
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let codec = codec::Codec::default();
+    let mut buf = BytesMut::new();
+    let connect = codec::Connect::default().client_id("user");
+    codec.encode(codec::Packet::Connect(Box::new(connect.clone())), &mut buf).unwrap();
+    codec.encode(codec::Packet::Connect(Box::new(connect.clone())), &mut buf).unwrap();
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, BytesCodec);
+
+    // second CONNECT follows first one immediately, more bytes arrive
+    // while handshake service is processing
+    framed.send(buf.split_to(buf.len() - 4).freeze()).await.unwrap();
+    sleep(Millis(50)).await;
+    framed.send(buf.split().freeze()).await.unwrap();
+
+    let mut data = BytesMut::new();
+    let mut packets = Vec::new();
+    while let Some(Ok(chunk)) = framed.next().await {
+        data.extend_from_slice(&chunk);
+        while let Some(pkt) = codec.decode(&mut data).unwrap() {
+            packets.push(pkt);
+        }
+    }
+    assert_eq!(packets.len(), 2);
+    match packets[0] {
+        codec::Packet::ConnectAck(ref ack) => {
+            assert_eq!(ack.reason_code, codec::ConnectAckReason::Success)
+        }
+        ref p => panic!("expected connect ack, got {:?}", p),
+    }
+    match packets[1] {
+        codec::Packet::Disconnect(ref pkt) => {
+            assert_eq!(pkt.reason_code, codec::DisconnectReasonCode::ProtocolError)
+        }
+        ref p => panic!("expected disconnect, got {:?}", p),
+    }
+    assert_eq!(handshakes.load(Relaxed), 1);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_max_inbound_topic_aliases() {
+    let result = Arc::new(Mutex::new(None));
+    let result2 = result.clone();
+    let srv = server::test_server(move || {
+        let result = result2.clone();
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let result = result.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                let alias_max = match framed.next().await.unwrap().unwrap() {
+                    codec::Packet::Connect(pkt) => pkt.topic_alias_max,
+                    p => panic!("expected connect, got {:?}", p),
+                };
+                framed
+                    .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                    .await
+                    .unwrap();
+                sleep(Millis(50)).await;
+
+                // alias within cap is accepted, alias over cap closes connection
+                let mut pkt = pkt_publish();
+                pkt.qos = codec::QoS::AtMostOnce;
+                pkt.packet_id = None;
+                pkt.properties.topic_alias = NonZeroU16::new(4);
+                framed.send(codec::Packet::Publish(pkt.clone())).await.unwrap();
+                pkt.properties.topic_alias = NonZeroU16::new(5);
+                framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+
+                if let Some(Ok(codec::Packet::Disconnect(pkt))) = framed.next().await {
+                    *result.lock().unwrap() = Some((alias_max, pkt.reason_code));
+                }
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .packet(|pkt| pkt.topic_alias_max = 10)
+        .max_inbound_topic_aliases(4)
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(
+        client.resource("test", |p: Publish| ok::<_, TestError>(p.ack())).start_default(),
+    );
+    sleep(Millis(500)).await;
+
+    assert_eq!(
+        *result.lock().unwrap(),
+        Some((4, codec::DisconnectReasonCode::TopicAliasInvalid))
+    );
+    assert_eq!(sink.alias_table(), vec![(4, ByteString::from("test"))]);
+}
+
+#[ntex::test]
+async fn test_sni_filter() -> std::io::Result<()> {
+    // plain tcp connections do not have server name
+    let srv = server::test_server(|| {
+        MqttServer::new(|con: Handshake<_>| {
+            assert_eq!(con.sni_hostname(), None);
+            ok::<_, TestError>(con.ack(St))
+        })
+        .sni_filter(|name| name.is_none())
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+    let client = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(client.is_ok());
+
+    // rejected connection is closed before handshake
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .sni_filter(|name| name == Some("tenant1.example.com"))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+    let err = client::MqttConnector::new(srv.addr()).client_id("user").connect().await;
+    assert!(matches!(
+        err,
+        Err(error::ClientError::Disconnected)
+            | Err(error::ClientError::Protocol(error::ProtocolError::Io(_)))
+    ));
+
+    Ok(())
+}
+
+const STALLED_PUBLISHES: u32 = 400;
+
+/// Every 50th publish defines topic alias
+const ALIAS_INTERVAL: u32 = 50;
+
+fn outbound_queue_server(policy: OverflowPolicy) -> server::TestServer {
+    outbound_queue_server_with(policy, true, false).0
+}
+
+/// Returns test server and number of publishes that failed with `Dropped` error
+fn outbound_queue_server_with(
+    policy: OverflowPolicy,
+    wait_ready: bool,
+    alias: bool,
+) -> (server::TestServer, Arc<AtomicUsize>) {
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let dropped2 = dropped.clone();
+    let srv = server::test_server(move || {
+        let dropped = dropped2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let sink = con.sink();
+            let dropped = dropped.clone();
+            ntex::rt::spawn(async move {
+                sleep(Millis(50)).await;
+                for idx in 0..STALLED_PUBLISHES {
+                    if wait_ready && !sink.ready().await {
+                        break;
+                    }
+                    let mut payload = vec![0; 64 * 1024];
+                    payload[..4].copy_from_slice(&idx.to_be_bytes());
+                    let res = sink
+                        .publish("test", payload)
+                        .properties(|props| {
+                            if alias && idx % ALIAS_INTERVAL == 0 {
+                                props.topic_alias = NonZeroU16::new(1);
+                            }
+                        })
+                        .send_at_most_once();
+                    if res == Err(error::SendPacketError::Dropped) {
+                        dropped.fetch_add(1, Relaxed);
+                    }
+                }
+            });
+            ok::<_, TestError>(con.ack(St))
+        })
+        .outbound_queue(4, policy)
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+    (srv, dropped)
+}
+
+/// Subscriber that does not read for a while, returns indexes of received
+/// publishes and reason code of received DISCONNECT
+async fn stalled_subscriber(
+    srv: &server::TestServer,
+) -> (Vec<u32>, Option<codec::DisconnectReasonCode>) {
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    let mut connect = codec::Connect::default().client_id("user");
+    connect.topic_alias_max = 1;
+    framed.send(codec::Packet::Connect(Box::new(connect))).await.unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    sleep(Millis(500)).await;
+
+    let mut received = Vec::new();
+    loop {
+        match ntex::time::timeout(Millis(500), framed.next()).await {
+            Ok(Some(Ok(codec::Packet::Publish(pkt)))) => {
+                received.push(u32::from_be_bytes(pkt.payload[..4].try_into().unwrap()))
+            }
+            Ok(Some(Ok(codec::Packet::Disconnect(pkt)))) => {
+                return (received, Some(pkt.reason_code))
+            }
+            _ => return (received, None),
+        }
+    }
+}
+
+#[ntex::test]
+async fn test_outbound_queue_backpressure() {
+    let srv = outbound_queue_server(OverflowPolicy::Backpressure);
+    let (received, disconnect) = stalled_subscriber(&srv).await;
+    assert_eq!(received, (0..STALLED_PUBLISHES).collect::<Vec<_>>());
+    assert_eq!(disconnect, None);
+}
+
+#[ntex::test]
+async fn test_outbound_queue_backpressure_not_ready() {
+    // publisher does not wait for readiness, queue is still bounded
+    let (srv, dropped) = outbound_queue_server_with(OverflowPolicy::Backpressure, false, false);
+    let (received, disconnect) = stalled_subscriber(&srv).await;
+    assert!(received.len() < STALLED_PUBLISHES as usize);
+    assert_eq!(received, (0..received.len() as u32).collect::<Vec<_>>());
+    assert_eq!(dropped.load(Relaxed), STALLED_PUBLISHES as usize - received.len());
+    assert_eq!(disconnect, None);
+}
+
+#[ntex::test]
+async fn test_outbound_queue_keeps_alias() {
+    for policy in &[OverflowPolicy::DropOldest, OverflowPolicy::DropNewest] {
+        let (srv, _) = outbound_queue_server_with(*policy, true, true);
+        let (received, disconnect) = stalled_subscriber(&srv).await;
+        assert!(received.len() < STALLED_PUBLISHES as usize);
+        for idx in (0..STALLED_PUBLISHES).step_by(ALIAS_INTERVAL as usize) {
+            assert!(received.contains(&idx), "{:?} dropped alias definition {}", policy, idx);
+        }
+        assert_eq!(disconnect, None);
+    }
+}
+
+#[ntex::test]
+async fn test_outbound_queue_drop_oldest() {
+    let srv = outbound_queue_server(OverflowPolicy::DropOldest);
+    let (received, disconnect) = stalled_subscriber(&srv).await;
+    assert!(received.len() < STALLED_PUBLISHES as usize);
+    assert!(received.windows(2).all(|w| w[0] < w[1]));
+    assert!(received.ends_with(&[396, 397, 398, 399]));
+    assert_eq!(disconnect, None);
+}
+
+#[ntex::test]
+async fn test_outbound_queue_drop_newest() {
+    let (srv, dropped) = outbound_queue_server_with(OverflowPolicy::DropNewest, true, false);
+    let (received, disconnect) = stalled_subscriber(&srv).await;
+    assert!(received.len() < STALLED_PUBLISHES as usize);
+    assert_eq!(received, (0..received.len() as u32).collect::<Vec<_>>());
+    assert_eq!(dropped.load(Relaxed), STALLED_PUBLISHES as usize - received.len());
+    assert_eq!(disconnect, None);
+}
+
+#[ntex::test]
+async fn test_outbound_queue_disconnect() {
+    let srv = outbound_queue_server(OverflowPolicy::Disconnect);
+    let (received, disconnect) = stalled_subscriber(&srv).await;
+    assert!(received.len() < STALLED_PUBLISHES as usize);
+    assert_eq!(received, (0..received.len() as u32).collect::<Vec<_>>());
+    assert_eq!(disconnect, Some(codec::DisconnectReasonCode::QuotaExceeded));
+}
+
+#[ntex::test]
+async fn test_max_distinct_topics() -> std::io::Result<()> {
+    let topics = Arc::new(AtomicUsize::new(0));
+    let topics2 = topics.clone();
+    let srv = server::test_server(move || {
+        let topics = topics2.clone();
+        MqttServer::new(|con: Handshake<_>| async move { Ok(con.ack(St)) })
+            .max_distinct_topics(2)
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let topics = topics.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    topics.store(session.sink().distinct_topics(), Relaxed);
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    for (id, topic) in ["topic1", "topic2", "topic1"].iter().enumerate() {
+        let mut pkt = pkt_publish();
+        pkt.topic = ByteString::from_static(topic);
+        pkt.packet_id = NonZeroU16::new(id as u16 + 1);
+        framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+        let pkt = framed.next().await.unwrap().unwrap();
+        assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+    }
+    assert_eq!(topics.load(Relaxed), 2);
+
+    // third distinct topic closes connection
+    let mut pkt = pkt_publish();
+    pkt.topic = ByteString::from_static("topic3");
+    pkt.packet_id = NonZeroU16::new(4);
+    framed.send(codec::Packet::Publish(pkt)).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::QuotaExceeded
+        ))
+    );
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_close_handle() -> std::io::Result<()> {
+    let store: Arc<Mutex<Option<(CloseHandle, CloseHandle)>>> = Arc::default();
+    let store2 = store.clone();
+    let srv = server::test_server(move || {
+        let store = store2.clone();
+        MqttServer::new(move |con: Handshake<_>| {
+            let sink = con.sink();
+            *store.lock().unwrap() = Some((sink.close_handle(), sink.close_handle()));
+            ok::<_, TestError>(con.ack(St))
+        })
+        .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+        .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    // server runs in other thread
+    let (handle, handle2) = store.lock().unwrap().take().unwrap();
+    assert!(handle.close(codec::DisconnectReasonCode::AdministrativeAction));
+    assert!(!handle.close(codec::DisconnectReasonCode::ServerShuttingDown));
+    // both calls return the same handle
+    assert!(!handle2.close(codec::DisconnectReasonCode::ServerShuttingDown));
+
+    let pkt = ntex::time::timeout(Millis(1000), framed.next()).await.unwrap();
+    assert!(matches!(
+        pkt,
+        Some(Ok(codec::Packet::Disconnect(codec::Disconnect {
+            reason_code: codec::DisconnectReasonCode::AdministrativeAction,
+            ..
+        })))
+    ));
+    sleep(Millis(100)).await;
+    assert!(handle.is_closed());
+    assert!(handle2.is_closed());
+
+    // connection is closed by peer, handle does nothing
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    let (handle, _) = store.lock().unwrap().take().unwrap();
+    drop(framed);
+    sleep(Millis(100)).await;
+    assert!(handle.is_closed());
+    assert!(!handle.close(codec::DisconnectReasonCode::AdministrativeAction));
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_request_mux() {
+    let srv = server::test_server(|| {
+        ntex::service::fn_service(|io: ntex::rt::net::TcpStream| async move {
+            let mut framed = Framed::new(io, codec::Codec::default());
+            let _ = framed.next().await.unwrap().unwrap();
+            framed
+                .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                .await
+                .unwrap();
+
+            if let codec::Packet::Subscribe(pkt) = framed.next().await.unwrap().unwrap() {
+                assert_eq!(pkt.topic_filters[0].0, "resp");
+                framed
+                    .send(codec::Packet::SubscribeAck(codec::SubscribeAck {
+                        packet_id: pkt.packet_id,
+                        status: vec![codec::SubscribeAckReason::GrantedQos1],
+                        properties: Default::default(),
+                        reason_string: None,
+                    }))
+                    .await
+                    .unwrap();
+            } else {
+                panic!("Expected SUBSCRIBE");
+            }
+
+            let mut requests = Vec::new();
+            for _ in 0..3 {
+                if let codec::Packet::Publish(pkt) = framed.next().await.unwrap().unwrap() {
+                    assert_eq!(pkt.topic, "req");
+                    assert_eq!(pkt.properties.response_topic.as_deref(), Some("resp"));
+                    requests.push(pkt);
+                } else {
+                    panic!("Expected PUBLISH");
+                }
+            }
+            let response = |req: &codec::Publish| {
+                let mut payload = b"resp-".to_vec();
+                payload.extend_from_slice(&req.payload);
+                let mut pkt = codec::Publish {
+                    dup: false,
+                    retain: false,
+                    qos: codec::QoS::AtMostOnce,
+                    topic: req.properties.response_topic.clone().unwrap(),
+                    packet_id: None,
+                    payload: Bytes::from(payload),
+                    properties: Default::default(),
+                };
+                pkt.properties.correlation_data = req.properties.correlation_data.clone();
+                codec::Packet::Publish(pkt)
+            };
+
+            // out of order responses, third request is answered too late
+            framed.send(response(&requests[1])).await.unwrap();
+            framed.send(response(&requests[0])).await.unwrap();
+            sleep(Millis(300)).await;
+            framed.send(response(&requests[2])).await.unwrap();
+            sleep(Millis(100)).await;
+            Ok::<_, ()>(())
+        })
+    });
+
+    let client =
+        client::MqttConnector::new(srv.addr()).client_id("user").connect().await.unwrap();
+    let fut = client.request_mux(ByteString::from_static("resp"));
+    ntex::rt::spawn(client.start_default());
+    let mux = fut.await.unwrap();
+
+    let (r1, r2, r3) = futures::future::join3(
+        mux.request("req", Bytes::from_static(b"1"), Millis(1000)),
+        mux.request("req", Bytes::from_static(b"2"), Millis(1000)),
+        mux.request("req", Bytes::from_static(b"3"), Millis(100)),
+    )
+    .await;
+    assert_eq!(r1.unwrap().payload(), &Bytes::from_static(b"resp-1"));
+    assert_eq!(r2.unwrap().payload(), &Bytes::from_static(b"resp-2"));
+    assert_eq!(r3.unwrap_err(), error::RequestError::Timeout);
+    assert_eq!(mux.pending(), 0);
+
+    // dropped request is forgotten
+    let fut = mux.request("req", Bytes::from_static(b"4"), Millis(1000));
+    assert_eq!(mux.pending(), 1);
+    drop(fut);
+    assert_eq!(mux.pending(), 0);
+
+    // late response is dropped
+    sleep(Millis(400)).await;
+    assert_eq!(mux.pending(), 0);
+}
+
+#[ntex::test]
+async fn test_publish_before_connack() {
+    let checked = Arc::new(AtomicUsize::new(0));
+    let checked2 = checked.clone();
+    let srv = server::test_server(move || {
+        let checked = checked2.clone();
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let checked = checked.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                let pkt = framed.next().await.unwrap().unwrap();
+                let disconnect = if let codec::Packet::Connect(pkt) = pkt {
+                    pkt.client_id == "disconnect"
+                } else {
+                    panic!("Expected CONNECT")
+                };
+                framed
+                    .send(codec::Packet::Publish(codec::Publish {
+                        dup: false,
+                        retain: false,
+                        qos: codec::QoS::AtMostOnce,
+                        topic: ByteString::from_static("test"),
+                        packet_id: None,
+                        payload: Bytes::new(),
+                        properties: Default::default(),
+                    }))
+                    .await
+                    .unwrap();
+
+                let pkt = framed.next().await;
+                if disconnect {
+                    assert_eq!(
+                        pkt.unwrap().unwrap(),
+                        codec::Packet::Disconnect(codec::Disconnect::new(
+                            codec::DisconnectReasonCode::ProtocolError
+                        ))
+                    );
+                } else {
+                    assert!(pkt.is_none());
+                }
+                checked.fetch_add(1, Relaxed);
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("disconnect")
+        .connect()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, error::ClientError::UnexpectedPacket(0x30)));
+
+    let err = client::MqttConnector::new(srv.addr())
+        .client_id("silent")
+        .disconnect_on_unexpected_packet(false)
+        .connect()
+        .await
+        .unwrap_err();
+    assert!(matches!(err, error::ClientError::UnexpectedPacket(0x30)));
+    sleep(Millis(100)).await;
+    assert_eq!(checked.load(Relaxed), 2);
+}
+
+/// Parse Prometheus text format, every sample must follow its TYPE line
+#[cfg(feature = "prometheus")]
+fn parse_metrics(text: &str) -> std::collections::HashMap<String, u64> {
+    let mut samples = std::collections::HashMap::new();
+    let mut family = None;
+    for line in text.lines() {
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            assert!(rest.split_once(' ').is_some(), "Invalid HELP line: {}", line);
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, kind) = rest.split_once(' ').unwrap();
+            assert!(kind == "counter" || kind == "gauge", "Invalid TYPE line: {}", line);
+            family = Some(name.to_string());
+        } else {
+            let (key, value) = line.rsplit_once(' ').unwrap();
+            let name = key.split('{').next().unwrap();
+            assert_eq!(Some(name), family.as_deref(), "Sample without TYPE: {}", line);
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+            if let Some(labels) = key.strip_prefix(name) {
+                if !labels.is_empty() {
+                    assert!(labels.starts_with('{') && labels.ends_with('}'));
+                    let (label, value) = labels[1..labels.len() - 1].split_once('=').unwrap();
+                    assert!(label.chars().all(|c| c.is_ascii_lowercase()));
+                    assert!(value.starts_with('"') && value.ends_with('"'));
+                }
+            }
+            samples.insert(key.to_string(), value.parse::<u64>().unwrap());
+        }
+    }
+    samples
+}
+
+#[cfg(feature = "prometheus")]
+#[ntex::test]
+async fn test_prometheus_metrics() -> std::io::Result<()> {
+    let metrics = ntex_mqtt::v5::Metrics::new();
+    let metrics2 = metrics.clone();
+    let conn_text = Arc::new(Mutex::new(String::new()));
+    let conn_text2 = conn_text.clone();
+    let srv = server::test_server(move || {
+        let conn_text = conn_text2.clone();
+        MqttServer::new(handshake)
+            .metrics(metrics2.clone())
+            .publish(ntex::service::fn_factory_with_config(move |session: Session<St>| {
+                let conn_text = conn_text.clone();
+                ok::<_, TestError>(ntex::service::fn_service(move |p: Publish| {
+                    *conn_text.lock().unwrap() = session.sink().metrics_text();
+                    ok::<_, TestError>(p.ack())
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    framed.send(codec::Packet::Publish(pkt_publish())).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::PublishAck(_)));
+
+    let samples = parse_metrics(&metrics.metrics_text());
+    assert_eq!(samples["mqtt_connections_total"], 1);
+    assert_eq!(samples["mqtt_connections"], 1);
+    assert_eq!(samples["mqtt_packets_received_total{type=\"connect\"}"], 1);
+    assert_eq!(samples["mqtt_packets_received_total{type=\"publish\"}"], 1);
+    assert_eq!(samples["mqtt_packets_sent_total{type=\"connack\"}"], 1);
+    assert_eq!(samples["mqtt_packets_sent_total{type=\"puback\"}"], 1);
+    assert!(samples["mqtt_bytes_in_total"] > 0);
+    assert!(samples["mqtt_bytes_out_total"] > 0);
+    assert_eq!(samples["mqtt_inflight"], 0);
+
+    // connection counters, publish is not acked yet
+    let samples = parse_metrics(&conn_text.lock().unwrap());
+    assert_eq!(samples["mqtt_packets_received_total{type=\"publish\"}"], 1);
+    assert_eq!(samples["mqtt_packets_sent_total{type=\"puback\"}"], 0);
+    assert!(!samples.contains_key("mqtt_connections"));
+
+    drop(framed);
+    sleep(Millis(100)).await;
+    let samples = parse_metrics(&metrics.metrics_text());
+    assert_eq!(samples["mqtt_connections_total"], 1);
+    assert_eq!(samples["mqtt_connections"], 0);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_connection_age() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .max_connection_age(Seconds(1))
+            .publish(|p: Publish| ok::<_, TestError>(p.ack()))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::default());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+    let start = Instant::now();
+
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::MaximumConnectTime
+        ))
+    );
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    assert!(framed.next().await.is_none());
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_client_connection_ttl() {
+    let received = Arc::new(Mutex::new(None));
+    let received2 = received.clone();
+    let srv = server::test_server(move || {
+        let received = received2.clone();
+        ntex::service::fn_service(move |io: ntex::rt::net::TcpStream| {
+            let received = received.clone();
+            async move {
+                let mut framed = Framed::new(io, codec::Codec::default());
+                let _ = framed.next().await.unwrap().unwrap();
+                framed
+                    .send(codec::Packet::ConnectAck(Box::new(codec::ConnectAck::default())))
+                    .await
+                    .unwrap();
+                let start = Instant::now();
+                let pkt = framed.next().await.unwrap().unwrap();
+                *received.lock().unwrap() = Some((pkt, start.elapsed()));
+                Ok::<_, ()>(())
+            }
+        })
+    });
+
+    let client = client::MqttConnector::new(srv.addr())
+        .client_id("user")
+        .keep_alive(Seconds(10))
+        .connection_ttl(Seconds(1))
+        .connect()
+        .await
+        .unwrap();
+    let sink = client.sink();
+    ntex::rt::spawn(client.start_default());
+    assert!(sink.is_open());
+
+    sleep(Millis(1500)).await;
+    assert!(!sink.is_open());
+    let (pkt, elapsed) = received.lock().unwrap().take().unwrap();
+    assert_eq!(
+        pkt,
+        codec::Packet::Disconnect(codec::Disconnect::new(
+            codec::DisconnectReasonCode::NormalDisconnection
+        ))
+    );
+    assert!(elapsed >= Duration::from_millis(900));
+}
+
+#[ntex::test]
+async fn test_deliver_retained() -> std::io::Result<()> {
+    let srv = server::test_server(|| {
+        MqttServer::new(handshake)
+            .control(ntex::service::fn_factory_with_config(|session: Session<St>| {
+                let subscribed = Rc::new(RefCell::new(Vec::<ByteString>::new()));
+                ok::<_, TestError>(ntex::service::fn_service(move |msg| match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        let mut subs = Vec::new();
+                        for mut sub in msg.iter_mut() {
+                            sub.confirm(sub.options().qos);
+                            let topic = sub.topic().clone();
+                            let new = !subscribed.borrow().contains(&topic);
+                            if new {
+                                subscribed.borrow_mut().push(topic.clone());
+                            }
+                            subs.push((topic, sub.options().clone(), new));
+                        }
+                        let session = session.clone();
+                        ntex::rt::spawn(async move {
+                            for (topic, opts, new) in subs {
+                                let mut pkt = pkt_publish();
+                                pkt.topic = topic;
+                                pkt.payload = Bytes::from_static(b"retained");
+                                session.deliver_retained(&opts, new, vec![pkt]);
+                            }
+                        });
+                        ok::<_, TestError>(msg.ack())
+                    }
+                    ControlMessage::Ping(msg) => ok(msg.ack()),
+                    _ => ok(msg.disconnect()),
+                }))
+            }))
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let subscribe = |id, topic, qos, retain_handling| {
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            topic_filters: vec![(
+                ByteString::from_static(topic),
+                codec::SubscriptionOptions {
+                    qos,
+                    no_local: false,
+                    retain_as_published: false,
+                    retain_handling,
+                },
+            )],
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        })
+    };
+
+    // always sent, qos is kept
+    framed
+        .send(subscribe(
+            1,
+            "topic1",
+            codec::QoS::AtLeastOnce,
+            codec::RetainHandling::AtSubscribe,
+        ))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(ref ack) if ack.packet_id.get() == 1));
+    let pkt = framed.next().await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "topic1");
+        assert!(pkt.retain);
+        assert_eq!(pkt.qos, codec::QoS::AtLeastOnce);
+        framed
+            .send(codec::Packet::PublishAck(codec::PublishAck {
+                packet_id: pkt.packet_id.unwrap(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+    } else {
+        panic!("Expected PUBLISH");
+    }
+
+    // existing subscription, not sent
+    framed
+        .send(subscribe(
+            2,
+            "topic1",
+            codec::QoS::AtLeastOnce,
+            codec::RetainHandling::AtSubscribeNew,
+        ))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(ref ack) if ack.packet_id.get() == 2));
+
+    // new subscription, qos is downgraded
+    framed
+        .send(subscribe(
+            3,
+            "topic2",
+            codec::QoS::AtMostOnce,
+            codec::RetainHandling::AtSubscribeNew,
+        ))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(ref ack) if ack.packet_id.get() == 3));
+    let pkt = framed.next().await.unwrap().unwrap();
+    if let codec::Packet::Publish(pkt) = pkt {
+        assert_eq!(pkt.topic, "topic2");
+        assert!(pkt.retain);
+        assert_eq!(pkt.qos, codec::QoS::AtMostOnce);
+        assert_eq!(pkt.packet_id, None);
+    } else {
+        panic!("Expected PUBLISH");
+    }
+
+    // never sent
+    framed
+        .send(subscribe(
+            4,
+            "topic3",
+            codec::QoS::AtLeastOnce,
+            codec::RetainHandling::NoAtSubscribe,
+        ))
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::SubscribeAck(ref ack) if ack.packet_id.get() == 4));
+    framed.send(codec::Packet::PingRequest).await.unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert_eq!(pkt, codec::Packet::PingResponse);
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_subscriptions_pipelined() -> std::io::Result<()> {
+    // control service acks subscribes with delay, "x" filter is rejected
+    let srv = server::test_server(move || {
+        MqttServer::new(handshake)
+            .max_subscriptions_per_session(2)
+            .control(move |msg| async move {
+                match msg {
+                    ControlMessage::Subscribe(mut msg) => {
+                        for mut sub in &mut msg {
+                            if sub.topic() != "x" {
+                                sub.confirm(codec::QoS::AtLeastOnce);
+                            }
+                        }
+                        sleep(Millis(50)).await;
+                        Ok::<_, TestError>(msg.ack())
+                    }
+                    _ => Ok(msg.disconnect()),
+                }
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let subscribe = |id, filters: &[&'static str]| {
+        let mut pkt = codec::Subscribe {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            topic_filters: Vec::new(),
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        };
+        for f in filters {
+            let opts = codec::SubscriptionOptions {
+                qos: codec::QoS::AtLeastOnce,
+                no_local: false,
+                retain_as_published: false,
+                retain_handling: codec::RetainHandling::AtSubscribe,
+            };
+            pkt.topic_filters.push((ByteString::from_static(f), opts));
+        }
+        codec::Packet::Subscribe(pkt)
+    };
+    let status = |pkt| match pkt {
+        codec::Packet::SubscribeAck(ack) => ack.status,
+        pkt => panic!("Expected SUBACK, got {:?}", pkt),
+    };
+
+    // second packet is checked before first one is acked
+    framed.send(subscribe(1, &["x", "a"])).await.unwrap();
+    framed.send(subscribe(2, &["b"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![
+            codec::SubscribeAckReason::UnspecifiedError,
+            codec::SubscribeAckReason::GrantedQos1
+        ]
+    );
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![codec::SubscribeAckReason::QuotaExceeded]
+    );
+
+    // filter rejected by control service is released
+    framed.send(subscribe(3, &["b"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![codec::SubscribeAckReason::GrantedQos1]
+    );
+    framed.send(subscribe(4, &["c"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![codec::SubscribeAckReason::QuotaExceeded]
+    );
+
+    Ok(())
+}
+
+#[ntex::test]
+async fn test_max_subscriptions_per_session() -> std::io::Result<()> {
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let calls2 = calls.clone();
+    let srv = server::test_server(move || {
+        let calls = calls2.clone();
+        MqttServer::new(handshake)
+            .max_subscriptions_per_session(2)
+            .control(move |msg| match msg {
+                ControlMessage::Subscribe(mut msg) => {
+                    for mut sub in &mut msg {
+                        calls.lock().unwrap().push(sub.topic().to_string());
+                        sub.confirm(codec::QoS::AtLeastOnce);
+                    }
+                    ok::<_, TestError>(msg.ack())
+                }
+                ControlMessage::Unsubscribe(msg) => ok(msg.ack()),
+                _ => ok(msg.disconnect()),
+            })
+            .finish()
+    });
+
+    let io = srv.connect().await.unwrap();
+    let mut framed = Framed::new(io, codec::Codec::new());
+    framed
+        .send(codec::Packet::Connect(Box::new(codec::Connect::default().client_id("user"))))
+        .await
+        .unwrap();
+    let _ = framed.next().await.unwrap().unwrap();
+
+    let opts = codec::SubscriptionOptions {
+        qos: codec::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_handling: codec::RetainHandling::AtSubscribe,
+    };
+    let subscribe = |id, filters: &[&'static str]| {
+        codec::Packet::Subscribe(codec::Subscribe {
+            packet_id: NonZeroU16::new(id).unwrap(),
+            topic_filters: filters
+                .iter()
+                .map(|f| (ByteString::from_static(f), opts.clone()))
+                .collect(),
+            id: None,
+            user_properties: codec::UserProperties::default(),
+        })
+    };
+    let status = |pkt| match pkt {
+        codec::Packet::SubscribeAck(ack) => ack.status,
+        pkt => panic!("Expected SUBACK, got {:?}", pkt),
+    };
+
+    // overflow within one packet
+    framed.send(subscribe(1, &["a", "b", "c"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![
+            codec::SubscribeAckReason::GrantedQos1,
+            codec::SubscribeAckReason::GrantedQos1,
+            codec::SubscribeAckReason::QuotaExceeded
+        ]
+    );
+    assert_eq!(*calls.lock().unwrap(), vec!["a", "b"]);
+
+    // overflow across packets, active filter is not counted
+    framed.send(subscribe(2, &["a", "d"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![codec::SubscribeAckReason::GrantedQos1, codec::SubscribeAckReason::QuotaExceeded]
+    );
+
+    // unsubscribe frees slot
+    framed
+        .send(
+            codec::Unsubscribe {
+                packet_id: NonZeroU16::new(3).unwrap(),
+                user_properties: Default::default(),
+                topic_filters: vec![ByteString::from("b")],
+            }
+            .into(),
+        )
+        .await
+        .unwrap();
+    let pkt = framed.next().await.unwrap().unwrap();
+    assert!(matches!(pkt, codec::Packet::UnsubscribeAck(_)));
+    framed.send(subscribe(4, &["d"])).await.unwrap();
+    assert_eq!(
+        status(framed.next().await.unwrap().unwrap()),
+        vec![codec::SubscribeAckReason::GrantedQos1]
+    );
+    assert_eq!(*calls.lock().unwrap(), vec!["a", "b", "a", "d"]);
+
+    Ok(())
+}
@@ -0,0 +1,373 @@
+#![cfg(any(feature = "openssl", feature = "rustls"))]
+use std::{convert::TryFrom, net::SocketAddr};
+
+use ntex::connect::Address;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use openssl::x509::{X509NameBuilder, X509};
+
+use ntex_mqtt::v5::PublishAck;
+
+#[derive(Debug)]
+struct TestError;
+
+impl From<()> for TestError {
+    fn from(_: ()) -> Self {
+        TestError
+    }
+}
+
+impl TryFrom<TestError> for PublishAck {
+    type Error = TestError;
+
+    fn try_from(err: TestError) -> Result<Self, Self::Error> {
+        Err(err)
+    }
+}
+
+/// Test server address, tls connectors verify `localhost` server name
+#[derive(Clone)]
+struct Localhost(SocketAddr);
+
+impl Address for Localhost {
+    fn host(&self) -> &str {
+        "localhost"
+    }
+
+    fn port(&self) -> Option<u16> {
+        Some(self.0.port())
+    }
+
+    fn addr(&self) -> Option<SocketAddr> {
+        Some(self.0)
+    }
+}
+
+/// Certificate and private key
+struct Identity {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Usage {
+    Ca,
+    Server,
+    Client,
+}
+
+/// Generate `localhost` certificate, issued by `issuer` or self-signed
+fn identity(name: &str, usage: Usage, issuer: Option<&Identity>) -> Identity {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap();
+    let subject = subject.build();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder.set_serial_number(&serial.to_asn1_integer().unwrap()).unwrap();
+    builder.set_subject_name(&subject).unwrap();
+    builder.set_issuer_name(issuer.map(|i| i.cert.subject_name()).unwrap_or(&subject)).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    builder.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+
+    if usage == Usage::Ca {
+        let constraints = BasicConstraints::new().critical().ca().build().unwrap();
+        builder.append_extension(constraints).unwrap();
+        builder.append_extension(KeyUsage::new().key_cert_sign().build().unwrap()).unwrap();
+    } else {
+        let ext_usage = if usage == Usage::Server {
+            ExtendedKeyUsage::new().server_auth().build().unwrap()
+        } else {
+            ExtendedKeyUsage::new().client_auth().build().unwrap()
+        };
+        let san = SubjectAlternativeName::new()
+            .dns("localhost")
+            .build(&builder.x509v3_context(issuer.map(|i| i.cert.as_ref()), None))
+            .unwrap();
+        builder.append_extension(KeyUsage::new().digital_signature().build().unwrap()).unwrap();
+        builder.append_extension(ext_usage).unwrap();
+        builder.append_extension(san).unwrap();
+    }
+
+    let sign_key = issuer.map(|i| &i.key).unwrap_or(&key);
+    builder.sign(sign_key, MessageDigest::sha256()).unwrap();
+    Identity { cert: builder.build(), key }
+}
+
+#[cfg(feature = "rustls")]
+mod rustls_tls {
+    use std::sync::{atomic::AtomicUsize, atomic::Ordering::Relaxed, Arc};
+
+    use futures::future::ok;
+    use ntex::rt::net::TcpStream;
+    use ntex::server::{self, rustls::Acceptor, rustls::TlsStream};
+    use ntex::service::{pipeline_factory, ServiceFactory};
+    use rustls::server::AllowAnyAuthenticatedClient;
+    use rustls::{client::ResolvesClientCert, sign, Certificate, PrivateKey, SignatureScheme};
+    use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+    use ntex_mqtt::{error::MqttError, v5::client, v5::codec, v5::Handshake, v5::MqttServer};
+
+    use super::*;
+
+    fn certificate(identity: &Identity) -> Certificate {
+        Certificate(identity.cert.to_der().unwrap())
+    }
+
+    fn private_key(identity: &Identity) -> PrivateKey {
+        PrivateKey(identity.key.private_key_to_pkcs8().unwrap())
+    }
+
+    fn roots(ca: &Identity) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(&certificate(ca)).unwrap();
+        roots
+    }
+
+    /// Resolver that counts certificate requests
+    struct Resolver {
+        key: Arc<sign::CertifiedKey>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl ResolvesClientCert for Resolver {
+        fn resolve(
+            &self,
+            _: &[&[u8]],
+            _: &[SignatureScheme],
+        ) -> Option<Arc<sign::CertifiedKey>> {
+            self.calls.fetch_add(1, Relaxed);
+            Some(self.key.clone())
+        }
+
+        fn has_certs(&self) -> bool {
+            true
+        }
+    }
+
+    #[ntex::test]
+    async fn test_client_cert_resolver() {
+        let ca = identity("ca", Usage::Ca, None);
+        let server_id = identity("localhost", Usage::Server, Some(&ca));
+        let client_id = identity("device", Usage::Client, Some(&ca));
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots(&ca)))
+            .with_single_cert(vec![certificate(&server_id)], private_key(&server_id))
+            .unwrap();
+        let srv = server::test_server(move || {
+            pipeline_factory(Acceptor::new(config.clone()))
+                .map_err(|_| MqttError::Service(TestError))
+                .and_then(
+                    MqttServer::new(|mut con: Handshake<TlsStream<TcpStream>>| {
+                        // client is authenticated with resolved certificate
+                        let certs = con.io().get_ref().1.peer_certificates().map(|c| c.len());
+                        if certs == Some(1) {
+                            ok::<_, TestError>(con.ack(()))
+                        } else {
+                            ok(con.failed(codec::ConnectAckReason::NotAuthorized))
+                        }
+                    })
+                    .finish()
+                    .map_init_err(|_| ()),
+                )
+        });
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let key = sign::any_supported_type(&private_key(&client_id)).unwrap();
+        let resolver = Resolver {
+            key: Arc::new(sign::CertifiedKey::new(vec![certificate(&client_id)], key)),
+            calls: calls.clone(),
+        };
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots(&ca))
+            .with_no_client_auth();
+
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .client_cert_resolver(config, resolver)
+            .connect()
+            .await;
+        assert!(client.is_ok());
+        assert_eq!(calls.load(Relaxed), 1);
+    }
+
+    #[ntex::test]
+    async fn test_sni_filter() {
+        let ca = identity("ca", Usage::Ca, None);
+        let server_id = identity("localhost", Usage::Server, Some(&ca));
+
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate(&server_id)], private_key(&server_id))
+            .unwrap();
+        let server = |name: &'static str| {
+            let config = config.clone();
+            server::test_server(move || {
+                pipeline_factory(Acceptor::new(config.clone()))
+                    .map_err(|_| MqttError::Service(TestError))
+                    .and_then(
+                        MqttServer::new(|con: Handshake<TlsStream<TcpStream>>| {
+                            ok::<_, TestError>(con.ack(()))
+                        })
+                        .sni_filter(move |sni| sni == Some(name))
+                        .finish()
+                        .map_init_err(|_| ()),
+                    )
+            })
+        };
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots(&ca))
+            .with_no_client_auth();
+
+        let srv = server("localhost");
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .rustls(client_config.clone())
+            .connect()
+            .await;
+        assert!(client.is_ok());
+
+        let srv = server("tenant");
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .rustls(client_config)
+            .connect()
+            .await;
+        assert!(client.is_err());
+    }
+}
+
+#[cfg(feature = "openssl")]
+mod openssl_tls {
+    use futures::future::ok;
+    use ntex::rt::net::TcpStream;
+    use ntex::server::{self, openssl::Acceptor, openssl::SslStream};
+    use ntex::service::{pipeline_factory, ServiceFactory};
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVersion};
+
+    use ntex_mqtt::{error::MqttError, v5::client, v5::Handshake, v5::MqttServer};
+
+    use super::*;
+
+    const PSK_IDENTITY: &[u8] = b"device";
+    const PSK_KEY: &[u8] = b"0123456789abcdef";
+
+    fn psk_acceptor() -> SslAcceptor {
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_max_proto_version(Some(SslVersion::TLS1_2)).unwrap();
+        builder.set_cipher_list("PSK").unwrap();
+        builder.set_psk_server_callback(|_, identity, psk_buf| {
+            if identity == Some(PSK_IDENTITY) {
+                psk_buf[..PSK_KEY.len()].copy_from_slice(PSK_KEY);
+                Ok(PSK_KEY.len())
+            } else {
+                Ok(0)
+            }
+        });
+        builder.build()
+    }
+
+    fn psk_server() -> server::TestServer {
+        server::test_server(move || {
+            pipeline_factory(Acceptor::new(psk_acceptor()))
+                .map_err(|_| MqttError::Service(TestError))
+                .and_then(
+                    MqttServer::new(|con: Handshake<SslStream<TcpStream>>| {
+                        ok::<_, TestError>(con.ack(()))
+                    })
+                    .finish()
+                    .map_init_err(|_| ()),
+                )
+        })
+    }
+
+    #[ntex::test]
+    async fn test_tls_psk() {
+        let srv = psk_server();
+
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .tls_psk(PSK_IDENTITY, PSK_KEY)
+            .unwrap()
+            .connect()
+            .await;
+        assert!(client.is_ok());
+
+        // wrong key fails handshake
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .tls_psk(PSK_IDENTITY, &b"fedcba9876543210"[..])
+            .unwrap()
+            .connect()
+            .await;
+        assert!(client.is_err());
+
+        let res = client::MqttConnector::new(Localhost(srv.addr())).tls_psk(&b""[..], PSK_KEY);
+        assert_eq!(res.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[ntex::test]
+    async fn test_sni_filter() {
+        let ca = identity("ca", Usage::Ca, None);
+        let server_id = identity("localhost", Usage::Server, Some(&ca));
+
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+        builder.set_certificate(&server_id.cert).unwrap();
+        builder.set_private_key(&server_id.key).unwrap();
+        let acceptor = builder.build();
+        let server = |name: &'static str| {
+            let acceptor = acceptor.clone();
+            server::test_server(move || {
+                pipeline_factory(Acceptor::new(acceptor.clone()))
+                    .map_err(|_| MqttError::Service(TestError))
+                    .and_then(
+                        MqttServer::new(|con: Handshake<SslStream<TcpStream>>| {
+                            ok::<_, TestError>(con.ack(()))
+                        })
+                        .sni_filter(move |sni| sni == Some(name))
+                        .finish()
+                        .map_init_err(|_| ()),
+                    )
+            })
+        };
+        let connector = || {
+            let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+            builder.cert_store_mut().add_cert(ca.cert.clone()).unwrap();
+            builder.build()
+        };
+
+        let srv = server("localhost");
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .openssl(connector())
+            .connect()
+            .await;
+        assert!(client.is_ok());
+
+        let srv = server("tenant");
+        let client = client::MqttConnector::new(Localhost(srv.addr()))
+            .client_id("device")
+            .openssl(connector())
+            .connect()
+            .await;
+        assert!(client.is_err());
+    }
+}